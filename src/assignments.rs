@@ -0,0 +1,363 @@
+// Modules for the reads fetched for the regions that are not counted in them, and for writing the reads back out
+// annotated with the regions they are counted in (--assignments-bam)
+use crate::data_loader::Region;
+use crate::error::KaiError;
+use rust_htslib::bam::{self, record::Aux, HeaderView, Read, Record};
+use std::collections::{HashMap, VecDeque};
+use std::io::Write;
+use std::ops::Index;
+
+// Reason a read fetched for a region is not counted in it, tallied per reason for the run summary, written with the
+// read to --unassigned-out and as its XS tag with --write-unassigned
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Unassigned {
+    Flags,
+    Secondary,
+    Supplementary,
+    ImproperPair,
+    OtherMate,
+    Duplicate,
+    LowMapq,
+    MissingNh,
+    Multimapping,
+    Splicing,
+    InsertSize,
+    ReadLength,
+    SoftClipped,
+    Blacklisted,
+    NoBarcode,
+    UnlistedBarcode,
+    WrongStrand,
+    MultipleRegions,
+    Ambiguous,
+    NoFeature,
+    LowOverlap,
+    Uncontained,
+    LowBaseQuality,
+    MissingBaseQuality,
+    MissingUmi,
+    UmiDuplicate,
+}
+
+impl Unassigned {
+    pub const ALL: [Unassigned; 26] = [
+        Unassigned::Flags,
+        Unassigned::Secondary,
+        Unassigned::Supplementary,
+        Unassigned::ImproperPair,
+        Unassigned::OtherMate,
+        Unassigned::Duplicate,
+        Unassigned::LowMapq,
+        Unassigned::MissingNh,
+        Unassigned::Multimapping,
+        Unassigned::Splicing,
+        Unassigned::InsertSize,
+        Unassigned::ReadLength,
+        Unassigned::SoftClipped,
+        Unassigned::Blacklisted,
+        Unassigned::NoBarcode,
+        Unassigned::UnlistedBarcode,
+        Unassigned::WrongStrand,
+        Unassigned::MultipleRegions,
+        Unassigned::Ambiguous,
+        Unassigned::NoFeature,
+        Unassigned::LowOverlap,
+        Unassigned::Uncontained,
+        Unassigned::LowBaseQuality,
+        Unassigned::MissingBaseQuality,
+        Unassigned::MissingUmi,
+        Unassigned::UmiDuplicate,
+    ];
+
+    // Reason codes follow the names of the skipped reads in summary.json
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Unassigned::Flags => "flags",
+            Unassigned::Secondary => "secondary",
+            Unassigned::Supplementary => "supplementary",
+            Unassigned::ImproperPair => "improper_pair",
+            Unassigned::OtherMate => "other_mate",
+            Unassigned::Duplicate => "duplicate",
+            Unassigned::LowMapq => "low_mapq",
+            Unassigned::MissingNh => "missing_nh",
+            Unassigned::Multimapping => "multimapped",
+            Unassigned::Splicing => "splicing",
+            Unassigned::InsertSize => "insert_size",
+            Unassigned::ReadLength => "read_length",
+            Unassigned::SoftClipped => "clipped",
+            Unassigned::Blacklisted => "blacklisted",
+            Unassigned::NoBarcode => "no_barcode",
+            Unassigned::UnlistedBarcode => "unlisted_barcode",
+            Unassigned::WrongStrand => "wrong_strand",
+            Unassigned::MultipleRegions => "multiple_regions",
+            Unassigned::Ambiguous => "ambiguous",
+            Unassigned::NoFeature => "no_feature",
+            Unassigned::LowOverlap => "low_overlap",
+            Unassigned::Uncontained => "not_contained",
+            Unassigned::LowBaseQuality => "low_baseq",
+            Unassigned::MissingBaseQuality => "missing_baseq",
+            Unassigned::MissingUmi => "missing_umi",
+            Unassigned::UmiDuplicate => "duplicate_umi",
+        }
+    }
+}
+
+// Reads not counted in a region, per reason
+#[derive(Clone, Default)]
+pub struct ReasonCounts([u64; Unassigned::ALL.len()]);
+
+impl ReasonCounts {
+    pub fn merge(&mut self, other: &ReasonCounts) {
+        for (total, other_total) in self.0.iter_mut().zip(other.0) {
+            *total += other_total;
+        }
+    }
+
+    // Function to iterate over the reasons in their order with their totals
+    pub fn iter(&self) -> impl Iterator<Item = (Unassigned, u64)> + '_ {
+        Unassigned::ALL.into_iter().zip(self.0.iter().copied())
+    }
+}
+
+impl Index<Unassigned> for ReasonCounts {
+    type Output = u64;
+
+    fn index(&self, reason: Unassigned) -> &u64 {
+        &self.0[reason as usize]
+    }
+}
+
+// Outcome of each read met in the regions, tallied once per read for the featureCounts summary: the reads counted in
+// at least one region, the reads never counted per the reason they are left out of their first region, the unmapped
+// reads never counted, and the mapped and unmapped reads met
+#[derive(Clone, Default)]
+pub struct ReadOutcomes {
+    pub assigned: u64,
+    pub unassigned: ReasonCounts,
+    pub unmapped: u64,
+    pub mapped_reads: u64,
+    pub unmapped_reads: u64,
+}
+
+impl ReadOutcomes {
+    pub fn merge(&mut self, other: &ReadOutcomes) {
+        self.assigned += other.assigned;
+        self.unassigned.merge(&other.unassigned);
+        self.unmapped += other.unmapped;
+        self.mapped_reads += other.mapped_reads;
+        self.unmapped_reads += other.unmapped_reads;
+    }
+}
+
+// Alignment identity: a read met in several regions is the same alignment when its name, position and flags match
+type AlignmentKey = (Vec<u8>, i32, i64, u16);
+
+// A read met in the regions counted so far: its identity and end, its record as read from the BAM file when it is written, the regions it is counted in, and the reason it is not counted in the first region that left it out
+struct Annotated {
+    key: AlignmentKey,
+    end: i64,
+    record: Option<Record>,
+    regions: Vec<String>,
+    reason: Option<Unassigned>,
+}
+
+// Outcomes of the reads of a counting group: the reads not counted per reason, their rows for --unassigned-out, and
+// with --assignments-bam or the featureCounts summary the reads met in the regions but not yet released, in the order
+// they are first met; a read is released once no later region of the group can fetch it, and groups never share reads
+pub struct Assignments<W> {
+    track_reads: bool,
+    write_unassigned: bool,
+    writer: Option<(String, bam::Writer)>,
+    pending: VecDeque<Annotated>,
+    // Number of reads released so far, which numbers the first pending read
+    released: usize,
+    index: HashMap<AlignmentKey, usize>,
+    counts: ReasonCounts,
+    outcomes: ReadOutcomes,
+    rows: Option<W>,
+    written: usize,
+    // Files written by the groups, copied in group order into the output when there are several
+    parts: Vec<String>,
+}
+
+impl<W> Default for Assignments<W> {
+    fn default() -> Self {
+        Assignments {
+            track_reads: false,
+            write_unassigned: false,
+            writer: None,
+            pending: VecDeque::new(),
+            released: 0,
+            index: HashMap::new(),
+            counts: ReasonCounts::default(),
+            outcomes: ReadOutcomes::default(),
+            rows: None,
+            written: 0,
+            parts: Vec::new(),
+        }
+    }
+}
+
+impl<W: Write> Assignments<W> {
+    // Function to follow the reads of a group, writing them to a BAM file at the given path when one is given and
+    // tallying their outcomes once per read if requested
+    pub fn new(writer: Option<(String, bam::Writer)>, track_reads: bool, write_unassigned: bool, rows: Option<W>) -> Self {
+        let track_reads = track_reads || writer.is_some();
+        Assignments { writer, track_reads, write_unassigned, rows, ..Default::default() }
+    }
+
+    fn entry(&mut self, record: &Record) -> &mut Annotated {
+        let key = (record.qname().to_vec(), record.tid(), record.pos(), record.flags());
+        let position = match self.index.get(&key) {
+            Some(&number) => number - self.released,
+            None => {
+                // Reads without reference-consuming operations cover their position, as for indexed fetches
+                let end = record.cigar_cached().map_or_else(|| record.cigar().end_pos(), |cigar| cigar.end_pos()).max(record.pos() + 1);
+                self.index.insert(key.clone(), self.released + self.pending.len());
+                let record = self.writer.is_some().then(|| record.clone());
+                self.pending.push_back(Annotated { key, end, record, regions: Vec::new(), reason: None });
+                self.pending.len() - 1
+            }
+        };
+        &mut self.pending[position]
+    }
+
+    // Function to record that a read is counted in a region
+    pub fn assign(&mut self, record: &Record, region: impl FnOnce() -> String) {
+        if !self.track_reads {
+            return;
+        }
+        let region = region();
+        let read = self.entry(record);
+        if !read.regions.contains(&region) {
+            read.regions.push(region);
+        }
+    }
+
+    // Function to record why a read is not counted in a region, keeping the first reason of the read for its XS tag
+    pub fn reject(&mut self, record: &Record, region: &Region, reason: Unassigned) -> std::io::Result<()> {
+        self.counts.0[reason as usize] += 1;
+        if let Some(rows) = &mut self.rows {
+            rows.write_all(record.qname())?;
+            writeln!(rows, "\t{}\t{}", region.key(), reason.as_str())?;
+        }
+        if self.track_reads {
+            self.entry(record).reason.get_or_insert(reason);
+        }
+        Ok(())
+    }
+
+    // Function to release the pending reads in the order they were first met, up to the first one that a later region
+    // may still fetch, given the contig and end of the read, tallying their outcomes and writing them to the BAM file.
+    // Counted reads get the comma-separated keys of their regions as XT:Z, replacing an XT tag of the aligner; with
+    // --write-unassigned, the reads never counted are written too, and every read gets XS:Z:Assigned or the reason it
+    // is left out of its first region as XS:Z
+    pub fn release(&mut self, done: impl Fn(i32, i64) -> bool) -> Result<(), KaiError> {
+        while self.pending.front().is_some_and(|read| done(read.key.1, read.end)) {
+            let read = self.pending.pop_front().unwrap();
+            self.index.remove(&read.key);
+            self.released += 1;
+            let unmapped = read.key.3 & 0x4 != 0;
+            if unmapped {
+                self.outcomes.unmapped_reads += 1;
+            } else {
+                self.outcomes.mapped_reads += 1;
+            }
+            if !read.regions.is_empty() {
+                self.outcomes.assigned += 1;
+            } else if unmapped {
+                self.outcomes.unmapped += 1;
+            } else {
+                // Reads are met either counted or with a reason
+                self.outcomes.unassigned.0[read.reason.unwrap_or(Unassigned::NoFeature) as usize] += 1;
+            }
+            let (Some((path, writer)), Some(mut record)) = (&mut self.writer, read.record) else { continue };
+            if read.regions.is_empty() && (!self.write_unassigned || read.reason.is_none()) {
+                continue;
+            }
+            let bam_error = |source| KaiError::BamWrite { path: path.clone(), source };
+            let _ = record.remove_aux(b"XT");
+            if self.write_unassigned {
+                let _ = record.remove_aux(b"XS");
+                let status = read.reason.filter(|_| read.regions.is_empty()).map_or("Assigned", Unassigned::as_str);
+                record.push_aux(b"XS", Aux::String(status)).map_err(bam_error)?;
+            }
+            if !read.regions.is_empty() {
+                record.push_aux(b"XT", Aux::String(&read.regions.join(","))).map_err(bam_error)?;
+            }
+            writer.write(&record).map_err(bam_error)?;
+            self.written += 1;
+        }
+        Ok(())
+    }
+
+    // Function to write the reads still pending once the group is counted and close its BAM file
+    pub fn finish(&mut self) -> Result<(), KaiError> {
+        self.release(|_, _| true)?;
+        if let Some((path, _)) = self.writer.take() {
+            self.parts.push(path);
+        }
+        Ok(())
+    }
+
+    // Function to take the rows of --unassigned-out once the group is counted
+    pub fn take_rows(&mut self) -> Option<W> {
+        self.rows.take()
+    }
+
+    pub fn counts(&self) -> &ReasonCounts {
+        &self.counts
+    }
+
+    pub fn outcomes(&self) -> &ReadOutcomes {
+        &self.outcomes
+    }
+
+    // Function to add the outcomes of another group, counted after this one
+    pub fn merge(&mut self, other: Assignments<W>) {
+        self.counts.merge(&other.counts);
+        self.outcomes.merge(&other.outcomes);
+        self.written += other.written;
+        self.parts.extend(other.parts);
+    }
+
+    // Function to copy the files of the groups, in group order, into the output BAM file unless the only group
+    // wrote it; returns the number of reads written
+    pub fn write_bam(&self, path: &str, header: &bam::Header) -> Result<usize, KaiError> {
+        if self.parts.iter().all(|part| part == path) {
+            return Ok(self.written);
+        }
+        let bam_error = |source| KaiError::BamWrite { path: path.to_string(), source };
+        let mut writer = bam::Writer::from_path(path, header, bam::Format::Bam).map_err(bam_error)?;
+        let mut record = Record::new();
+        for part in &self.parts {
+            let mut reader = bam::Reader::from_path(part).map_err(|source| KaiError::BamOpen { path: part.clone(), source })?;
+            while let Some(result) = reader.read(&mut record) {
+                result.map_err(|source| KaiError::BamOpen { path: part.clone(), source })?;
+                writer.write(&record).map_err(bam_error)?;
+            }
+        }
+        Ok(self.written)
+    }
+}
+
+// Function to copy the header of the input BAM file, marked as sorted by coordinate when the reads are written in
+// coordinate order, with a @PG line for kai under an ID the input does not use
+pub fn assignments_header(header: &HeaderView, command: &str, sorted: bool) -> bam::Header {
+    let text = String::from_utf8_lossy(header.as_bytes());
+    let sort_order = if sorted { "coordinate" } else { "unsorted" };
+    let mut lines: Vec<String> = vec![format!("@HD\tVN:1.6\tSO:{}", sort_order)];
+    lines.extend(text.lines().filter(|line| !line.is_empty() && !line.starts_with("@HD\t")).map(str::to_string));
+    let program_ids: Vec<&str> = text.lines()
+        .filter(|line| line.starts_with("@PG\t"))
+        .filter_map(|line| line.split('\t').find_map(|field| field.strip_prefix("ID:")))
+        .collect();
+    // The previous program of the chain is the last @PG line of the input
+    let previous = program_ids.last().map(|id| format!("\tPP:{}", id)).unwrap_or_default();
+    let id = std::iter::once("kai".to_string())
+        .chain((1..).map(|n| format!("kai.{}", n)))
+        .find(|id| !program_ids.contains(&id.as_str()))
+        .unwrap();
+    lines.push(format!("@PG\tID:{}\tPN:kai{}\tCL:{}", id, previous, command.replace(['\t', '\n'], " ")));
+    bam::Header::from_template(&HeaderView::from_bytes(lines.join("\n").as_bytes()))
+}
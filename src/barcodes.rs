@@ -0,0 +1,129 @@
+// Modules for correcting cell barcodes against the whitelist
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use crate::cli::BarcodeSuffix;
+
+// Function to encode a barcode of up to 32 bases with two bits per base, returning None for other characters (N)
+fn encode(barcode: &[u8]) -> Option<u64> {
+    if barcode.len() > 32 {
+        return None;
+    }
+    barcode.iter().try_fold(0u64, |key, base| {
+        let bits = match base {
+            b'A' => 0,
+            b'C' => 1,
+            b'G' => 2,
+            b'T' => 3,
+            _ => return None,
+        };
+        Some(key << 2 | bits)
+    })
+}
+
+// Barcodes of interest, those of the most common length made only of ACGT being held as two-bit encoded keys so that
+// read barcodes are looked up without hashing or allocating a String; other barcodes (with N, of another length or
+// with a suffix) are looked up as strings
+#[derive(Default)]
+pub struct Whitelist {
+    length: usize,
+    encoded: HashSet<u64>,
+    others: HashSet<String>,
+}
+
+impl Whitelist {
+    pub fn new<S: AsRef<str>>(barcodes: &[S]) -> Self {
+        let mut lengths: HashMap<usize, usize> = HashMap::new();
+        for barcode in barcodes.iter().map(|barcode| barcode.as_ref()).filter(|barcode| encode(barcode.as_bytes()).is_some()) {
+            *lengths.entry(barcode.len()).or_default() += 1;
+        }
+        // Ties go to the shortest length so that the choice does not depend on the hash order
+        let length = lengths.into_iter().max_by_key(|&(length, count)| (count, std::cmp::Reverse(length))).map_or(0, |(length, _)| length);
+        let mut encoded = HashSet::with_capacity(barcodes.len());
+        let mut others = HashSet::new();
+        for barcode in barcodes.iter().map(|barcode| barcode.as_ref()) {
+            match encode(barcode.as_bytes()).filter(|_| barcode.len() == length) {
+                Some(key) => {
+                    encoded.insert(key);
+                }
+                None => {
+                    others.insert(barcode.to_string());
+                }
+            }
+        }
+        Whitelist { length, encoded, others }
+    }
+
+    // Function to count the distinct barcodes
+    pub fn len(&self) -> usize {
+        self.encoded.len() + self.others.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn contains(&self, barcode: &str) -> bool {
+        match encode(barcode.as_bytes()).filter(|_| barcode.len() == self.length) {
+            Some(key) => self.encoded.contains(&key),
+            None => self.others.contains(barcode),
+        }
+    }
+}
+
+// Whitelist barcodes indexed by each of their positions replaced with a wildcard, so that the barcodes one mismatch
+// away from a read barcode are found with one lookup per position
+#[derive(Clone)]
+pub struct BarcodeCorrector {
+    wildcards: HashMap<(usize, String), Vec<String>>,
+    corrections: HashMap<String, Option<String>>,
+}
+
+impl BarcodeCorrector {
+    pub fn new<S: AsRef<str>>(whitelist: &[S]) -> Self {
+        let mut wildcards: HashMap<(usize, String), Vec<String>> = HashMap::new();
+        for barcode in whitelist.iter().map(|barcode| barcode.as_ref()) {
+            for position in 0..barcode.len() {
+                wildcards.entry((position, wildcard(barcode, position))).or_default().push(barcode.to_string());
+            }
+        }
+        BarcodeCorrector { wildcards, corrections: HashMap::new() }
+    }
+
+    // Function to find the unique whitelist barcode within one mismatch of a barcode missing from the whitelist,
+    // returning None when there is no such barcode or several of them
+    pub fn correct(&mut self, barcode: &str) -> Option<String> {
+        if let Some(correction) = self.corrections.get(barcode) {
+            return correction.clone();
+        }
+        let mut hits: HashSet<&String> = HashSet::new();
+        for position in 0..barcode.len() {
+            if let Some(candidates) = self.wildcards.get(&(position, wildcard(barcode, position))) {
+                hits.extend(candidates);
+            }
+        }
+        let correction = match hits.len() {
+            1 => hits.into_iter().next().cloned(),
+            _ => None,
+        };
+        self.corrections.insert(barcode.to_string(), correction.clone());
+        correction
+    }
+}
+
+// Function to replace the character of a barcode at a position with a wildcard
+fn wildcard(barcode: &str, position: usize) -> String {
+    barcode.char_indices().map(|(i, c)| if i == position { '*' } else { c }).collect()
+}
+
+// Function to bring a barcode to the suffix convention chosen with --barcode-suffix: 'strip' drops a Cell Ranger GEM
+// group suffix (-1, -2, ...), 'require' appends -<gem_group> to barcodes without one, 'keep' leaves barcodes unchanged
+pub fn normalise_suffix<'a>(barcode: &'a str, policy: BarcodeSuffix, gem_group: u32) -> Cow<'a, str> {
+    let suffixed = barcode
+        .rsplit_once('-')
+        .filter(|(_, suffix)| !suffix.is_empty() && suffix.bytes().all(|b| b.is_ascii_digit()));
+    match (policy, suffixed) {
+        (BarcodeSuffix::Strip, Some((base, _))) => Cow::Borrowed(base),
+        (BarcodeSuffix::Require, None) => Cow::Owned(format!("{}-{}", barcode, gem_group)),
+        _ => Cow::Borrowed(barcode),
+    }
+}
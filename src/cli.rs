@@ -0,0 +1,1152 @@
+// Modules for the command-line arguments of kai, parsed and checked into the options of a run
+use crate::data_loader;
+use crate::normalize;
+use clap::builder::PossibleValue;
+use clap::{Arg, ArgMatches, Command};
+use flate2::Compression;
+use std::path::PathBuf;
+
+// Macro declaring the values of an option as an enum parsed by clap (ValueEnum, written out as clap is built
+// without its derive macros), with the value names printed by Display as given on the command line
+macro_rules! value_enum {
+    ($name:ident { $($variant:ident => $value:literal),+ $(,)? }) => {
+        #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+        pub enum $name {
+            $($variant),+
+        }
+
+        impl $name {
+            pub fn as_str(self) -> &'static str {
+                match self {
+                    $($name::$variant => $value),+
+                }
+            }
+        }
+
+        impl clap::ValueEnum for $name {
+            fn value_variants<'a>() -> &'a [Self] {
+                &[$($name::$variant),+]
+            }
+
+            fn to_possible_value(&self) -> Option<PossibleValue> {
+                Some(PossibleValue::new(self.as_str()))
+            }
+        }
+
+        impl std::fmt::Display for $name {
+            fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str(self.as_str())
+            }
+        }
+    };
+}
+
+// Mode of operation: one count per region, or per region and cell barcode
+value_enum!(Mode { Bulk => "bulk", Single => "single" });
+
+// Handling of reads without NH tag (--missing-nh)
+value_enum!(MissingNh { Pass => "pass", Drop => "drop", UseFlags => "use-flags" });
+
+// Counting of multimapped reads (--multimap)
+value_enum!(Multimap { Skip => "skip", Fraction => "fraction", Primary => "primary", Em => "em" });
+
+// Mates of paired-end reads that are counted (--read)
+value_enum!(Mate { First => "1", Second => "2", Both => "both" });
+
+// Reads counted by their splicing (--reads)
+value_enum!(SpliceFilter { All => "all", Spliced => "spliced", Unspliced => "unspliced" });
+
+// Whether single-end reads pass the insert size filters (--insert-filter-single)
+value_enum!(SingleEndInserts { Pass => "pass", Drop => "drop" });
+
+// Format of the regions file (--format)
+value_enum!(RegionFormat { Auto => "auto", Bed => "bed", Gtf => "gtf", Saf => "saf", Peak => "peak" });
+
+// Handling of malformed BED lines (--bed-errors)
+value_enum!(BedErrors { Strict => "strict", Skip => "skip" });
+
+// Coordinate convention of BED regions, --region and the outputs (--coordinates)
+value_enum!(Coordinates { Bed => "bed", OneBased => "one-based" });
+
+// Handling of features sharing the same name (--on-duplicate-names)
+value_enum!(DuplicateNames { Suffix => "suffix", Error => "error", Coords => "coords" });
+
+// Length --min-overlap-frac is a fraction of (--frac-of)
+value_enum!(FracOf { Read => "read", Region => "region" });
+
+// Part of the read tested against the regions (--count-mode)
+value_enum!(CountMode { Overlap => "overlap", FivePrime => "5prime", ThreePrime => "3prime" });
+
+// Counting of reads overlapping several regions (--overlap-mode)
+value_enum!(OverlapMode {
+    Union => "union",
+    IntersectionStrict => "intersection-strict",
+    IntersectionNonempty => "intersection-nonempty",
+});
+
+// Regions a read is counted in (--assign)
+value_enum!(Assign { All => "all", Unique => "unique" });
+
+// Strand of the reads counted in stranded regions (--strandedness)
+value_enum!(Strandedness { None => "none", Forward => "forward", Reverse => "reverse" });
+
+// Library type setting the strandedness (--library-type)
+value_enum!(LibraryType {
+    Unstranded => "fr-unstranded",
+    FirstStrand => "fr-firststrand",
+    SecondStrand => "fr-secondstrand",
+});
+
+// Renaming of region contigs to the 'chr' prefix of the BAM header (--chr-prefix)
+value_enum!(ChrPrefix { Auto => "auto", Add => "add", Strip => "strip", None => "none" });
+
+// Way the BAM file is read (--engine)
+value_enum!(Engine { Fetch => "fetch", Sweep => "sweep" });
+
+// Report of the progress of counting (--progress)
+value_enum!(ProgressReport { Bar => "bar", Log => "log", None => "none" });
+
+// Layout of the outputs (--output-format)
+value_enum!(OutputFormat {
+    Kai => "kai",
+    Cellranger => "cellranger",
+    DenseTsv => "dense-tsv",
+    Featurecounts => "featurecounts",
+});
+
+// Columns of features.tsv.gz (--feature-columns)
+value_enum!(FeatureColumns { One => "1", Three => "3" });
+
+// Compression of the output files (--compression)
+value_enum!(OutputCompression { Gzip => "gzip", None => "none" });
+
+// Collapsing of UMIs into molecules (--umi-collapse)
+value_enum!(UmiCollapse { Exact => "exact", Hamming1 => "hamming1", Directional => "directional" });
+
+// Matching of the GEM group suffixes of barcodes (--barcode-suffix)
+value_enum!(BarcodeSuffix { Keep => "keep", Strip => "strip", Require => "require" });
+
+// Format of the coverage track (--coverage-out)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CoverageFormat {
+    Bedgraph,
+    Bigwig,
+}
+
+impl clap::ValueEnum for CoverageFormat {
+    fn value_variants<'a>() -> &'a [Self] {
+        &[CoverageFormat::Bedgraph, CoverageFormat::Bigwig]
+    }
+
+    // BigWig is listed only to be rejected with its own message
+    fn to_possible_value(&self) -> Option<PossibleValue> {
+        Some(match self {
+            CoverageFormat::Bedgraph => PossibleValue::new("bedgraph"),
+            CoverageFormat::Bigwig => PossibleValue::new("bigwig").hide(true),
+        })
+    }
+}
+
+// Function to set up the command-line arguments of kai
+pub fn command() -> Command {
+    Command::new("kai")
+        .version("0.2.0")
+        .author("NaotoKubota")
+        .about("Count reads mapped to regions of interest from bulk/single-cell RNA-seq data")
+        .arg(Arg::new("mode")
+            .required(true)
+            .value_parser(clap::value_parser!(Mode))
+            .help("Mode of operation: 'bulk' or 'single'"))
+        .arg(Arg::new("bam_file")
+            .required(true)
+            .help("Path to the BAM file, or '-' for stdin with --stream; several BAM files are counted together as a comma-separated list of [sample=]path or a .txt/.tsv sample sheet (path or sample<TAB>path per line), with a Count column per sample in bulk mode and barcodes prefixed with <sample>_ in single mode"))
+        .arg(Arg::new("regions_file")
+            .help("Path to the BED, GTF/GFF3 or SAF file containing regions of interest, or '-' for stdin (optional with --region); several files can be counted together as a comma-separated list of [label=]path"))
+        .arg(Arg::new("output_dir")
+            .help("Output directory for the output files, or '-' to write the bulk count table to stdout uncompressed"))
+        .arg(Arg::new("region")
+            .short('r')
+            .long("region")
+            .action(clap::ArgAction::Append)
+            .help("Region to count, as chr:start-end or chr:start..end (in the --coordinates convention, like the output keys); can be repeated"))
+        .arg(Arg::new("max_loci")
+            .short('l')
+            .long("max-loci")
+            .default_value("1")
+            .value_parser(clap::value_parser!(u32))
+            .help("Maximum number of loci the read maps to"))
+        .arg(Arg::new("missing_nh")
+            .long("missing-nh")
+            .default_value("pass")
+            .value_parser(clap::value_parser!(MissingNh))
+            .help("How to handle reads without NH tag: count them as unique, skip them, or treat secondary alignments as mapping to several loci and others as unique (aligners such as bwa)"))
+        .arg(Arg::new("multimap")
+            .long("multimap")
+            .default_value("skip")
+            .value_parser(clap::value_parser!(Multimap))
+            .help("How to count multimapped reads: skip those mapping to more than --max-loci loci, add 1/NH for every alignment, count only the primary alignment (secondary flag unset, HI:1 when set), or distribute them by EM from unique-read counts; --max-loci only applies to 'skip'"))
+        .arg(Arg::new("em_iterations")
+            .long("em-iterations")
+            .default_value("100")
+            .value_parser(clap::value_parser!(usize))
+            .help("Maximum number of EM rounds for --multimap em"))
+        .arg(Arg::new("min_mapq")
+            .long("min-mapq")
+            .default_value("0")
+            .value_parser(clap::value_parser!(u8))
+            .help("Minimum mapping quality of counted reads; MAPQ 255 (unavailable) passes unless --treat-mapq-255-as-zero is set"))
+        .arg(Arg::new("treat_mapq_255_as_zero")
+            .long("treat-mapq-255-as-zero")
+            .action(clap::ArgAction::SetTrue)
+            .help("Treat reads with MAPQ 255 (unavailable) as MAPQ 0 for --min-mapq"))
+        .arg(Arg::new("require_flags")
+            .long("require-flags")
+            .help("Count only reads with all of these SAM flags set, as an integer or names (e.g. paired,proper_pair)"))
+        .arg(Arg::new("exclude_flags")
+            .long("exclude-flags")
+            .help("Skip reads with any of these SAM flags set, as an integer or names (e.g. secondary,supplementary,dup,qcfail)"))
+        .arg(Arg::new("skip_qcfail")
+            .long("skip-qcfail")
+            .action(clap::ArgAction::SetTrue)
+            .help("Skip reads failing platform/vendor quality checks (FLAG 0x200), adding qcfail to --exclude-flags"))
+        .arg(Arg::new("primary_only")
+            .long("primary-only")
+            .action(clap::ArgAction::SetTrue)
+            .help("Skip secondary and supplementary alignments so that each read is counted once (recommended)"))
+        .arg(Arg::new("proper_pairs_only")
+            .long("proper-pairs-only")
+            .action(clap::ArgAction::SetTrue)
+            .help("Count only reads in properly paired alignments (FLAG 0x2); single-end reads are not filtered"))
+        .arg(Arg::new("read")
+            .long("read")
+            .default_value("both")
+            .value_parser(clap::value_parser!(Mate))
+            .help("Count only read 1 or read 2 of paired-end reads; single-end reads are treated as read 1"))
+        .arg(Arg::new("reads")
+            .long("reads")
+            .default_value("all")
+            .value_parser(clap::value_parser!(SpliceFilter))
+            .help("Count all reads, only spliced reads (with at least one N in the CIGAR), or only unspliced reads"))
+        .arg(Arg::new("min_read_length")
+            .long("min-read-length")
+            .value_parser(clap::value_parser!(usize))
+            .help("Skip reads shorter than this length (from the CIGAR when the sequence is not stored)"))
+        .arg(Arg::new("max_read_length")
+            .long("max-read-length")
+            .value_parser(clap::value_parser!(usize))
+            .help("Skip reads longer than this length (from the CIGAR when the sequence is not stored)"))
+        .arg(Arg::new("max_softclip_frac")
+            .long("max-softclip-frac")
+            .value_parser(clap::value_parser!(f64))
+            .help("Skip reads whose soft-clipped bases exceed this fraction of the read length"))
+        .arg(Arg::new("count_hardclip")
+            .long("count-hardclip")
+            .action(clap::ArgAction::SetTrue)
+            .help("Include hard-clipped bases in --max-softclip-frac"))
+        .arg(Arg::new("min_block_baseq")
+            .long("min-block-baseq")
+            .value_parser(clap::value_parser!(f64))
+            .help("Skip reads whose bases aligned in the region have a mean Phred quality below this value; reads without qualities pass"))
+        .arg(Arg::new("require_baseq")
+            .long("require-baseq")
+            .action(clap::ArgAction::SetTrue)
+            .requires("min_block_baseq")
+            .help("Skip reads without base qualities under --min-block-baseq"))
+        .arg(Arg::new("min_insert")
+            .long("min-insert")
+            .value_parser(clap::value_parser!(i64))
+            .help("Skip paired reads whose absolute insert size (TLEN) is below this value"))
+        .arg(Arg::new("max_insert")
+            .long("max-insert")
+            .value_parser(clap::value_parser!(i64))
+            .help("Skip paired reads whose absolute insert size (TLEN) is above this value"))
+        .arg(Arg::new("insert_filter_single")
+            .long("insert-filter-single")
+            .default_value("pass")
+            .value_parser(clap::value_parser!(SingleEndInserts))
+            .help("Whether single-end reads pass or fail --min-insert/--max-insert"))
+        .arg(Arg::new("ignore_duplicates")
+            .long("ignore-duplicates")
+            .action(clap::ArgAction::SetTrue)
+            .help("Skip reads flagged as PCR/optical duplicates"))
+        .arg(Arg::new("format")
+            .short('f')
+            .long("format")
+            .default_value("auto")
+            .value_parser(clap::value_parser!(RegionFormat))
+            .help("Format of the regions file; 'auto' detects GTF/GFF3, SAF and narrowPeak/broadPeak from the file extension or SAF header"))
+        .arg(Arg::new("bed_errors")
+            .long("bed-errors")
+            .default_value("strict")
+            .value_parser(clap::value_parser!(BedErrors))
+            .help("How to handle malformed BED lines: stop with an error or skip them with a warning"))
+        .arg(Arg::new("coordinates")
+            .long("coordinates")
+            .default_value("bed")
+            .value_parser(clap::value_parser!(Coordinates))
+            .help("Coordinate convention of BED regions and --region: 'bed' (0-based, half-open) or 'one-based' (1-based, inclusive); outputs use the same convention"))
+        .arg(Arg::new("keep_peak_stats")
+            .long("keep-peak-stats")
+            .action(clap::ArgAction::SetTrue)
+            .help("Add the signalValue and qValue columns of narrowPeak/broadPeak regions to the bulk output"))
+        .arg(Arg::new("normalize")
+            .long("normalize")
+            .value_delimiter(',')
+            .action(clap::ArgAction::Append)
+            .value_parser(clap::value_parser!(normalize::Method))
+            .help("Append normalised columns to the bulk output, as a comma-separated list: CPM (counts per million counted reads), RPKM (CPM per kilobase of region) and TPM (per-kilobase rates scaled to sum to a million), with four decimals; regions without bases get NA for RPKM and TPM, and several BAM files give one <sample>_<method> column per sample"))
+        .arg(Arg::new("library_size")
+            .long("library-size")
+            .value_delimiter(',')
+            .action(clap::ArgAction::Append)
+            .value_parser(clap::value_parser!(u64).range(1..))
+            .requires("normalize")
+            .help("Library size used by CPM and RPKM in place of the sum of the counts, as a comma-separated list with one value per BAM file"))
+        .arg(Arg::new("gene_name")
+            .long("gene-name")
+            .action(clap::ArgAction::SetTrue)
+            .help("Use gene_name instead of gene_id as the feature name for GTF/GFF3 input"))
+        .arg(Arg::new("on_duplicate_names")
+            .long("on-duplicate-names")
+            .visible_alias("dedup-names")
+            .default_value("suffix")
+            .value_parser(clap::value_parser!(DuplicateNames))
+            .help("How to handle features sharing the same name: suffix them with _1, _2, ..., stop with an error, or add their coordinates as name|chr:start-end"))
+        .arg(Arg::new("group_map")
+            .long("group-map")
+            .help("Two-column file mapping region names (or chr:start-end keys) to groups; reads are counted once per group over the union of its regions"))
+        .arg(Arg::new("keep_duplicates")
+            .long("keep-duplicates")
+            .action(clap::ArgAction::SetTrue)
+            .help("Count identical regions separately with keys suffixed :1, :2, ... instead of keeping only one of them"))
+        .arg(Arg::new("min_overlap")
+            .long("min-overlap")
+            .default_value("1")
+            .value_parser(clap::value_parser!(usize))
+            .help("Minimum number of aligned bases of a read overlapping the region"))
+        .arg(Arg::new("min_overlap_frac")
+            .long("min-overlap-frac")
+            .value_parser(clap::value_parser!(f64))
+            .help("Minimum fraction of the read's aligned bases (or of the region, see --frac-of) overlapping the region"))
+        .arg(Arg::new("frac_of")
+            .long("frac-of")
+            .default_value("read")
+            .value_parser(clap::value_parser!(FracOf))
+            .help("Whether --min-overlap-frac is a fraction of the read's aligned bases or of the region length"))
+        .arg(Arg::new("contained")
+            .long("contained")
+            .action(clap::ArgAction::SetTrue)
+            .help("Count only reads whose whole alignment lies within the region, e.g. for small features like miRNA loci"))
+        .arg(Arg::new("contained_include_clips")
+            .long("contained-include-clips")
+            .action(clap::ArgAction::SetTrue)
+            .requires("contained")
+            .help("Include soft-clipped bases in the read span checked by --contained"))
+        .arg(Arg::new("count_gapped_overlap")
+            .long("count-gapped-overlap")
+            .action(clap::ArgAction::SetTrue)
+            .help("Also count reads whose skipped span (N operation) overlaps the region, e.g. for exclusion junctions; each read is still counted once"))
+        .arg(Arg::new("split_gapped_column")
+            .long("split-gapped-column")
+            .action(clap::ArgAction::SetTrue)
+            .requires("count_gapped_overlap")
+            .help("Report reads counted by --count-gapped-overlap in a separate GappedCount column (bulk mode)"))
+        .arg(Arg::new("count_mode")
+            .long("count-mode")
+            .default_value("overlap")
+            .value_parser(clap::value_parser!(CountMode))
+            .help("Count reads overlapping the region, or only reads whose 5' or 3' end (strand-aware, excluding soft clips) falls in it"))
+        .arg(Arg::new("extend_reads")
+            .long("extend-reads")
+            .value_parser(clap::value_parser!(usize))
+            .help("Test overlaps with each read extended from its 5' end to this fragment length in the 3' direction (single-end ChIP-seq)"))
+        .arg(Arg::new("tn5_shift")
+            .long("tn5-shift")
+            .action(clap::ArgAction::SetTrue)
+            .help("Shift + strand reads by +4 bp and - strand reads by -5 bp before overlap testing, to centre ATAC-seq reads on the Tn5 insertion"))
+        .arg(Arg::new("overlap_mode")
+            .long("overlap-mode")
+            .default_value("union")
+            .value_parser(clap::value_parser!(OverlapMode))
+            .help("How to count reads overlapping several regions: count them in every region (union), or only in the single region containing all their aligned bases (intersection-strict) or all their bases covered by any region (intersection-nonempty)"))
+        .arg(Arg::new("assign")
+            .long("assign")
+            .default_value("all")
+            .value_parser(clap::value_parser!(Assign))
+            .help("Count reads in all the regions they overlap, or skip reads overlapping several regions so that each read is counted at most once (the intersection overlap modes already count reads once)"))
+        .arg(Arg::new("strandedness")
+            .short('s')
+            .long("strandedness")
+            .default_value("none")
+            .value_parser(clap::value_parser!(Strandedness))
+            .help("Count only reads on the same strand as the region (forward) or on the opposite strand (reverse); paired-end reads use the strand of read 1"))
+        .arg(Arg::new("library_type")
+            .long("library-type")
+            .value_parser(clap::value_parser!(LibraryType))
+            .help("Library type setting the strandedness: fr-firststrand (dUTP) is reverse, fr-secondstrand is forward (overrides --strandedness)"))
+        .arg(Arg::new("by_read_group")
+            .long("by-read-group")
+            .action(clap::ArgAction::SetTrue)
+            .help("Count reads per read group (RG tag) in bulk mode, with one column per @RG ID, unknown_rg for reads without RG, and the total in Count"))
+        .arg(Arg::new("emit_antisense")
+            .long("emit-antisense")
+            .action(clap::ArgAction::SetTrue)
+            .help("Count reads on the wrong strand of stranded regions separately (AntisenseCount column next to Count, or antisense_count_barcodes.tsv.gz in single mode) instead of dropping them; unstranded regions have no antisense reads"))
+        .arg(Arg::new("chr_prefix")
+            .long("chr-prefix")
+            .default_value("auto")
+            .value_parser(clap::value_parser!(ChrPrefix))
+            .help("Add or strip the 'chr' prefix of region contigs to match the BAM header; 'auto' renames only regions whose contig is missing from the header"))
+        .arg(Arg::new("skip_missing_contigs")
+            .long("skip-missing-contigs")
+            .action(clap::ArgAction::SetTrue)
+            .help("Drop regions on contigs missing from the BAM header instead of stopping with an error"))
+        .arg(Arg::new("include_chromosomes")
+            .long("include-chromosomes")
+            .help("Count only regions on these contigs, as a comma-separated list or a file with one contig per line; '*' and '?' wildcards are allowed (applied before --exclude-chromosomes)"))
+        .arg(Arg::new("exclude_chromosomes")
+            .long("exclude-chromosomes")
+            .help("Contigs to leave out, as a comma-separated list or a file with one contig per line; '*' and '?' wildcards are allowed (e.g. chrM,chrUn_*,*_alt)"))
+        .arg(Arg::new("blacklist")
+            .long("blacklist")
+            .help("BED file of regions (e.g. the ENCODE blacklist) whose overlapping reads are ignored entirely"))
+        .arg(Arg::new("bin_size")
+            .long("bin-size")
+            .value_parser(clap::value_parser!(usize))
+            .help("Count reads in genome-wide bins of this size derived from the BAM header (can replace the regions file)"))
+        .arg(Arg::new("tss_regions")
+            .long("tss-regions")
+            .value_name("gtf_file")
+            .help("Count reads in windows around the TSS of each gene of this GTF/GFF3 file (can replace the regions file)"))
+        .arg(Arg::new("upstream")
+            .long("upstream")
+            .default_value("1000")
+            .value_parser(clap::value_parser!(usize))
+            .help("Bases upstream of the TSS included in --tss-regions windows"))
+        .arg(Arg::new("downstream")
+            .long("downstream")
+            .default_value("1000")
+            .value_parser(clap::value_parser!(usize))
+            .help("Bases downstream of the TSS (including the TSS) included in --tss-regions windows"))
+        .arg(Arg::new("tss_per_transcript")
+            .long("tss-per-transcript")
+            .action(clap::ArgAction::SetTrue)
+            .help("Build one --tss-regions window per transcript (named by transcript_id) instead of per gene"))
+        .arg(Arg::new("extend")
+            .long("extend")
+            .value_parser(clap::value_parser!(usize))
+            .help("Pad every region by this many bases on each side, clipped to the contig bounds"))
+        .arg(Arg::new("extend_upstream")
+            .long("extend-upstream")
+            .value_parser(clap::value_parser!(usize))
+            .help("Pad regions by this many bases upstream of their strand (overrides --extend)"))
+        .arg(Arg::new("extend_downstream")
+            .long("extend-downstream")
+            .value_parser(clap::value_parser!(usize))
+            .help("Pad regions by this many bases downstream of their strand (overrides --extend)"))
+        .arg(Arg::new("merge_regions")
+            .long("merge-regions")
+            .value_name("gap")
+            .num_args(0..=1)
+            .default_missing_value("0")
+            .value_parser(clap::value_parser!(usize))
+            .help("Merge overlapping or book-ended regions on the same chromosome, and optionally regions within <gap> bases"))
+        .arg(Arg::new("no_sort")
+            .long("no-sort")
+            .action(clap::ArgAction::SetTrue)
+            .help("Count and report regions in input order instead of sorting them by contig (BAM header order), start and end"))
+        .arg(Arg::new("engine")
+            .long("engine")
+            .default_value("fetch")
+            .value_parser(clap::value_parser!(Engine))
+            .help("Read the BAM file with one indexed fetch per region, or once per chromosome while sweeping through the regions (faster for many or overlapping regions)"))
+        .arg(Arg::new("fetch_merge_distance")
+            .long("fetch-merge-distance")
+            .value_name("bases")
+            .default_value("16384")
+            .value_parser(clap::value_parser!(u32))
+            .help("Read consecutive regions starting within this distance of the previous ones with one indexed fetch in the fetch engine (16384 is the BAI bin size), giving each region its reads from the window; 0 fetches every region on its own"))
+        .arg(Arg::new("stream")
+            .long("stream")
+            .action(clap::ArgAction::SetTrue)
+            .help("Read a coordinate-sorted BAM file (SO:coordinate) once from start to end without its index, sweeping through the regions as with --engine sweep; also reads the BAM file from stdin"))
+        .arg(Arg::new("progress")
+            .long("progress")
+            .default_value("bar")
+            .value_parser(clap::value_parser!(ProgressReport))
+            .help("Report progress as a bar with elapsed time, ETA and reads/s (log lines when stderr is not a terminal), as log lines at each 1% of the regions, or not at all"))
+        .arg(Arg::new("timings")
+            .long("timings")
+            .action(clap::ArgAction::SetTrue)
+            .help("Report the wall-clock time and counters (regions, reads, bytes written) of each stage at the end of the run"))
+        .arg(Arg::new("threads")
+            .long("threads")
+            .default_value("1")
+            .value_parser(clap::value_parser!(u32).range(1..))
+            .help("Number of threads counting chromosomes (or BAM files, when several are given) in parallel, each with its own BAM reader; outputs are identical whatever the number of threads"))
+        .arg(Arg::new("fail_fast")
+            .long("fail-fast")
+            .action(clap::ArgAction::SetTrue)
+            .help("Stop at the first BAM file that fails when several are given, instead of writing the outputs of the other samples and reporting the failures at the end"))
+        .arg(Arg::new("low_memory")
+            .long("low-memory")
+            .action(clap::ArgAction::SetTrue)
+            .help("Write the counts of single mode to temporary files and sort them on disk instead of keeping every feature and barcode count in memory, for matrices larger than RAM; outputs are identical"))
+        .arg(Arg::new("tmp_dir")
+            .long("tmp-dir")
+            .value_name("dir")
+            .requires("low_memory")
+            .help("Directory for the temporary files of --low-memory, removed at the end of the run [default: system temporary directory]"))
+        .arg(Arg::new("velocity")
+            .long("velocity")
+            .action(clap::ArgAction::SetTrue)
+            .help("Also write spliced, unspliced and ambiguous matrices for RNA velocity in single mode, using region blocks as exons and the gaps between them as introns (GTF or BED12 regions)"))
+        .arg(Arg::new("observed_features_only")
+            .long("observed-features-only")
+            .action(clap::ArgAction::SetTrue)
+            .help("List only regions with counted reads in features.tsv.gz instead of every region (single mode)"))
+        .arg(Arg::new("output_format")
+            .long("output-format")
+            .default_value("kai")
+            .value_parser(clap::value_parser!(OutputFormat))
+            .help("Layout of the outputs: Kai's files; in single mode the Cell Ranger matrix.mtx.gz, barcodes.tsv.gz and three-column features.tsv.gz (every region listed) read by Scanpy's read_10x_mtx and Seurat's Read10X, in the output directory created if missing, or a zero-filled features x barcodes matrix.tsv.gz for small panels in place of matrix.mtx.gz and count_barcodes.tsv.gz; in bulk mode the featureCounts table featurecounts.txt (a count column per BAM file) and its featurecounts.txt.summary in place of count.tsv.gz"))
+        .arg(Arg::new("feature_type")
+            .long("feature-type")
+            .default_value("Peaks")
+            .help("Feature type written in the third column of features.tsv.gz (the set label is written instead when several regions files are counted)"))
+        .arg(Arg::new("feature_columns")
+            .long("feature-columns")
+            .default_value("3")
+            .value_parser(clap::value_parser!(FeatureColumns))
+            .help("Columns of features.tsv.gz in single mode: the feature key, its name and its type as read by Scanpy's read_10x_mtx, or only the feature key as in earlier versions"))
+        .arg(Arg::new("coverage_out")
+            .long("coverage-out")
+            .value_parser(clap::value_parser!(CoverageFormat))
+            .help("Also write the depth of the counted reads over the regions as coverage.bedgraph.gz (bulk mode), from the same reads and weights as the counts; overlapping regions are merged so that each read covers a base once"))
+        .arg(Arg::new("assignments_bam")
+            .long("assignments-bam")
+            .value_name("BAM")
+            .help("Also write the counted reads to this BAM file as they are counted, tagged with the comma-separated keys of the regions they are counted in as XT:Z (replacing an aligner XT tag); reads are in coordinate order unless --no-sort keeps unsorted regions, marking the file SO:unsorted"))
+        .arg(Arg::new("write_unassigned")
+            .long("write-unassigned")
+            .action(clap::ArgAction::SetTrue)
+            .requires("assignments_bam")
+            .help("Also write the reads fetched for the regions but never counted to --assignments-bam, and tag every read with XS:Z:Assigned or the reason it is left out of its first region as XS:Z (replacing an aligner XS tag)"))
+        .arg(Arg::new("unassigned_out")
+            .long("unassigned-out")
+            .value_name("TSV")
+            .help("Also write the name, region and reason of every read fetched for a region but not counted in it to this file (gzip-compressed when its name ends with .gz), one row per read and region"))
+        .arg(Arg::new("per_base_profiles")
+            .long("per-base-profiles")
+            .action(clap::ArgAction::SetTrue)
+            .help("Also write per_base_profiles.tsv.gz with a row per region holding the comma-separated depth of the counted reads at each base from start to end (deletions and skipped spans add no depth)"))
+        .arg(Arg::new("max_profile_length")
+            .long("max-profile-length")
+            .default_value("100000")
+            .value_parser(clap::value_parser!(usize))
+            .help("Leave regions longer than this many bases out of --per-base-profiles, with a warning"))
+        .arg(Arg::new("depth_stats")
+            .long("depth-stats")
+            .action(clap::ArgAction::SetTrue)
+            .help("Append the mean and median depth of the counted reads over the bases of each region (within its blocks) to the bulk output as MeanDepth and MedianDepth, with four decimals"))
+        .arg(Arg::new("max_depth_stats_length")
+            .long("max-depth-stats-length")
+            .default_value("100000")
+            .value_parser(clap::value_parser!(usize))
+            .requires("depth_stats")
+            .help("Give regions longer than this many bases only MeanDepth with --depth-stats (MedianDepth is NA), with a warning, so that their depth at each base is not kept"))
+        .arg(Arg::new("compression")
+            .long("compression")
+            .default_value("gzip")
+            .value_parser(clap::value_parser!(OutputCompression))
+            .help("Compression of the output files: gzip (.gz names) or none, dropping .gz from the file names"))
+        .arg(Arg::new("compression_level")
+            .long("compression-level")
+            .value_parser(clap::value_parser!(u32).range(0..=9))
+            .help("gzip compression level of the output files, from 0 (stored) to 9 (smallest) [default: 6]"))
+        .arg(Arg::new("max_dense_cells")
+            .long("max-dense-cells")
+            .default_value("100000000")
+            .value_parser(clap::value_parser!(u64))
+            .help("Largest matrix (features x barcodes) written by --output-format dense-tsv without --force-dense"))
+        .arg(Arg::new("force_dense")
+            .long("force-dense")
+            .action(clap::ArgAction::SetTrue)
+            .help("Write --output-format dense-tsv matrices larger than --max-dense-cells"))
+        .arg(Arg::new("transpose")
+            .long("transpose")
+            .action(clap::ArgAction::SetTrue)
+            .help("Write the MatrixMarket matrices of single mode as barcodes x features (rows following barcodes.tsv.gz, columns features.tsv.gz) instead of features x barcodes, with a comment line naming the axes; the entries are sorted in memory, so this cannot be combined with --low-memory"))
+        .arg(Arg::new("barcode_tag")
+            .long("barcode-tag")
+            .default_value("CB")
+            .help("Tag holding the cell barcode in single mode (e.g. CB, CR, XC, BC)"))
+        .arg(Arg::new("barcode_fallback_tag")
+            .long("barcode-fallback-tag")
+            .help("Tag used for the cell barcode when --barcode-tag is missing (e.g. CR)"))
+        .arg(Arg::new("keep_untagged")
+            .long("keep-untagged")
+            .action(clap::ArgAction::SetTrue)
+            .help("Count reads without barcode under the NO_BARCODE barcode instead of dropping them (single mode)"))
+        .arg(Arg::new("umi_tag")
+            .long("umi-tag")
+            .num_args(0..=1)
+            .default_missing_value("UB,UR")
+            .help("Count distinct UMIs per region and barcode in single mode instead of reads, reading them from the first present tag of this comma-separated list (UB,UR when no value is given; e.g. XM for Drop-seq)"))
+        .arg(Arg::new("umi_collapse")
+            .long("umi-collapse")
+            .default_value("exact")
+            .value_parser(clap::value_parser!(UmiCollapse))
+            .help("How to collapse UMIs into molecules: identical UMIs only, UMIs connected within one mismatch, or the UMI-tools directional method"))
+        .arg(Arg::new("require_umi")
+            .long("require-umi")
+            .action(clap::ArgAction::SetTrue)
+            .help("Skip reads without a UMI instead of counting them as reads when --umi-tag is set"))
+        .arg(Arg::new("cell_barcode_file")
+            .short('c')
+            .long("cell-barcodes")
+            .value_parser(clap::value_parser!(String))
+            .help("Optional file specifying cell barcodes of interest"))
+        .arg(Arg::new("barcode_suffix")
+            .long("barcode-suffix")
+            .default_value("keep")
+            .value_parser(clap::value_parser!(BarcodeSuffix))
+            .help("How to match GEM group suffixes (e.g. -1) of BAM and --cell-barcodes barcodes: as written, removed from both, or added as -<gem-group> where missing"))
+        .arg(Arg::new("gem_group")
+            .long("gem-group")
+            .value_parser(clap::value_parser!(u32).range(1..))
+            .help("GEM group suffix added by --barcode-suffix require [default: 1]"))
+        .arg(Arg::new("correct_barcodes")
+            .long("correct-barcodes")
+            .action(clap::ArgAction::SetTrue)
+            .requires("cell_barcode_file")
+            .help("Reassign barcodes missing from --cell-barcodes to the unique listed barcode one mismatch away, if any"))
+        .arg(Arg::new("verbose")
+            .short('v')
+            .long("verbose")
+            .action(clap::ArgAction::SetTrue)
+            .help("Enable verbose output to print all arguments"))
+}
+
+// Options of a run, from the parsed arguments
+pub struct Options<'a> {
+    pub mode: Mode,
+    pub bam_samples: Vec<(String, String)>,
+    pub multi_sample: bool,
+    pub region_args: Vec<&'a String>,
+    pub bin_size: Option<usize>,
+    pub tss_regions: Option<&'a String>,
+    pub upstream: usize,
+    pub downstream: usize,
+    pub tss_per_transcript: bool,
+    pub regions_file: Option<&'a String>,
+    pub output_dir: &'a String,
+    pub velocity: bool,
+    pub observed_features_only: bool,
+    pub cellranger_output: bool,
+    pub dense_output: bool,
+    pub featurecounts_output: bool,
+    pub feature_type: &'a String,
+    pub single_feature_column: bool,
+    pub max_dense_cells: u64,
+    pub force_dense: bool,
+    pub stdout_output: bool,
+    pub compress_outputs: bool,
+    pub compression_level: Compression,
+    pub max_loci: u32,
+    pub missing_nh: MissingNh,
+    pub multimap: Multimap,
+    pub fractional: bool,
+    pub em: bool,
+    pub em_iterations: usize,
+    pub min_mapq: u8,
+    pub treat_mapq_255_as_zero: bool,
+    pub require_flags: u16,
+    pub primary_only: bool,
+    pub proper_pairs_only: bool,
+    pub read_filter: Mate,
+    pub ignore_duplicates: bool,
+    pub min_insert: Option<i64>,
+    pub max_insert: Option<i64>,
+    pub drop_single_insert: bool,
+    pub min_read_length: Option<usize>,
+    pub max_read_length: Option<usize>,
+    pub max_softclip_frac: Option<f64>,
+    pub min_block_baseq: Option<f64>,
+    pub require_baseq: bool,
+    pub count_hardclip: bool,
+    pub splice_filter: SpliceFilter,
+    pub exclude_flags: u16,
+    pub cell_barcode_file: Option<&'a String>,
+    pub correct_barcodes: bool,
+    pub by_read_group: bool,
+    pub coverage_out: Option<CoverageFormat>,
+    pub normalize: Vec<normalize::Method>,
+    pub library_sizes: Option<Vec<u64>>,
+    pub assignments_bam: Option<&'a String>,
+    pub write_unassigned: bool,
+    pub unassigned_out: Option<&'a String>,
+    pub per_base_profiles: bool,
+    pub max_profile_length: usize,
+    pub depth_stats: bool,
+    pub max_depth_stats_length: usize,
+    pub barcode_suffix: BarcodeSuffix,
+    pub gem_group: u32,
+    pub barcode_tag: &'a String,
+    pub barcode_fallback_tag: Option<&'a String>,
+    pub keep_untagged: bool,
+    pub umi_tags: Option<Vec<String>>,
+    pub require_umi: bool,
+    pub umi_collapse: UmiCollapse,
+    pub regions_format: RegionFormat,
+    pub bed_errors: BedErrors,
+    pub one_based: bool,
+    pub keep_peak_stats: bool,
+    pub use_gene_name: bool,
+    pub on_duplicate_names: DuplicateNames,
+    pub group_map: Option<&'a String>,
+    pub keep_duplicates: bool,
+    pub strandedness: Strandedness,
+    pub emit_antisense: bool,
+    pub tn5_shift: bool,
+    pub extend_reads: Option<usize>,
+    pub count_mode: CountMode,
+    pub overlap_mode: OverlapMode,
+    pub assign_unique: bool,
+    pub min_overlap: usize,
+    pub min_overlap_frac: Option<f64>,
+    pub frac_of_region: bool,
+    pub contained: bool,
+    pub contained_include_clips: bool,
+    pub count_gapped_overlap: bool,
+    pub split_gapped_column: bool,
+    pub chr_prefix: ChrPrefix,
+    pub skip_missing_contigs: bool,
+    pub blacklist: Option<&'a String>,
+    pub include_chromosomes: Option<&'a String>,
+    pub exclude_chromosomes: Option<&'a String>,
+    pub extend_upstream: Option<usize>,
+    pub extend_downstream: Option<usize>,
+    pub merge_gap: Option<usize>,
+    pub no_sort: bool,
+    pub sweep: bool,
+    pub stream: bool,
+    pub fetch_merge_distance: i64,
+    pub threads: usize,
+    pub fail_fast: bool,
+    pub low_memory: bool,
+    pub transpose: bool,
+    pub tmp_dir: PathBuf,
+    pub progress: ProgressReport,
+    pub report_timings: bool,
+    pub verbose: bool,
+}
+
+// Function to parse the arguments into the options of a run, stopping with a usage error when they conflict
+pub fn parse(matches: &ArgMatches) -> Result<Options<'_>, Box<dyn std::error::Error>> {
+    // Conflicting arguments are reported as clap usage errors of the command
+    let mut cli = command();
+
+    let mode =*matches.get_one::<Mode>("mode").unwrap();
+    let bam_samples = data_loader::parse_bam_samples(matches.get_one::<String>("bam_file").unwrap())?;
+    let bam_file = bam_samples[0].1.as_str();
+    let multi_sample = bam_samples.len() > 1;
+    let region_args: Vec<&String> = matches.get_many::<String>("region").unwrap_or_default().collect();
+    let bin_size = matches.get_one::<usize>("bin_size").copied();
+    let tss_regions = matches.get_one::<String>("tss_regions");
+    let upstream = *matches.get_one::<usize>("upstream").unwrap();
+    let downstream = *matches.get_one::<usize>("downstream").unwrap();
+    let tss_per_transcript = matches.get_flag("tss_per_transcript");
+    // The regions file can be omitted when regions are given with --region, --bin-size or --tss-regions
+    let (regions_file, output_dir) = match (
+        matches.get_one::<String>("regions_file"),
+        matches.get_one::<String>("output_dir"),
+    ) {
+        (Some(regions_file), Some(output_dir)) => (Some(regions_file), output_dir),
+        (Some(output_dir), None) if !region_args.is_empty() || bin_size.is_some() || tss_regions.is_some() => {
+            (None, output_dir)
+        }
+        _ => cli.error(
+            clap::error::ErrorKind::MissingRequiredArgument,
+            "A regions file and an output directory are required (the regions file can be replaced by --region, --bin-size or --tss-regions)",
+        ).exit(),
+    };
+    let velocity = matches.get_flag("velocity");
+    let observed_features_only = matches.get_flag("observed_features_only");
+    if velocity && mode != Mode::Single {
+        cli.error(clap::error::ErrorKind::ArgumentConflict, "--velocity requires the single mode").exit();
+    }
+    let output_format = *matches.get_one::<OutputFormat>("output_format").unwrap();
+    let cellranger_output = output_format == OutputFormat::Cellranger;
+    let dense_output = output_format == OutputFormat::DenseTsv;
+    let featurecounts_output = output_format == OutputFormat::Featurecounts;
+    let feature_type = matches.get_one::<String>("feature_type").unwrap();
+    let single_feature_column = *matches.get_one::<FeatureColumns>("feature_columns").unwrap() == FeatureColumns::One;
+    let max_dense_cells = *matches.get_one::<u64>("max_dense_cells").unwrap();
+    let force_dense = matches.get_flag("force_dense");
+    // Bulk counts can be piped from stdout, single mode writing several files
+    let stdout_output = output_dir == "-";
+    if stdout_output && mode != Mode::Bulk {
+        cli.error(clap::error::ErrorKind::ArgumentConflict, "Single mode writes several files and needs an output directory instead of '-' (stdout)").exit();
+    }
+    let compress_outputs = *matches.get_one::<OutputCompression>("compression").unwrap() == OutputCompression::Gzip;
+    let compression_level = match matches.get_one::<u32>("compression_level") {
+        Some(_) if !compress_outputs => {
+            cli.error(clap::error::ErrorKind::ArgumentConflict, "--compression-level requires --compression gzip").exit()
+        }
+        Some(&level) => Compression::new(level),
+        None => Compression::default(),
+    };
+    if (cellranger_output || dense_output) && mode != Mode::Single {
+        cli.error(clap::error::ErrorKind::ArgumentConflict, format!("--output-format {} requires the single mode", output_format)).exit();
+    }
+    if featurecounts_output && (mode != Mode::Bulk || stdout_output) {
+        cli.error(clap::error::ErrorKind::ArgumentConflict, "--output-format featurecounts requires the bulk mode and an output directory").exit();
+    }
+    if featurecounts_output && matches.get_flag("stream") {
+        cli.error(clap::error::ErrorKind::ArgumentConflict, "--output-format featurecounts reads the reads outside the regions from the BAM index and cannot be used with --stream").exit();
+    }
+    if cellranger_output && single_feature_column {
+        cli.error(clap::error::ErrorKind::ArgumentConflict, "--output-format cellranger writes three-column features.tsv.gz and cannot be used with --feature-columns 1").exit();
+    }
+    if cellranger_output && observed_features_only {
+        cli.error(clap::error::ErrorKind::ArgumentConflict, "--output-format cellranger lists every region and cannot be used with --observed-features-only").exit();
+    }
+    let max_loci = *matches.get_one::<u32>("max_loci").unwrap();
+    let missing_nh = *matches.get_one::<MissingNh>("missing_nh").unwrap();
+    let multimap = *matches.get_one::<Multimap>("multimap").unwrap();
+    let fractional = multimap == Multimap::Fraction;
+    let em = multimap == Multimap::Em;
+    let em_iterations = *matches.get_one::<usize>("em_iterations").unwrap();
+    let min_mapq = *matches.get_one::<u8>("min_mapq").unwrap();
+    let treat_mapq_255_as_zero = matches.get_flag("treat_mapq_255_as_zero");
+    let require_flags = matches.get_one::<String>("require_flags").map_or(Ok(0), |flags| data_loader::parse_sam_flags(flags))?;
+    let primary_only = matches.get_flag("primary_only");
+    let proper_pairs_only = matches.get_flag("proper_pairs_only");
+    let read_filter = *matches.get_one::<Mate>("read").unwrap();
+    let ignore_duplicates = matches.get_flag("ignore_duplicates");
+    let min_insert = matches.get_one::<i64>("min_insert").copied();
+    let max_insert = matches.get_one::<i64>("max_insert").copied();
+    let drop_single_insert = *matches.get_one::<SingleEndInserts>("insert_filter_single").unwrap() == SingleEndInserts::Drop;
+    let min_read_length = matches.get_one::<usize>("min_read_length").copied();
+    let max_read_length = matches.get_one::<usize>("max_read_length").copied();
+    let max_softclip_frac = matches.get_one::<f64>("max_softclip_frac").copied();
+    let min_block_baseq = matches.get_one::<f64>("min_block_baseq").copied();
+    let require_baseq = matches.get_flag("require_baseq");
+    let count_hardclip = matches.get_flag("count_hardclip");
+    let splice_filter = *matches.get_one::<SpliceFilter>("reads").unwrap();
+    let mut exclude_flags = matches.get_one::<String>("exclude_flags").map_or(Ok(0), |flags| data_loader::parse_sam_flags(flags))?;
+    if matches.get_flag("skip_qcfail") {
+        exclude_flags |= 0x200;
+    }
+    let cell_barcode_file = matches.get_one::<String>("cell_barcode_file");
+    let correct_barcodes = matches.get_flag("correct_barcodes");
+    let by_read_group = matches.get_flag("by_read_group");
+    if by_read_group && mode != Mode::Bulk {
+        cli.error(clap::error::ErrorKind::ArgumentConflict, "--by-read-group requires the bulk mode").exit();
+    }
+    if matches.get_flag("stream") && *matches.get_one::<u32>("threads").unwrap() > 1 {
+        cli.error(clap::error::ErrorKind::ArgumentConflict, "--stream reads the BAM file once and cannot be combined with --threads").exit();
+    }
+    if multi_sample && matches.get_flag("stream") {
+        cli.error(clap::error::ErrorKind::ArgumentConflict, "--stream reads a single BAM file and cannot be combined with several BAM files").exit();
+    }
+    let coverage_out = matches.get_one::<CoverageFormat>("coverage_out").copied();
+    if coverage_out == Some(CoverageFormat::Bigwig) {
+        cli.error(clap::error::ErrorKind::InvalidValue, "--coverage-out bigwig is not supported: the coverage track is written as bedGraph only").exit();
+    }
+    let mut normalize: Vec<normalize::Method> = Vec::new();
+    for &method in matches.get_many::<normalize::Method>("normalize").into_iter().flatten() {
+        if !normalize.contains(&method) {
+            normalize.push(method);
+        }
+    }
+    let library_sizes: Option<Vec<u64>> = matches.get_many::<u64>("library_size").map(|sizes| sizes.copied().collect());
+    if !normalize.is_empty() && mode != Mode::Bulk {
+        cli.error(clap::error::ErrorKind::ArgumentConflict, "--normalize requires the bulk mode").exit();
+    }
+    if library_sizes.as_ref().is_some_and(|sizes| sizes.len() != bam_samples.len()) {
+        cli.error(
+            clap::error::ErrorKind::ValueValidation,
+            format!("--library-size needs one value per BAM file ({} given for {} BAM files)", library_sizes.as_ref().unwrap().len(), bam_samples.len()),
+        ).exit();
+    }
+    let assignments_bam = matches.get_one::<String>("assignments_bam");
+    let write_unassigned = matches.get_flag("write_unassigned");
+    if assignments_bam.is_some() && (multi_sample || em) {
+        cli.error(
+            clap::error::ErrorKind::ArgumentConflict,
+            "--assignments-bam requires a single BAM file, and cannot be combined with --multimap em",
+        ).exit();
+    }
+    let unassigned_out = matches.get_one::<String>("unassigned_out");
+    if unassigned_out.is_some() && multi_sample {
+        cli.error(clap::error::ErrorKind::ArgumentConflict, "--unassigned-out requires a single BAM file").exit();
+    }
+    let per_base_profiles = matches.get_flag("per_base_profiles");
+    let max_profile_length = *matches.get_one::<usize>("max_profile_length").unwrap();
+    if per_base_profiles && (multi_sample || em || output_dir == "-") {
+        cli.error(
+            clap::error::ErrorKind::ArgumentConflict,
+            "--per-base-profiles requires a single BAM file and an output directory, and cannot be combined with --multimap em",
+        ).exit();
+    }
+    let depth_stats = matches.get_flag("depth_stats");
+    let max_depth_stats_length = *matches.get_one::<usize>("max_depth_stats_length").unwrap();
+    if depth_stats && (mode != Mode::Bulk || multi_sample || em) {
+        cli.error(
+            clap::error::ErrorKind::ArgumentConflict,
+            "--depth-stats requires the bulk mode with a single BAM file, and cannot be combined with --multimap em",
+        ).exit();
+    }
+    if coverage_out.is_some() && (mode != Mode::Bulk || multi_sample || em || output_dir == "-") {
+        cli.error(
+            clap::error::ErrorKind::ArgumentConflict,
+            "--coverage-out requires the bulk mode with a single BAM file and an output directory, and cannot be combined with --multimap em",
+        ).exit();
+    }
+    if multi_sample && mode == Mode::Bulk {
+        for (flag, name) in [("by_read_group", "--by-read-group"), ("emit_antisense", "--emit-antisense"), ("split_gapped_column", "--split-gapped-column")] {
+            if matches.get_flag(flag) {
+                cli.error(clap::error::ErrorKind::ArgumentConflict, format!("{} cannot be combined with several BAM files, which give one Count column per sample", name)).exit();
+            }
+        }
+    }
+    if bam_file == "-" && !matches.get_flag("stream") {
+        cli.error(clap::error::ErrorKind::InvalidValue, "Reading the BAM file from stdin requires --stream").exit();
+    }
+    let barcode_suffix = *matches.get_one::<BarcodeSuffix>("barcode_suffix").unwrap();
+    let gem_group = matches.get_one::<u32>("gem_group").copied();
+    if gem_group.is_some() && barcode_suffix != BarcodeSuffix::Require {
+        cli.error(clap::error::ErrorKind::ArgumentConflict, "--gem-group is only used with --barcode-suffix require").exit();
+    }
+    let gem_group = gem_group.unwrap_or(1);
+    let barcode_tag = matches.get_one::<String>("barcode_tag").unwrap();
+    let barcode_fallback_tag = matches.get_one::<String>("barcode_fallback_tag");
+    let keep_untagged = matches.get_flag("keep_untagged") && mode == Mode::Single;
+    let umi_tags: Option<Vec<String>> = matches.get_one::<String>("umi_tag")
+        .filter(|_| mode == Mode::Single)
+        .map(|tags| tags.split(',').map(|tag| tag.trim().to_string()).collect());
+    for tag in umi_tags.iter().flatten() {
+        if tag.len() != 2 {
+            cli.error(clap::error::ErrorKind::InvalidValue, format!("Invalid UMI tag '{}': SAM tags have two characters", tag)).exit();
+        }
+    }
+    let require_umi = matches.get_flag("require_umi");
+    let umi_collapse = *matches.get_one::<UmiCollapse>("umi_collapse").unwrap();
+    for tag in std::iter::once(barcode_tag).chain(barcode_fallback_tag) {
+        if tag.len() != 2 {
+            cli.error(clap::error::ErrorKind::InvalidValue, format!("Invalid barcode tag '{}': SAM tags have two characters", tag)).exit();
+        }
+    }
+    let regions_format = *matches.get_one::<RegionFormat>("format").unwrap();
+    let bed_errors = *matches.get_one::<BedErrors>("bed_errors").unwrap();
+    let one_based = *matches.get_one::<Coordinates>("coordinates").unwrap() == Coordinates::OneBased;
+    let keep_peak_stats = matches.get_flag("keep_peak_stats");
+    let use_gene_name = matches.get_flag("gene_name");
+    let on_duplicate_names = *matches.get_one::<DuplicateNames>("on_duplicate_names").unwrap();
+    let group_map = matches.get_one::<String>("group_map");
+    let keep_duplicates = matches.get_flag("keep_duplicates");
+    let strandedness = match matches.get_one::<LibraryType>("library_type") {
+        Some(LibraryType::FirstStrand) => Strandedness::Reverse,
+        Some(LibraryType::SecondStrand) => Strandedness::Forward,
+        Some(LibraryType::Unstranded) => Strandedness::None,
+        None => *matches.get_one::<Strandedness>("strandedness").unwrap(),
+    };
+    let emit_antisense = matches.get_flag("emit_antisense");
+    let tn5_shift = matches.get_flag("tn5_shift");
+    let extend_reads = matches.get_one::<usize>("extend_reads").copied();
+    let count_mode = *matches.get_one::<CountMode>("count_mode").unwrap();
+    let overlap_mode = *matches.get_one::<OverlapMode>("overlap_mode").unwrap();
+    let assign_unique = *matches.get_one::<Assign>("assign").unwrap() == Assign::Unique;
+    let min_overlap = *matches.get_one::<usize>("min_overlap").unwrap();
+    let min_overlap_frac = matches.get_one::<f64>("min_overlap_frac").copied();
+    let frac_of_region = *matches.get_one::<FracOf>("frac_of").unwrap() == FracOf::Region;
+    let contained = matches.get_flag("contained");
+    let contained_include_clips = matches.get_flag("contained_include_clips");
+    let count_gapped_overlap = matches.get_flag("count_gapped_overlap");
+    let split_gapped_column = matches.get_flag("split_gapped_column");
+    if split_gapped_column && mode != Mode::Bulk {
+        cli.error(clap::error::ErrorKind::ArgumentConflict, "--split-gapped-column requires the bulk mode").exit();
+    }
+    // The featureCounts table has no room for Kai's extra columns
+    let extra_columns = [
+        (by_read_group, "--by-read-group"),
+        (emit_antisense, "--emit-antisense"),
+        (split_gapped_column, "--split-gapped-column"),
+        (keep_peak_stats, "--keep-peak-stats"),
+        (!normalize.is_empty(), "--normalize"),
+        (depth_stats, "--depth-stats"),
+    ];
+    if let Some((_, option)) = extra_columns.iter().find(|(given, _)| featurecounts_output && *given) {
+        cli.error(clap::error::ErrorKind::ArgumentConflict, format!("--output-format featurecounts cannot be combined with {}", option)).exit();
+    }
+    let chr_prefix = *matches.get_one::<ChrPrefix>("chr_prefix").unwrap();
+    let skip_missing_contigs = matches.get_flag("skip_missing_contigs");
+    let blacklist = matches.get_one::<String>("blacklist");
+    let include_chromosomes = matches.get_one::<String>("include_chromosomes");
+    let exclude_chromosomes = matches.get_one::<String>("exclude_chromosomes");
+    let extend = matches.get_one::<usize>("extend").copied();
+    let extend_upstream = matches.get_one::<usize>("extend_upstream").copied().or(extend);
+    let extend_downstream = matches.get_one::<usize>("extend_downstream").copied().or(extend);
+    let merge_gap = matches.get_one::<usize>("merge_regions").copied();
+    let no_sort = matches.get_flag("no_sort");
+    let sweep = *matches.get_one::<Engine>("engine").unwrap() == Engine::Sweep;
+    let stream = matches.get_flag("stream");
+    let fetch_merge_distance = *matches.get_one::<u32>("fetch_merge_distance").unwrap() as i64;
+    let sweep = sweep || stream;
+    let threads = *matches.get_one::<u32>("threads").unwrap() as usize;
+    let fail_fast = matches.get_flag("fail_fast");
+    let low_memory = matches.get_flag("low_memory");
+    if low_memory && mode != Mode::Single {
+        cli.error(clap::error::ErrorKind::ArgumentConflict, "--low-memory requires the single mode").exit();
+    }
+    if low_memory && em {
+        cli.error(clap::error::ErrorKind::ArgumentConflict, "--multimap em keeps every multimapped read in memory and cannot be combined with --low-memory").exit();
+    }
+    let transpose = matches.get_flag("transpose");
+    if transpose && (mode != Mode::Single || dense_output || low_memory) {
+        cli.error(
+            clap::error::ErrorKind::ArgumentConflict,
+            "--transpose applies to the MatrixMarket matrices of single mode, and cannot be combined with --output-format dense-tsv or --low-memory",
+        ).exit();
+    }
+    let tmp_dir = matches.get_one::<String>("tmp_dir").map_or_else(std::env::temp_dir, std::path::PathBuf::from);
+    let progress = *matches.get_one::<ProgressReport>("progress").unwrap();
+    let report_timings = matches.get_flag("timings");
+    let verbose = matches.get_flag("verbose");
+
+    Ok(Options {
+        mode,
+        bam_samples,
+        multi_sample,
+        region_args,
+        bin_size,
+        tss_regions,
+        upstream,
+        downstream,
+        tss_per_transcript,
+        regions_file,
+        output_dir,
+        velocity,
+        observed_features_only,
+        cellranger_output,
+        dense_output,
+        featurecounts_output,
+        feature_type,
+        single_feature_column,
+        max_dense_cells,
+        force_dense,
+        stdout_output,
+        compress_outputs,
+        compression_level,
+        max_loci,
+        missing_nh,
+        multimap,
+        fractional,
+        em,
+        em_iterations,
+        min_mapq,
+        treat_mapq_255_as_zero,
+        require_flags,
+        primary_only,
+        proper_pairs_only,
+        read_filter,
+        ignore_duplicates,
+        min_insert,
+        max_insert,
+        drop_single_insert,
+        min_read_length,
+        max_read_length,
+        max_softclip_frac,
+        min_block_baseq,
+        require_baseq,
+        count_hardclip,
+        splice_filter,
+        exclude_flags,
+        cell_barcode_file,
+        correct_barcodes,
+        by_read_group,
+        coverage_out,
+        normalize,
+        library_sizes,
+        assignments_bam,
+        write_unassigned,
+        unassigned_out,
+        per_base_profiles,
+        max_profile_length,
+        depth_stats,
+        max_depth_stats_length,
+        barcode_suffix,
+        gem_group,
+        barcode_tag,
+        barcode_fallback_tag,
+        keep_untagged,
+        umi_tags,
+        require_umi,
+        umi_collapse,
+        regions_format,
+        bed_errors,
+        one_based,
+        keep_peak_stats,
+        use_gene_name,
+        on_duplicate_names,
+        group_map,
+        keep_duplicates,
+        strandedness,
+        emit_antisense,
+        tn5_shift,
+        extend_reads,
+        count_mode,
+        overlap_mode,
+        assign_unique,
+        min_overlap,
+        min_overlap_frac,
+        frac_of_region,
+        contained,
+        contained_include_clips,
+        count_gapped_overlap,
+        split_gapped_column,
+        chr_prefix,
+        skip_missing_contigs,
+        blacklist,
+        include_chromosomes,
+        exclude_chromosomes,
+        extend_upstream,
+        extend_downstream,
+        merge_gap,
+        no_sort,
+        sweep,
+        stream,
+        fetch_merge_distance,
+        threads,
+        fail_fast,
+        low_memory,
+        transpose,
+        tmp_dir,
+        progress,
+        report_timings,
+        verbose,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn command_arguments_are_consistent() {
+        command().debug_assert();
+    }
+
+    #[test]
+    fn option_values_are_parsed_into_their_enums() {
+        let args = ["kai", "single", "in.bam", "regions.bed", "out", "--count-mode", "5prime", "--read", "2"];
+        let matches = command().try_get_matches_from(args).unwrap();
+        assert_eq!(matches.get_one::<Mode>("mode"), Some(&Mode::Single));
+        assert_eq!(matches.get_one::<CountMode>("count_mode"), Some(&CountMode::FivePrime));
+        assert_eq!(matches.get_one::<OverlapMode>("overlap_mode"), Some(&OverlapMode::Union));
+        assert_eq!(matches.get_one::<Mate>("read").map(|mate| mate.to_string()), Some("2".to_string()));
+        assert!(command().try_get_matches_from(["kai", "bulk", "in.bam", "regions.bed", "out", "--read", "3"]).is_err());
+    }
+}
@@ -0,0 +1,236 @@
+// Modules for the depth of the counted reads over the regions (--coverage-out, --per-base-profiles and --depth-stats)
+use crate::data_loader::Region;
+use std::collections::HashMap;
+use std::io::Write;
+
+// Read identity, as for the reads ignored on blacklisted regions
+type ReadKey = (Vec<u8>, i64, u16);
+
+// Depth changes of the counted reads per chromosome, and the regions each read was added for when its region shares
+// bases with other regions, so that a read counted in overlapping regions covers their shared bases once
+#[derive(Default)]
+pub struct Coverage {
+    events: HashMap<String, Vec<(i64, f64)>>,
+    shared_reads: HashMap<ReadKey, Vec<usize>>,
+}
+
+// Function to tell which regions share bases with another region of the same chromosome
+pub fn shared_regions(regions: &[Region]) -> Vec<bool> {
+    let mut shared = vec![false; regions.len()];
+    let mut blocks: Vec<(&str, usize, usize, usize)> = regions.iter().enumerate()
+        .flat_map(|(index, region)| region.blocks.iter().map(move |&(start, end)| (region.chromosome.as_str(), start, end, index)))
+        .collect();
+    blocks.sort_unstable();
+    // The block reaching furthest among the previous blocks of the chromosome, and its region
+    let mut furthest: Option<(&str, usize, usize)> = None;
+    for (chromosome, start, end, index) in blocks {
+        match furthest {
+            Some((furthest_chromosome, furthest_end, furthest_index)) if furthest_chromosome == chromosome && start < furthest_end => {
+                if furthest_index != index {
+                    shared[index] = true;
+                    shared[furthest_index] = true;
+                }
+                if end > furthest_end {
+                    furthest = Some((chromosome, end, index));
+                }
+            }
+            _ => furthest = Some((chromosome, end, index)),
+        }
+    }
+    shared
+}
+
+// Function to keep the parts of the read blocks within the region blocks
+fn clip_blocks(read_blocks: &[(i64, i64)], blocks: &[(usize, usize)]) -> Vec<(i64, i64)> {
+    let mut pieces = Vec::new();
+    for &(read_start, read_end) in read_blocks {
+        for &(start, end) in blocks {
+            let (piece_start, piece_end) = (read_start.max(start as i64), read_end.min(end as i64));
+            if piece_start < piece_end {
+                pieces.push((piece_start, piece_end));
+            }
+        }
+    }
+    pieces
+}
+
+// Function to remove the region blocks from the pieces of a read
+fn subtract_blocks(pieces: Vec<(i64, i64)>, blocks: &[(usize, usize)]) -> Vec<(i64, i64)> {
+    let mut remaining = pieces;
+    for &(start, end) in blocks {
+        let (start, end) = (start as i64, end as i64);
+        remaining = remaining.into_iter()
+            .flat_map(|(piece_start, piece_end)| {
+                [(piece_start, piece_end.min(start)), (piece_start.max(end), piece_end)]
+            })
+            .filter(|(piece_start, piece_end)| piece_start < piece_end)
+            .collect();
+    }
+    remaining
+}
+
+// Function to add the bases of read blocks within a region to its depth at each base from its start
+pub fn add_profile(profile: &mut [f64], region_start: usize, read_blocks: &[(i64, i64)], weight: f64) {
+    let region_start = region_start as i64;
+    for &(start, end) in read_blocks {
+        let start = (start - region_start).clamp(0, profile.len() as i64) as usize;
+        let end = (end - region_start).clamp(0, profile.len() as i64) as usize;
+        for depth in profile.iter_mut().take(end).skip(start) {
+            *depth += weight;
+        }
+    }
+}
+
+// Depth of the counted reads over a region: at each base from its start while the region is short enough to keep it,
+// and summed over the bases of its blocks
+pub struct RegionDepth {
+    pub profile: Option<Vec<f64>>,
+    block_depth: f64,
+}
+
+impl RegionDepth {
+    pub fn new(region: &Region, keep_profile: bool) -> Self {
+        RegionDepth { profile: keep_profile.then(|| vec![0.0; region.end - region.start]), block_depth: 0.0 }
+    }
+
+    // Function to add the bases of the blocks of a counted read
+    pub fn add(&mut self, region: &Region, read_blocks: &[(i64, i64)], weight: f64) {
+        if let Some(profile) = &mut self.profile {
+            add_profile(profile, region.start, read_blocks, weight);
+        }
+        let bases: i64 = clip_blocks(read_blocks, &region.blocks).iter().map(|(start, end)| end - start).sum();
+        self.block_depth += bases as f64 * weight;
+    }
+}
+
+// Depth of the counted reads over the bases of the region blocks of a feature for --depth-stats: the summed depth, the
+// number of bases, and the depth at each base unless a region of the feature is too long to keep it
+#[derive(Default)]
+pub struct DepthStats {
+    depth_sum: f64,
+    bases: u64,
+    depths: Vec<f64>,
+    capped: bool,
+}
+
+impl DepthStats {
+    // Function to add the depth of a region of the feature
+    pub fn add_region(&mut self, region: &Region, depth: &RegionDepth) {
+        self.depth_sum += depth.block_depth;
+        self.bases += region.blocks.iter().map(|(start, end)| (end - start) as u64).sum::<u64>();
+        match &depth.profile {
+            Some(profile) if !self.capped => {
+                for &(start, end) in &region.blocks {
+                    self.depths.extend_from_slice(&profile[start - region.start..end - region.start]);
+                }
+            }
+            _ => {
+                self.capped = true;
+                self.depths = Vec::new();
+            }
+        }
+    }
+
+    // Function to add the regions of the feature counted by another group
+    pub fn merge(&mut self, other: DepthStats) {
+        self.depth_sum += other.depth_sum;
+        self.bases += other.bases;
+        self.capped |= other.capped;
+        if self.capped {
+            self.depths = Vec::new();
+        } else {
+            self.depths.extend(other.depths);
+        }
+    }
+
+    // Function to give the mean depth over the bases, None for a feature without bases
+    pub fn mean(&self) -> Option<f64> {
+        (self.bases > 0).then(|| self.depth_sum / self.bases as f64)
+    }
+
+    // Function to give the median depth over the bases (the mean of the two middle depths for an even number of
+    // bases), None for a feature without bases or with a region too long to keep its depth at each base
+    pub fn median(&mut self) -> Option<f64> {
+        if self.capped || self.depths.is_empty() {
+            return None;
+        }
+        self.depths.sort_unstable_by(f64::total_cmp);
+        let middle = self.depths.len() / 2;
+        Some(if self.depths.len().is_multiple_of(2) { (self.depths[middle - 1] + self.depths[middle]) / 2.0 } else { self.depths[middle] })
+    }
+}
+
+impl Coverage {
+    // Function to add the bases of a read counted in a region, within the region blocks and leaving out the bases
+    // already added for the regions sharing bases with it
+    pub fn add(
+        &mut self,
+        regions: &[Region],
+        shared: &[bool],
+        region_index: usize,
+        read: impl FnOnce() -> ReadKey,
+        read_blocks: &[(i64, i64)],
+        weight: f64,
+    ) {
+        let region = &regions[region_index];
+        let mut pieces = clip_blocks(read_blocks, &region.blocks);
+        if shared[region_index] {
+            let added = self.shared_reads.entry(read()).or_default();
+            for &other in added.iter() {
+                pieces = subtract_blocks(pieces, &regions[other].blocks);
+            }
+            added.push(region_index);
+        }
+        let events = self.events.entry(region.chromosome.clone()).or_default();
+        for (start, end) in pieces {
+            events.push((start, weight));
+            events.push((end, -weight));
+        }
+    }
+
+    // Function to add the depth changes of another group; groups never share reads
+    pub fn merge(&mut self, other: Coverage) {
+        for (chromosome, events) in other.events {
+            self.events.entry(chromosome).or_default().extend(events);
+        }
+    }
+
+    // Function to write the depth as bedGraph intervals (0-based, half-open) in the order of the BAM header,
+    // joining neighbouring intervals of the same depth and leaving out bases without reads
+    pub fn write_bedgraph(&mut self, output: &mut impl Write, contigs: &[(String, u64)], format_depth: fn(f64) -> String) -> std::io::Result<()> {
+        for (chromosome, _) in contigs {
+            let Some(events) = self.events.get_mut(chromosome) else { continue };
+            events.sort_by_key(|&(position, _)| position);
+            let mut depth = 0.0;
+            // Interval being extended: start, end and formatted depth
+            let mut current: Option<(i64, i64, String)> = None;
+            let mut i = 0;
+            while i < events.len() {
+                let position = events[i].0;
+                while i < events.len() && events[i].0 == position {
+                    depth += events[i].1;
+                    i += 1;
+                }
+                let Some(&(next, _)) = events.get(i) else { break };
+                // Sums of fractional weights may not come back to exactly 0
+                if depth.abs() < 1e-9 {
+                    depth = 0.0;
+                    continue;
+                }
+                let value = format_depth(depth);
+                current = match current {
+                    Some((start, end, current_value)) if end == position && current_value == value => Some((start, next, value)),
+                    Some((start, end, current_value)) => {
+                        writeln!(output, "{}\t{}\t{}\t{}", chromosome, start, end, current_value)?;
+                        Some((position, next, value))
+                    }
+                    None => Some((position, next, value)),
+                };
+            }
+            if let Some((start, end, value)) = current {
+                writeln!(output, "{}\t{}\t{}\t{}", chromosome, start, end, value)?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -1,18 +1,985 @@
 // Modules for data loading
-use std::collections::{HashSet};
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
-use std::io::{BufRead, BufReader};
+use std::io::{self, BufRead, BufReader};
+use log::{info, debug, warn};
+use flate2::read::MultiGzDecoder;
+use itertools::Itertools;
+use crate::cli::{BedErrors, DuplicateNames, RegionFormat};
+use crate::error::KaiError;
 
-// Function to load the cell barcodes
-pub fn load_cell_barcodes(file_path: Option<&String>) -> Result<HashSet<String>, Box<dyn std::error::Error>> {
-    let mut barcodes = HashSet::new();
+// Region of interest, made of one or more blocks (0-based, half-open) on a single chromosome
+pub struct Region {
+    pub chromosome: String,
+    // Chromosome name as given in the input, when it was renamed to match the BAM header
+    pub input_chromosome: Option<String>,
+    pub start: usize,
+    pub end: usize,
+    pub name: Option<String>,
+    pub strand: char,
+    pub blocks: Vec<(usize, usize)>,
+    // Whether coordinates are reported 1-based inclusive (as given by the user) instead of BED-style
+    pub one_based: bool,
+    // signalValue and qValue columns of narrowPeak/broadPeak files
+    pub peak_stats: Option<(String, String)>,
+    // Label of the regions file the region comes from, when several files are counted together
+    pub set: Option<String>,
+}
+
+impl Region {
+    // Function to create a single-block region
+    pub fn new(chromosome: String, start: usize, end: usize, name: Option<String>, strand: char) -> Region {
+        Region { chromosome, input_chromosome: None, start, end, name, strand, blocks: vec![(start, end)], one_based: false, peak_stats: None, set: None }
+    }
+
+    // Start coordinate to report in the outputs, following the coordinate convention of the input
+    pub fn output_start(&self) -> usize {
+        if self.one_based { self.start + 1 } else { self.start }
+    }
+
+    // Chromosome name to report in the outputs, following the naming of the input
+    pub fn output_chromosome(&self) -> &str {
+        self.input_chromosome.as_deref().unwrap_or(&self.chromosome)
+    }
+
+    // Feature name: the region name if available, otherwise its coordinates
+    pub fn feature_name(&self) -> String {
+        match &self.name {
+            Some(name) => name.clone(),
+            None => format!("{}:{}-{}", self.output_chromosome(), self.output_start(), self.end),
+        }
+    }
+
+    // Feature key used in the outputs, prefixed with the set label so that sets never share keys
+    pub fn key(&self) -> String {
+        match &self.set {
+            Some(set) => format!("{}|{}", set, self.feature_name()),
+            None => self.feature_name(),
+        }
+    }
+}
+
+// Function to parse a strand column, treating anything but '+' or '-' as unstranded
+fn parse_strand(field: Option<&&str>) -> char {
+    match field.map(|strand| strand.trim()) {
+        Some("+") => '+',
+        Some("-") => '-',
+        _ => '.',
+    }
+}
+
+// Function to open a plain, gzip or bgzip-compressed text file for reading ('-' reads from stdin)
+pub fn open_text_file(file_path: &str) -> Result<Box<dyn BufRead>, Box<dyn std::error::Error>> {
+    let mut reader: Box<dyn BufRead> = if file_path == "-" {
+        Box::new(BufReader::new(io::stdin()))
+    } else {
+        let file = File::open(file_path).map_err(|source| KaiError::InputOpen { path: file_path.to_string(), source })?;
+        Box::new(BufReader::new(file))
+    };
+    // Sniff the gzip magic bytes rather than trusting the extension
+    let is_gzip = reader.fill_buf()?.starts_with(&[0x1f, 0x8b]);
+
+    let lower = file_path.to_lowercase();
+    if (lower.ends_with(".gz") || lower.ends_with(".bgz")) && !is_gzip {
+        return Err(format!("{} has a compressed file extension but is not gzip/bgzip-compressed", file_path).into());
+    }
+    if is_gzip {
+        // MultiGzDecoder also reads bgzip files, which are a series of gzip members
+        Ok(Box::new(BufReader::new(MultiGzDecoder::new(reader))))
+    } else {
+        Ok(reader)
+    }
+}
+
+// Function to load the cell barcodes in file order
+pub fn load_cell_barcodes(file_path: Option<&String>) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let mut barcodes = Vec::new();
     if let Some(path) = file_path {
-        let file = File::open(path)?;
-        let reader = BufReader::new(file);
+        let reader = open_text_file(path)?;
         for line in reader.lines() {
             let barcode = line?.trim().to_string();
-            barcodes.insert(barcode);
+            barcodes.push(barcode);
         }
     }
     Ok(barcodes)
 }
+
+// Function to load a two-column mapping of region names to the groups (meta-features) they are counted in
+pub fn load_group_map(path: &str) -> Result<HashMap<String, String>, Box<dyn std::error::Error>> {
+    let reader = open_text_file(path)?;
+    let mut group_map = HashMap::new();
+    for (index, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 2 {
+            return Err(format!("{}:{}: expected region and group columns", path, index + 1).into());
+        }
+        group_map.insert(fields[0].to_string(), fields[1].to_string());
+    }
+    info!("Loaded {} region to group assignments", group_map.len());
+    Ok(group_map)
+}
+
+// Function to combine the regions mapped to the same group into a single region over the union of their blocks,
+// so that a read overlapping several members of a group is counted once for it
+pub fn aggregate_groups(
+    regions: Vec<Region>,
+    group_map: &HashMap<String, String>,
+) -> Result<Vec<Region>, Box<dyn std::error::Error>> {
+    let n_input = regions.len();
+    let mut aggregated = Vec::with_capacity(n_input);
+    let mut group_order: Vec<(Option<String>, String)> = Vec::new();
+    let mut groups: HashMap<(Option<String>, String), Region> = HashMap::new();
+    for region in regions {
+        let Some(group) = group_map.get(&region.feature_name()) else {
+            aggregated.push(region);
+            continue;
+        };
+        let group_key = (region.set.clone(), group.clone());
+        match groups.get_mut(&group_key) {
+            Some(group_region) => {
+                if group_region.chromosome != region.chromosome {
+                    return Err(format!(
+                        "Group {} has regions on different chromosomes ({} and {})",
+                        group, group_region.chromosome, region.chromosome
+                    ).into());
+                }
+                if group_region.strand != region.strand {
+                    group_region.strand = '.';
+                }
+                group_region.blocks.extend(region.blocks);
+            }
+            None => {
+                group_order.push(group_key.clone());
+                groups.insert(group_key, region);
+            }
+        }
+    }
+    let n_ungrouped = aggregated.len();
+    if n_ungrouped > 0 {
+        warn!("{} regions are not assigned to any group and are counted on their own", n_ungrouped);
+    }
+    for group_key in group_order {
+        let mut region = groups.remove(&group_key).unwrap();
+        region.blocks = merge_intervals(std::mem::take(&mut region.blocks));
+        region.start = region.blocks.first().unwrap().0;
+        region.end = region.blocks.last().unwrap().1;
+        region.name = Some(group_key.1);
+        aggregated.push(region);
+    }
+    info!("Aggregated {} regions into {} groups", n_input - n_ungrouped, aggregated.len() - n_ungrouped);
+    Ok(aggregated)
+}
+
+// Function to read a list of values given either as a file with one value per line or as a comma-separated list
+pub fn load_list_argument(argument: &str) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let values: Vec<String> = if std::path::Path::new(argument).is_file() {
+        open_text_file(argument)?
+            .lines()
+            .collect::<Result<Vec<_>, _>>()?
+            .into_iter()
+            .map(|line| line.trim().to_string())
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .collect()
+    } else {
+        argument.split(',').map(|value| value.trim().to_string()).filter(|value| !value.is_empty()).collect()
+    };
+    Ok(values)
+}
+
+// Names of the SAM flag bits, following samtools
+pub const SAM_FLAG_NAMES: [(&str, u16); 12] = [
+    ("paired", 0x1),
+    ("proper_pair", 0x2),
+    ("unmap", 0x4),
+    ("munmap", 0x8),
+    ("reverse", 0x10),
+    ("mreverse", 0x20),
+    ("read1", 0x40),
+    ("read2", 0x80),
+    ("secondary", 0x100),
+    ("qcfail", 0x200),
+    ("dup", 0x400),
+    ("supplementary", 0x800),
+];
+
+// Function to parse SAM flags given as an integer (decimal or 0x hexadecimal) or a comma-separated list of flag names
+pub fn parse_sam_flags(value: &str) -> Result<u16, Box<dyn std::error::Error>> {
+    let value = value.trim();
+    if let Some(hex) = value.strip_prefix("0x").or_else(|| value.strip_prefix("0X")) {
+        return u16::from_str_radix(hex, 16).map_err(|_| format!("Invalid SAM flags '{}'", value).into());
+    }
+    if let Ok(flags) = value.parse::<u16>() {
+        return Ok(flags);
+    }
+    let mut flags = 0;
+    for name in value.split(',').map(|name| name.trim().to_lowercase()) {
+        let name = match name.as_str() {
+            "unmapped" => "unmap",
+            "duplicate" => "dup",
+            "supp" => "supplementary",
+            "qc_fail" => "qcfail",
+            other => other,
+        };
+        let (_, bit) = SAM_FLAG_NAMES.iter()
+            .find(|(flag_name, _)| *flag_name == name)
+            .ok_or_else(|| format!(
+                "Unknown SAM flag '{}': expected an integer or one of {}",
+                name,
+                SAM_FLAG_NAMES.iter().map(|(flag_name, _)| flag_name).join(",")
+            ))?;
+        flags |= bit;
+    }
+    Ok(flags)
+}
+
+// Function to format SAM flags as the integer value followed by the flag names
+pub fn format_sam_flags(flags: u16) -> String {
+    let names = SAM_FLAG_NAMES.iter().filter(|(_, bit)| flags & bit != 0).map(|(name, _)| name).join(",");
+    format!("{} ({})", flags, if names.is_empty() { "none" } else { names.as_str() })
+}
+
+// Function to split a comma-separated list of regions files, each optionally labelled as name=path,
+// into (set label, path) pairs; a single unlabelled file gets no set label
+pub fn parse_region_sets(regions_arg: &str) -> Vec<(Option<String>, String)> {
+    let entries: Vec<&str> = regions_arg.split(',').filter(|entry| !entry.is_empty()).collect();
+    let mut sets = Vec::with_capacity(entries.len());
+    for entry in &entries {
+        let (label, path) = match entry.split_once('=') {
+            Some((label, path)) if !label.contains('/') => (Some(label.to_string()), path.to_string()),
+            _ => (None, entry.to_string()),
+        };
+        sets.push((label, path));
+    }
+    if sets.len() > 1 || sets.iter().any(|(label, _)| label.is_some()) {
+        for (label, path) in sets.iter_mut() {
+            if label.is_none() {
+                // Default to the file name without directories and extensions
+                let file_name = path.rsplit('/').next().unwrap_or(path);
+                let stem = file_name
+                    .trim_end_matches(".gz")
+                    .trim_end_matches(".bgz");
+                let stem = stem.split_once('.').map_or(stem, |(stem, _)| stem);
+                *label = Some(stem.to_string());
+            }
+        }
+    }
+    sets
+}
+
+// Function to parse the BAM files argument into (sample, path) pairs: a comma-separated list of [sample=]path, or a
+// .txt/.tsv sample sheet with one BAM file per line as path or sample<TAB>path; samples are named after the file
+// name without directories and extensions unless given
+pub fn parse_bam_samples(bam_arg: &str) -> Result<Vec<(String, String)>, Box<dyn std::error::Error>> {
+    let lower = bam_arg.to_lowercase();
+    let entries: Vec<(Option<String>, String)> = if lower.ends_with(".txt") || lower.ends_with(".tsv") {
+        let mut entries = Vec::new();
+        for line in open_text_file(bam_arg)?.lines() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            entries.push(match line.split_once('\t') {
+                Some((sample, path)) => (Some(sample.trim().to_string()), path.trim().to_string()),
+                None => (None, line.to_string()),
+            });
+        }
+        if entries.is_empty() {
+            return Err(format!("Sample sheet {} lists no BAM files", bam_arg).into());
+        }
+        entries
+    } else {
+        parse_region_sets(bam_arg)
+    };
+    let samples: Vec<(String, String)> = entries
+        .into_iter()
+        .map(|(sample, path)| {
+            let sample = sample.unwrap_or_else(|| {
+                let file_name = path.rsplit('/').next().unwrap_or(&path);
+                file_name.split_once('.').map_or(file_name, |(stem, _)| stem).to_string()
+            });
+            (sample, path)
+        })
+        .collect();
+    if let Some(sample) = samples.iter().map(|(sample, _)| sample).duplicates().next() {
+        return Err(format!("Sample {} is given for several BAM files: name them with sample=path", sample).into());
+    }
+    Ok(samples)
+}
+
+// Function to guess the format of the regions file from its extension or SAF header line
+pub fn detect_regions_format(file_path: &str) -> RegionFormat {
+    // Regions read from stdin cannot be peeked at without consuming them
+    if file_path == "-" {
+        return RegionFormat::Bed;
+    }
+    let lower = file_path.to_lowercase();
+    let lower = lower.trim_end_matches(".gz").trim_end_matches(".bgz");
+    if lower.ends_with(".gtf") || lower.ends_with(".gff") || lower.ends_with(".gff3") {
+        return RegionFormat::Gtf;
+    }
+    if lower.ends_with(".saf") {
+        return RegionFormat::Saf;
+    }
+    if lower.ends_with(".narrowpeak") || lower.ends_with(".broadpeak") {
+        return RegionFormat::Peak;
+    }
+    let first_line = open_text_file(file_path).ok().and_then(|reader| {
+        reader.lines().map_while(Result::ok).find(|line| !line.starts_with('#'))
+    });
+    match first_line {
+        Some(line) if line.starts_with("GeneID\t") => RegionFormat::Saf,
+        _ => RegionFormat::Bed,
+    }
+}
+
+// Function to parse a BED coordinate, rejecting negative and non-numeric values
+fn parse_bed_coordinate(value: &str, column: &str) -> Result<usize, (String, String)> {
+    value.trim().parse::<usize>().map_err(|_| {
+        let message = if value.trim().parse::<i64>().is_ok() {
+            format!("Negative {} coordinate", column)
+        } else {
+            format!("Non-numeric {} coordinate", column)
+        };
+        (format!("{}='{}'", column, value), message)
+    })
+}
+
+// Function to parse a single BED line into a region, returning the offending field and a message on failure
+fn parse_bed_line(line: &str, one_based: bool, peak: bool) -> Result<Region, (String, String)> {
+    let fields: Vec<&str> = line.split('\t').collect();
+    if fields.len() < 3 {
+        return Err((format!("{} columns", fields.len()), "Fewer than three columns".to_string()));
+    }
+
+    let chromosome = fields[0].to_string();
+    let mut start = parse_bed_coordinate(fields[1], "start")?;
+    let end = parse_bed_coordinate(fields[2], "end")?;
+    if start > end {
+        return Err((format!("start={} end={}", start, end), "Start coordinate greater than end".to_string()));
+    }
+    // Convert 1-based inclusive coordinates to 0-based half-open
+    if one_based {
+        if start == 0 {
+            return Err(("start=0".to_string(), "Start coordinate 0 with one-based coordinates".to_string()));
+        }
+        start -= 1;
+    }
+    // Use the name column if present, falling back to the coordinates for '.' or empty names
+    let name = fields.get(3)
+        .map(|name| name.trim())
+        .filter(|name| !name.is_empty() && *name != ".")
+        .map(|name| name.to_string());
+
+    let strand = parse_strand(fields.get(5));
+
+    let mut region = Region::new(chromosome, start, end, name, strand);
+    // Expand BED12 blocks (blockSizes and blockStarts are relative to chromStart)
+    if fields.len() >= 12 {
+        region.blocks = parse_bed12_blocks(start, end, fields[9], fields[10], fields[11])
+            .map_err(|e| ("blocks".to_string(), e))?;
+    }
+    // Keep signalValue and qValue of narrowPeak (BED6+4) and broadPeak (BED6+3) lines
+    if peak {
+        if fields.len() < 9 {
+            return Err((format!("{} columns", fields.len()), "Fewer than nine columns for a peak file".to_string()));
+        }
+        region.peak_stats = Some((fields[6].trim().to_string(), fields[8].trim().to_string()));
+    }
+    Ok(region)
+}
+
+// Function to parse the BED (or narrowPeak/broadPeak) file containing regions of interest,
+// stopping ('strict') or skipping ('skip') malformed lines
+pub fn parse_bed_file(
+    bed_file: &str,
+    policy: BedErrors,
+    one_based: bool,
+    peak: bool,
+) -> Result<Vec<Region>, Box<dyn std::error::Error>> {
+    let reader = open_text_file(bed_file)?;
+
+    let mut regions = Vec::new();
+    let mut skipped = 0;
+    let mut header_lines = 0;
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue; // Skip empty lines
+        }
+        if is_bed_header_line(&line) {
+            header_lines += 1;
+            continue; // Skip comment, track and browser lines
+        }
+        match parse_bed_line(&line, one_based, peak) {
+            Ok(region) => regions.push(region),
+            Err((field, message)) => {
+                let error = KaiError::BedParse { file: bed_file.to_string(), line_number: i + 1, field, message };
+                if policy == BedErrors::Strict {
+                    return Err(error.into());
+                }
+                warn!("Skipping malformed BED line {}", error);
+                skipped += 1;
+            }
+        }
+    }
+
+    if header_lines > 0 {
+        info!("Skipped {} header lines in {}", header_lines, bed_file);
+    }
+    if skipped > 0 {
+        warn!("Skipped {} malformed lines in {}", skipped, bed_file);
+    }
+    info!("Parsed {} regions", regions.len());
+    if regions.is_empty() {
+        return Err(format!("No regions found in {}", bed_file).into());
+    }
+    Ok(regions)
+}
+
+// Function to check whether a BED line is a comment, or a UCSC track or browser line
+fn is_bed_header_line(line: &str) -> bool {
+    if line.starts_with('#') {
+        return true;
+    }
+    let first_word = line.split_whitespace().next().unwrap_or("");
+    first_word == "track" || first_word == "browser"
+}
+
+// Function to parse a region given as chr:start-end or chr:start..end, allowing thousands separators
+pub fn parse_region_string(region: &str, one_based: bool) -> Result<Region, Box<dyn std::error::Error>> {
+    let invalid = || format!("Invalid region '{}': expected chr:start-end or chr:start..end", region);
+    // Split on the last colon so that contig names containing colons are kept intact
+    let (chromosome, range) = region.trim().rsplit_once(':').ok_or_else(invalid)?;
+    let (start, end) = range.split_once("..").or_else(|| range.split_once('-')).ok_or_else(invalid)?;
+    let parse_coordinate = |value: &str| value.trim().replace([',', '_'], "").parse::<usize>().map_err(|_| invalid());
+    let mut start = parse_coordinate(start)?;
+    let end = parse_coordinate(end)?;
+    if one_based {
+        if start == 0 {
+            return Err(invalid().into());
+        }
+        start -= 1;
+    }
+    if chromosome.is_empty() || start >= end {
+        return Err(invalid().into());
+    }
+    Ok(Region::new(chromosome.to_string(), start, end, None, '.'))
+}
+
+// Function to expand the blocks of a BED12 line into absolute intervals
+fn parse_bed12_blocks(
+    start: usize,
+    end: usize,
+    block_count: &str,
+    block_sizes: &str,
+    block_starts: &str,
+) -> Result<Vec<(usize, usize)>, String> {
+    let block_count = block_count.trim().parse::<usize>().map_err(|_| "Invalid blockCount".to_string())?;
+    let parse_list = |list: &str, what: &str| -> Result<Vec<usize>, String> {
+        list.trim().trim_end_matches(',').split(',')
+            .map(|value| value.trim().parse::<usize>().map_err(|_| format!("Invalid {}", what)))
+            .collect()
+    };
+    let sizes = parse_list(block_sizes, "blockSizes")?;
+    let starts = parse_list(block_starts, "blockStarts")?;
+    if sizes.len() != block_count || starts.len() != block_count {
+        return Err(format!("blockCount {} does not match blockSizes/blockStarts", block_count));
+    }
+
+    let mut blocks = Vec::with_capacity(block_count);
+    for (size, offset) in sizes.into_iter().zip(starts) {
+        let block_start = start + offset;
+        let block_end = block_start + size;
+        if block_end > end {
+            return Err("Block extends beyond chromEnd".to_string());
+        }
+        blocks.push((block_start, block_end));
+    }
+    blocks.sort_unstable();
+    Ok(blocks)
+}
+
+// Function to resolve regions sharing the same feature name, either by suffixing them (_1, _2, ... in input order),
+// by adding their coordinates (name|chr:start-end, suffixed if still shared), or by failing
+pub fn resolve_duplicate_names(regions: &mut [Region], policy: DuplicateNames) -> Result<(), Box<dyn std::error::Error>> {
+    // Names only need to be unique within a set
+    let count_names = |regions: &[Region]| {
+        let mut name_counts: HashMap<(Option<String>, String), usize> = HashMap::new();
+        for region in regions {
+            *name_counts.entry((region.set.clone(), region.feature_name())).or_insert(0) += 1;
+        }
+        name_counts
+    };
+    let mut name_counts = count_names(regions);
+    let duplicates: Vec<&(Option<String>, String)> = name_counts.iter()
+        .filter(|(_, &count)| count > 1)
+        .map(|(name, _)| name)
+        .sorted()
+        .collect();
+    if duplicates.is_empty() {
+        return Ok(());
+    }
+    if policy == DuplicateNames::Error {
+        return Err(format!(
+            "Region names used more than once: {}",
+            duplicates.iter().map(|(_, name)| name).join(", ")
+        ).into());
+    }
+
+    if policy == DuplicateNames::Coords {
+        info!("Adding coordinates to {} duplicated region names", duplicates.len());
+        let duplicates: HashSet<(Option<String>, String)> = duplicates.into_iter().cloned().collect();
+        for region in regions.iter_mut() {
+            let Some(name) = region.name.as_ref() else { continue };
+            if duplicates.contains(&(region.set.clone(), name.clone())) {
+                let new_name = format!("{}|{}:{}-{}", name, region.output_chromosome(), region.output_start(), region.end);
+                debug!("Renamed duplicated region {} to {}", name, new_name);
+                region.name = Some(new_name);
+            }
+        }
+        // Regions sharing both name and coordinates are suffixed below
+        name_counts = count_names(regions);
+    }
+
+    let duplicates: HashSet<(Option<String>, String)> = name_counts.iter()
+        .filter(|(_, &count)| count > 1)
+        .map(|(name, _)| name.clone())
+        .collect();
+    if duplicates.is_empty() {
+        return Ok(());
+    }
+    info!("Suffixing {} duplicated region names", duplicates.len());
+    let mut used_names: HashSet<(Option<String>, String)> = name_counts.into_keys().collect();
+    let mut next_suffix: HashMap<(Option<String>, String), usize> = HashMap::new();
+    for region in regions.iter_mut() {
+        let name = region.feature_name();
+        let set_name = (region.set.clone(), name.clone());
+        if !duplicates.contains(&set_name) {
+            continue;
+        }
+        let suffix = next_suffix.entry(set_name).or_insert(0);
+        // Skip suffixes that would collide with names already present in the file
+        let new_name = loop {
+            *suffix += 1;
+            let candidate = format!("{}_{}", name, suffix);
+            if used_names.insert((region.set.clone(), candidate.clone())) {
+                break candidate;
+            }
+        };
+        debug!("Renamed duplicated region {} at {}:{}-{} to {}", name, region.chromosome, region.start, region.end, new_name);
+        region.name = Some(new_name);
+    }
+    Ok(())
+}
+
+// Function to parse the attribute column of a GTF (key "value";) or GFF3 (key=value;) line
+fn parse_gtf_attributes(attributes: &str) -> HashMap<String, String> {
+    let mut parsed = HashMap::new();
+    let mut in_quotes = false;
+    let mut current = String::new();
+    let mut entries = Vec::new();
+    for c in attributes.chars() {
+        match c {
+            '"' => in_quotes = !in_quotes,
+            ';' if !in_quotes => entries.push(std::mem::take(&mut current)),
+            _ => current.push(c),
+        }
+    }
+    entries.push(current);
+
+    for entry in entries {
+        let entry = entry.trim();
+        if entry.is_empty() {
+            continue;
+        }
+        // GFF3 uses key=value, GTF uses key "value" (quotes already stripped above)
+        let (key, value) = match entry.split_once('=') {
+            Some((key, value)) if !key.contains(' ') => (key, value),
+            _ => match entry.split_once(char::is_whitespace) {
+                Some((key, value)) => (key, value),
+                None => continue,
+            },
+        };
+        // Keep the first occurrence of repeated keys (e.g. multiple tag attributes)
+        parsed.entry(key.trim().to_string()).or_insert_with(|| value.trim().to_string());
+    }
+    parsed
+}
+
+// Features spanning several input lines (GTF exons, SAF rows), with their blocks not yet merged
+type FeatureBlocks = HashMap<String, Region>;
+
+// Function to merge overlapping or book-ended intervals
+pub fn merge_intervals(mut intervals: Vec<(usize, usize)>) -> Vec<(usize, usize)> {
+    intervals.sort_unstable();
+    let mut merged: Vec<(usize, usize)> = Vec::with_capacity(intervals.len());
+    for (start, end) in intervals {
+        match merged.last_mut() {
+            Some(last) if start <= last.1 => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    merged
+}
+
+// Function to parse a GTF/GFF3 file and build one region per gene from the union of its exons
+pub fn parse_gtf_file(gtf_file: &str, use_gene_name: bool) -> Result<Vec<Region>, Box<dyn std::error::Error>> {
+    let reader = open_text_file(gtf_file)?;
+
+    // Genes in order of first appearance, with their chromosome and exons
+    let mut gene_order: Vec<String> = Vec::new();
+    let mut gene_exons: FeatureBlocks = HashMap::new();
+    let mut gene_names: HashMap<String, String> = HashMap::new();
+    // GFF3 exons point to their transcript via Parent, and transcripts point to their gene
+    let mut transcript_genes: HashMap<String, String> = HashMap::new();
+    let mut gff3_exons: Vec<(String, Region)> = Vec::new();
+
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.starts_with('#') || line.trim().is_empty() {
+            continue; // Skip headers or empty lines
+        }
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() < 9 {
+            debug!("Invalid GTF format: {}", line);
+            continue;
+        }
+
+        let attributes = parse_gtf_attributes(fields[8]);
+        let feature = fields[2];
+
+        // Record gene names and transcript-to-gene links from non-exon lines
+        if feature != "exon" {
+            if let Some(id) = attributes.get("ID") {
+                if let Some(parent) = attributes.get("Parent") {
+                    transcript_genes.insert(id.clone(), parent.clone());
+                }
+                if let Some(name) = attributes.get("gene_name").or_else(|| attributes.get("Name")) {
+                    if feature == "gene" {
+                        gene_names.insert(id.clone(), name.clone());
+                    }
+                }
+            }
+            continue;
+        }
+
+        // Convert 1-based inclusive coordinates to 0-based half-open
+        let start = fields[3].parse::<usize>()
+            .map_err(|_| format!("Invalid start coordinate in GTF line: {}", line))?;
+        let start = one_based_start(start, gtf_file, i + 1)?;
+        let end = fields[4].parse::<usize>()
+            .map_err(|_| format!("Invalid end coordinate in GTF line: {}", line))?;
+        let exon = Region::new(fields[0].to_string(), start, end, None, parse_strand(fields.get(6)));
+
+        let gene_id = match attributes.get("gene_id") {
+            Some(gene_id) => gene_id.clone(),
+            None => {
+                match attributes.get("Parent") {
+                    Some(parent) => {
+                        // Exons may belong to several transcripts of the same gene
+                        for transcript in parent.split(',') {
+                            gff3_exons.push((transcript.to_string(), Region::new(exon.chromosome.clone(), start, end, None, exon.strand)));
+                        }
+                    }
+                    None => debug!("Exon without gene_id or Parent: {}", line),
+                }
+                continue;
+            }
+        };
+        if let Some(name) = attributes.get("gene_name") {
+            gene_names.entry(gene_id.clone()).or_insert_with(|| name.clone());
+        }
+        add_feature_block(&mut gene_order, &mut gene_exons, gene_id, exon)?;
+    }
+
+    // Resolve GFF3 exons to their genes through the transcript records
+    for (transcript, exon) in gff3_exons {
+        let gene_id = transcript_genes.get(&transcript).cloned().unwrap_or(transcript);
+        add_feature_block(&mut gene_order, &mut gene_exons, gene_id, exon)?;
+    }
+
+    let regions = build_feature_regions(gene_order, gene_exons, |gene_id| {
+        if use_gene_name {
+            gene_names.get(&gene_id).cloned().unwrap_or(gene_id)
+        } else {
+            gene_id
+        }
+    });
+
+    info!("Parsed {} genes", regions.len());
+    Ok(regions)
+}
+
+// Function to build promoter windows around the TSS (5' end) of each gene, or of each transcript
+pub fn parse_tss_regions(
+    gtf_file: &str,
+    upstream: usize,
+    downstream: usize,
+    use_gene_name: bool,
+    per_transcript: bool,
+) -> Result<Vec<Region>, Box<dyn std::error::Error>> {
+    let reader = open_text_file(gtf_file)?;
+    let (id_attribute, record_features): (&str, &[&str]) = if per_transcript {
+        ("transcript_id", &["transcript", "mRNA"])
+    } else {
+        ("gene_id", &["gene"])
+    };
+
+    // Spans of gene/transcript records, with spans built from exons as a fallback for GTFs without such records
+    let mut record_order: Vec<String> = Vec::new();
+    let mut record_spans: FeatureBlocks = HashMap::new();
+    let mut exon_order: Vec<String> = Vec::new();
+    let mut exon_spans: FeatureBlocks = HashMap::new();
+    let mut names: HashMap<String, String> = HashMap::new();
+
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.starts_with('#') || line.trim().is_empty() {
+            continue; // Skip headers or empty lines
+        }
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() < 9 {
+            debug!("Invalid GTF format: {}", line);
+            continue;
+        }
+        let feature = fields[2];
+        let is_record = record_features.contains(&feature);
+        if !is_record && feature != "exon" {
+            continue;
+        }
+
+        let attributes = parse_gtf_attributes(fields[8]);
+        let id = match attributes.get(id_attribute).or_else(|| if is_record { attributes.get("ID") } else { None }) {
+            Some(id) => id.clone(),
+            None => continue,
+        };
+        if let Some(name) = attributes.get("gene_name").or_else(|| attributes.get("Name")) {
+            names.entry(id.clone()).or_insert_with(|| name.clone());
+        }
+
+        // Convert 1-based inclusive coordinates to 0-based half-open
+        let start = fields[3].parse::<usize>()
+            .map_err(|_| format!("Invalid start coordinate in GTF line: {}", line))?;
+        let start = one_based_start(start, gtf_file, i + 1)?;
+        let end = fields[4].parse::<usize>()
+            .map_err(|_| format!("Invalid end coordinate in GTF line: {}", line))?;
+        let span = Region::new(fields[0].to_string(), start, end, None, parse_strand(fields.get(6)));
+        if is_record {
+            add_feature_block(&mut record_order, &mut record_spans, id, span)?;
+        } else {
+            add_feature_block(&mut exon_order, &mut exon_spans, id, span)?;
+        }
+    }
+
+    // Use exon spans only for features without a gene/transcript record
+    let mut n_from_exons = 0;
+    for id in exon_order {
+        if let std::collections::hash_map::Entry::Vacant(entry) = record_spans.entry(id.clone()) {
+            entry.insert(exon_spans.remove(&id).unwrap());
+            record_order.push(id);
+            n_from_exons += 1;
+        }
+    }
+    if n_from_exons > 0 {
+        info!("{} features without {} records were located from their exons", n_from_exons, record_features.join("/"));
+    }
+    let features = build_feature_regions(record_order, record_spans, |id| id);
+
+    let mut windows = Vec::with_capacity(features.len());
+    for feature in features {
+        // The TSS is the first base on the + strand and the last base on the - strand
+        let (start, end) = if feature.strand == '-' {
+            let tss = feature.end - 1;
+            ((tss + 1).saturating_sub(downstream), tss + 1 + upstream)
+        } else {
+            (feature.start.saturating_sub(upstream), feature.start + downstream)
+        };
+        let id = feature.name.unwrap();
+        let name = if use_gene_name && !per_transcript {
+            names.get(&id).cloned().unwrap_or(id)
+        } else {
+            id
+        };
+        windows.push(Region::new(feature.chromosome, start, end, Some(name), feature.strand));
+    }
+
+    info!("Built {} TSS windows", windows.len());
+    Ok(windows)
+}
+
+// Function to parse a SAF file (GeneID, Chr, Start, End, Strand) and aggregate rows sharing a GeneID
+pub fn parse_saf_file(saf_file: &str) -> Result<Vec<Region>, Box<dyn std::error::Error>> {
+    let reader = open_text_file(saf_file)?;
+
+    let mut feature_order: Vec<String> = Vec::new();
+    let mut feature_blocks: FeatureBlocks = HashMap::new();
+
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.starts_with('#') || line.starts_with("GeneID\t") || line.trim().is_empty() {
+            continue; // Skip headers or empty lines
+        }
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() < 4 {
+            debug!("Invalid SAF format: {}", line);
+            continue;
+        }
+
+        // Convert 1-based inclusive coordinates to 0-based half-open
+        let start = fields[2].parse::<usize>()
+            .map_err(|_| format!("Invalid start coordinate in SAF line: {}", line))?;
+        let start = one_based_start(start, saf_file, i + 1)?;
+        let end = fields[3].parse::<usize>()
+            .map_err(|_| format!("Invalid end coordinate in SAF line: {}", line))?;
+
+        let row = Region::new(fields[1].to_string(), start, end, None, parse_strand(fields.get(4)));
+        add_feature_block(&mut feature_order, &mut feature_blocks, fields[0].to_string(), row)?;
+    }
+
+    let regions = build_feature_regions(feature_order, feature_blocks, |gene_id| gene_id);
+
+    info!("Parsed {} features", regions.len());
+    Ok(regions)
+}
+
+// Function to convert the 1-based start of a GTF or SAF line to 0-based, rejecting a start of 0
+fn one_based_start(start: usize, file: &str, line_number: usize) -> Result<usize, KaiError> {
+    start.checked_sub(1).ok_or_else(|| KaiError::BedParse {
+        file: file.to_string(),
+        line_number,
+        field: "start=0".to_string(),
+        message: "Start coordinate 0 with one-based coordinates".to_string(),
+    })
+}
+
+// Function to add a block to its feature, rejecting features spread over several chromosomes
+fn add_feature_block(
+    feature_order: &mut Vec<String>,
+    feature_blocks: &mut FeatureBlocks,
+    feature_id: String,
+    block: Region,
+) -> Result<(), Box<dyn std::error::Error>> {
+    match feature_blocks.get_mut(&feature_id) {
+        Some(feature) => {
+            if feature.chromosome != block.chromosome {
+                return Err(format!(
+                    "Feature {} has intervals on different chromosomes ({} and {})",
+                    feature_id, feature.chromosome, block.chromosome
+                ).into());
+            }
+            if feature.strand != block.strand {
+                feature.strand = '.';
+            }
+            feature.blocks.push((block.start, block.end));
+        }
+        None => {
+            feature_order.push(feature_id.clone());
+            feature_blocks.insert(feature_id, block);
+        }
+    }
+    Ok(())
+}
+
+// Function to build one region per feature from its merged blocks, in order of first appearance
+fn build_feature_regions<F>(feature_order: Vec<String>, mut feature_blocks: FeatureBlocks, name_of: F) -> Vec<Region>
+where
+    F: Fn(String) -> String,
+{
+    let mut regions = Vec::with_capacity(feature_order.len());
+    for feature_id in feature_order {
+        let mut region = feature_blocks.remove(&feature_id).unwrap();
+        region.blocks = merge_intervals(std::mem::take(&mut region.blocks));
+        region.start = region.blocks.first().unwrap().0;
+        region.end = region.blocks.last().unwrap().1;
+        region.name = Some(name_of(feature_id));
+        regions.push(region);
+    }
+    regions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Function to write a regions file to the temporary directory, returning its path
+    fn write_file(name: &str, content: &str) -> String {
+        let path = std::env::temp_dir().join(format!("kai_test_{}_{}", std::process::id(), name));
+        std::fs::write(&path, content).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn gtf_exons_start_at_one_based_coordinates() {
+        let exon = |start: usize, end: usize| format!("chr1\ttest\texon\t{}\t{}\t.\t+\t.\tgene_id \"g1\"; transcript_id \"t1\";\n", start, end);
+        let path = write_file("exons.gtf", &(exon(1, 100) + &exon(201, 300)));
+        let regions = parse_gtf_file(&path, false).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(regions.len(), 1);
+        assert_eq!(regions[0].blocks, vec![(0, 100), (200, 300)]);
+
+        let path = write_file("zero.gtf", &(exon(1, 100) + &exon(0, 300)));
+        let error = parse_gtf_file(&path, false).map(|_| ()).unwrap_err().to_string();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(error, format!("{}:2: Start coordinate 0 with one-based coordinates (start=0)", path));
+    }
+
+    #[test]
+    fn tss_windows_follow_the_gene_strand() {
+        let gene = |strand: char, start: usize, id: &str| {
+            format!("chr1\ttest\tgene\t{}\t{}\t.\t{}\t.\tgene_id \"{}\";\n", start, start + 999, strand, id)
+        };
+        let path = write_file("genes.gtf", &(gene('+', 1001, "plus") + &gene('-', 5001, "minus")));
+        let windows = parse_tss_regions(&path, 100, 50, false, false).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        // TSS at base 1001 (0-based 1000) on +, and at base 6000 (0-based 5999) on -
+        assert_eq!((windows[0].start, windows[0].end), (900, 1050));
+        assert_eq!((windows[1].start, windows[1].end), (5950, 6100));
+
+        let path = write_file("zero_gene.gtf", &gene('+', 0, "zero"));
+        let error = parse_tss_regions(&path, 100, 50, false, false).map(|_| ()).unwrap_err().to_string();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(error, format!("{}:1: Start coordinate 0 with one-based coordinates (start=0)", path));
+    }
+
+    #[test]
+    fn saf_rows_are_converted_to_half_open_and_aggregated() {
+        let path = write_file("rows.saf", "GeneID\tChr\tStart\tEnd\tStrand\ngeneA\tchr1\t1\t100\t+\ngeneB\tchr1\t501\t501\t-\ngeneA\tchr1\t201\t300\t+\n");
+        let regions = parse_saf_file(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(regions.len(), 2);
+        assert_eq!((regions[0].key(), regions[0].start, regions[0].end), ("geneA".to_string(), 0, 300));
+        assert_eq!(regions[0].blocks, vec![(0, 100), (200, 300)]);
+        // A single-base feature covers one base
+        assert_eq!((regions[1].key(), regions[1].start, regions[1].end, regions[1].strand), ("geneB".to_string(), 500, 501, '-'));
+    }
+
+    #[test]
+    fn saf_start_of_zero_is_rejected() {
+        let path = write_file("zero.saf", "geneA\tchr1\t1\t100\t+\ngeneB\tchr1\t0\t100\t+\n");
+        let error = parse_saf_file(&path).map(|_| ()).unwrap_err().to_string();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(error, format!("{}:2: Start coordinate 0 with one-based coordinates (start=0)", path));
+    }
+
+    #[test]
+    fn malformed_bed_lines_stop_or_are_skipped() {
+        let good = "chr1\t100\t200\tgood\n";
+        for (line, field, message) in [
+            ("chr1\t-5\t200\n", "start='-5'", "Negative start coordinate"),
+            ("chr1\t100\tend\n", "end='end'", "Non-numeric end coordinate"),
+            ("chr1\t100\n", "2 columns", "Fewer than three columns"),
+        ] {
+            let path = write_file("malformed.bed", &format!("# regions\n{}{}{}", good, line, good));
+            let error = parse_bed_file(&path, BedErrors::Strict, false, false).map(|_| ()).unwrap_err().to_string();
+            assert_eq!(error, format!("{}:3: {} ({})", path, message, field));
+            let regions = parse_bed_file(&path, BedErrors::Skip, false, false).unwrap();
+            std::fs::remove_file(&path).unwrap();
+            assert_eq!(regions.iter().map(|region| (region.start, region.end)).collect::<Vec<_>>(), vec![(100, 200), (100, 200)]);
+        }
+    }
+}
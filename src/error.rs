@@ -0,0 +1,23 @@
+// Modules for the errors reported to users
+use thiserror::Error;
+
+// Errors with the context (file, line, region) needed to fix the input, printed as a single message
+#[derive(Debug, Error)]
+pub enum KaiError {
+    #[error("{file}:{line_number}: {message} ({field})")]
+    BedParse { file: String, line_number: usize, field: String, message: String },
+    #[error("Cannot open {path}: {source}")]
+    InputOpen { path: String, source: std::io::Error },
+    #[error("Cannot open BAM file {path}: {source}")]
+    BamOpen { path: String, source: rust_htslib::errors::Error },
+    #[error("BAM file {path} has no index: create one with `samtools index {path}`, or read it without one with --stream")]
+    MissingBamIndex { path: String },
+    #[error("Cannot read {region} from {path}: {source}")]
+    BamFetch { path: String, region: String, source: rust_htslib::errors::Error },
+    #[error("Cannot write BAM file {path}: {source}")]
+    BamWrite { path: String, source: rust_htslib::errors::Error },
+    #[error("Cannot write {path}: {source}")]
+    Output { path: String, source: std::io::Error },
+    #[error("Cannot use temporary file {path} (see --tmp-dir): {source}")]
+    Spill { path: String, source: std::io::Error },
+}
@@ -0,0 +1,152 @@
+// Modules for the featureCounts layout of the bulk outputs (--output-format featurecounts)
+use crate::assignments::{ReadOutcomes, Unassigned};
+use crate::data_loader::Region;
+use itertools::Itertools;
+use std::collections::HashMap;
+use std::io::Write;
+
+// Categories of the reads not counted in the .summary file of featureCounts 2.0, in its order
+const CATEGORIES: [&str; 13] = [
+    "Unassigned_Unmapped",
+    "Unassigned_Read_Type",
+    "Unassigned_Singleton",
+    "Unassigned_MappingQuality",
+    "Unassigned_Chimera",
+    "Unassigned_FragmentLength",
+    "Unassigned_Duplicate",
+    "Unassigned_MultiMapping",
+    "Unassigned_Secondary",
+    "Unassigned_NonSplit",
+    "Unassigned_NoFeatures",
+    "Unassigned_Overlapping_Length",
+    "Unassigned_Ambiguity",
+];
+
+// Function to give the featureCounts category of a reason a mapped read is not counted; the reads left out by filters
+// featureCounts does not have count as read types it does not take
+fn category(reason: Unassigned) -> &'static str {
+    match reason {
+        Unassigned::ImproperPair => "Unassigned_Singleton",
+        Unassigned::LowMapq => "Unassigned_MappingQuality",
+        Unassigned::Supplementary => "Unassigned_Chimera",
+        Unassigned::InsertSize => "Unassigned_FragmentLength",
+        Unassigned::Duplicate | Unassigned::UmiDuplicate => "Unassigned_Duplicate",
+        Unassigned::Multimapping => "Unassigned_MultiMapping",
+        Unassigned::Secondary => "Unassigned_Secondary",
+        Unassigned::Splicing => "Unassigned_NonSplit",
+        Unassigned::Blacklisted | Unassigned::WrongStrand | Unassigned::NoFeature => "Unassigned_NoFeatures",
+        Unassigned::LowOverlap | Unassigned::Uncontained => "Unassigned_Overlapping_Length",
+        Unassigned::MultipleRegions | Unassigned::Ambiguous => "Unassigned_Ambiguity",
+        Unassigned::Flags
+        | Unassigned::OtherMate
+        | Unassigned::MissingNh
+        | Unassigned::ReadLength
+        | Unassigned::SoftClipped
+        | Unassigned::NoBarcode
+        | Unassigned::UnlistedBarcode
+        | Unassigned::LowBaseQuality
+        | Unassigned::MissingBaseQuality
+        | Unassigned::MissingUmi => "Unassigned_Read_Type",
+    }
+}
+
+// Function to count the bases covered by the blocks of the regions of a feature, once where they overlap
+fn covered_bases(regions: &[&Region]) -> usize {
+    let blocks = regions.iter()
+        .flat_map(|region| region.blocks.iter().map(move |&(start, end)| (region.chromosome.as_str(), start, end)))
+        .sorted_unstable();
+    let mut bases = 0;
+    // Block being extended: chromosome, start and end
+    let mut current: Option<(&str, usize, usize)> = None;
+    for (chromosome, start, end) in blocks {
+        current = match current {
+            Some((current_chromosome, current_start, current_end)) if current_chromosome == chromosome && start <= current_end => {
+                Some((chromosome, current_start, current_end.max(end)))
+            }
+            Some((_, current_start, current_end)) => {
+                bases += current_end - current_start;
+                Some((chromosome, start, end))
+            }
+            None => Some((chromosome, start, end)),
+        };
+    }
+    bases + current.map_or(0, |(_, start, end)| end - start)
+}
+
+// Function to write the count table: a comment line with the program and its command, then a row per feature with
+// its key, the chromosome, 1-based start and end and strand of each block of its regions joined by ';', the number of
+// bases they cover and its count in each BAM file
+pub fn write_table(
+    output: &mut impl Write,
+    version: &str,
+    args: &[String],
+    features: &[(String, Vec<&Region>)],
+    columns: &[(&str, &HashMap<u32, f64>)],
+    format_count: fn(f64) -> String,
+) -> std::io::Result<()> {
+    let command = args.iter().map(|arg| format!("\"{}\"", arg)).join(" ");
+    writeln!(output, "# Program:kai v{}; Command:{}", version, command)?;
+    write!(output, "Geneid\tChr\tStart\tEnd\tStrand\tLength")?;
+    for (name, _) in columns {
+        write!(output, "\t{}", name)?;
+    }
+    writeln!(output)?;
+    for (feature, (key, regions)) in features.iter().enumerate() {
+        let blocks: Vec<(&Region, usize, usize)> = regions.iter()
+            .flat_map(|&region| region.blocks.iter().map(move |&(start, end)| (region, start, end)))
+            .collect();
+        write!(output, "{}\t{}", key, blocks.iter().map(|(region, _, _)| region.output_chromosome()).join(";"))?;
+        write!(output, "\t{}", blocks.iter().map(|(_, start, _)| start + 1).join(";"))?;
+        write!(output, "\t{}", blocks.iter().map(|(_, _, end)| end).join(";"))?;
+        write!(output, "\t{}\t{}", blocks.iter().map(|(region, _, _)| region.strand).join(";"), covered_bases(regions))?;
+        for (_, counts) in columns {
+            write!(output, "\t{}", format_count(counts.get(&(feature as u32)).copied().unwrap_or(0.0)))?;
+        }
+        writeln!(output)?;
+    }
+    Ok(())
+}
+
+// Column of the .summary file for a BAM file: its reads counted (Assigned) and not counted per category, each read
+// tallied once in the category of the first region that leaves it out so that the column sums to the reads of the file
+pub struct SummaryColumn {
+    assigned: u64,
+    categories: [u64; CATEGORIES.len()],
+}
+
+impl SummaryColumn {
+    // Function to tally the column from the outcomes of the reads met in the regions and the mapped and unmapped reads
+    // in the index of the BAM file; the mapped reads never met lie outside every region (Unassigned_NoFeatures)
+    pub fn new(outcomes: &ReadOutcomes, mapped: u64, unmapped: u64) -> Self {
+        let slot = |name: &str| CATEGORIES.iter().position(|&category| category == name).unwrap();
+        let mut categories = [0; CATEGORIES.len()];
+        for (reason, count) in outcomes.unassigned.iter() {
+            categories[slot(category(reason))] += count;
+        }
+        categories[slot("Unassigned_Unmapped")] += unmapped.saturating_sub(outcomes.unmapped_reads) + outcomes.unmapped;
+        categories[slot("Unassigned_NoFeatures")] += mapped.saturating_sub(outcomes.mapped_reads);
+        SummaryColumn { assigned: outcomes.assigned, categories }
+    }
+}
+
+// Function to write the .summary file with a column per BAM file
+pub fn write_summary(output: &mut impl Write, columns: &[(&str, SummaryColumn)]) -> std::io::Result<()> {
+    write!(output, "Status")?;
+    for (name, _) in columns {
+        write!(output, "\t{}", name)?;
+    }
+    writeln!(output)?;
+    write!(output, "Assigned")?;
+    for (_, column) in columns {
+        write!(output, "\t{}", column.assigned)?;
+    }
+    writeln!(output)?;
+    for (position, name) in CATEGORIES.iter().enumerate() {
+        write!(output, "{}", name)?;
+        for (_, column) in columns {
+            write!(output, "\t{}", column.categories[position])?;
+        }
+        writeln!(output)?;
+    }
+    Ok(())
+}
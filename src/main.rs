@@ -1,306 +1,2911 @@
-use clap::{Arg, Command};
-use rust_htslib::bam::{IndexedReader, Read};
-use rust_htslib::bam::record::{Aux, Cigar};
-use std::collections::{HashMap, HashSet};
+use rust_htslib::bam::{self, HeaderView, IndexedReader, Read};
+use rust_htslib::bam::record::{Aux, Cigar, CigarStringView, Record};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::hash::Hash;
+use std::ops::AddAssign;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
 use std::fs::File;
-use std::io::{Write, BufRead};
-use std::io;
-use log::{info, debug, LevelFilter};
-use env_logger;
+use std::io::{BufWriter, Write};
+use log::{info, debug, warn, LevelFilter};
 use itertools::Itertools;
 use flate2::write::GzEncoder;
 use flate2::Compression;
 
+mod assignments;
+mod barcodes;
+mod cli;
+mod coverage;
 mod data_loader;
+mod error;
+mod featurecounts;
+mod normalize;
+mod progress;
+mod regions;
+mod spill;
+mod summary;
+mod timings;
+mod umi;
 
-struct Region {
-    chromosome: String,
-    start: usize,
-    end: usize,
+use assignments::Unassigned;
+use cli::{BarcodeSuffix, CountMode, Mate, MissingNh, Mode, Multimap, OverlapMode, RegionFormat, SpliceFilter, Strandedness, UmiCollapse};
+use data_loader::Region;
+use error::KaiError;
+
+// Function to get the strand of the fragment a read comes from, using read 1 orientation for paired-end reads
+fn fragment_strand(record: &Record) -> char {
+    let reverse = record.is_reverse() != (record.is_paired() && record.is_last_in_template());
+    if reverse { '-' } else { '+' }
+}
+
+// UMI sequence, packed two bits per base when it only contains A, C, G and T
+#[derive(PartialEq, Eq, Hash)]
+enum Umi {
+    Packed(u64),
+    Raw(String),
+}
+
+// Function to encode a UMI compactly, with a leading 1 bit marking its length
+fn pack_umi(umi: &str) -> Umi {
+    if umi.len() > 31 {
+        return Umi::Raw(umi.to_string());
+    }
+    let mut packed: u64 = 1;
+    for base in umi.bytes() {
+        let code = match base {
+            b'A' => 0,
+            b'C' => 1,
+            b'G' => 2,
+            b'T' => 3,
+            _ => return Umi::Raw(umi.to_string()),
+        };
+        packed = (packed << 2) | code;
+    }
+    Umi::Packed(packed)
+}
+
+// Barcode collecting reads without barcode under --keep-untagged
+const UNTAGGED_BARCODE: &str = "NO_BARCODE";
+
+// Function to read a string aux tag
+fn string_tag(record: &Record, tag: &[u8]) -> Option<String> {
+    str_tag(record, tag).map(str::to_string)
+}
+
+// Function to borrow a string aux tag from a read without copying it
+fn str_tag<'a>(record: &'a Record, tag: &[u8]) -> Option<&'a str> {
+    match record.aux(tag) {
+        Ok(Aux::String(value)) => Some(value),
+        _ => None,
+    }
+}
+
+// Function to read an integer aux tag stored with any integer width, logging tags of other types
+fn integer_tag(record: &Record, tag: &[u8]) -> Option<i64> {
+    match record.aux(tag) {
+        Ok(Aux::U8(value)) => Some(value as i64),
+        Ok(Aux::I8(value)) => Some(value as i64),
+        Ok(Aux::U16(value)) => Some(value as i64),
+        Ok(Aux::I16(value)) => Some(value as i64),
+        Ok(Aux::U32(value)) => Some(value as i64),
+        Ok(Aux::I32(value)) => Some(value as i64),
+        Ok(other) => {
+            debug!("Ignoring {} tag of non-integer type {:?}", String::from_utf8_lossy(tag), other);
+            None
+        }
+        Err(_) => None,
+    }
+}
+
+// Candidate regions of each multimapped read, keyed by read name and cell barcode
+type MultimapCandidates = HashMap<(Vec<u8>, Option<String>), Vec<u32>>;
+
+// BAM reader of a group of regions: indexed to fetch chromosomes or regions, or streamed once in coordinate order
+enum BamInput {
+    Indexed(IndexedReader),
+    Stream(bam::Reader),
+}
+
+impl BamInput {
+    fn header(&self) -> &HeaderView {
+        match self {
+            BamInput::Indexed(reader) => reader.header(),
+            BamInput::Stream(reader) => reader.header(),
+        }
+    }
+
+    fn read(&mut self, record: &mut Record) -> Option<Result<(), rust_htslib::errors::Error>> {
+        match self {
+            BamInput::Indexed(reader) => reader.read(record),
+            BamInput::Stream(reader) => reader.read(record),
+        }
+    }
+
+    // Function to move the reader to the start of a chromosome; a streamed BAM file is simply read on
+    fn fetch_contig(&mut self, tid: u32) -> Result<(), rust_htslib::errors::Error> {
+        match self {
+            BamInput::Indexed(reader) => reader.fetch(tid),
+            BamInput::Stream(_) => Ok(()),
+        }
+    }
+
+    // Function to fetch the reads overlapping a region, only found through the index
+    fn fetch_region(&mut self, chromosome: &[u8], start: i64, end: i64) -> Result<(), rust_htslib::errors::Error> {
+        match self {
+            BamInput::Indexed(reader) => reader.fetch((chromosome, start, end)),
+            BamInput::Stream(_) => Err(rust_htslib::errors::Error::Fetch),
+        }
+    }
+}
+
+// Names numbered in order of first appearance, so that counts are keyed by small integers and names are only
+// looked up again when writing the outputs
+#[derive(Default)]
+struct Interner {
+    names: Vec<String>,
+    ids: HashMap<String, u32>,
+}
+
+impl Interner {
+    // Function to get the number of a name, numbering it if it is new
+    fn intern(&mut self, name: &str) -> u32 {
+        if let Some(&id) = self.ids.get(name) {
+            return id;
+        }
+        let id = self.names.len() as u32;
+        self.names.push(name.to_string());
+        self.ids.insert(name.to_string(), id);
+        id
+    }
+
+    fn name(&self, id: u32) -> &str {
+        &self.names[id as usize]
+    }
+}
+
+// Counts per feature and barcode number
+type Counts = HashMap<u32, HashMap<u32, f64>>;
+
+// Matrices of single mode written to the spill files of --low-memory, followed by the three velocity matrices
+const REGION_MATRIX: u8 = 0;
+const ANTISENSE_MATRIX: u8 = 1;
+const VELOCITY_MATRIX: u8 = 2;
+
+// Function to add a count of a barcode to the counts of a feature, or to the spill file with --low-memory
+fn add_count(counts: &mut Counts, spill: &mut Option<spill::Spill>, matrix: u8, feature: u32, barcode: u32, count: f64) -> Result<(), KaiError> {
+    match spill {
+        Some(spill) => spill.add(matrix, feature, barcode, count),
+        None => {
+            *counts.entry(feature).or_default().entry(barcode).or_insert(0.0) += count;
+            Ok(())
+        }
+    }
+}
+
+// Counts and read tallies of a group of regions, summed over the groups counted by each thread
+#[derive(Default)]
+struct Tally {
+    region_counts: Counts,
+    region_totals: HashMap<u32, f64>,
+    read_group_counts: HashMap<u32, HashMap<String, f64>>,
+    velocity_counts: [Counts; 3],
+    antisense_counts: Counts,
+    antisense_totals: HashMap<u32, f64>,
+    gapped_totals: HashMap<u32, f64>,
+    gapped_reads: u64,
+    cell_barcodes: Interner,
+    coverage: coverage::Coverage,
+    // Rows of per_base_profiles.tsv.gz, as written by the group
+    profile_rows: Vec<u8>,
+    // Depth of the counted reads over the bases of each feature for --depth-stats
+    depth_stats: HashMap<u32, coverage::DepthStats>,
+    // Reads not counted per reason, and reads to write to --assignments-bam
+    assignments: assignments::Assignments<RowBuffer>,
+    // Rows of --unassigned-out, as written by the group
+    unassigned_rows: Vec<u8>,
+    // Spill files of --low-memory, with the merged number of each of their barcodes once tallies are merged
+    spill_files: Vec<(std::path::PathBuf, Option<Vec<u32>>)>,
+    blacklisted_reads: HashSet<(Vec<u8>, i64, u16)>,
+    missing_nh_reads: u64,
+    examined_reads: u64,
+    fractional_reads: u64,
+    multimap_candidates: MultimapCandidates,
+    duplicate_reads: u64,
+    missing_flag_reads: HashMap<u16, usize>,
+    excluded_flag_reads: HashMap<u16, usize>,
+    ambiguous_reads: u64,
+    multi_region_reads: u64,
+    primary_tag_reads: u64,
+    fallback_tag_reads: u64,
+    no_barcode_reads: u64,
+    exact_barcode_reads: u64,
+    corrected_barcode_reads: u64,
+    unlisted_barcode_example: Option<String>,
+    umi_missing_reads: u64,
+    umi_tag_reads: HashMap<String, u64>,
+    umi_duplicate_reads: u64,
+    no_feature_reads: u64,
+    fetched_reads: u64,
+    filtered_reads: u64,
+    read_time: Duration,
+}
+
+// Function to add the values of a map to those of another
+fn merge_sums<K: Eq + Hash, V: AddAssign + Default>(into: &mut HashMap<K, V>, from: HashMap<K, V>) {
+    for (key, value) in from {
+        *into.entry(key).or_default() += value;
+    }
+}
+
+// Function to add counts per feature and barcode to those of another group, renumbering its barcodes
+fn merge_counts(into: &mut Counts, from: Counts, barcode_ids: &[u32]) {
+    for (feature, counts) in from {
+        merge_sums(into.entry(feature).or_default(), counts.into_iter().map(|(barcode, count)| (barcode_ids[barcode as usize], count)).collect());
+    }
+}
+
+impl Tally {
+    // Function to add the tallies of another group, keeping the first unlisted barcode example
+    fn merge(&mut self, other: Tally) {
+        let barcode_ids: Vec<u32> = other.cell_barcodes.names.iter().map(|barcode| self.cell_barcodes.intern(barcode)).collect();
+        merge_counts(&mut self.region_counts, other.region_counts, &barcode_ids);
+        merge_sums(&mut self.region_totals, other.region_totals);
+        for (feature, counts) in other.read_group_counts {
+            merge_sums(self.read_group_counts.entry(feature).or_default(), counts);
+        }
+        for (counts, other_counts) in self.velocity_counts.iter_mut().zip(other.velocity_counts) {
+            merge_counts(counts, other_counts, &barcode_ids);
+        }
+        merge_counts(&mut self.antisense_counts, other.antisense_counts, &barcode_ids);
+        merge_sums(&mut self.antisense_totals, other.antisense_totals);
+        merge_sums(&mut self.gapped_totals, other.gapped_totals);
+        for (path, ids) in other.spill_files {
+            let ids = match ids {
+                Some(ids) => ids.iter().map(|&barcode| barcode_ids[barcode as usize]).collect(),
+                None => barcode_ids.clone(),
+            };
+            self.spill_files.push((path, Some(ids)));
+        }
+        self.coverage.merge(other.coverage);
+        self.profile_rows.extend(other.profile_rows);
+        for (feature, stats) in other.depth_stats {
+            self.depth_stats.entry(feature).or_default().merge(stats);
+        }
+        self.assignments.merge(other.assignments);
+        self.unassigned_rows.extend(other.unassigned_rows);
+        self.blacklisted_reads.extend(other.blacklisted_reads);
+        for (read, candidates) in other.multimap_candidates {
+            let merged = self.multimap_candidates.entry(read).or_default();
+            for candidate in candidates {
+                if !merged.contains(&candidate) {
+                    merged.push(candidate);
+                }
+            }
+        }
+        merge_sums(&mut self.missing_flag_reads, other.missing_flag_reads);
+        merge_sums(&mut self.excluded_flag_reads, other.excluded_flag_reads);
+        merge_sums(&mut self.umi_tag_reads, other.umi_tag_reads);
+        self.unlisted_barcode_example = self.unlisted_barcode_example.take().or(other.unlisted_barcode_example);
+        for (total, other_total) in [
+            (&mut self.gapped_reads, other.gapped_reads),
+            (&mut self.missing_nh_reads, other.missing_nh_reads),
+            (&mut self.examined_reads, other.examined_reads),
+            (&mut self.fractional_reads, other.fractional_reads),
+            (&mut self.duplicate_reads, other.duplicate_reads),
+            (&mut self.ambiguous_reads, other.ambiguous_reads),
+            (&mut self.multi_region_reads, other.multi_region_reads),
+            (&mut self.primary_tag_reads, other.primary_tag_reads),
+            (&mut self.fallback_tag_reads, other.fallback_tag_reads),
+            (&mut self.no_barcode_reads, other.no_barcode_reads),
+            (&mut self.exact_barcode_reads, other.exact_barcode_reads),
+            (&mut self.corrected_barcode_reads, other.corrected_barcode_reads),
+            (&mut self.umi_missing_reads, other.umi_missing_reads),
+            (&mut self.umi_duplicate_reads, other.umi_duplicate_reads),
+            (&mut self.no_feature_reads, other.no_feature_reads),
+            (&mut self.fetched_reads, other.fetched_reads),
+            (&mut self.filtered_reads, other.filtered_reads),
+        ] {
+            *total += other_total;
+        }
+        self.read_time += other.read_time;
+    }
+
+    // Function to add the EM shares of the multimapped reads to the counts of their candidate regions, by barcode in
+    // single mode and by read group in bulk mode
+    fn resolve_multimappers(&mut self, mode: Mode, iterations: usize) {
+        let unique_counts: HashMap<u32, f64> = if mode == Mode::Single {
+            self.region_counts.iter().map(|(&feature, counts)| (feature, counts.values().sum())).collect()
+        } else {
+            self.region_totals.clone()
+        };
+        for ((feature, cell_barcode), count) in resolve_multimappers_em(&self.multimap_candidates, &unique_counts, iterations) {
+            match cell_barcode {
+                Some(cb) if mode == Mode::Single => {
+                    *self.region_counts.entry(feature).or_default().entry(self.cell_barcodes.intern(&cb)).or_insert(0.0) += count;
+                }
+                Some(read_group) => {
+                    *self.read_group_counts.entry(feature).or_default().entry(read_group).or_insert(0.0) += count;
+                    *self.region_totals.entry(feature).or_insert(0.0) += count;
+                }
+                None => *self.region_totals.entry(feature).or_insert(0.0) += count,
+            }
+        }
+    }
+}
+
+// Function to distribute multimapped reads over their candidate regions by expectation-maximisation, starting from
+// the unique-read counts of the regions; returns the fractional count of each (region, barcode) pair
+fn resolve_multimappers_em(
+    candidates: &MultimapCandidates,
+    unique_counts: &HashMap<u32, f64>,
+    iterations: usize,
+) -> HashMap<(u32, Option<String>), f64> {
+    let unique = |region: u32| unique_counts.get(&region).copied().unwrap_or(0.0);
+    // Share of a read assigned to each candidate region given the current abundances
+    let shares = |regions: &[u32], abundance: &HashMap<u32, f64>| -> Vec<f64> {
+        let total: f64 = regions.iter().map(|r| abundance[r]).sum();
+        regions.iter()
+            .map(|r| if total > 0.0 { abundance[r] / total } else { 1.0 / regions.len() as f64 })
+            .collect()
+    };
+
+    let mut abundance: HashMap<u32, f64> = candidates.values()
+        .flatten()
+        .map(|&r| (r, unique(r)))
+        .collect();
+    for iteration in 1..=iterations {
+        let mut next: HashMap<u32, f64> = abundance.keys().map(|&r| (r, unique(r))).collect();
+        for regions in candidates.values() {
+            for (region, share) in regions.iter().zip(shares(regions, &abundance)) {
+                *next.get_mut(region).unwrap() += share;
+            }
+        }
+        let max_change = next.iter().map(|(r, value)| (value - abundance[r]).abs()).fold(0.0, f64::max);
+        debug!("EM iteration {}: maximum change {:.6}", iteration, max_change);
+        abundance = next;
+        if max_change < 1e-6 {
+            info!("EM converged after {} iterations", iteration);
+            break;
+        }
+        if iteration == iterations {
+            info!("EM stopped after {} iterations (maximum change {:.6})", iteration, max_change);
+        }
+    }
+
+    let mut assigned: HashMap<(u32, Option<String>), f64> = HashMap::new();
+    for ((_, cell_barcode), regions) in candidates {
+        for (&region, share) in regions.iter().zip(shares(regions, &abundance)) {
+            *assigned.entry((region, cell_barcode.clone())).or_insert(0.0) += share;
+        }
+    }
+    assigned
+}
+
+// Function to classify a read against the exons (blocks) and introns (gaps between blocks) of a region following
+// velocyto conventions: exonic-only reads are spliced, unspliced reads touching an intron are unspliced, and the rest
+// (spliced reads touching an intron, reads extending beyond the region) are ambiguous
+fn velocity_class(record: &Record, read_blocks: &[(i64, i64)], region: &Region) -> usize {
+    let aligned: i64 = read_blocks.iter().map(|&(start, end)| end - start).sum();
+    let exonic = regions::overlap_bases(read_blocks, &region.blocks);
+    let spanned = regions::overlap_bases(read_blocks, &[(region.start, region.end)]);
+    let intronic = spanned - exonic;
+    let has_junction = read_cigar(record).iter().any(|cigar| matches!(cigar, Cigar::RefSkip(_)));
+    if intronic == 0 && exonic == aligned {
+        0 // spliced
+    } else if intronic > 0 && !has_junction && spanned == aligned {
+        1 // unspliced
+    } else {
+        2 // ambiguous
+    }
+}
+
+// Counts of an output matrix of single mode, in memory or sorted in a temporary file with --low-memory
+enum MatrixCounts<'a> {
+    Memory(&'a Counts),
+    Sorted(&'a spill::SortedMatrix),
 }
 
-fn parse_bed_file(bed_file: &str) -> io::Result<Vec<Region>> {
-    let file = File::open(bed_file)?;
-    let reader = io::BufReader::new(file);
+// (feature, barcode column, count) entries of a matrix, sorted by feature then column
+type MatrixEntries<'a> = Box<dyn Iterator<Item = Result<(u32, usize, f64), KaiError>> + 'a>;
+
+impl<'a> MatrixCounts<'a> {
+    fn entries(&self) -> usize {
+        match self {
+            MatrixCounts::Memory(counts) => counts.values().map(|c| c.len()).sum(),
+            MatrixCounts::Sorted(sorted) => sorted.entries,
+        }
+    }
+
+    fn has_feature(&self, feature: u32) -> bool {
+        match self {
+            MatrixCounts::Memory(counts) => counts.contains_key(&feature),
+            MatrixCounts::Sorted(sorted) => sorted.features.contains(&feature),
+        }
+    }
+
+    // Function to go through the entries of the matrix in order, features being numbered from 0 to n_features - 1
+    fn iter(&self, n_features: usize, barcode_columns: &'a [usize]) -> Result<MatrixEntries<'a>, KaiError> {
+        match *self {
+            MatrixCounts::Memory(counts) => Ok(Box::new((0..n_features as u32).filter_map(|feature| counts.get(&feature).map(|c| (feature, c))).flat_map(
+                move |(feature, cell_counts)| {
+                    cell_counts.iter()
+                        .map(|(&barcode, &count)| (barcode_columns[barcode as usize], count))
+                        .sorted_by_key(|&(j, _)| j)
+                        .map(move |(j, count)| Ok((feature, j, count)))
+                },
+            ))),
+            MatrixCounts::Sorted(sorted) => Ok(Box::new(sorted.iter()?)),
+        }
+    }
+}
+
+// Function to write the header lines of a MatrixMarket file of features x barcodes, or of barcodes x features with
+// --transpose, naming the axes in a comment line
+fn write_matrix_header(output: &mut impl Write, field: &str, n_features: usize, n_barcodes: usize, entries: usize, transpose: bool) -> std::io::Result<()> {
+    writeln!(output, "%%MatrixMarket matrix coordinate {} general", field)?;
+    if transpose {
+        writeln!(output, "% rows: barcodes (barcodes.tsv.gz), columns: features (features.tsv.gz)")?;
+        writeln!(output, "{} {} {}", n_barcodes, n_features, entries)
+    } else {
+        writeln!(output, "%")?;
+        writeln!(output, "{} {} {}", n_features, n_barcodes, entries)
+    }
+}
+
+// Function to write the entries of a transposed matrix, given as (barcode column, feature row, count) and sorted by
+// barcode then feature like the entries of the untransposed matrices
+fn write_transposed_entries(output: &mut impl Write, mut entries: Vec<(usize, usize, f64)>) -> std::io::Result<()> {
+    entries.sort_unstable_by_key(|&(j, i, _)| (j, i));
+    for (j, i, count) in entries {
+        writeln!(output, "{} {} {}", j + 1, i + 1, format_count(count))?;
+    }
+    Ok(())
+}
+
+// Function to write a MatrixMarket file of per-barcode counts for the listed features
+fn write_matrix_market(
+    mut matrix_file: OutputFile,
+    feature_list: &[u32],
+    feature_rows: &[usize],
+    barcode_columns: &[usize],
+    counts: &MatrixCounts,
+    field: &str,
+    transpose: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    write_matrix_header(&mut matrix_file, field, feature_list.len(), barcode_columns.len(), counts.entries(), transpose)?;
+    let mut transposed = Vec::new();
+    for entry in counts.iter(feature_rows.len(), barcode_columns)? {
+        let (feature, j, count) = entry?;
+        let i = feature_rows[feature as usize];
+        if transpose {
+            transposed.push((j, i, count));
+        } else {
+            writeln!(matrix_file, "{} {} {}", i + 1, j + 1, format_count(count))?;
+        }
+    }
+    write_transposed_entries(&mut matrix_file, transposed)?;
+    Ok(())
+}
+
+// Function to write a zero-filled matrix with a row per listed feature and a column per barcode, in the order of
+// features.tsv.gz and barcodes.tsv.gz, filling one row at a time from the sorted entries
+fn write_dense_matrix(
+    path: &str,
+    feature_list: &[u32],
+    features: &Interner,
+    barcode_names: &[&str],
+    barcode_columns: &[usize],
+    counts: &MatrixCounts,
+    level: Compression,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut matrix_file = create_output(path, level)?;
+    write!(matrix_file, "Feature")?;
+    for barcode in barcode_names {
+        write!(matrix_file, "\t{}", barcode)?;
+    }
+    writeln!(matrix_file)?;
+    let mut entries = counts.iter(features.names.len(), barcode_columns)?.peekable();
+    let mut row = vec![0.0; barcode_names.len()];
+    for &feature in feature_list {
+        row.fill(0.0);
+        while let Some(entry) = entries.next_if(|entry| entry.as_ref().map_or(true, |&(entry_feature, _, _)| entry_feature == feature)) {
+            let (_, j, count) = entry?;
+            row[j] = count;
+        }
+        write!(matrix_file, "{}", features.name(feature))?;
+        for &count in &row {
+            write!(matrix_file, "\t{}", format_count(count))?;
+        }
+        writeln!(matrix_file)?;
+    }
+    Ok(())
+}
+
+// Features of a cell ranked for the fraction of its counts in its most counted features (as Scanpy's
+// pct_counts_in_top_50_genes)
+const QC_TOP_FEATURES: usize = 50;
+
+// Function to tell mitochondrial contigs by their usual names
+fn is_mitochondrial(chromosome: &str) -> bool {
+    matches!(chromosome, "chrM" | "chrMT" | "MT" | "M")
+}
+
+// Function to write per-barcode QC metrics from the entries of the region matrix: total count, features with counts,
+// fraction of the count in the most counted features, and fraction on mitochondrial features when there are some
+fn write_cell_qc(
+    path: &str,
+    counts: &MatrixCounts,
+    n_features: usize,
+    barcode_names: &[&str],
+    barcode_columns: &[usize],
+    mito_features: Option<&[bool]>,
+    level: Compression,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut totals = vec![0.0; barcode_names.len()];
+    let mut feature_numbers = vec![0usize; barcode_names.len()];
+    let mut mito_totals = vec![0.0; barcode_names.len()];
+    // Largest counts of each barcode, at most QC_TOP_FEATURES of them
+    let mut top_counts: Vec<Vec<f64>> = vec![Vec::new(); barcode_names.len()];
+    for entry in counts.iter(n_features, barcode_columns)? {
+        let (feature, j, count) = entry?;
+        totals[j] += count;
+        if count > 0.0 {
+            feature_numbers[j] += 1;
+        }
+        if mito_features.is_some_and(|mito_features| mito_features[feature as usize]) {
+            mito_totals[j] += count;
+        }
+        let top = &mut top_counts[j];
+        if top.len() < QC_TOP_FEATURES {
+            top.push(count);
+        } else if let Some((smallest, _)) = top.iter().enumerate().min_by(|a, b| a.1.total_cmp(b.1)).filter(|(_, &smallest)| smallest < count) {
+            top[smallest] = count;
+        }
+    }
+
+    let fraction = |part: f64, total: f64| if total > 0.0 { part / total } else { 0.0 };
+    let mut qc_file = create_output(path, level)?;
+    write!(qc_file, "Barcode\tTotalCount\tFeatures\tTop{}Fraction", QC_TOP_FEATURES)?;
+    if mito_features.is_some() {
+        write!(qc_file, "\tMitoFraction")?;
+    }
+    writeln!(qc_file)?;
+    for (j, barcode) in barcode_names.iter().enumerate() {
+        let top_total: f64 = top_counts[j].iter().sum();
+        write!(qc_file, "{}\t{}\t{}\t{}", barcode, format_count(totals[j]), feature_numbers[j], format_count(fraction(top_total, totals[j])))?;
+        if mito_features.is_some() {
+            write!(qc_file, "\t{}", format_count(fraction(mito_totals[j], totals[j])))?;
+        }
+        writeln!(qc_file)?;
+    }
+    Ok(())
+}
+
+// Function to get the CIGAR of a read, decoded once when the read is read (see Record::cache_cigar)
+fn read_cigar(record: &Record) -> Cow<'_, CigarStringView> {
+    match record.cigar_cached() {
+        Some(cigar) => Cow::Borrowed(cigar),
+        None => Cow::Owned(record.cigar()),
+    }
+}
+
+// Output file, gzip-compressed when its name ends with .gz (see --compression), or the stdout of bulk mode
+enum OutputFile {
+    Gzip(GzEncoder<File>),
+    Plain(BufWriter<File>),
+    Stdout(BufWriter<std::io::Stdout>),
+}
+
+impl Write for OutputFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            OutputFile::Gzip(file) => file.write(buf),
+            OutputFile::Plain(file) => file.write(buf),
+            OutputFile::Stdout(stdout) => stdout.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            OutputFile::Gzip(file) => file.flush(),
+            OutputFile::Plain(file) => file.flush(),
+            OutputFile::Stdout(stdout) => stdout.flush(),
+        }
+    }
+}
+
+// Rows written by a counting group, gzip-compressed as a member of their output file unless --compression none;
+// gzip members concatenated in group order form the output file
+enum RowBuffer {
+    Gzip(GzEncoder<Vec<u8>>),
+    Plain(Vec<u8>),
+}
+
+impl RowBuffer {
+    fn new(compress: bool, level: Compression) -> Self {
+        if compress {
+            RowBuffer::Gzip(GzEncoder::new(Vec::new(), level))
+        } else {
+            RowBuffer::Plain(Vec::new())
+        }
+    }
+
+    fn finish(self) -> std::io::Result<Vec<u8>> {
+        match self {
+            RowBuffer::Gzip(buffer) => buffer.finish(),
+            RowBuffer::Plain(buffer) => Ok(buffer),
+        }
+    }
+}
+
+impl Write for RowBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            RowBuffer::Gzip(buffer) => buffer.write(buf),
+            RowBuffer::Plain(buffer) => buffer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            RowBuffer::Gzip(buffer) => buffer.flush(),
+            RowBuffer::Plain(buffer) => buffer.flush(),
+        }
+    }
+}
+
+// Function to write a file of rows written by the counting groups, after a header compressed the same way
+fn write_rows(path: &str, header: &str, rows: &[u8], compress: bool, level: Compression) -> Result<(), KaiError> {
+    let output_error = |source| KaiError::Output { path: path.to_string(), source };
+    let mut header_row = RowBuffer::new(compress, level);
+    writeln!(header_row, "{}", header).map_err(output_error)?;
+    let mut file = File::create(path).map_err(output_error)?;
+    file.write_all(&header_row.finish().map_err(output_error)?).map_err(output_error)?;
+    file.write_all(rows).map_err(output_error)
+}
+
+// Function to create an output file, compressed at the --compression-level when its name ends with .gz
+fn create_output(path: &str, level: Compression) -> Result<OutputFile, KaiError> {
+    let file = File::create(path).map_err(|source| KaiError::Output { path: path.to_string(), source })?;
+    if path.ends_with(".gz") {
+        Ok(OutputFile::Gzip(GzEncoder::new(file, level)))
+    } else {
+        Ok(OutputFile::Plain(BufWriter::new(file)))
+    }
+}
+
+// Function to open an indexed BAM file, telling a missing index from other errors
+fn open_indexed_bam(path: &str) -> Result<IndexedReader, KaiError> {
+    IndexedReader::from_path(path).map_err(|source| match source {
+        rust_htslib::errors::Error::BamInvalidIndex { .. } => KaiError::MissingBamIndex { path: path.to_string() },
+        source => KaiError::BamOpen { path: path.to_string(), source },
+    })
+}
+
+// Function to format a count, with up to four decimals for fractional counts
+fn format_count(count: f64) -> String {
+    if count.fract() == 0.0 {
+        format!("{}", count as u64)
+    } else {
+        format!("{:.4}", count).trim_end_matches('0').trim_end_matches('.').to_string()
+    }
+}
+
+// Function to shift the aligned blocks of a read by a number of bases, clipped at the contig bounds
+fn shift_blocks(blocks: Vec<(i64, i64)>, shift: i64, contig_length: i64) -> Vec<(i64, i64)> {
+    if shift == 0 {
+        return blocks;
+    }
+    blocks.into_iter()
+        .map(|(start, end)| ((start + shift).clamp(0, contig_length), (end + shift).clamp(0, contig_length)))
+        .filter(|(start, end)| start < end)
+        .collect()
+}
+
+// Function to get the length of a read, from its CIGAR when the sequence is not stored
+fn read_length(record: &Record) -> usize {
+    if record.seq_len() > 0 {
+        return record.seq_len();
+    }
+    read_cigar(record).iter()
+        .map(|cigar| match cigar {
+            Cigar::Match(len) | Cigar::Ins(len) | Cigar::SoftClip(len) | Cigar::Equal(len) | Cigar::Diff(len) => *len as usize,
+            _ => 0,
+        })
+        .sum()
+}
+
+// Function to get the fraction of a read that is clipped, from its CIGAR, optionally counting hard clips
+fn clipped_fraction(record: &Record, count_hardclip: bool) -> f64 {
+    let mut clipped = 0;
+    let mut length = 0;
+    for cigar in read_cigar(record).iter() {
+        match cigar {
+            Cigar::SoftClip(len) => {
+                clipped += len;
+                length += len;
+            }
+            Cigar::HardClip(len) if count_hardclip => {
+                clipped += len;
+                length += len;
+            }
+            Cigar::Match(len) | Cigar::Ins(len) | Cigar::Equal(len) | Cigar::Diff(len) => length += len,
+            _ => {}
+        }
+    }
+    if length == 0 { 0.0 } else { clipped as f64 / length as f64 }
+}
+
+// Function to get the reference intervals covered by the aligned (M/=/X) blocks of a read
+fn aligned_blocks(record: &Record) -> Vec<(i64, i64)> {
+    aligned_query_blocks(record).into_iter().map(|(start, end, _)| (start, end)).collect()
+}
+
+// Function to list the reference spans skipped by a read (N operations, usually introns)
+fn skipped_blocks(record: &Record) -> Vec<(i64, i64)> {
+    let mut blocks = Vec::new();
+    let mut pos = record.pos();
+    for cigar in read_cigar(record).iter() {
+        match cigar {
+            Cigar::Match(len) | Cigar::Equal(len) | Cigar::Diff(len) | Cigar::Del(len) => pos += *len as i64,
+            Cigar::RefSkip(len) => {
+                blocks.push((pos, pos + *len as i64));
+                pos += *len as i64;
+            }
+            _ => {}
+        }
+    }
+    blocks
+}
+
+// Function to list the aligned reference blocks of a read with the query offset of their first base, counting soft
+// clips and insertions as query bases
+fn aligned_query_blocks(record: &Record) -> Vec<(i64, i64, usize)> {
+    let mut blocks = Vec::new();
+    let mut pos = record.pos();
+    let mut query_pos = 0;
+    for cigar in read_cigar(record).iter() {
+        match cigar {
+            Cigar::Match(len) | Cigar::Equal(len) | Cigar::Diff(len) => {
+                blocks.push((pos, pos + *len as i64, query_pos));
+                pos += *len as i64;
+                query_pos += *len as usize;
+            }
+            Cigar::Del(len) | Cigar::RefSkip(len) => pos += *len as i64,
+            Cigar::Ins(len) | Cigar::SoftClip(len) => query_pos += *len as usize,
+            _ => {}
+        }
+    }
+    blocks
+}
+
+// Function to compute the mean base quality of the query bases aligned inside the region blocks, or None when the
+// read has no quality string or no aligned base in the region
+fn overlap_mean_baseq(record: &Record, region_blocks: &[(usize, usize)]) -> Option<f64> {
+    let qualities = record.qual();
+    if qualities.first().is_none_or(|&quality| quality == 255) {
+        return None;
+    }
+    let (mut total, mut bases) = (0u64, 0u64);
+    for (start, end, query_start) in aligned_query_blocks(record) {
+        for &(region_start, region_end) in region_blocks {
+            let overlap_start = start.max(region_start as i64);
+            let overlap_end = end.min(region_end as i64);
+            for ref_pos in overlap_start..overlap_end {
+                total += qualities[query_start + (ref_pos - start) as usize] as u64;
+                bases += 1;
+            }
+        }
+    }
+    (bases > 0).then(|| total as f64 / bases as f64)
+}
+
+// Options of the per-read filters
+struct ReadFilters<'a> {
+    require_flags: u16,
+    exclude_flags: u16,
+    primary_only: bool,
+    proper_pairs_only: bool,
+    read_filter: Mate,
+    ignore_duplicates: bool,
+    treat_mapq_255_as_zero: bool,
+    min_mapq: u8,
+    missing_nh: MissingNh,
+    multimap: Multimap,
+    max_loci: u32,
+    tn5_shift: bool,
+    splice_filter: SpliceFilter,
+    min_insert: Option<i64>,
+    max_insert: Option<i64>,
+    drop_single_insert: bool,
+    min_read_length: Option<usize>,
+    max_read_length: Option<usize>,
+    max_softclip_frac: Option<f64>,
+    count_hardclip: bool,
+    blacklist_index: Option<&'a regions::IntervalIndex>,
+    mode: Mode,
+    barcode_tag: &'a str,
+    barcode_fallback_tag: Option<&'a str>,
+    by_read_group: bool,
+    barcode_suffix: BarcodeSuffix,
+    gem_group: u32,
+    // Listed cell barcodes, when reads are kept only for them
+    whitelist: Option<&'a barcodes::Whitelist>,
+    keep_untagged: bool,
+    strandedness: Strandedness,
+    emit_antisense: bool,
+    region_index: Option<&'a regions::RegionIndex>,
+    regions: &'a [Region],
+    feature_ids: &'a [u32],
+    overlap_mode: OverlapMode,
+    extend_reads: Option<usize>,
+    count_mode: CountMode,
+    min_overlap: usize,
+    count_gapped_overlap: bool,
+    contained: bool,
+    contained_include_clips: bool,
+    min_overlap_frac: Option<f64>,
+    frac_of_region: bool,
+    min_block_baseq: Option<f64>,
+    require_baseq: bool,
+    umi_tags: Option<&'a [String]>,
+    umi_collapse: UmiCollapse,
+    require_umi: bool,
+}
+
+// State of the per-read filters: the barcode corrector, the barcodes of the reads, and the reads tallied by the filters
+#[derive(Default)]
+struct FilterState {
+    barcode_corrector: Option<barcodes::BarcodeCorrector>,
+    cell_barcodes: Interner,
+    blacklisted_reads: HashSet<(Vec<u8>, i64, u16)>,
+    missing_nh_reads: u64,
+    single_end_warned: bool,
+    paired_extension_warned: bool,
+    examined_reads: u64,
+    duplicate_reads: u64,
+    // Reads skipped on each flag bit, either missing a required flag or carrying an excluded one
+    missing_flag_reads: HashMap<u16, usize>,
+    excluded_flag_reads: HashMap<u16, usize>,
+    ambiguous_reads: u64,
+    multi_region_reads: u64,
+    primary_tag_reads: u64,
+    fallback_tag_reads: u64,
+    no_barcode_reads: u64,
+    exact_barcode_reads: u64,
+    corrected_barcode_reads: u64,
+    unlisted_barcode_example: Option<String>,
+    umi_missing_reads: u64,
+    umi_tag_reads: HashMap<String, u64>,
+    umi_type_warned: bool,
+    umi_duplicate_reads: u64,
+    no_feature_reads: u64,
+    gapped_reads: u64,
+}
+
+// A read passing the filters of a region, with what counting it takes
+struct Accepted {
+    weight: f64,
+    // Multimapped read deferred to the EM of --multimap em
+    em_candidate: bool,
+    barcode: Option<u32>,
+    read_group: Option<String>,
+    antisense: bool,
+    gapped: bool,
+    // Barcode and UMI of a read grouped into molecules once the region is counted
+    collapsed_umi: Option<(u32, String)>,
+    // Whether the read carries the barcode reserved by --keep-untagged
+    reserved_barcode: bool,
+    // Bases of the read counted in the region (the extended fragment with --extend-reads) for the coverage track and
+    // the depth profiles
+    blocks: Vec<(i64, i64)>,
+}
+
+impl ReadFilters<'_> {
+    // Function to run a read fetched for a region through the filters in turn, giving the read to count, None when it
+    // is counted in another region, or the reason it is not counted
+    fn filter_read(
+        &self,
+        state: &mut FilterState,
+        record: &Record,
+        current_index: usize,
+        contig_length: i64,
+        region_umis: &mut HashMap<(u32, bool), HashSet<Umi>>,
+    ) -> Result<Option<Accepted>, Unassigned> {
+        let region = &self.regions[current_index];
+        let feature = self.feature_ids[current_index];
+        // Skip read if its flags do not pass the required and excluded flag filters
+        let flags = record.flags();
+        let missing_flags = self.require_flags & !flags;
+        let excluded = self.exclude_flags & flags;
+        if missing_flags != 0 || excluded != 0 {
+            for (_, bit) in data_loader::SAM_FLAG_NAMES {
+                if missing_flags & bit != 0 {
+                    *state.missing_flag_reads.entry(bit).or_insert(0) += 1;
+                }
+                if excluded & bit != 0 {
+                    *state.excluded_flag_reads.entry(bit).or_insert(0) += 1;
+                }
+            }
+            return Err(Unassigned::Flags);
+        }
+        // Skip secondary and supplementary alignments before NH handling
+        if self.primary_only && (record.is_secondary() || record.is_supplementary()) {
+            return Err(if record.is_secondary() { Unassigned::Secondary } else { Unassigned::Supplementary });
+        }
+        // Skip paired reads that are not properly paired, or not the selected mate
+        if (self.proper_pairs_only || self.read_filter != Mate::Both) && !record.is_paired() && !state.single_end_warned {
+            warn!("Single-end reads found: they pass --proper-pairs-only and are treated as read 1 by --read");
+            state.single_end_warned = true;
+        }
+        if self.proper_pairs_only && record.is_paired() && !record.is_proper_pair() {
+            return Err(Unassigned::ImproperPair);
+        }
+        let is_read2 = record.is_paired() && record.is_last_in_template();
+        if (self.read_filter == Mate::First && is_read2) || (self.read_filter == Mate::Second && !is_read2) {
+            return Err(Unassigned::OtherMate);
+        }
+        // Skip duplicates before their barcodes are recorded
+        state.examined_reads += 1;
+        if record.is_duplicate() {
+            state.duplicate_reads += 1;
+            if self.ignore_duplicates {
+                return Err(Unassigned::Duplicate);
+            }
+        }
+        // Skip read if its mapping quality is below min_mapq
+        let mapq = match record.mapq() {
+            255 if self.treat_mapq_255_as_zero => 0,
+            mapq => mapq,
+        };
+        if mapq < self.min_mapq {
+            return Err(Unassigned::LowMapq);
+        }
+        // Skip read if NH tag exceeds max_loci, or weight it by 1/NH when counting multimappers fractionally
+        let nh = match integer_tag(record, b"NH") {
+            Some(nh) => nh.max(1),
+            None => {
+                state.missing_nh_reads += 1;
+                match self.missing_nh {
+                    MissingNh::Drop => return Err(Unassigned::MissingNh),
+                    // Secondary alignments map to at least two loci
+                    MissingNh::UseFlags if record.is_secondary() => 2,
+                    _ => 1,
+                }
+            }
+        };
+        let em_candidate = self.multimap == Multimap::Em && nh > 1;
+        let weight = if self.multimap == Multimap::Fraction {
+            1.0 / nh as f64
+        } else if self.multimap == Multimap::Em {
+            1.0
+        } else if self.multimap == Multimap::Primary {
+            // Use HI:1 as the primary alignment when the aligner does not set the secondary flag
+            if record.is_secondary() || (nh > 1 && integer_tag(record, b"HI").is_some_and(|hi| hi != 1)) {
+                return Err(Unassigned::Multimapping); // Skip non-primary alignments of multimappers
+            }
+            1.0
+        } else if nh > self.max_loci as i64 {
+            return Err(Unassigned::Multimapping); // Skip reads with more than max_loci loci
+        } else {
+            1.0
+        };
+
+        // Shift of the read coordinates centring ATAC-seq reads on the Tn5 insertion
+        let read_shift: i64 = match (self.tn5_shift, record.is_reverse()) {
+            (false, _) => 0,
+            (true, false) => 4,
+            (true, true) => -5,
+        };
+
+        // Skip read if it is not of the selected splicing status
+        if self.splice_filter != SpliceFilter::All {
+            let spliced = read_cigar(record).iter().any(|cigar| matches!(cigar, Cigar::RefSkip(_)));
+            if spliced != (self.splice_filter == SpliceFilter::Spliced) {
+                return Err(Unassigned::Splicing);
+            }
+        }
+
+        // Skip read if its insert size is out of the allowed range
+        if self.min_insert.is_some() || self.max_insert.is_some() {
+            let passes = if record.is_paired() {
+                let insert_size = record.insert_size().abs();
+                !(self.min_insert.is_some_and(|min| insert_size < min) || self.max_insert.is_some_and(|max| insert_size > max))
+            } else {
+                !self.drop_single_insert
+            };
+            if !passes {
+                return Err(Unassigned::InsertSize);
+            }
+        }
+
+        // Skip read if its length is out of the allowed range
+        if self.min_read_length.is_some() || self.max_read_length.is_some() {
+            let length = read_length(record);
+            if self.min_read_length.is_some_and(|min| length < min) || self.max_read_length.is_some_and(|max| length > max) {
+                return Err(Unassigned::ReadLength);
+            }
+        }
+
+        // Skip read if too much of it is clipped
+        if let Some(max_softclip_frac) = self.max_softclip_frac {
+            if clipped_fraction(record, self.count_hardclip) > max_softclip_frac {
+                return Err(Unassigned::SoftClipped);
+            }
+        }
+
+        // Skip read if any of its aligned blocks overlaps a blacklisted region
+        if let Some(blacklist_index) = self.blacklist_index {
+            let blacklisted = aligned_blocks(record).iter()
+                .any(|&(start, end)| blacklist_index.overlaps(&region.chromosome, start, end));
+            if blacklisted {
+                state.blacklisted_reads.insert((record.qname().to_vec(), record.pos(), record.flags()));
+                return Err(Unassigned::Blacklisted);
+            }
+        }
+
+        // Extract the cell barcode from its tag, or from the fallback tag, if in single mode
+        let cell_barcode = if self.mode == Mode::Single {
+            let primary = str_tag(record, self.barcode_tag.as_bytes());
+            let fallback = match (&primary, self.barcode_fallback_tag) {
+                (None, Some(fallback_tag)) => str_tag(record, fallback_tag.as_bytes()),
+                _ => None,
+            };
+            match (primary, fallback) {
+                (Some(cb), _) => {
+                    state.primary_tag_reads += 1;
+                    Some(cb)
+                }
+                (None, Some(cb)) => {
+                    state.fallback_tag_reads += 1;
+                    Some(cb)
+                }
+                (None, None) => {
+                    state.no_barcode_reads += 1;
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        // Take the read group of the read for --by-read-group
+        let read_group = self.by_read_group.then(|| string_tag(record, b"RG").unwrap_or_else(|| "unknown_rg".to_string()));
+
+        // Skip read if its barcode is not in the list of interest, unless it can be corrected to a listed barcode;
+        // barcodes are borrowed from the read, and only copied when a new barcode is counted
+        let mut cell_barcode = cell_barcode.map(|cb| barcodes::normalise_suffix(cb, self.barcode_suffix, self.gem_group));
+        if let (Some(cb), Some(whitelist)) = (&cell_barcode, self.whitelist) {
+            if whitelist.contains(cb) {
+                state.exact_barcode_reads += 1;
+            } else {
+                match state.barcode_corrector.as_mut().and_then(|corrector| corrector.correct(cb)) {
+                    Some(corrected) => {
+                        state.corrected_barcode_reads += 1;
+                        cell_barcode = Some(Cow::Owned(corrected));
+                    }
+                    None => {
+                        state.unlisted_barcode_example.get_or_insert_with(|| cb.to_string());
+                        return Err(Unassigned::UnlistedBarcode); // Skip reads with cell barcodes not in the list of interest
+                    }
+                }
+            }
+        }
+        // Collect reads without barcode under the sentinel barcode, which no read barcode may use
+        let reserved_barcode = self.keep_untagged && cell_barcode.as_deref() == Some(UNTAGGED_BARCODE);
+        if self.keep_untagged && cell_barcode.is_none() {
+            cell_barcode = Some(Cow::Borrowed(UNTAGGED_BARCODE));
+        }
+        let barcode = cell_barcode.as_deref().map(|cb| state.cell_barcodes.intern(cb));
+
+        // Skip read if its strand does not match the region strand for stranded libraries, unless counted as antisense
+        let mut antisense = false;
+        if self.strandedness != Strandedness::None && region.strand != '.' {
+            let same_strand = fragment_strand(record) == region.strand;
+            if same_strand != (self.strandedness == Strandedness::Forward) {
+                if !self.emit_antisense {
+                    return Err(Unassigned::WrongStrand); // Skip reads on the wrong strand
+                }
+                antisense = true;
+            }
+        }
+
+        // Count the read only in the region it is assigned to when it overlaps several regions
+        if let (Some(region_index), false) = (self.region_index, antisense) {
+            let read_blocks = shift_blocks(aligned_blocks(record), read_shift, contig_length);
+            let (Some(&(read_start, _)), Some(&(_, read_end))) = (read_blocks.first(), read_blocks.last()) else {
+                return Err(Unassigned::LowOverlap);
+            };
+            let read_strand = fragment_strand(record);
+            let candidates: Vec<(usize, &Region)> = region_index.overlapping(&region.chromosome, read_start, read_end)
+                .into_iter()
+                .map(|index| (index, &self.regions[index]))
+                .filter(|(_, candidate)| {
+                    self.strandedness == Strandedness::None
+                        || candidate.strand == '.'
+                        || (read_strand == candidate.strand) == (self.strandedness == Strandedness::Forward)
+                })
+                .collect();
+            if self.overlap_mode == OverlapMode::Union {
+                // Skip reads whose aligned bases overlap more than one region under --assign unique
+                let overlapped: Vec<usize> = candidates.iter()
+                    .filter(|(_, candidate)| regions::overlap_bases(&read_blocks, &candidate.blocks) > 0)
+                    .map(|&(index, _)| index)
+                    .collect();
+                if overlapped.iter().map(|&index| self.feature_ids[index]).unique().count() > 1 {
+                    if overlapped.first() == Some(&current_index) {
+                        state.multi_region_reads += 1;
+                    }
+                    return Err(Unassigned::MultipleRegions);
+                }
+            } else {
+                // Tally unassigned reads once, in the first of their candidate regions
+                let first_candidate = candidates.first().map(|&(index, _)| index);
+                match regions::assign_read(&read_blocks, &candidates, self.overlap_mode == OverlapMode::IntersectionStrict) {
+                    regions::OverlapAssignment::Feature(index) if self.feature_ids[index] == feature => {}
+                    regions::OverlapAssignment::Feature(_) => return Ok(None),
+                    regions::OverlapAssignment::Ambiguous => {
+                        if first_candidate == Some(current_index) {
+                            state.ambiguous_reads += 1;
+                        }
+                        return Err(Unassigned::Ambiguous);
+                    }
+                    regions::OverlapAssignment::NoFeature => {
+                        if first_candidate == Some(current_index) {
+                            state.no_feature_reads += 1;
+                        }
+                        return Err(Unassigned::NoFeature);
+                    }
+                }
+            }
+        }
+
+        // Sum the overlap of the aligned blocks with the region blocks, not counting RefSkip, Ins or SoftClip
+        let read_blocks = shift_blocks(aligned_blocks(record), read_shift, contig_length);
+        let mut overlap_bases = regions::overlap_bases(&read_blocks, &region.blocks);
+        let mut aligned_bases: i64 = read_blocks.iter().map(|(start, end)| end - start).sum();
+        let mut fragment_block = None;
+
+        // Replace the aligned blocks by the read extended to the fragment length on its strand
+        if let Some(fragment_length) = self.extend_reads {
+            if record.is_paired() && !state.paired_extension_warned {
+                warn!("--extend-reads is meant for single-end data: paired-end reads are extended on their own, ignoring the fragment extents given by their mates");
+                state.paired_extension_warned = true;
+            }
+            let (Some(&(first_start, _)), Some(&(_, last_end))) = (read_blocks.first(), read_blocks.last()) else {
+                return Err(Unassigned::LowOverlap);
+            };
+            let fragment_length = fragment_length as i64;
+            let fragment = if record.is_reverse() {
+                ((last_end - fragment_length).max(0), last_end)
+            } else {
+                (first_start, (first_start + fragment_length).min(contig_length))
+            };
+            overlap_bases = regions::overlap_bases(&[fragment], &region.blocks);
+            aligned_bases = fragment.1 - fragment.0;
+            fragment_block = Some(fragment);
+        }
+
+        // Count the read once if it overlaps the region enough, or if its 5' or 3' end falls in the region
+        if self.count_mode != CountMode::Overlap {
+            let (Some(&(first_start, _)), Some(&(_, last_end))) = (read_blocks.first(), read_blocks.last()) else {
+                return Err(Unassigned::LowOverlap);
+            };
+            let five_prime = self.count_mode == CountMode::FivePrime;
+            let end_position = if record.is_reverse() == five_prime { last_end - 1 } else { first_start };
+            let inside = region.blocks.iter().any(|&(start, end)| end_position >= start as i64 && end_position < end as i64);
+            if !inside {
+                return Err(Unassigned::LowOverlap);
+            }
+        }
+        // Count reads whose matched blocks miss the region through their skipped spans, if requested
+        let mut gapped = false;
+        if self.count_mode == CountMode::Overlap && (overlap_bases == 0 || overlap_bases < self.min_overlap as i64) {
+            let read_gaps = shift_blocks(skipped_blocks(record), read_shift, contig_length);
+            gapped = self.count_gapped_overlap && self.extend_reads.is_none() && regions::overlap_bases(&read_gaps, &region.blocks) > 0;
+            if !gapped {
+                return Err(Unassigned::LowOverlap);
+            }
+            state.gapped_reads += 1;
+        }
+        // Skip read if its alignment, with its soft clips if requested, is not within the region
+        if self.contained {
+            let cigar = read_cigar(record);
+            let (mut read_start, mut read_end) = (record.pos() + read_shift, cigar.end_pos() + read_shift);
+            if self.contained_include_clips {
+                read_start -= cigar.leading_softclips();
+                read_end += cigar.trailing_softclips();
+            }
+            if read_start < region.start as i64 || read_end > region.end as i64 {
+                return Err(Unassigned::Uncontained);
+            }
+        }
+        if let (Some(min_overlap_frac), CountMode::Overlap, false) = (self.min_overlap_frac, self.count_mode, gapped) {
+            let covered_bases = if self.frac_of_region { region.blocks.iter().map(|&(start, end)| (end - start) as i64).sum() } else { aligned_bases };
+            if (overlap_bases as f64) < min_overlap_frac * covered_bases as f64 {
+                return Err(Unassigned::LowOverlap);
+            }
+        }
+        // Skip read if the bases supporting the overlap have a low mean quality
+        if let Some(min_block_baseq) = self.min_block_baseq {
+            if record.qual().first().is_none_or(|&quality| quality == 255) {
+                if self.require_baseq {
+                    return Err(Unassigned::MissingBaseQuality);
+                }
+            } else if overlap_mean_baseq(record, &region.blocks).is_some_and(|mean_baseq| mean_baseq < min_block_baseq) {
+                return Err(Unassigned::LowBaseQuality);
+            }
+        }
+        // Count each UMI once per region and barcode, counting reads without UMI as molecules unless required
+        let mut collapsed_umi = None;
+        if let (Some(umi_tags), Some(barcode)) = (self.umi_tags, barcode) {
+            let mut read_umi = None;
+            for tag in umi_tags {
+                match record.aux(tag.as_bytes()) {
+                    Ok(Aux::String(umi)) => {
+                        *state.umi_tag_reads.entry(tag.clone()).or_insert(0) += 1;
+                        read_umi = Some(umi.to_string());
+                        break;
+                    }
+                    Ok(other) => {
+                        if !state.umi_type_warned {
+                            warn!("UMI tag {} holds a non-string value ({:?}); such UMIs are ignored", tag, other);
+                            state.umi_type_warned = true;
+                        }
+                    }
+                    Err(_) => {}
+                }
+            }
+            match read_umi {
+                Some(umi) if self.umi_collapse != UmiCollapse::Exact && !em_candidate => collapsed_umi = Some((barcode, umi)),
+                Some(umi) => {
+                    if !region_umis.entry((barcode, antisense)).or_default().insert(pack_umi(&umi)) {
+                        state.umi_duplicate_reads += 1;
+                        return Err(Unassigned::UmiDuplicate);
+                    }
+                }
+                None => {
+                    state.umi_missing_reads += 1;
+                    if self.require_umi {
+                        return Err(Unassigned::MissingUmi);
+                    }
+                }
+            }
+        }
+        let blocks = fragment_block.map_or(read_blocks, |fragment| vec![fragment]);
+        Ok(Some(Accepted { weight, em_candidate, barcode, read_group, antisense, gapped, collapsed_umi, reserved_barcode, blocks }))
+    }
+}
+
+fn main() {
+    // Report errors as a single message rather than their debug representation
+    if let Err(error) = run() {
+        eprintln!("Error: {}", error);
+        std::process::exit(1);
+    }
+}
+
+fn run() -> Result<(), Box<dyn std::error::Error>> {
+    // Parse and check the command-line arguments
+    let command = cli::command();
+    let matches = command.clone().get_matches();
+    let cli::Options {
+        mode, bam_samples, multi_sample, region_args, bin_size, tss_regions, upstream, downstream, tss_per_transcript,
+        regions_file, output_dir, velocity, observed_features_only, cellranger_output, dense_output,
+        featurecounts_output, feature_type, single_feature_column, max_dense_cells, force_dense, stdout_output,
+        compress_outputs, compression_level, max_loci, missing_nh, multimap, fractional, em, em_iterations, min_mapq,
+        treat_mapq_255_as_zero, require_flags, primary_only, proper_pairs_only, read_filter, ignore_duplicates,
+        min_insert, max_insert, drop_single_insert, min_read_length, max_read_length, max_softclip_frac,
+        min_block_baseq, require_baseq, count_hardclip, splice_filter, exclude_flags, cell_barcode_file,
+        correct_barcodes, by_read_group, coverage_out, normalize, library_sizes, assignments_bam, write_unassigned,
+        unassigned_out, per_base_profiles, max_profile_length, depth_stats, max_depth_stats_length, barcode_suffix,
+        gem_group, barcode_tag, barcode_fallback_tag, keep_untagged, umi_tags, require_umi, umi_collapse,
+        regions_format, bed_errors, one_based, keep_peak_stats, use_gene_name, on_duplicate_names, group_map,
+        keep_duplicates, strandedness, emit_antisense, tn5_shift, extend_reads, count_mode, overlap_mode, assign_unique,
+        min_overlap, min_overlap_frac, frac_of_region, contained, contained_include_clips, count_gapped_overlap,
+        split_gapped_column, chr_prefix, skip_missing_contigs, blacklist, include_chromosomes, exclude_chromosomes,
+        extend_upstream, extend_downstream, merge_gap, no_sort, sweep, stream, fetch_merge_distance, threads, fail_fast,
+        low_memory, transpose, tmp_dir, progress, report_timings, verbose
+    } = cli::parse(&matches)?;
+    let bam_file = bam_samples[0].1.as_str();
+
+    // Initialize the logger with the appropriate level
+    if verbose {
+        env_logger::Builder::from_default_env()
+            .target(env_logger::Target::Stderr)
+            .filter(None, LevelFilter::Debug)
+            .init();
+    } else {
+        env_logger::Builder::from_default_env()
+            .target(env_logger::Target::Stderr)
+            .filter(None, LevelFilter::Info)
+            .init();
+    }
+
+    let mut timings = timings::Timings::new();
+
+    // Log all arguments if verbose is enabled
+    info!("Running kai");
+    info!("Mode: {}", mode);
+    if multi_sample {
+        info!("BAM files: {}", bam_samples.iter().map(|(sample, path)| format!("{}={}", sample, path)).join(", "));
+    } else {
+        info!("BAM file: {}", bam_file);
+    }
+    info!("Regions file: {}", regions_file.map_or("None", |path| path.as_str()));
+    if !region_args.is_empty() {
+        info!("Regions from command line: {}", region_args.iter().join(", "));
+    }
+    if let Some(bin_size) = bin_size {
+        info!("Bin size: {}", bin_size);
+    }
+    if let Some(tss_regions) = tss_regions {
+        info!("TSS windows: {} (upstream {}, downstream {})", tss_regions, upstream, downstream);
+    }
+    info!("Output prefix: {}", output_dir);
+    if fractional {
+        info!("Multimapped reads: counted as 1/NH");
+    } else if multimap == Multimap::Primary {
+        info!("Multimapped reads: counted at their primary alignment");
+    } else if em {
+        info!("Multimapped reads: distributed by EM (up to {} iterations)", em_iterations);
+    } else {
+        info!("Maximum loci (NH): {}", max_loci);
+    }
+    info!("Minimum MAPQ: {}", min_mapq);
+    info!("Required flags: {}", data_loader::format_sam_flags(require_flags));
+    // The excluded flags include qcfail under --skip-qcfail; --primary-only is applied after these flag filters
+    info!("Excluded flags: {}", data_loader::format_sam_flags(exclude_flags));
+    info!("Strandedness: {}", strandedness);
+    // Load cell barcodes of interest, keeping them as strings only for the corrector of --correct-barcodes
+    let (whitelist, barcode_corrector) = if mode == Mode::Single {
+        let listed = data_loader::load_cell_barcodes(cell_barcode_file)?;
+        let listed: Vec<Cow<str>> = listed.iter().map(|barcode| barcodes::normalise_suffix(barcode, barcode_suffix, gem_group)).collect();
+        let whitelist = barcodes::Whitelist::new(&listed);
+        info!(
+            "Cell barcodes of interest: {}",
+            if whitelist.is_empty() {
+                "None (processing all reads)".to_string()
+            } else {
+                format!("{} barcodes", whitelist.len())
+            }
+        );
+        let barcode_corrector = (correct_barcodes && !whitelist.is_empty()).then(|| barcodes::BarcodeCorrector::new(&listed));
+        (whitelist, barcode_corrector)
+    } else {
+        (barcodes::Whitelist::default(), None)
+    };
+
+    // Open the BAM index, or the BAM file itself to read it once in coordinate order with --stream
+    if bam_file != "-" {
+        if let Err(source) = std::fs::metadata(bam_file) {
+            return Err(KaiError::InputOpen { path: bam_file.to_string(), source }.into());
+        }
+    }
+    let mut stream_reader = None;
+    let header: HeaderView = if stream {
+        let reader = if bam_file == "-" { bam::Reader::from_stdin() } else { bam::Reader::from_path(bam_file) }
+            .map_err(|source| KaiError::BamOpen { path: bam_file.to_string(), source })?;
+        let header = reader.header().clone();
+        if regions::sort_order(&header).as_deref() != Some("coordinate") {
+            return Err(format!("BAM file {} is not sorted by coordinate (SO:coordinate in its @HD header line), which --stream requires", bam_file).into());
+        }
+        stream_reader = Some(reader);
+        header
+    } else {
+        open_indexed_bam(bam_file)?.header().clone()
+    };
+    let contigs = regions::contig_lengths(&header);
+    let mut read_group_ids: Vec<String> = Vec::new();
+    if by_read_group {
+        for (id, sample) in regions::read_groups(&header) {
+            info!("Read group: {}{}", id, sample.map(|sample| format!(" (sample {})", sample)).unwrap_or_default());
+            read_group_ids.push(id);
+        }
+        if read_group_ids.is_empty() {
+            warn!("No @RG lines in the BAM header: all reads are counted as unknown_rg");
+        }
+    }
+
+    // Parse the files containing regions of interest, labelling regions with their set when several files are given
+    let region_sets = regions_file.map(|arg| data_loader::parse_region_sets(arg)).unwrap_or_default();
+    let mut regions: Vec<Region> = Vec::new();
+    for (set, regions_path) in &region_sets {
+        let format = match regions_format {
+            RegionFormat::Auto => data_loader::detect_regions_format(regions_path),
+            format => format,
+        };
+        info!("Regions format of {}: {}", regions_path, format);
+        let mut set_regions = match format {
+            RegionFormat::Gtf => {
+                info!("Parsing genes from GTF/GFF3 file");
+                data_loader::parse_gtf_file(regions_path, use_gene_name)?
+            }
+            RegionFormat::Saf => {
+                info!("Parsing features from SAF file");
+                data_loader::parse_saf_file(regions_path)?
+            }
+            RegionFormat::Peak => {
+                info!("Parsing peaks from narrowPeak/broadPeak file");
+                data_loader::parse_bed_file(regions_path, bed_errors, one_based, true)?
+            }
+            _ => {
+                info!("Parsing regions of interest from BED file");
+                data_loader::parse_bed_file(regions_path, bed_errors, one_based, false)?
+            }
+        };
+        if let Some(set) = set {
+            info!("Region set {}: {} regions", set, set_regions.len());
+            for region in set_regions.iter_mut() {
+                region.set = Some(set.clone());
+            }
+        }
+        regions.extend(set_regions);
+    }
+    let use_sets = region_sets.iter().any(|(set, _)| set.is_some());
+
+    // Report coordinates back in the convention given by the user
+    for region in regions.iter_mut() {
+        region.one_based = one_based;
+    }
 
-    let mut regions = Vec::new();
-    for line in reader.lines() {
-        let line = line?;
-        if line.starts_with('#') || line.trim().is_empty() {
-            continue; // Skip headers or empty lines
+    // Add regions given on the command line, skipping those already in the regions file
+    let mut region_keys: HashSet<String> = regions.iter().map(|r| r.key()).collect();
+    for region_arg in &region_args {
+        let mut region = data_loader::parse_region_string(region_arg, one_based)?;
+        region.one_based = one_based;
+        if region_keys.insert(region.key()) {
+            regions.push(region);
+        } else {
+            debug!("Region {} is already in the regions file", region_arg);
         }
-        let fields: Vec<&str> = line.split('\t').collect();
-        if fields.len() < 3 {
-            debug!("Invalid BED format: {}", line);
-            continue;
+    }
+    // Add windows around the TSSs of the annotation
+    if let Some(tss_regions) = tss_regions {
+        info!("Building TSS windows from GTF/GFF3 file");
+        let mut windows = data_loader::parse_tss_regions(tss_regions, upstream, downstream, use_gene_name, tss_per_transcript)?;
+        regions::harmonise_chr_prefix(&mut windows, &contigs, chr_prefix);
+        regions::clip_to_contigs(&mut windows, &contigs);
+        for window in windows.iter_mut() {
+            window.one_based = one_based;
         }
+        regions.extend(windows);
+    }
 
-        let chromosome = fields[0].to_string();
-        let start = fields[1].parse::<usize>().expect("Invalid start coordinate");
-        let end = fields[2].parse::<usize>().expect("Invalid end coordinate");
+    // Add genome-wide bins from the contigs of the BAM header
+    if let Some(bin_size) = bin_size {
+        regions.extend(regions::make_bins(&contigs, bin_size, one_based)?);
+    }
+    regions::remove_duplicate_regions(&mut regions, keep_duplicates);
+    if let Some(group_map) = group_map {
+        let group_map = data_loader::load_group_map(group_map)?;
+        regions = data_loader::aggregate_groups(regions, &group_map)?;
+    }
 
-        regions.push(Region {
-            chromosome,
-            start,
-            end,
-        });
+    // Check that every region is on a contig of the BAM header before counting, restricted to the selected contigs
+    regions::harmonise_chr_prefix(&mut regions, &contigs, chr_prefix);
+    let mut excluded_regions = 0;
+    if let Some(include_chromosomes) = include_chromosomes {
+        let patterns = data_loader::load_list_argument(include_chromosomes)?;
+        excluded_regions += regions::include_chromosomes(&mut regions, &patterns, &contigs);
     }
+    if let Some(exclude_chromosomes) = exclude_chromosomes {
+        let patterns = data_loader::load_list_argument(exclude_chromosomes)?;
+        excluded_regions += regions::exclude_chromosomes(&mut regions, &patterns);
+    }
+    let missing_contig_regions = regions::check_contigs(&mut regions, &contigs, skip_missing_contigs)?;
 
-    info!("Parsed {} regions", regions.len());
-    Ok(regions)
-
-}
-
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    // Set up command-line arguments using clap
-    let matches = Command::new("kai")
-        .version("0.2.0")
-        .author("NaotoKubota")
-        .about("Count reads mapped to regions of interest from bulk/single-cell RNA-seq data")
-        .arg(Arg::new("mode")
-            .required(true)
-            .value_parser(["bulk", "single"])
-            .help("Mode of operation: 'bulk' or 'single'"))
-        .arg(Arg::new("bam_file")
-            .required(true)
-            .help("Path to the BAM file"))
-        .arg(Arg::new("regions_file")
-            .required(true)
-            .help("Path to the BED file containing regions of interest"))
-        .arg(Arg::new("output_dir")
-            .required(true)
-            .help("Output directory for the output files"))
-        .arg(Arg::new("max_loci")
-            .short('l')
-            .long("max-loci")
-            .default_value("1")
-            .value_parser(clap::value_parser!(u32))
-            .help("Maximum number of loci the read maps to"))
-        .arg(Arg::new("cell_barcode_file")
-            .short('c')
-            .long("cell-barcodes")
-            .value_parser(clap::value_parser!(String))
-            .help("Optional file specifying cell barcodes of interest"))
-        .arg(Arg::new("verbose")
-            .short('v')
-            .long("verbose")
-            .action(clap::ArgAction::SetTrue)
-            .help("Enable verbose output to print all arguments"))
-        .get_matches();
-
-    // Parse arguments
-    let mode = matches.get_one::<String>("mode").unwrap();
-    let bam_file = matches.get_one::<String>("bam_file").unwrap();
-    let regions_file = matches.get_one::<String>("regions_file").unwrap();
-    let output_dir = matches.get_one::<String>("output_dir").unwrap();
-    let max_loci = *matches.get_one::<u32>("max_loci").unwrap();
-    let cell_barcode_file = matches.get_one::<String>("cell_barcode_file");
-    let verbose = matches.get_flag("verbose");
+    // Pad regions if requested
+    if extend_upstream.is_some() || extend_downstream.is_some() {
+        regions::extend_regions(&mut regions, extend_upstream.unwrap_or(0), extend_downstream.unwrap_or(0), &contigs);
+    }
 
-    // Initialize the logger with the appropriate level
-    if verbose {
-        env_logger::Builder::from_default_env()
-            .filter(None, LevelFilter::Debug)
-            .init();
-    } else {
-        env_logger::Builder::from_default_env()
-            .filter(None, LevelFilter::Info)
-            .init();
+    // Merge overlapping regions if requested
+    if let Some(gap) = merge_gap {
+        regions = regions::merge_regions(regions, gap);
     }
 
-    // Log all arguments if verbose is enabled
-    info!("Running kai");
-    info!("Mode: {}", mode);
-    info!("BAM file: {}", bam_file);
-    info!("Regions file: {}", regions_file);
-    info!("Output prefix: {}", output_dir);
-    info!("Maximum loci (NH): {}", max_loci);
-    // Load cell barcodes of interest
-    let cell_barcodes_of_interest = if mode == "single" {
-        let barcodes = data_loader::load_cell_barcodes(cell_barcode_file)?;
-        info!(
-            "Cell barcodes of interest: {}",
-            if barcodes.is_empty() {
-                "None (processing all reads)".to_string()
-            } else {
-                format!("{} barcodes", barcodes.len())
-            }
-        );
-        barcodes
+    // Make feature names unique once the final set of regions is known
+    data_loader::resolve_duplicate_names(&mut regions, on_duplicate_names)?;
+
+    // Sort regions so that fetches are grouped by chromosome and move forward through the BAM file
+    if no_sort {
+        info!("Keeping regions in input order");
+    } else if regions::sort_regions(&mut regions, &contigs) {
+        info!("Regions are already sorted");
     } else {
-        HashSet::new()
-    };
+        info!("Sorted regions by contig and position");
+    }
 
-    // Parse the BED file containing regions of interest
-    info!("Parsing regions of interest from BED file");
-    let regions = parse_bed_file(&regions_file)?;
+    // Index the blacklisted intervals for lookups of every read
+    let blacklist_index = match blacklist {
+        Some(blacklist) => {
+            info!("Loading blacklisted regions from {}", blacklist);
+            let mut blacklist_regions = data_loader::parse_bed_file(blacklist, bed_errors, one_based, false)?;
+            regions::harmonise_chr_prefix(&mut blacklist_regions, &contigs, chr_prefix);
+            Some(regions::IntervalIndex::new(&blacklist_regions))
+        }
+        None => None,
+    };
+    // Number the features (regions sharing a key are counted together) for the counts of every group
+    let mut features = Interner::default();
+    let feature_ids: Vec<u32> = regions.iter().map(|region| features.intern(&region.key())).collect();
+    if per_base_profiles {
+        let long_regions = regions.iter().filter(|region| region.end - region.start > max_profile_length).count();
+        if long_regions > 0 {
+            warn!("{} regions longer than --max-profile-length {} are left out of per_base_profiles.tsv.gz", long_regions, max_profile_length);
+        }
+    }
+    if depth_stats {
+        let long_regions = regions.iter().filter(|region| region.end - region.start > max_depth_stats_length).count();
+        if long_regions > 0 {
+            warn!("{} regions longer than --max-depth-stats-length {} get no MedianDepth", long_regions, max_depth_stats_length);
+        }
+    }
+    // Regions sharing bases with others, whose reads are tracked so that the coverage track counts them once
+    let shared_regions = if coverage_out.is_some() { coverage::shared_regions(&regions) } else { Vec::new() };
 
-    // Prepare a map for counting reads per region and optionally by cell barcode
-    let mut region_counts: HashMap<String, HashMap<String, u32>> = HashMap::new();
-    let mut region_totals: HashMap<String, u32> = HashMap::new();
-    let mut cell_barcodes: HashSet<String> = HashSet::new();
+    // Index the regions to find every region overlapped by a read in the intersection overlap modes
+    let region_index = (overlap_mode != OverlapMode::Union || assign_unique).then(|| regions::RegionIndex::new(&regions));
+    let read_filters = ReadFilters {
+        require_flags,
+        exclude_flags,
+        primary_only,
+        proper_pairs_only,
+        read_filter,
+        ignore_duplicates,
+        treat_mapq_255_as_zero,
+        min_mapq,
+        missing_nh,
+        multimap,
+        max_loci,
+        tn5_shift,
+        splice_filter,
+        min_insert,
+        max_insert,
+        drop_single_insert,
+        min_read_length,
+        max_read_length,
+        max_softclip_frac,
+        count_hardclip,
+        blacklist_index: blacklist_index.as_ref(),
+        mode,
+        barcode_tag,
+        barcode_fallback_tag: barcode_fallback_tag.map(String::as_str),
+        by_read_group,
+        barcode_suffix,
+        gem_group,
+        whitelist: (cell_barcode_file.is_some() && !whitelist.is_empty()).then_some(&whitelist),
+        keep_untagged,
+        strandedness,
+        emit_antisense,
+        region_index: region_index.as_ref(),
+        regions: &regions,
+        feature_ids: &feature_ids,
+        overlap_mode,
+        extend_reads,
+        count_mode,
+        min_overlap,
+        count_gapped_overlap,
+        contained,
+        contained_include_clips,
+        min_overlap_frac,
+        frac_of_region,
+        min_block_baseq,
+        require_baseq,
+        umi_tags: umi_tags.as_deref(),
+        umi_collapse,
+        require_umi,
+    };
 
-    // Open the BAM index
-    let mut bam = IndexedReader::from_path(bam_file)?;
+    let contig_lengths: HashMap<&str, u64> = contigs.iter().map(|(name, length)| (name.as_str(), *length)).collect();
 
-    // Counter for tracking the number of regions processed
-    let mut region_counter = 0;
-    let mut last_percentage = 0;
+    timings.end_stage("Loading inputs", vec![format!("{} regions", regions.len())]);
 
     // Count reads mapped to regions of interest
     info!("Counting reads mapped to regions of interest");
-    for region in &regions {
-        let region_key = format!("{}:{}-{}", region.chromosome, region.start, region.end);
-        region_counter += 1;
+    // The sweep engine visits regions by contig and start whatever their output order, keeping the reads of the
+    // current chromosome that may still overlap the next regions
+    let contig_index: HashMap<&str, usize> = contigs.iter().enumerate().map(|(i, (name, _))| (name.as_str(), i)).collect();
+    let region_position = |i: usize| (contig_index.get(regions[i].chromosome.as_str()).copied(), regions[i].start);
+    let region_order: Vec<usize> = if sweep {
+        (0..regions.len()).sorted_by_key(|&i| (region_position(i), regions[i].end)).collect()
+    } else {
+        (0..regions.len()).collect()
+    };
+    // Reads of --assignments-bam are written in the order they are first met, which is the coordinate order when the
+    // regions are visited by contig and start; with several groups, each writes its reads to a temporary file
+    let assignments_header = assignments_bam.map(|_| {
+        let sorted = region_order.windows(2).all(|pair| region_position(pair[0]) <= region_position(pair[1]));
+        assignments::assignments_header(&header, &std::env::args().join(" "), sorted)
+    });
+    let assignments_parts = (assignments_bam.is_some() && threads > 1).then(|| spill::SpillDir::create(&tmp_dir)).transpose()?;
 
-        // Calculate and log progress at each 1% increment
-        let progress_percentage = (region_counter * 100) / regions.len();
-        if progress_percentage > last_percentage {
-            info!("Progress: {}% / ({} / {})", progress_percentage, region_counter, regions.len());
-            last_percentage = progress_percentage;
+    // Count a group of regions of a BAM file (all of them, or one chromosome per group with --threads) with its own
+    // BAM reader and tallies, merged in group order once every group is counted
+    let progress = progress::Progress::new(progress, regions.len() * bam_samples.len(), sweep);
+    let spill_dir = if low_memory { Some(spill::SpillDir::create(&tmp_dir)?) } else { None };
+    let count_group = |bam_file: &str, group: &[usize], stream_reader: Option<bam::Reader>| -> Result<Tally, Box<dyn std::error::Error + Send + Sync>> {
+        let mut bam = match stream_reader {
+            Some(reader) => BamInput::Stream(reader),
+            None => BamInput::Indexed(IndexedReader::from_path(bam_file)?),
+        };
+        let mut filter_state = FilterState { barcode_corrector: barcode_corrector.clone(), ..Default::default() };
+        let unassigned_rows = unassigned_out.map(|path| RowBuffer::new(path.ends_with(".gz"), compression_level));
+        let assignments_writer = match (assignments_bam, &assignments_header) {
+            (Some(path), Some(header)) => {
+                let path = assignments_parts.as_ref().map_or_else(|| path.clone(), |parts| parts.file_path("assignments", "bam").display().to_string());
+                let mut writer = bam::Writer::from_path(&path, header, bam::Format::Bam).map_err(|source| KaiError::BamWrite { path: path.clone(), source })?;
+                if assignments_parts.is_some() {
+                    writer.set_compression_level(bam::CompressionLevel::Uncompressed).map_err(|source| KaiError::BamWrite { path: path.clone(), source })?;
+                }
+                Some((path, writer))
+            }
+            _ => None,
+        };
+        let mut assignments = assignments::Assignments::new(assignments_writer, featurecounts_output, write_unassigned, unassigned_rows);
+        let mut spill = spill_dir.as_ref().map(spill::Spill::create).transpose()?;
+        // Prepare a map for counting reads per region and optionally by cell barcode
+        let mut region_counts: Counts = HashMap::new();
+        let mut region_totals: HashMap<u32, f64> = HashMap::new();
+        let mut read_group_counts: HashMap<u32, HashMap<String, f64>> = HashMap::new();
+        // Spliced, unspliced and ambiguous counts per region and cell barcode for --velocity
+        let mut velocity_counts: [Counts; 3] = Default::default();
+        let mut antisense_counts: Counts = HashMap::new();
+        let mut antisense_totals: HashMap<u32, f64> = HashMap::new();
+        let mut gapped_totals: HashMap<u32, f64> = HashMap::new();
+        let mut coverage = coverage::Coverage::default();
+        let mut fractional_reads: u64 = 0;
+        let mut multimap_candidates: MultimapCandidates = HashMap::new();
+        let mut profile_rows = RowBuffer::new(compress_outputs, compression_level);
+        let mut depth_stats_totals: HashMap<u32, coverage::DepthStats> = HashMap::new();
+        // Reads fetched, reads passing the filters and time spent fetching and decoding reads for --timings
+        let mut fetched_reads: u64 = 0;
+        let mut filtered_reads: u64 = 0;
+        let mut read_time = Duration::ZERO;
+        let mut sweep_chromosome: Option<&str> = None;
+        let mut sweep_window: VecDeque<(i64, Record)> = VecDeque::new();
+        // Records dropped from the window, read into again instead of allocating a record per read
+        let mut record_pool: Vec<Record> = Vec::new();
+        let mut sweep_exhausted = false;
+        let mut sweep_reads: u64 = 0;
+        // First read of a later chromosome met while streaming, and position of the last read to check the sort order
+        let mut stream_pending: Option<Record> = None;
+        let mut stream_position = (false, -1, -1);
+        let mut fetched_record = Record::new();
+        // Consecutive regions of a chromosome starting within --fetch-merge-distance of the end of the previous ones
+        // share one fetch window, read once while sweeping through its regions as the sweep engine does
+        let fetch_margin = extend_reads.unwrap_or(0).max(if tn5_shift { 5 } else { 0 });
+        let fetch_span = |region: &Region| (region.start.saturating_sub(fetch_margin) as i64, (region.end + fetch_margin) as i64);
+        let mut fetch_windows: Vec<(&str, i64, i64, i64)> = Vec::new();
+        let mut window_of: Vec<usize> = Vec::with_capacity(group.len());
+        for &current_index in group {
+            let region = &regions[current_index];
+            let (start, end) = fetch_span(region);
+            match fetch_windows.last_mut() {
+                Some((chromosome, _, last_start, window_end))
+                    if fetch_merge_distance > 0 && *chromosome == region.chromosome && start >= *last_start && start <= *window_end + fetch_merge_distance =>
+                {
+                    *last_start = start;
+                    *window_end = (*window_end).max(end);
+                }
+                _ => fetch_windows.push((&region.chromosome, start, start, end)),
+            }
+            window_of.push(fetch_windows.len() - 1);
         }
+        let windowed = sweep || fetch_merge_distance > 0;
+        // Smallest fetch start of the later regions of the group on the contig of each region, before which the reads
+        // of that contig are not fetched again and can be written to --assignments-bam
+        let mut later_fetch_starts = vec![i64::MAX; group.len()];
+        let mut contig_fetch_starts: HashMap<&str, i64> = HashMap::new();
+        for (position, &current_index) in group.iter().enumerate().rev() {
+            let region = &regions[current_index];
+            let fetch_start = contig_fetch_starts.entry(region.chromosome.as_str()).or_insert(i64::MAX);
+            later_fetch_starts[position] = *fetch_start;
+            *fetch_start = (*fetch_start).min(fetch_span(region).0);
+        }
+        let mut release_bounds: HashMap<i32, i64> = HashMap::new();
+        let mut current_window = None;
+        for (position, &current_index) in group.iter().enumerate() {
+            let region = &regions[current_index];
+            let feature = feature_ids[current_index];
+            let region_counter = progress.start_region();
+            // UMIs seen in the region per barcode (and sense), dropped once the region is counted
+            let mut region_umis: HashMap<(u32, bool), HashSet<Umi>> = HashMap::new();
+            // Reads per UMI and weight of the first of them, collapsed once the region is counted
+            let mut region_umi_counts: HashMap<(u32, bool), HashMap<String, (u32, f64)>> = HashMap::new();
 
-        // Fetch reads in the region
-        let chrom_bytes = region.chromosome.as_bytes();
-        bam.fetch((chrom_bytes, region.start as i64, region.end as i64))?;
-
-        // Iterate over reads in the region
-        for result in bam.records() {
-            let record = result?;
-            // Skip read if NH tag exceeds max_loci
-            if let Ok(Aux::U8(nh)) = record.aux(b"NH") {
-                if nh > max_loci as u8 {
-                    continue; // Skip reads with more than max_loci loci
+            // Fetch reads in the region, widened to the reads shifted into it
+            let chrom_bytes = region.chromosome.as_bytes();
+            let (fetch_start, fetch_end) = fetch_span(region);
+            let contig_length = contig_lengths.get(region.chromosome.as_str()).copied().unwrap_or(u64::MAX) as i64;
+            let read_start = Instant::now();
+            if sweep {
+                if sweep_chromosome != Some(region.chromosome.as_str()) {
+                    if let Some(chromosome) = sweep_chromosome {
+                        progress.chromosome_done(chromosome, sweep_reads, region_counter - 1);
+                    }
+                    let tid = bam.header().tid(chrom_bytes).ok_or_else(|| format!("Contig {} is not in the BAM header", region.chromosome))?;
+                    bam.fetch_contig(tid)
+                        .map_err(|source| KaiError::BamFetch { path: bam_file.to_string(), region: region.chromosome.clone(), source })?;
+                    sweep_chromosome = Some(region.chromosome.as_str());
+                    record_pool.extend(sweep_window.drain(..).map(|(_, record)| record));
+                    sweep_exhausted = false;
                 }
+            } else if windowed && current_window != Some(window_of[position]) {
+                let (_, window_start, _, window_end) = fetch_windows[window_of[position]];
+                bam.fetch_region(chrom_bytes, window_start, window_end)
+                    .map_err(|source| KaiError::BamFetch { path: bam_file.to_string(), region: region.key(), source })?;
+                current_window = Some(window_of[position]);
+                record_pool.extend(sweep_window.drain(..).map(|(_, record)| record));
+                sweep_exhausted = false;
+                stream_position = (false, -1, -1);
             }
-
-            // Extract Cell Barcode (CB) from tags if in single mode
-            let cell_barcode = if mode == "single" {
-                match record.aux(b"CB") {
-                    Ok(Aux::String(cb)) => Some(cb.to_string()),
-                    _ => None,
+            if windowed {
+                // Forget the reads ending before the region, then read on past its end
+                while sweep_window.front().is_some_and(|&(end, _)| end <= fetch_start) {
+                    record_pool.extend(sweep_window.pop_front().map(|(_, record)| record));
                 }
-            } else {
-                None
-            };
-
-            // Skip read if its barcode is not in the list of interest
-            if let Some(cb) = &cell_barcode {
-                if cell_barcode_file.is_some() && !cell_barcodes_of_interest.is_empty() && !cell_barcodes_of_interest.contains(cb) {
-                    continue; // Skip reads with cell barcodes not in the list of interest
-                }
-                cell_barcodes.insert(cb.clone());
-            }
-
-            // Get the start position of the read
-            let mut current_pos = record.pos();
-
-            // Check cigar string to determine if the read overlaps the region with matching bases, not like RefSkip or SoftClip
-            let cigar_vec = record.cigar(); // Create a longer-lived binding for the cigar data
-            let cigars: Vec<_> = cigar_vec.iter().collect();
-            for i in 0..cigars.len() {
-                let cigar = cigars[i];
-                if let Cigar::Match(_) | Cigar::Equal(_) | Cigar::Diff(_) = cigar {
-                    let cigar_len = cigar.len() as i64;
-                    let cigar_end = current_pos + cigar_len;
-                    // Increment the count if the read overlaps the region at least partially
-                    if current_pos < region.end.try_into().unwrap() && cigar_end > region.start.try_into().unwrap() {
-                        if mode == "single" {
-                            if let Some(cb) = &cell_barcode {
-                                let region_entry = region_counts
-                                    .entry(region_key.to_string())
-                                    .or_insert_with(HashMap::new);
-                                *region_entry.entry(cb.clone()).or_insert(0) += 1;
+                let tid = bam.header().tid(chrom_bytes).map_or(-1, |tid| tid as i32);
+                while !sweep_exhausted && sweep_window.back().is_none_or(|(_, record)| record.pos() < fetch_end) {
+                    let record = match stream_pending.take() {
+                        Some(record) => record,
+                        None => {
+                            let mut record = record_pool.pop().unwrap_or_default();
+                            match bam.read(&mut record) {
+                                Some(result) => {
+                                    result?;
+                                    sweep_reads += 1;
+                                    // Unmapped reads without a position come last in a coordinate-sorted BAM file
+                                    let position = (record.tid() < 0, record.tid(), record.pos());
+                                    if position < stream_position {
+                                        return Err(format!("BAM file {} is not sorted by coordinate: read {} is out of order", bam_file, String::from_utf8_lossy(record.qname())).into());
+                                    }
+                                    stream_position = position;
+                                    record
+                                }
+                                None => {
+                                    record_pool.push(record);
+                                    sweep_exhausted = true;
+                                    continue;
+                                }
                             }
-                        } else if mode == "bulk" {
-                            *region_totals
-                            .entry(region_key.to_string())
-                            .or_insert(0) += 1;
                         }
-                        break; // Break the loop to avoid double counting
+                    };
+                    // A streamed BAM file holds every chromosome: skip the reads of chromosomes without regions and
+                    // keep the first read past the chromosome for the next one
+                    if record.tid() != tid {
+                        if record.tid() < 0 || record.tid() > tid {
+                            stream_pending = Some(record);
+                            sweep_exhausted = true;
+                        } else {
+                            record_pool.push(record);
+                        }
+                        continue;
+                    }
+                    // Reads without reference-consuming operations cover their position, as for indexed fetches
+                    let mut record = record;
+                    record.cache_cigar();
+                    let end = read_cigar(&record).end_pos().max(record.pos() + 1);
+                    sweep_window.push_back((end, record));
+                }
+            } else {
+                bam.fetch_region(chrom_bytes, fetch_start, fetch_end)
+                    .map_err(|source| KaiError::BamFetch { path: bam_file.to_string(), region: region.key(), source })?;
+            }
+            // The sweep engine and fetch windows give the reads an indexed fetch of the region would return, in the same
+            // order
+            let mut window_records = sweep_window.iter()
+                .filter(|(end, record)| record.pos() < fetch_end && *end > fetch_start)
+                .map(|(_, record)| record);
+            read_time += read_start.elapsed();
+            // Depth of the counted reads over the region for --per-base-profiles and --depth-stats, at each base when
+            // either needs it
+            let region_span = region.end - region.start;
+            let write_profile = per_base_profiles && region_span <= max_profile_length;
+            let mut depth = (per_base_profiles || depth_stats)
+                .then(|| coverage::RegionDepth::new(region, write_profile || (depth_stats && region_span <= max_depth_stats_length)));
+            // Iterate over reads in the region, read into the same record with its CIGAR decoded once, and timed
+            let mut region_reads: u64 = 0;
+            loop {
+                let read_start = Instant::now();
+                let record: &Record = if windowed {
+                    match window_records.next() {
+                        Some(record) => record,
+                        None => break,
+                    }
+                } else {
+                    match bam.read(&mut fetched_record) {
+                        Some(result) => {
+                            result?;
+                            fetched_record.cache_cigar();
+                            &fetched_record
+                        }
+                        None => break,
+                    }
+                };
+                read_time += read_start.elapsed();
+                region_reads += 1;
+                let accepted = match read_filters.filter_read(&mut filter_state, record, current_index, contig_length, &mut region_umis) {
+                    Ok(Some(accepted)) => accepted,
+                    // Reads counted in another region they overlap
+                    Ok(None) => continue,
+                    Err(reason) => {
+                        assignments.reject(record, region, reason)?;
+                        continue;
+                    }
+                };
+                if accepted.reserved_barcode {
+                    return Err(format!(
+                        "Read {} has the barcode {} reserved by --keep-untagged",
+                        String::from_utf8_lossy(record.qname()), UNTAGGED_BARCODE
+                    ).into());
+                }
+                let Accepted { weight, em_candidate, barcode, read_group, antisense, gapped, collapsed_umi, blocks, .. } = accepted;
+                // Classify the counted read for RNA velocity when it falls within the region span
+                if velocity && !antisense {
+                    if let Some(barcode) = barcode {
+                        let velocity_blocks = aligned_blocks(record);
+                        if regions::overlap_bases(&velocity_blocks, &[(region.start, region.end)]) > 0 {
+                            let class = velocity_class(record, &velocity_blocks, region);
+                            add_count(&mut velocity_counts[class], &mut spill, VELOCITY_MATRIX + class as u8, feature, barcode, weight)?;
+                        }
+                    }
+                }
+                // Group the read into the molecules of its UMI, counted once the region is done
+                if let Some((barcode, umi)) = collapsed_umi {
+                    let umi_entry = region_umi_counts
+                        .entry((barcode, antisense))
+                        .or_default()
+                        .entry(umi)
+                        .or_insert((0, weight));
+                    umi_entry.0 += 1;
+                    filtered_reads += 1;
+                    assignments.assign(record, || region.key());
+                    // Reads grouped into molecules add to the depth as reads
+                    if let (Some(depth), false) = (&mut depth, antisense) {
+                        depth.add(region, &blocks, weight);
                     }
-                } else if let Cigar::SoftClip(_) = cigar {
                     continue;
+                }
+                if mode == Mode::Single && barcode.is_none() {
+                    assignments.reject(record, region, Unassigned::NoBarcode)?;
                 } else {
-                    current_pos += match cigar {
-                        Cigar::Ins(l) | Cigar::Del(l) | Cigar::RefSkip(l) => *l as i64,
-                        _ => 0,
-                    };
+                    filtered_reads += 1;
+                    assignments.assign(record, || region.key());
+                }
+                if antisense {
+                    match barcode {
+                        Some(barcode) => add_count(&mut antisense_counts, &mut spill, ANTISENSE_MATRIX, feature, barcode, weight)?,
+                        None if mode == Mode::Bulk => *antisense_totals.entry(feature).or_insert(0.0) += weight,
+                        None => {}
+                    }
+                    continue;
+                }
+                if gapped && split_gapped_column {
+                    *gapped_totals.entry(feature).or_insert(0.0) += weight;
+                    continue;
+                }
+                if em_candidate {
+                    // Defer multimapped reads until the unique-read counts are known
+                    if mode == Mode::Bulk || barcode.is_some() {
+                        let cell_barcode = barcode.map(|barcode| filter_state.cell_barcodes.names[barcode as usize].clone());
+                        let read_regions = multimap_candidates
+                            .entry((record.qname().to_vec(), cell_barcode.or(read_group.clone())))
+                            .or_default();
+                        if !read_regions.contains(&feature) {
+                            read_regions.push(feature);
+                        }
+                    }
+                    continue;
+                }
+                if weight < 1.0 {
+                    fractional_reads += 1;
+                }
+                // Add the counted bases (of the extended fragment with --extend-reads) to the depth of the region
+                if let Some(depth) = depth.as_mut().filter(|_| mode == Mode::Bulk || barcode.is_some()) {
+                    depth.add(region, &blocks, weight);
+                }
+                if mode == Mode::Single {
+                    if let Some(barcode) = barcode {
+                        add_count(&mut region_counts, &mut spill, REGION_MATRIX, feature, barcode, weight)?;
+                    }
+                } else if mode == Mode::Bulk {
+                    *region_totals
+                    .entry(feature)
+                    .or_insert(0.0) += weight;
+                    if let Some(read_group) = read_group {
+                        *read_group_counts.entry(feature).or_default().entry(read_group).or_insert(0.0) += weight;
+                    }
+                    // Add the counted bases (of the extended fragment with --extend-reads) to the coverage track
+                    if coverage_out.is_some() {
+                        let read = || (record.qname().to_vec(), record.pos(), record.flags());
+                        coverage.add(&regions, &shared_regions, current_index, read, &blocks, weight);
+                    }
+                }
+            }
+            progress.add_reads(region_reads);
+            fetched_reads += region_reads;
+
+            // Count the molecules of the region from its collapsed UMIs
+            for ((barcode, antisense), umi_reads) in region_umi_counts {
+                let umi_counts: HashMap<String, u32> = umi_reads.iter().map(|(umi, &(count, _))| (umi.clone(), count)).collect();
+                let molecules = umi::collapse_umis(&umi_counts, umi_collapse);
+                filter_state.umi_duplicate_reads += umi_counts.values().map(|&count| count as u64).sum::<u64>() - molecules.len() as u64;
+                let (counts, matrix) = if antisense { (&mut antisense_counts, ANTISENSE_MATRIX) } else { (&mut region_counts, REGION_MATRIX) };
+                for umi in molecules {
+                    add_count(counts, &mut spill, matrix, feature, barcode, umi_reads[umi].1)?;
+                }
+            }
+
+            if let Some(profile) = depth.as_ref().and_then(|depth| depth.profile.as_ref()).filter(|_| write_profile) {
+                write!(profile_rows, "{}\t{}\t{}\t{}\t", region.output_chromosome(), region.output_start(), region.end, region.feature_name())?;
+                writeln!(profile_rows, "{}", profile.iter().map(|&depth| format_count(depth)).join(","))?;
+            }
+            if let Some(depth) = depth.filter(|_| depth_stats) {
+                depth_stats_totals.entry(feature).or_default().add_region(region, &depth);
+            }
+            if let Some(tid) = bam.header().tid(chrom_bytes) {
+                release_bounds.insert(tid as i32, later_fetch_starts[position]);
+            }
+            assignments.release(|tid, end| release_bounds.get(&tid).is_some_and(|&bound| end <= bound))?;
+        }
+        assignments.finish()?;
+
+        if let Some(chromosome) = sweep_chromosome {
+            progress.chromosome_done(chromosome, sweep_reads, progress.regions_started());
+        }
+
+        let FilterState {
+            cell_barcodes, blacklisted_reads, missing_nh_reads, examined_reads, duplicate_reads, missing_flag_reads,
+            excluded_flag_reads, ambiguous_reads, multi_region_reads, primary_tag_reads, fallback_tag_reads,
+            no_barcode_reads, exact_barcode_reads, corrected_barcode_reads, unlisted_barcode_example, umi_missing_reads,
+            umi_tag_reads, umi_duplicate_reads, no_feature_reads, gapped_reads, ..
+        } = filter_state;
+        Ok(Tally {
+            region_counts,
+            region_totals,
+            read_group_counts,
+            velocity_counts,
+            antisense_counts,
+            antisense_totals,
+            gapped_totals,
+            gapped_reads,
+            cell_barcodes,
+            coverage,
+            profile_rows: profile_rows.finish()?,
+            depth_stats: depth_stats_totals,
+            unassigned_rows: assignments.take_rows().map(RowBuffer::finish).transpose()?.unwrap_or_default(),
+            assignments,
+            spill_files: spill.map(spill::Spill::finish).transpose()?.map(|path| vec![(path, None)]).unwrap_or_default(),
+            blacklisted_reads,
+            missing_nh_reads,
+            examined_reads,
+            fractional_reads,
+            multimap_candidates,
+            duplicate_reads,
+            missing_flag_reads,
+            excluded_flag_reads,
+            ambiguous_reads,
+            multi_region_reads,
+            primary_tag_reads,
+            fallback_tag_reads,
+            no_barcode_reads,
+            exact_barcode_reads,
+            corrected_barcode_reads,
+            unlisted_barcode_example,
+            umi_missing_reads,
+            umi_tag_reads,
+            umi_duplicate_reads,
+            no_feature_reads,
+            fetched_reads,
+            filtered_reads,
+            read_time,
+        })
+    };
+
+    // With several threads, count each chromosome as its own group, handed to the next idle thread
+    let count_sample = |bam_file: &str, threads: usize, stream_reader: Option<bam::Reader>| -> Result<Tally, Box<dyn std::error::Error + Send + Sync>> {
+        if threads == 1 {
+            return count_group(bam_file, &region_order, stream_reader);
+        }
+        let mut groups: Vec<Vec<usize>> = Vec::new();
+        let mut group_index: HashMap<&str, usize> = HashMap::new();
+        for &current_index in &region_order {
+            let group = *group_index.entry(regions[current_index].chromosome.as_str()).or_insert_with(|| {
+                groups.push(Vec::new());
+                groups.len() - 1
+            });
+            groups[group].push(current_index);
+        }
+        let next_group = AtomicUsize::new(0);
+        let mut group_tallies: Vec<(usize, Tally)> = std::thread::scope(|scope| {
+            let workers: Vec<_> = (0..threads.min(groups.len()))
+                .map(|_| scope.spawn(|| -> Result<Vec<(usize, Tally)>, Box<dyn std::error::Error + Send + Sync>> {
+                    let mut tallies = Vec::new();
+                    loop {
+                        let group = next_group.fetch_add(1, Ordering::Relaxed);
+                        let Some(group_regions) = groups.get(group) else { break };
+                        tallies.push((group, count_group(bam_file, group_regions, None)?));
+                    }
+                    Ok(tallies)
+                }))
+                .collect();
+            workers.into_iter()
+                .map(|worker| worker.join().unwrap_or_else(|panic| std::panic::resume_unwind(panic)))
+                .collect::<Result<Vec<_>, _>>()
+        })?.into_iter().flatten().collect();
+        group_tallies.sort_by_key(|(group, _)| *group);
+        Ok(group_tallies.into_iter().fold(Tally::default(), |mut tally, (_, group_tally)| {
+            tally.merge(group_tally);
+            tally
+        }))
+    };
+
+    // Several BAM files are counted in parallel, one per thread, after checking that they share the reference
+    // sequences of the first one; a failed sample does not stop the others unless --fail-fast is given
+    let mut sample_tallies: Vec<(usize, Tally)> = Vec::new();
+    let mut failed_samples: Vec<usize> = Vec::new();
+    if multi_sample {
+        let next_sample = AtomicUsize::new(0);
+        let failed = AtomicBool::new(false);
+        let results: Vec<(usize, Result<Tally, String>)> = std::thread::scope(|scope| {
+            let workers: Vec<_> = (0..threads.min(bam_samples.len()))
+                .map(|_| scope.spawn(|| {
+                    let mut results = Vec::new();
+                    loop {
+                        let sample = next_sample.fetch_add(1, Ordering::Relaxed);
+                        let Some((_, path)) = bam_samples.get(sample) else { break };
+                        if fail_fast && failed.load(Ordering::Relaxed) {
+                            break;
+                        }
+                        let result = std::fs::metadata(path)
+                            .map_err(|source| KaiError::InputOpen { path: path.to_string(), source }.into())
+                            .and_then(|_| open_indexed_bam(path).map_err(|error| error.into()))
+                            .and_then(|bam| match regions::contig_lengths(bam.header()) == contigs {
+                                true => Ok(()),
+                                false => Err(format!("BAM file {} does not have the reference sequences of {}", path, bam_file).into()),
+                            })
+                            .and_then(|_| count_sample(path, 1, None))
+                            .map_err(|error: Box<dyn std::error::Error + Send + Sync>| error.to_string());
+                        if result.is_err() {
+                            failed.store(true, Ordering::Relaxed);
+                        }
+                        results.push((sample, result));
+                    }
+                    results
+                }))
+                .collect();
+            workers.into_iter()
+                .flat_map(|worker| worker.join().unwrap_or_else(|panic| std::panic::resume_unwind(panic)))
+                .sorted_by_key(|(sample, _)| *sample)
+                .collect()
+        });
+        for (sample, result) in results {
+            match result {
+                Ok(tally) => sample_tallies.push((sample, tally)),
+                Err(error) => {
+                    if fail_fast {
+                        return Err(format!("Sample {}: {}", bam_samples[sample].0, error).into());
+                    }
+                    warn!("Sample {} failed: {}", bam_samples[sample].0, error);
+                    failed_samples.push(sample);
+                }
+            }
+        }
+        if sample_tallies.is_empty() {
+            return Err(format!("All {} samples failed", bam_samples.len()).into());
+        }
+    } else {
+        let tally = count_sample(bam_file, threads, stream_reader.take()).map_err(|error| error as Box<dyn std::error::Error>)?;
+        sample_tallies.push((0, tally));
+    }
+    progress.finish();
+    let tally_sum = |field: fn(&Tally) -> u64| -> u64 { sample_tallies.iter().map(|(_, tally)| field(tally)).sum() };
+    timings.end_stage("Counting", vec![
+        format!("{} regions", regions.len()),
+        format!("{} reads fetched", tally_sum(|tally| tally.fetched_reads)),
+        format!("{} reads passing filters", tally_sum(|tally| tally.filtered_reads)),
+        format!(
+            "{:.3} s fetching and decoding reads (summed over threads)",
+            sample_tallies.iter().map(|(_, tally)| tally.read_time.as_secs_f64()).sum::<f64>()
+        ),
+    ]);
+
+    // Distribute the multimapped reads of each sample over their candidate regions
+    let multimapped_reads: usize = sample_tallies.iter().map(|(_, tally)| tally.multimap_candidates.len()).sum();
+    if em {
+        for (_, tally) in sample_tallies.iter_mut().filter(|(_, tally)| !tally.multimap_candidates.is_empty()) {
+            info!("Resolving {} multimapped reads by EM", tally.multimap_candidates.len());
+            tally.resolve_multimappers(mode, em_iterations);
+        }
+        timings.end_stage("Resolving multimappers (EM)", vec![format!("{} multimapped reads", multimapped_reads)]);
+    }
+
+    // Keep the counts of each sample for their column in bulk mode, and prefix their barcodes in single mode
+    let mut sample_totals: Vec<(usize, HashMap<u32, f64>)> = Vec::new();
+    let mut sample_filtered_reads: Vec<(usize, u64)> = Vec::new();
+    let mut sample_read_outcomes: Vec<(usize, assignments::ReadOutcomes)> = Vec::new();
+    let tally = if multi_sample {
+        let mut merged = Tally::default();
+        for (sample, mut tally) in sample_tallies {
+            sample_filtered_reads.push((sample, tally.filtered_reads));
+            sample_read_outcomes.push((sample, tally.assignments.outcomes().clone()));
+            if mode == Mode::Bulk {
+                sample_totals.push((sample, tally.region_totals.clone()));
+            } else {
+                let mut prefixed = Interner::default();
+                for barcode in &tally.cell_barcodes.names {
+                    prefixed.intern(&format!("{}_{}", bam_samples[sample].0, barcode));
                 }
+                tally.cell_barcodes = prefixed;
             }
+            merged.merge(tally);
         }
+        merged
+    } else {
+        sample_tallies.pop().unwrap().1
+    };
+    let Tally {
+        region_counts, region_totals, read_group_counts, velocity_counts, antisense_counts,
+        antisense_totals, gapped_totals, gapped_reads, cell_barcodes, mut coverage, profile_rows, depth_stats: mut depth_stats_totals, assignments, unassigned_rows, spill_files,
+        blacklisted_reads, missing_nh_reads, examined_reads, fractional_reads, multimap_candidates: _, duplicate_reads,
+        missing_flag_reads, excluded_flag_reads, ambiguous_reads, multi_region_reads, primary_tag_reads, fallback_tag_reads,
+        no_barcode_reads, exact_barcode_reads, corrected_barcode_reads,
+        unlisted_barcode_example, umi_missing_reads, umi_tag_reads, umi_duplicate_reads, no_feature_reads, fetched_reads,
+        filtered_reads, read_time: _
+    } = tally;
+    // Reads skipped by the filters, tallied per reason where they are skipped
+    let skipped = assignments.counts().clone();
+    let (secondary_reads, supplementary_reads) = (skipped[Unassigned::Secondary], skipped[Unassigned::Supplementary]);
+    let (improper_pair_reads, other_mate_reads) = (skipped[Unassigned::ImproperPair], skipped[Unassigned::OtherMate]);
+    let (low_mapq_reads, multimap_skipped_reads) = (skipped[Unassigned::LowMapq], skipped[Unassigned::Multimapping]);
+    let (splice_filtered_reads, insert_filtered_reads) = (skipped[Unassigned::Splicing], skipped[Unassigned::InsertSize]);
+    let (length_filtered_reads, clipped_reads) = (skipped[Unassigned::ReadLength], skipped[Unassigned::SoftClipped]);
+    let (unassigned_barcode_reads, wrong_strand_reads) = (skipped[Unassigned::UnlistedBarcode], skipped[Unassigned::WrongStrand]);
+    let (low_overlap_reads, uncontained_reads) = (skipped[Unassigned::LowOverlap], skipped[Unassigned::Uncontained]);
+    let (low_baseq_reads, missing_baseq_reads) = (skipped[Unassigned::LowBaseQuality], skipped[Unassigned::MissingBaseQuality]);
+
+    // Warn when most barcoded reads miss the list of interest, which usually means their suffix conventions differ
+    let barcoded_reads = exact_barcode_reads + corrected_barcode_reads + unassigned_barcode_reads;
+    if let Some(example) = unlisted_barcode_example.filter(|_| unassigned_barcode_reads as f64 > 0.9 * barcoded_reads as f64) {
+        warn!(
+            "{} of {} barcoded reads (e.g. barcode {}) are not in the cell barcodes list: check that the list and the BAM use the same barcode suffixes (see --barcode-suffix)",
+            unassigned_barcode_reads, barcoded_reads, example
+        );
     }
 
-    // Write results based on mode
+    // Barcodes are sorted to give the matrix columns
+    let barcode_list: Vec<u32> = (0..cell_barcodes.names.len() as u32).sorted_by_key(|&barcode| cell_barcodes.name(barcode)).collect();
+    // Matrix column of each barcode number, following the sorted barcodes
+    let mut barcode_columns = vec![0; barcode_list.len()];
+    for (j, &barcode) in barcode_list.iter().enumerate() {
+        barcode_columns[barcode as usize] = j;
+    }
+
+    // Sort the spilled counts of --low-memory into their matrices
+    let sorted_matrices = match &spill_dir {
+        Some(spill_dir) => {
+            info!("Sorting the counts of {} spill files", spill_files.len());
+            let sorted_matrices = spill::sort(spill_dir, &spill_files, &barcode_columns, VELOCITY_MATRIX as usize + 3)?;
+            timings.end_stage("Sorting counts (--low-memory)", vec![
+                format!("{} matrix entries", sorted_matrices.iter().map(|matrix| matrix.entries).sum::<usize>()),
+            ]);
+            Some(sorted_matrices)
+        }
+        None => None,
+    };
+    let matrix_counts = |matrix: u8, counts| match &sorted_matrices {
+        Some(sorted_matrices) => MatrixCounts::Sorted(&sorted_matrices[matrix as usize]),
+        None => MatrixCounts::Memory(counts),
+    };
+    let region_matrix = matrix_counts(REGION_MATRIX, &region_counts);
+    let velocity_matrices: Vec<MatrixCounts> = velocity_counts.iter().enumerate()
+        .map(|(class, counts)| matrix_counts(VELOCITY_MATRIX + class as u8, counts))
+        .collect();
+
+    // Write results based on mode, keeping the paths written for --timings
     info!("Writing output files");
-    if mode == "single" {
-        // Prepare output files with compression
-        let mut matrix_file = GzEncoder::new(File::create(format!("{}/matrix.mtx.gz", output_dir))?, Compression::default());
-        let mut barcodes_file = GzEncoder::new(File::create(format!("{}/barcodes.tsv.gz", output_dir))?, Compression::default());
-        let mut features_file = GzEncoder::new(File::create(format!("{}/features.tsv.gz", output_dir))?, Compression::default());
-        let mut output_tsv = GzEncoder::new(File::create(format!("{}/count_barcodes.tsv.gz", output_dir))?, Compression::default());
+    let mut output_paths: Vec<String> = Vec::new();
+    let mut output_path = |name: &str| {
+        let name = if compress_outputs { name } else { name.trim_end_matches(".gz") };
+        let path = format!("{}/{}", output_dir, name);
+        output_paths.push(path.clone());
+        path
+    };
+    if mode == Mode::Single {
+        // The Cell Ranger layout is read from a directory of its own, created with its parents when missing
+        if cellranger_output {
+            std::fs::create_dir_all(output_dir).map_err(|source| KaiError::Output { path: output_dir.to_string(), source })?;
+        }
+        // Features follow the order of the regions, keeping regions without counts so that matrices share dimensions
+        let feature_list: Vec<u32> = (0..features.names.len() as u32)
+            .filter(|feature| {
+                !observed_features_only || region_matrix.has_feature(*feature) || velocity_matrices.iter().any(|counts| counts.has_feature(*feature))
+            })
+            .collect();
+        // Matrix row of each listed feature
+        let mut feature_rows = vec![0; features.names.len()];
+        for (i, &feature) in feature_list.iter().enumerate() {
+            feature_rows[feature as usize] = i;
+        }
+
+        // Refuse dense matrices too large to be meant before writing anything
+        let dense_cells = feature_list.len() as u64 * barcode_list.len() as u64;
+        if dense_output && dense_cells > max_dense_cells && !force_dense {
+            return Err(format!(
+                "--output-format dense-tsv would write {} features x {} barcodes = {} cells, more than --max-dense-cells {}: use the sparse outputs, raise --max-dense-cells or add --force-dense",
+                feature_list.len(), barcode_list.len(), dense_cells, max_dense_cells
+            ).into());
+        }
+
+        // Prepare output files with compression (count_barcodes.tsv.gz is not part of the Cell Ranger layout, and the
+        // dense matrix replaces both sparse files)
+        let mut matrix_file = if dense_output { None } else { Some(create_output(&output_path("matrix.mtx.gz"), compression_level)?) };
+        let mut barcodes_file = create_output(&output_path("barcodes.tsv.gz"), compression_level)?;
+        let mut features_file = create_output(&output_path("features.tsv.gz"), compression_level)?;
+        let mut output_tsv = if cellranger_output || dense_output { None } else { Some(create_output(&output_path("count_barcodes.tsv.gz"), compression_level)?) };
 
         // Write barcodes.tsv.gz
         debug!("Writing barcodes.tsv.gz");
-        let barcode_list: Vec<_> = cell_barcodes.iter().sorted().collect();
-        for barcode in &barcode_list {
-            writeln!(barcodes_file, "{}", barcode)?;
+        for &barcode in &barcode_list {
+            writeln!(barcodes_file, "{}", cell_barcodes.name(barcode))?;
         }
 
         // Write features.tsv.gz
         debug!("Writing features.tsv.gz");
-        let feature_list: Vec<_> = region_counts.keys().sorted().collect();
-        for feature in &feature_list {
-            writeln!(features_file, "{}", feature)?;
-        }
-
-        // Buffers to accumulate lines for matrix.mtx.gz and output.tsv.gz
-        let mut matrix_buffer: Vec<String> = Vec::new();
-        let mut tsv_buffer: Vec<String> = Vec::new();
-
-        // Add the header lines to the matrix buffer
-        matrix_buffer.push("%%MatrixMarket matrix coordinate integer general".to_string());
-        matrix_buffer.push("%".to_string());
-        matrix_buffer.push(format!(
-            "{} {} {}",
-            feature_list.len(),
-            barcode_list.len(),
-            region_counts.values().map(|c| c.len()).sum::<usize>()
-        ));
-
-        // Add sparse matrix data and TSV data to the buffers
-        debug!("Writing matrix.mtx.gz and count_barcodes.tsv.gz");
-        let barcode_map: HashMap<_, _> = barcode_list.iter().enumerate().map(|(i, b)| (b.as_str(), i)).collect();
-        tsv_buffer.push("Feature\tBarcode\tCount".to_string());
-        for (i, feature) in feature_list.iter().enumerate() {
-            if let Some(cell_counts) = region_counts.get(*feature) {
-                for (barcode, count) in cell_counts {
-                    if let Some(&j) = barcode_map.get(barcode.as_str()) {
-                        matrix_buffer.push(format!("{} {} {}", i + 1, j + 1, count));
-                        tsv_buffer.push(format!("{}\t{}\t{}", feature, barcode, count));
-                    }
+        let region_map: HashMap<String, &Region> = regions.iter().map(|r| (r.key(), r)).collect();
+        for &feature in &feature_list {
+            let feature = features.name(feature);
+            if single_feature_column {
+                writeln!(features_file, "{}", feature)?;
+            } else {
+                // Features hold their ID (the key, set-prefixed with several regions files), their name and their type,
+                // the set of the feature standing for its type when there are sets
+                let region = region_map[feature];
+                let feature_type = if use_sets { region.set.as_deref().unwrap_or(".") } else { feature_type.as_str() };
+                writeln!(features_file, "{}\t{}\t{}", feature, region.feature_name(), feature_type)?;
+            }
+        }
+
+        let barcode_names: Vec<&str> = barcode_list.iter().map(|&barcode| cell_barcodes.name(barcode)).collect();
+        let field = if fractional || em { "real" } else { "integer" };
+        if let Some(matrix_file) = &mut matrix_file {
+            // Write the header lines, counting the entries from the counts before writing them
+            write_matrix_header(matrix_file, field, feature_list.len(), barcode_list.len(), region_matrix.entries(), transpose)?;
+
+            // Write sparse matrix data and TSV data as they are produced, sorted by feature then barcode (barcode
+            // indices follow the sorted barcodes) so that identical inputs give identical files
+            debug!("Writing matrix.mtx.gz and count_barcodes.tsv.gz");
+            if let Some(output_tsv) = &mut output_tsv {
+                writeln!(output_tsv, "Feature\tBarcode\tCount")?;
+            }
+            // (the entries of a transposed matrix are written once all are read, count_barcodes.tsv.gz keeping its order)
+            let mut transposed = Vec::new();
+            for entry in region_matrix.iter(features.names.len(), &barcode_columns)? {
+                let (feature, j, count) = entry?;
+                let i = feature_rows[feature as usize];
+                if transpose {
+                    transposed.push((j, i, count));
+                } else {
+                    writeln!(matrix_file, "{} {} {}", i + 1, j + 1, format_count(count))?;
                 }
+                if let Some(output_tsv) = &mut output_tsv {
+                    writeln!(output_tsv, "{}\t{}\t{}", features.name(feature), cell_barcodes.name(barcode_list[j]), format_count(count))?;
+                }
+            }
+            write_transposed_entries(matrix_file, transposed)?;
+        } else {
+            debug!("Writing matrix.tsv.gz");
+            let path = output_path("matrix.tsv.gz");
+            write_dense_matrix(&path, &feature_list, &features, &barcode_names, &barcode_columns, &region_matrix, compression_level)?;
+        }
+
+        // Write the RNA velocity matrices sharing barcodes.tsv.gz and features.tsv.gz
+        if velocity {
+            for (name, counts) in ["spliced", "unspliced", "ambiguous"].iter().zip(&velocity_matrices) {
+                debug!("Writing {}.mtx.gz", name);
+                let matrix_file = create_output(&output_path(&format!("{}.mtx.gz", name)), compression_level)?;
+                write_matrix_market(matrix_file, &feature_list, &feature_rows, &barcode_columns, counts, field, transpose)?;
             }
         }
 
-        // Write the accumulated lines to the compressed output files
-        for line in matrix_buffer {
-            writeln!(matrix_file, "{}", line)?;
+        // Write antisense_count_barcodes.tsv.gz
+        if emit_antisense {
+            debug!("Writing antisense_count_barcodes.tsv.gz");
+            let mut antisense_tsv = create_output(&output_path("antisense_count_barcodes.tsv.gz"), compression_level)?;
+            writeln!(antisense_tsv, "Feature\tBarcode\tCount")?;
+            for entry in matrix_counts(ANTISENSE_MATRIX, &antisense_counts).iter(features.names.len(), &barcode_columns)? {
+                let (feature, j, count) = entry?;
+                writeln!(antisense_tsv, "{}\t{}\t{}", features.name(feature), cell_barcodes.name(barcode_list[j]), format_count(count))?;
+            }
         }
-        for line in tsv_buffer {
-            writeln!(output_tsv, "{}", line)?;
+
+        // Write cell_qc.tsv.gz from the region counts, with a row for every barcode of barcodes.tsv.gz
+        if !cellranger_output {
+            debug!("Writing cell_qc.tsv.gz");
+            let mut mito_features = vec![false; features.names.len()];
+            for (region, &feature) in regions.iter().zip(&feature_ids) {
+                if is_mitochondrial(&region.chromosome) {
+                    mito_features[feature as usize] = true;
+                }
+            }
+            let mito_features = mito_features.contains(&true).then_some(mito_features);
+            let path = output_path("cell_qc.tsv.gz");
+            write_cell_qc(&path, &region_matrix, features.names.len(), &barcode_names, &barcode_columns, mito_features.as_deref(), compression_level)?;
         }
 
+    } else if featurecounts_output {
+        // Features list all their regions, and columns are named after the BAM files as featureCounts does
+        let mut feature_regions: Vec<(String, Vec<&Region>)> = features.names.iter().map(|key| (key.clone(), Vec::new())).collect();
+        for (region, &feature) in regions.iter().zip(&feature_ids) {
+            feature_regions[feature as usize].1.push(region);
+        }
+        let (columns, summary_columns) = if multi_sample {
+            let columns: Vec<(&str, &HashMap<u32, f64>)> = sample_totals.iter()
+                .map(|(sample, totals)| (bam_samples[*sample].1.as_str(), totals))
+                .collect();
+            let summary_columns: Vec<(&str, &assignments::ReadOutcomes)> = sample_read_outcomes.iter()
+                .map(|(sample, outcomes)| (bam_samples[*sample].1.as_str(), outcomes))
+                .collect();
+            (columns, summary_columns)
+        } else {
+            (vec![(bam_file, &region_totals)], vec![(bam_file, assignments.outcomes())])
+        };
+        // Reads outside every region are never fetched, and are taken from the index of their BAM file
+        let mut summary = Vec::new();
+        for (path, outcomes) in summary_columns {
+            let (mapped, unmapped) = open_indexed_bam(path)?.index_stats()
+                .map_err(|source| KaiError::BamOpen { path: path.to_string(), source })?
+                .iter()
+                .fold((0, 0), |(mapped, unmapped), &(_, _, contig_mapped, contig_unmapped)| (mapped + contig_mapped, unmapped + contig_unmapped));
+            summary.push((path, featurecounts::SummaryColumn::new(outcomes, mapped, unmapped)));
+        }
+        let args: Vec<String> = std::env::args().collect();
+        let path = output_path("featurecounts.txt");
+        debug!("Writing {}", path);
+        let mut output_file = create_output(&path, compression_level)?;
+        featurecounts::write_table(&mut output_file, command.get_version().unwrap_or_default(), &args, &feature_regions, &columns, format_count)?;
+        output_file.flush()?;
+        let path = output_path("featurecounts.txt.summary");
+        debug!("Writing {}", path);
+        let mut summary_file = create_output(&path, compression_level)?;
+        featurecounts::write_summary(&mut summary_file, &summary)?;
+        summary_file.flush()?;
     } else {
-        let mut output_file = GzEncoder::new(File::create(format!("{}/count.tsv.gz", output_dir))?, Compression::default());
-        debug!("Writing count.tsv.gz");
-        writeln!(output_file, "Chr\tStart\tEnd\tRegion\tCount")?;
-        for (region, count) in region_totals.iter().sorted() {
-            let fields: Vec<&str> = region.split([':', '-']).collect();
-            writeln!(output_file, "{}\t{}\t{}\t{}", fields[0], fields[1], fields[2], count)?;
+        let mut output_file = if stdout_output {
+            debug!("Writing the count table to stdout");
+            OutputFile::Stdout(BufWriter::new(std::io::stdout()))
+        } else {
+            debug!("Writing count.tsv.gz");
+            create_output(&output_path("count.tsv.gz"), compression_level)?
+        };
+        let region_map: HashMap<String, &Region> = regions.iter().map(|r| (r.key(), r)).collect();
+        if use_sets {
+            write!(output_file, "Set\t")?;
+        }
+        // Read groups follow the header, then those only found in reads
+        if by_read_group {
+            let mut extra_read_groups: Vec<String> = read_group_counts
+                .values()
+                .flat_map(|counts| counts.keys())
+                .filter(|read_group| !read_group_ids.contains(read_group) && read_group.as_str() != "unknown_rg")
+                .cloned()
+                .collect::<HashSet<_>>()
+                .into_iter()
+                .collect();
+            extra_read_groups.sort();
+            read_group_ids.extend(extra_read_groups);
+            read_group_ids.push("unknown_rg".to_string());
+        }
+        write!(output_file, "Chr\tStart\tEnd\tRegion")?;
+        for read_group in &read_group_ids {
+            write!(output_file, "\t{}", read_group)?;
+        }
+        // Several BAM files give one count column per sample that was counted
+        if multi_sample {
+            for (sample, _) in &sample_totals {
+                write!(output_file, "\t{}", bam_samples[*sample].0)?;
+            }
+        } else {
+            write!(output_file, "\tCount")?;
+        }
+        if emit_antisense {
+            write!(output_file, "\tAntisenseCount")?;
+        }
+        if split_gapped_column {
+            write!(output_file, "\tGappedCount")?;
+        }
+        if keep_peak_stats {
+            write!(output_file, "\tSignalValue\tQValue")?;
+        }
+        // Each count column is normalised with its own library size and rate sum, over the lengths of the regions
+        let feature_lengths: Vec<u64> = features.names.iter()
+            .map(|region_key| region_map[region_key].blocks.iter().map(|&(start, end)| (end - start) as u64).sum())
+            .collect();
+        let count_columns: Vec<(Option<usize>, &HashMap<u32, f64>)> = if multi_sample {
+            sample_totals.iter().map(|(sample, totals)| (Some(*sample), totals)).collect()
+        } else {
+            vec![(None, &region_totals)]
+        };
+        let scales: Vec<normalize::Scale> = count_columns.iter()
+            .map(|&(sample, totals)| {
+                let counts = feature_lengths.iter().enumerate()
+                    .map(|(feature, &length)| (totals.get(&(feature as u32)).copied().unwrap_or(0.0), length));
+                normalize::Scale::new(counts, library_sizes.as_ref().map(|sizes| sizes[sample.unwrap_or(0)]))
+            })
+            .collect();
+        for method in &normalize {
+            for (sample, _) in &count_columns {
+                match sample {
+                    Some(sample) => write!(output_file, "\t{}_{}", bam_samples[*sample].0, method.column_name())?,
+                    None => write!(output_file, "\t{}", method.column_name())?,
+                }
+            }
+        }
+        if depth_stats {
+            write!(output_file, "\tMeanDepth\tMedianDepth")?;
+        }
+        writeln!(output_file)?;
+        // Rows follow the order of the regions, with a count of 0 for regions without reads
+        for (feature, region_key) in features.names.iter().enumerate() {
+            let feature = feature as u32;
+            let count = region_totals.get(&feature).copied();
+            let antisense_count = antisense_totals.get(&feature).copied();
+            let gapped_count = gapped_totals.get(&feature).copied();
+            let region = region_map[region_key];
+            if use_sets {
+                write!(output_file, "{}\t", region.set.as_deref().unwrap_or("."))?;
+            }
+            write!(output_file, "{}\t{}\t{}\t{}", region.output_chromosome(), region.output_start(), region.end, region.feature_name())?;
+            for read_group in &read_group_ids {
+                let read_group_count = read_group_counts.get(&feature).and_then(|counts| counts.get(read_group)).copied();
+                write!(output_file, "\t{}", format_count(read_group_count.unwrap_or(0.0)))?;
+            }
+            if multi_sample {
+                for (_, totals) in &sample_totals {
+                    write!(output_file, "\t{}", format_count(totals.get(&feature).copied().unwrap_or(0.0)))?;
+                }
+            } else {
+                write!(output_file, "\t{}", format_count(count.unwrap_or(0.0)))?;
+            }
+            if emit_antisense {
+                write!(output_file, "\t{}", format_count(antisense_count.unwrap_or(0.0)))?;
+            }
+            if split_gapped_column {
+                write!(output_file, "\t{}", format_count(gapped_count.unwrap_or(0.0)))?;
+            }
+            if keep_peak_stats {
+                let (signal_value, q_value) = region.peak_stats.clone().unwrap_or_else(|| (".".to_string(), ".".to_string()));
+                write!(output_file, "\t{}\t{}", signal_value, q_value)?;
+            }
+            for &method in &normalize {
+                for ((_, totals), scale) in count_columns.iter().zip(&scales) {
+                    let count = totals.get(&feature).copied().unwrap_or(0.0);
+                    write!(output_file, "\t{}", normalize::format_value(scale.value(method, count, feature_lengths[feature as usize])))?;
+                }
+            }
+            // Regions never fetched (on chromosomes missing from the BAM file) have a depth of 0 over their bases
+            if depth_stats {
+                let unfetched = (feature_lengths[feature as usize] > 0).then_some(0.0);
+                let stats = depth_stats_totals.get_mut(&feature);
+                let (mean, median) = stats.map_or((unfetched, unfetched), |stats| (stats.mean(), stats.median()));
+                write!(output_file, "\t{}\t{}", normalize::format_value(mean), normalize::format_value(median))?;
+            }
+            writeln!(output_file)?;
+        }
+        // Report errors of stdout (e.g. a closed pipe) instead of losing them when the buffer is dropped
+        output_file.flush()?;
+    }
+    if coverage_out.is_some() {
+        debug!("Writing coverage.bedgraph.gz");
+        let mut coverage_file = create_output(&output_path("coverage.bedgraph.gz"), compression_level)?;
+        coverage.write_bedgraph(&mut coverage_file, &contigs, format_count)?;
+    }
+    if per_base_profiles {
+        debug!("Writing per_base_profiles.tsv.gz");
+        let path = output_path("per_base_profiles.tsv.gz");
+        write_rows(&path, "Chr\tStart\tEnd\tRegion\tDepth", &profile_rows, compress_outputs, compression_level)?;
+    }
+    if let Some(path) = unassigned_out {
+        debug!("Writing {}", path);
+        write_rows(path, "Read\tRegion\tReason", &unassigned_rows, path.ends_with(".gz"), compression_level)?;
+        output_paths.push(path.clone());
+    }
+    if let Some(path) = assignments_bam {
+        let written_reads = assignments.write_bam(path, assignments_header.as_ref().unwrap())?;
+        debug!("Wrote {} reads to {}", written_reads, path);
+        output_paths.push(path.clone());
+    }
+
+    let bytes_written: u64 = output_paths.iter().filter_map(|path| std::fs::metadata(path).ok()).map(|metadata| metadata.len()).sum();
+    timings.end_stage("Writing outputs", vec![format!("{} files", output_paths.len()), format!("{} bytes written", bytes_written)]);
+
+    // Report a summary of the run
+    info!("Summary:");
+    info!("  Regions counted: {}", regions.len());
+    if missing_contig_regions > 0 {
+        info!("  Regions dropped on missing contigs: {}", missing_contig_regions);
+    }
+    if splice_filter != SpliceFilter::All {
+        info!("  Reads skipped as not {}: {}", splice_filter, splice_filtered_reads);
+    }
+    if mode == Mode::Single {
+        info!("  Reads with a {} barcode: {}", barcode_tag, primary_tag_reads);
+        if let Some(fallback_tag) = barcode_fallback_tag {
+            info!("  Reads with a {} (fallback) barcode: {}", fallback_tag, fallback_tag_reads);
+        }
+        let barcode_reads = primary_tag_reads + fallback_tag_reads + no_barcode_reads;
+        let no_barcode_rate = if barcode_reads > 0 { 100.0 * no_barcode_reads as f64 / barcode_reads as f64 } else { 0.0 };
+        info!(
+            "  Reads without barcode: {} ({:.2}%{})",
+            no_barcode_reads, no_barcode_rate, if keep_untagged { ", counted as NO_BARCODE" } else { ", skipped" }
+        );
+        if barcode_corrector.is_some() {
+            info!("  Reads with a listed barcode: {}", exact_barcode_reads);
+            info!("  Reads with a corrected barcode: {}", corrected_barcode_reads);
+            info!("  Reads with an unassigned barcode: {}", unassigned_barcode_reads);
+        }
+    }
+    if let Some(umi_tags) = &umi_tags {
+        let umi_reads: u64 = umi_tag_reads.values().sum();
+        for tag in umi_tags {
+            let reads = umi_tag_reads.get(tag).copied().unwrap_or(0);
+            let fill_rate = if umi_reads + umi_missing_reads > 0 { 100.0 * reads as f64 / (umi_reads + umi_missing_reads) as f64 } else { 0.0 };
+            info!("  Reads with a {} UMI: {} ({:.2}%)", tag, reads, fill_rate);
         }
+        info!("  Reads collapsed as duplicate UMIs ({}): {}", umi_collapse, umi_duplicate_reads);
+        info!("  Reads without UMI{}: {}", if require_umi { " (skipped)" } else { " (counted as reads)" }, umi_missing_reads);
+    }
+    if min_insert.is_some() || max_insert.is_some() {
+        info!("  Reads skipped for their insert size: {}", insert_filtered_reads);
+    }
+    if min_read_length.is_some() || max_read_length.is_some() {
+        info!("  Reads skipped for their length: {}", length_filtered_reads);
+    }
+    if let Some(max_softclip_frac) = max_softclip_frac {
+        info!("  Reads skipped with a clipped fraction above {}: {}", max_softclip_frac, clipped_reads);
+    }
+    if contained {
+        info!("  Reads skipped as not contained in the region: {}", uncontained_reads);
+    }
+    if count_gapped_overlap {
+        info!("  Reads counted by a skipped span overlapping the region: {}", gapped_reads);
+    }
+    if let Some(min_block_baseq) = min_block_baseq {
+        info!("  Reads skipped with a mean base quality below {}: {}", min_block_baseq, low_baseq_reads);
+        if require_baseq {
+            info!("  Reads skipped without base qualities: {}", missing_baseq_reads);
+        }
+    }
+    if assign_unique && overlap_mode == OverlapMode::Union {
+        info!("  Reads skipped as overlapping several regions: {}", multi_region_reads);
+    }
+    if overlap_mode != OverlapMode::Union {
+        info!("  __ambiguous: {}", ambiguous_reads);
+        info!("  __no_feature: {}", no_feature_reads);
+    }
+    if multimap == Multimap::Skip {
+        info!("  Reads skipped as mapping to more than {} loci: {}", max_loci, multimap_skipped_reads);
+    } else if multimap == Multimap::Primary {
+        info!("  Non-primary alignments of multimapped reads skipped: {}", multimap_skipped_reads);
+    }
+    if fractional {
+        info!("  Multimapped reads counted fractionally: {}", fractional_reads);
+    }
+    if em {
+        info!("  Multimapped reads resolved by EM: {}", multimapped_reads);
+    }
+    if primary_only {
+        info!("  Secondary alignments skipped: {}", secondary_reads);
+        info!("  Supplementary alignments skipped: {}", supplementary_reads);
+    }
+    if proper_pairs_only {
+        info!("  Improperly paired reads skipped: {}", improper_pair_reads);
+    }
+    if read_filter != Mate::Both {
+        info!("  Reads skipped by the read {} filter: {}", read_filter, other_mate_reads);
+    }
+    let duplication_rate = if examined_reads > 0 { 100.0 * duplicate_reads as f64 / examined_reads as f64 } else { 0.0 };
+    info!(
+        "  Duplicate reads: {} of {} ({:.2}%){}",
+        duplicate_reads,
+        examined_reads,
+        duplication_rate,
+        if ignore_duplicates { ", skipped" } else { "" }
+    );
+    for (name, bit) in data_loader::SAM_FLAG_NAMES {
+        if let Some(count) = missing_flag_reads.get(&bit) {
+            info!("  Reads dropped without required flag {}: {}", name, count);
+        }
+        if let Some(count) = excluded_flag_reads.get(&bit) {
+            info!("  Reads dropped with excluded flag {}: {}", name, count);
+        }
+    }
+    if min_mapq > 0 {
+        info!("  Reads dropped for MAPQ below {}: {}", min_mapq, low_mapq_reads);
+    }
+    info!(
+        "  Reads without NH tag: {}{}",
+        missing_nh_reads,
+        match missing_nh { MissingNh::Drop => " (skipped)", MissingNh::UseFlags => " (multimapped if secondary)", MissingNh::Pass => " (counted as unique)" }
+    );
+    if blacklist_index.is_some() {
+        info!("  Reads ignored on blacklisted regions: {}", blacklisted_reads.len());
+    }
+    if excluded_regions > 0 {
+        info!("  Regions dropped on unselected contigs: {}", excluded_regions);
+    }
+
+    if multi_sample {
+        info!("Samples:");
+        for (sample, (name, _)) in bam_samples.iter().enumerate() {
+            match sample_filtered_reads.iter().find(|(counted, _)| *counted == sample) {
+                Some((_, reads)) => info!("  {}: {} reads counted", name, reads),
+                None => info!("  {}: failed", name),
+            }
+        }
+    }
+
+    if report_timings {
+        timings.report();
     }
 
+    // Write summary.json, whose field names are kept stable for aggregation tools (see the README)
+    if !stdout_output {
+        let count = |value: u64| summary::Json::Integer(value);
+        let flag_counts = |flag_reads: &HashMap<u16, usize>| summary::Json::object(
+            data_loader::SAM_FLAG_NAMES.iter()
+                .filter_map(|&(name, bit)| flag_reads.get(&bit).map(|&reads| (name, summary::Json::from(reads))))
+                .collect(),
+        );
+        // Every option under its name with underscores, positional arguments being listed with the inputs
+        let parameters: Vec<(&str, summary::Json)> = command.get_arguments()
+            .filter(|arg| !arg.is_positional())
+            .map(|arg| {
+                let id = arg.get_id().as_str();
+                let raw_values = || matches.get_raw(id).into_iter().flatten().map(|value| summary::Json::from(value.to_string_lossy().into_owned()));
+                let value = match arg.get_action() {
+                    clap::ArgAction::SetTrue => summary::Json::Bool(matches.get_flag(id)),
+                    clap::ArgAction::Append => summary::Json::Array(raw_values().collect()),
+                    _ => raw_values().next().unwrap_or(summary::Json::Null),
+                };
+                (id, value)
+            })
+            .collect();
+        let samples: Vec<summary::Json> = bam_samples.iter().enumerate().map(|(sample, (name, path))| {
+            let reads = sample_filtered_reads.iter().find(|(counted, _)| *counted == sample).map(|&(_, reads)| reads);
+            summary::Json::object(vec![
+                ("name", name.as_str().into()),
+                ("path", path.as_str().into()),
+                ("status", if reads.is_some() || !multi_sample { "counted" } else { "failed" }.into()),
+                ("assigned_reads", if multi_sample { reads.into() } else { count(filtered_reads) }),
+            ])
+        }).collect();
+        let summary = summary::Json::object(vec![
+            ("kai_version", command.get_version().unwrap_or_default().into()),
+            ("mode", mode.as_str().into()),
+            ("inputs", summary::Json::object(vec![
+                ("bam_files", summary::Json::Array(samples)),
+                ("regions_file", regions_file.map(|path| path.as_str()).into()),
+                ("cell_barcodes_file", cell_barcode_file.map(|path| path.as_str()).into()),
+                ("output_dir", output_dir.as_str().into()),
+            ])),
+            ("parameters", summary::Json::object(parameters)),
+            ("regions", summary::Json::object(vec![
+                ("counted", regions.len().into()),
+                ("dropped_on_missing_contigs", missing_contig_regions.into()),
+                ("dropped_on_unselected_contigs", excluded_regions.into()),
+            ])),
+            ("barcodes", if mode == Mode::Single { barcode_list.len().into() } else { summary::Json::Null }),
+            ("reads", summary::Json::object(vec![
+                ("fetched", count(fetched_reads)),
+                ("examined", count(examined_reads)),
+                ("assigned", count(filtered_reads)),
+                ("duplicates", count(duplicate_reads)),
+                ("missing_nh", count(missing_nh_reads)),
+                ("multimapped_fractional", count(fractional_reads)),
+                ("multimapped_em", multimapped_reads.into()),
+                ("gapped_overlap", count(gapped_reads)),
+            ])),
+            ("skipped_reads", summary::Json::object(vec![
+                ("missing_required_flag", flag_counts(&missing_flag_reads)),
+                ("excluded_flag", flag_counts(&excluded_flag_reads)),
+                ("secondary", count(secondary_reads)),
+                ("supplementary", count(supplementary_reads)),
+                ("improper_pair", count(improper_pair_reads)),
+                ("other_mate", count(other_mate_reads)),
+                ("duplicate", count(if ignore_duplicates { duplicate_reads } else { 0 })),
+                ("low_mapq", count(low_mapq_reads)),
+                ("missing_nh", count(if missing_nh == MissingNh::Drop { missing_nh_reads } else { 0 })),
+                ("multimapped", count(multimap_skipped_reads)),
+                ("splicing", count(splice_filtered_reads)),
+                ("insert_size", count(insert_filtered_reads)),
+                ("read_length", count(length_filtered_reads)),
+                ("clipped", count(clipped_reads)),
+                ("blacklisted", blacklisted_reads.len().into()),
+                ("no_barcode", count(if keep_untagged { 0 } else { no_barcode_reads })),
+                ("unlisted_barcode", count(unassigned_barcode_reads)),
+                ("wrong_strand", count(wrong_strand_reads)),
+                ("multiple_regions", count(multi_region_reads)),
+                ("ambiguous", count(ambiguous_reads)),
+                ("no_feature", count(no_feature_reads)),
+                ("low_overlap", count(low_overlap_reads)),
+                ("not_contained", count(uncontained_reads)),
+                ("low_baseq", count(low_baseq_reads)),
+                ("missing_baseq", count(missing_baseq_reads)),
+                ("duplicate_umi", count(umi_duplicate_reads)),
+                ("missing_umi", count(if require_umi { umi_missing_reads } else { 0 })),
+            ])),
+            ("unassigned_reads", summary::Json::object(skipped.iter().map(|(reason, total)| (reason.as_str(), count(total))).collect())),
+            ("barcode_reads", summary::Json::object(vec![
+                ("primary_tag", count(primary_tag_reads)),
+                ("fallback_tag", count(fallback_tag_reads)),
+                ("no_barcode", count(no_barcode_reads)),
+                ("listed", count(exact_barcode_reads)),
+                ("corrected", count(corrected_barcode_reads)),
+                ("unlisted", count(unassigned_barcode_reads)),
+            ])),
+            ("timings", summary::Json::object(vec![
+                ("total_seconds", timings.total().as_secs_f64().into()),
+                ("stages", summary::Json::Array(timings.stages().map(|(name, elapsed)| summary::Json::object(vec![
+                    ("name", name.into()),
+                    ("seconds", elapsed.as_secs_f64().into()),
+                ])).collect())),
+            ])),
+        ]);
+        let path = format!("{}/summary.json", output_dir);
+        std::fs::write(&path, summary.render()).map_err(|source| KaiError::Output { path: path.clone(), source })?;
+        debug!("Wrote {}", path);
+    }
+    if !failed_samples.is_empty() {
+        return Err(format!(
+            "{} of {} samples failed ({}); the outputs hold the other samples",
+            failed_samples.len(),
+            bam_samples.len(),
+            failed_samples.iter().map(|&sample| &bam_samples[sample].0).join(", ")
+        ).into());
+    }
     info!("Finished processing");
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_htslib::bam::{self, HeaderView};
+
+    // Function to build a read on chr1 from its 0-based position and CIGAR
+    fn read(pos: i64, cigar: &str) -> Record {
+        let header = HeaderView::from_bytes(b"@SQ\tSN:chr1\tLN:100000\n");
+        let length: u32 = bam::record::CigarString::try_from(cigar).unwrap().iter()
+            .filter(|op| matches!(op, Cigar::Match(_) | Cigar::Ins(_) | Cigar::SoftClip(_) | Cigar::Equal(_) | Cigar::Diff(_)))
+            .map(|op| op.len())
+            .sum();
+        let line = format!("read\t0\tchr1\t{}\t60\t{}\t*\t0\t0\t{}\t*", pos + 1, cigar, "A".repeat(length as usize));
+        Record::from_sam(&header, line.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn aligned_blocks_skip_introns_and_deletions() {
+        assert_eq!(aligned_blocks(&read(100, "30M1000N30M")), vec![(100, 130), (1130, 1160)]);
+        assert_eq!(aligned_blocks(&read(100, "5S20M2I10M3D10M5H")), vec![(100, 120), (120, 130), (133, 143)]);
+    }
+
+    #[test]
+    fn spliced_read_overlaps_regions_at_its_second_exon() {
+        let blocks = aligned_blocks(&read(100, "30M1000N30M"));
+        // Region starting at the first base of the second aligned block, and just after its last base
+        assert_eq!(regions::overlap_bases(&blocks, &[(1130, 1200)]), 30);
+        assert_eq!(regions::overlap_bases(&blocks, &[(1159, 1200)]), 1);
+        assert_eq!(regions::overlap_bases(&blocks, &[(1160, 1200)]), 0);
+        // Region inside the intron, and one reaching a base into each aligned block
+        assert_eq!(regions::overlap_bases(&blocks, &[(130, 1130)]), 0);
+        assert_eq!(regions::overlap_bases(&blocks, &[(129, 1131)]), 2);
+        // Region ending just before the read, and at its first base
+        assert_eq!(regions::overlap_bases(&blocks, &[(50, 100)]), 0);
+        assert_eq!(regions::overlap_bases(&blocks, &[(50, 101)]), 1);
+    }
+
+    #[test]
+    fn nh_tag_is_read_in_every_integer_encoding() {
+        let values = [Aux::U8(3), Aux::I8(3), Aux::U16(3), Aux::I16(3), Aux::U32(3), Aux::I32(3)];
+        for value in values {
+            let mut record = read(100, "30M");
+            record.push_aux(b"NH", value).unwrap();
+            assert_eq!(integer_tag(&record, b"NH"), Some(3));
+        }
+        // Values too wide for a byte are stored in wider encodings when read from SAM text
+        let header = HeaderView::from_bytes(b"@SQ\tSN:chr1\tLN:100000\n");
+        for nh in [1, 300, 70000] {
+            let line = format!("read\t0\tchr1\t101\t60\t4M\t*\t0\t0\tACGT\t*\tNH:i:{}", nh);
+            assert_eq!(integer_tag(&Record::from_sam(&header, line.as_bytes()).unwrap(), b"NH"), Some(nh));
+        }
+        // A non-integer NH tag and a missing one are both ignored
+        let mut record = read(100, "30M");
+        assert_eq!(integer_tag(&record, b"NH"), None);
+        record.push_aux(b"NH", Aux::String("2")).unwrap();
+        assert_eq!(integer_tag(&record, b"NH"), None);
+    }
+
+    #[test]
+    fn overlap_mean_baseq_skips_soft_clips_and_insertions() {
+        // Each query base of 5S20M2I30M has its offset as quality: the blocks 100-120 and 120-150 read bases 5-24 and 27-56
+        let header = HeaderView::from_bytes(b"@SQ\tSN:chr1\tLN:100000\n");
+        let qualities: String = (0..57u8).map(|offset| (offset + 33) as char).collect();
+        let line = format!("read\t0\tchr1\t101\t60\t5S20M2I30M\t*\t0\t0\t{}\t{}", "A".repeat(57), qualities);
+        let record = Record::from_sam(&header, line.as_bytes()).unwrap();
+        assert_eq!(overlap_mean_baseq(&record, &[(100, 120)]), Some(14.5));
+        assert_eq!(overlap_mean_baseq(&record, &[(120, 150)]), Some(41.5));
+        // Region across the insertion, reading bases 20-24 and 27-31
+        assert_eq!(overlap_mean_baseq(&record, &[(115, 125)]), Some(25.5));
+        assert_eq!(overlap_mean_baseq(&record, &[(200, 300)]), None);
+        // Records without qualities have no mean
+        assert_eq!(overlap_mean_baseq(&read(100, "5S20M2I30M"), &[(100, 120)]), None);
+    }
+
+    // Function to write a matrix of two features and three barcodes to a temporary file, returning its lines
+    fn matrix_lines(transpose: bool) -> Vec<String> {
+        let counts: Counts = HashMap::from([(0, HashMap::from([(2, 5.0), (0, 1.0)])), (1, HashMap::from([(1, 2.0)]))]);
+        // Barcodes 0, 1 and 2 are the third, first and second lines of barcodes.tsv.gz
+        let barcode_columns = [2, 0, 1];
+        let path = std::env::temp_dir().join(format!("kai_test_{}_{}.mtx", std::process::id(), transpose));
+        let path = path.to_string_lossy().into_owned();
+        let matrix_file = create_output(&path, Compression::default()).unwrap();
+        write_matrix_market(matrix_file, &[0, 1], &[0, 1], &barcode_columns, &MatrixCounts::Memory(&counts), "integer", transpose).unwrap();
+        let lines = std::fs::read_to_string(&path).unwrap().lines().map(str::to_string).collect();
+        std::fs::remove_file(&path).unwrap();
+        lines
+    }
+
+    #[test]
+    fn matrix_market_columns_are_barcode_line_numbers() {
+        assert_eq!(matrix_lines(false), vec!["%%MatrixMarket matrix coordinate integer general", "%", "2 3 3", "1 2 5", "1 3 1", "2 1 2"]);
+        assert_eq!(&matrix_lines(true)[2..], ["3 2 3", "1 2 2", "2 1 5", "3 1 1"]);
+    }
+
+    // Two-exon region: exon 1000-1100, intron 1100-2000, exon 2000-2100
+    fn two_exon_region() -> Region {
+        let mut region = Region::new("chr1".to_string(), 1000, 2100, Some("gene".to_string()), '+');
+        region.blocks = vec![(1000, 1100), (2000, 2100)];
+        region
+    }
+
+    fn classify(pos: i64, cigar: &str) -> usize {
+        let record = read(pos, cigar);
+        velocity_class(&record, &aligned_blocks(&record), &two_exon_region())
+    }
+
+    #[test]
+    fn velocity_read_joining_exons_is_spliced() {
+        assert_eq!(classify(1050, "50M900N50M"), 0);
+    }
+
+    #[test]
+    fn velocity_read_inside_an_exon_is_spliced() {
+        assert_eq!(classify(1020, "60M"), 0);
+    }
+
+    #[test]
+    fn velocity_unspliced_read_over_exon_intron_junction_is_unspliced() {
+        assert_eq!(classify(1050, "100M"), 1);
+        assert_eq!(classify(1950, "100M"), 1);
+    }
+
+    #[test]
+    fn velocity_read_inside_intron_is_unspliced() {
+        assert_eq!(classify(1500, "100M"), 1);
+    }
+
+    #[test]
+    fn velocity_spliced_read_ending_in_intron_is_ambiguous() {
+        assert_eq!(classify(1050, "50M100N50M"), 2);
+    }
+
+    #[test]
+    fn velocity_read_extending_beyond_region_is_ambiguous() {
+        assert_eq!(classify(950, "100M"), 2);
+        assert_eq!(classify(2050, "100M"), 2);
+    }
+}
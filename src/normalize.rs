@@ -0,0 +1,89 @@
+// Modules for the normalised count columns of the bulk table (--normalize)
+
+// Normalisation of a count column: counts per million counted reads, reads per kilobase of region per million
+// counted reads, or transcripts per million (per-kilobase rates scaled to sum to a million over the regions)
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Method {
+    Cpm,
+    Rpkm,
+    Tpm,
+}
+
+impl clap::ValueEnum for Method {
+    fn value_variants<'a>() -> &'a [Self] {
+        &[Method::Cpm, Method::Rpkm, Method::Tpm]
+    }
+
+    fn to_possible_value(&self) -> Option<clap::builder::PossibleValue> {
+        Some(clap::builder::PossibleValue::new(match self {
+            Method::Cpm => "cpm",
+            Method::Rpkm => "rpkm",
+            Method::Tpm => "tpm",
+        }))
+    }
+}
+
+impl Method {
+    pub fn column_name(self) -> &'static str {
+        match self {
+            Method::Cpm => "CPM",
+            Method::Rpkm => "RPKM",
+            Method::Tpm => "TPM",
+        }
+    }
+}
+
+// Library size and sum of the per-kilobase rates of a count column, from the counts and lengths of its regions
+pub struct Scale {
+    library_size: f64,
+    rate_sum: f64,
+}
+
+impl Scale {
+    // The library size is the sum of the counts unless given; regions without bases have no rate
+    pub fn new(counts: impl Iterator<Item = (f64, u64)>, library_size: Option<u64>) -> Self {
+        let (mut count_sum, mut rate_sum) = (0.0, 0.0);
+        for (count, length) in counts {
+            count_sum += count;
+            if length > 0 {
+                rate_sum += count / (length as f64 / 1000.0);
+            }
+        }
+        Scale { library_size: library_size.map_or(count_sum, |size| size as f64), rate_sum }
+    }
+
+    // Function to normalise the count of a region, None when it is undefined (a region without bases, or no
+    // counted reads at all)
+    pub fn value(&self, method: Method, count: f64, length: u64) -> Option<f64> {
+        let length_kb = length as f64 / 1000.0;
+        match method {
+            Method::Cpm if self.library_size > 0.0 => Some(count / self.library_size * 1e6),
+            Method::Rpkm if self.library_size > 0.0 && length > 0 => Some(count / length_kb / self.library_size * 1e6),
+            Method::Tpm if self.rate_sum > 0.0 && length > 0 => Some(count / length_kb / self.rate_sum * 1e6),
+            _ => None,
+        }
+    }
+}
+
+// Function to write a normalised value with four decimals, or NA when undefined
+pub fn format_value(value: Option<f64>) -> String {
+    value.map_or_else(|| "NA".to_string(), |value| format!("{:.4}", value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn regions_without_bases_have_no_rate() {
+        // Counts 3 and 1 over 100 and 1000 bases, and a read in a region without bases counted in the library alone
+        let scale = Scale::new([(3.0, 100), (1.0, 1000), (1.0, 0)].into_iter(), None);
+        assert_eq!(format_value(scale.value(Method::Cpm, 1.0, 0)), "200000.0000");
+        assert_eq!(format_value(scale.value(Method::Rpkm, 1.0, 0)), "NA");
+        assert_eq!(format_value(scale.value(Method::Tpm, 1.0, 0)), "NA");
+        assert_eq!(format_value(scale.value(Method::Tpm, 3.0, 100)), "967741.9355");
+        // Nothing is defined without counted reads
+        let empty = Scale::new([(0.0, 100)].into_iter(), None);
+        assert_eq!(format_value(empty.value(Method::Cpm, 0.0, 100)), "NA");
+    }
+}
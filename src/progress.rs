@@ -0,0 +1,133 @@
+// Modules for reporting the progress of counting
+use crate::cli::ProgressReport;
+use log::info;
+use std::io::{IsTerminal, Write};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[derive(PartialEq)]
+enum Style {
+    Bar,
+    Log,
+    Silent,
+}
+
+// Progress over the regions shared by the counting threads, drawn as a bar with elapsed time, ETA and reads/s on
+// an interactive stderr, logged at each 1% of the regions (or per chromosome by the sweep engine) otherwise
+pub struct Progress {
+    style: Style,
+    total: usize,
+    sweep: bool,
+    start: Instant,
+    regions: AtomicUsize,
+    reads: AtomicU64,
+    last_percentage: AtomicUsize,
+    last_draw: Mutex<Option<Instant>>,
+}
+
+impl Progress {
+    // Function to choose the report from --progress, falling back to log lines when stderr is not a terminal
+    pub fn new(progress: ProgressReport, total: usize, sweep: bool) -> Self {
+        let style = match progress {
+            ProgressReport::Bar if std::io::stderr().is_terminal() => Style::Bar,
+            ProgressReport::None => Style::Silent,
+            _ => Style::Log,
+        };
+        Progress {
+            style,
+            total,
+            sweep,
+            start: Instant::now(),
+            regions: AtomicUsize::new(0),
+            reads: AtomicU64::new(0),
+            last_percentage: AtomicUsize::new(0),
+            last_draw: Mutex::new(None),
+        }
+    }
+
+    // Function to record the start of a region, returning the number of regions started so far
+    pub fn start_region(&self) -> usize {
+        let region_counter = self.regions.fetch_add(1, Ordering::Relaxed) + 1;
+        let progress_percentage = (region_counter * 100) / self.total;
+        match self.style {
+            Style::Log if !self.sweep && progress_percentage > self.last_percentage.fetch_max(progress_percentage, Ordering::Relaxed) => {
+                info!("Progress: {}% / ({} / {})", progress_percentage, region_counter, self.total);
+            }
+            Style::Bar => self.draw(region_counter, false),
+            _ => {}
+        }
+        region_counter
+    }
+
+    // Function to get the number of regions started so far
+    pub fn regions_started(&self) -> usize {
+        self.regions.load(Ordering::Relaxed)
+    }
+
+    // Function to add the reads read for a region to the reads/s rate of the bar
+    pub fn add_reads(&self, reads: u64) {
+        self.reads.fetch_add(reads, Ordering::Relaxed);
+    }
+
+    // Function to log the end of a chromosome read by the sweep engine
+    pub fn chromosome_done(&self, chromosome: &str, reads: u64, region_counter: usize) {
+        if self.style == Style::Log {
+            info!("Progress: {} reads read, {} / {} regions counted ({} done)", reads, region_counter, self.total, chromosome);
+        }
+    }
+
+    // Function to draw the bar a last time once every region is counted
+    pub fn finish(&self) {
+        if self.style == Style::Bar {
+            self.draw(self.regions.load(Ordering::Relaxed), true);
+        }
+    }
+
+    // Function to redraw the bar on stderr, at most every 100 ms unless it is the last time
+    fn draw(&self, region_counter: usize, last: bool) {
+        let Ok(mut last_draw) = self.last_draw.try_lock() else { return };
+        let now = Instant::now();
+        if !last && last_draw.is_some_and(|time| now - time < Duration::from_millis(100)) {
+            return;
+        }
+        *last_draw = Some(now);
+
+        let fraction = region_counter as f64 / self.total as f64;
+        let elapsed = now - self.start;
+        let filled = (fraction * 30.0) as usize;
+        let eta = if region_counter > 0 {
+            format_duration(elapsed.mul_f64((1.0 - fraction) / fraction))
+        } else {
+            "-".to_string()
+        };
+        let reads_per_second = self.reads.load(Ordering::Relaxed) as f64 / elapsed.as_secs_f64().max(1e-3);
+        let mut stderr = std::io::stderr().lock();
+        let _ = write!(
+            stderr,
+            "\r[{}{}] {:>3}% {}/{} regions, {} elapsed, ETA {}, {:.0} reads/s\x1b[K",
+            "#".repeat(filled),
+            "-".repeat(30 - filled),
+            (fraction * 100.0) as usize,
+            region_counter,
+            self.total,
+            format_duration(elapsed),
+            eta,
+            reads_per_second,
+        );
+        if last {
+            let _ = writeln!(stderr);
+        }
+        let _ = stderr.flush();
+    }
+}
+
+// Function to format a duration as [h:]mm:ss
+fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    if seconds >= 3600 {
+        format!("{}:{:02}:{:02}", seconds / 3600, seconds / 60 % 60, seconds % 60)
+    } else {
+        format!("{:02}:{:02}", seconds / 60, seconds % 60)
+    }
+}
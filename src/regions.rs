@@ -0,0 +1,538 @@
+// Modules for operations on parsed regions of interest
+use std::collections::{BTreeMap, HashMap, HashSet};
+use log::{debug, info, warn};
+use rust_htslib::bam::HeaderView;
+use itertools::Itertools;
+
+use crate::cli::ChrPrefix;
+use crate::data_loader::{self, Region};
+
+// Function to get the contig names and lengths from the BAM header, in header order
+pub fn contig_lengths(header: &HeaderView) -> Vec<(String, u64)> {
+    (0..header.target_count())
+        .map(|tid| {
+            let name = String::from_utf8_lossy(header.tid2name(tid)).into_owned();
+            (name, header.target_len(tid).unwrap_or(0))
+        })
+        .collect()
+}
+
+// Function to list the read groups of the BAM header as their ID and sample (SM) name
+pub fn read_groups(header: &HeaderView) -> Vec<(String, Option<String>)> {
+    String::from_utf8_lossy(header.as_bytes())
+        .lines()
+        .filter(|line| line.starts_with("@RG\t"))
+        .filter_map(|line| {
+            let field = |tag: &str| line.split('\t').find_map(|field| field.strip_prefix(tag)).map(|value| value.to_string());
+            Some((field("ID:")?, field("SM:")))
+        })
+        .collect()
+}
+
+// Function to read the sort order (SO) of the @HD header line
+pub fn sort_order(header: &HeaderView) -> Option<String> {
+    String::from_utf8_lossy(header.as_bytes())
+        .lines()
+        .find(|line| line.starts_with("@HD\t"))?
+        .split('\t')
+        .find_map(|field| field.strip_prefix("SO:"))
+        .map(|value| value.to_string())
+}
+
+// Function to generate fixed-size bins over every contig, truncating the last bin of each contig
+pub fn make_bins(contigs: &[(String, u64)], bin_size: usize, one_based: bool) -> Result<Vec<Region>, Box<dyn std::error::Error>> {
+    if bin_size == 0 {
+        return Err("--bin-size must be greater than 0".into());
+    }
+    let mut bins = Vec::new();
+    for (contig, length) in contigs {
+        let length = *length as usize;
+        for start in (0..length).step_by(bin_size) {
+            let mut bin = Region::new(contig.clone(), start, (start + bin_size).min(length), None, '.');
+            bin.one_based = one_based;
+            bins.push(bin);
+        }
+    }
+    info!("Generated {} bins of {} bases", bins.len(), bin_size);
+    Ok(bins)
+}
+
+// Function to clip regions to the contig lengths of the BAM header
+pub fn clip_to_contigs(regions: &mut [Region], contigs: &[(String, u64)]) {
+    let lengths: HashMap<&str, usize> = contigs.iter().map(|(name, len)| (name.as_str(), *len as usize)).collect();
+    for region in regions.iter_mut() {
+        let Some(&length) = lengths.get(region.chromosome.as_str()) else { continue };
+        region.end = region.end.min(length);
+        region.start = region.start.min(region.end);
+        for block in region.blocks.iter_mut() {
+            block.1 = block.1.min(length);
+            block.0 = block.0.min(block.1);
+        }
+    }
+}
+
+// Function to pad regions by a number of bases upstream and downstream of their strand (unstranded regions are
+// treated as '+'), extending the outer blocks and clipping at 0 and at the contig lengths of the BAM header
+pub fn extend_regions(regions: &mut [Region], upstream: usize, downstream: usize, contigs: &[(String, u64)]) {
+    for region in regions.iter_mut() {
+        let (before, after) = if region.strand == '-' { (downstream, upstream) } else { (upstream, downstream) };
+        region.start = region.start.saturating_sub(before);
+        region.end += after;
+        if let Some(first) = region.blocks.first_mut() {
+            first.0 = first.0.saturating_sub(before);
+        }
+        if let Some(last) = region.blocks.last_mut() {
+            last.1 += after;
+        }
+    }
+    clip_to_contigs(regions, contigs);
+    info!("Extended regions by {} bases upstream and {} bases downstream", upstream, downstream);
+}
+
+// Sorted, non-overlapping intervals per contig for fast overlap lookups of reads
+pub struct IntervalIndex {
+    intervals: HashMap<String, Vec<(usize, usize)>>,
+}
+
+impl IntervalIndex {
+    // Function to build the index from the blocks of the regions, merging overlapping intervals
+    pub fn new(regions: &[Region]) -> Self {
+        let mut intervals: HashMap<String, Vec<(usize, usize)>> = HashMap::new();
+        for region in regions {
+            intervals.entry(region.chromosome.clone()).or_default().extend(region.blocks.iter().copied());
+        }
+        for contig_intervals in intervals.values_mut() {
+            *contig_intervals = data_loader::merge_intervals(std::mem::take(contig_intervals));
+        }
+        IntervalIndex { intervals }
+    }
+
+    // Function to check whether [start, end) overlaps any interval of the contig, by binary search
+    pub fn overlaps(&self, chromosome: &str, start: i64, end: i64) -> bool {
+        let Some(intervals) = self.intervals.get(chromosome) else { return false };
+        let i = intervals.partition_point(|&(_, interval_end)| interval_end as i64 <= start);
+        i < intervals.len() && (intervals[i].0 as i64) < end
+    }
+}
+
+// Regions of each contig sorted by start, for finding all the regions a read overlaps
+pub struct RegionIndex {
+    by_contig: HashMap<String, Vec<(usize, usize, usize)>>,
+    max_length: HashMap<String, usize>,
+}
+
+impl RegionIndex {
+    // Function to build the index from the spans of the regions, keeping their positions in the slice
+    pub fn new(regions: &[Region]) -> Self {
+        let mut by_contig: HashMap<String, Vec<(usize, usize, usize)>> = HashMap::new();
+        let mut max_length: HashMap<String, usize> = HashMap::new();
+        for (index, region) in regions.iter().enumerate() {
+            by_contig.entry(region.chromosome.clone()).or_default().push((region.start, region.end, index));
+            let length = max_length.entry(region.chromosome.clone()).or_insert(0);
+            *length = (*length).max(region.end - region.start);
+        }
+        for spans in by_contig.values_mut() {
+            spans.sort_unstable();
+        }
+        RegionIndex { by_contig, max_length }
+    }
+
+    // Function to get the positions of the regions whose span overlaps [start, end)
+    pub fn overlapping(&self, chromosome: &str, start: i64, end: i64) -> Vec<usize> {
+        let Some(spans) = self.by_contig.get(chromosome) else { return Vec::new() };
+        // Regions starting before start - max_length cannot reach start
+        let lowest_start = (start - self.max_length[chromosome] as i64).max(0) as usize;
+        let first = spans.partition_point(|&(span_start, _, _)| span_start < lowest_start);
+        spans[first..].iter()
+            .take_while(|&&(span_start, _, _)| (span_start as i64) < end)
+            .filter(|&&(_, span_end, _)| span_end as i64 > start)
+            .map(|&(_, _, index)| index)
+            .collect()
+    }
+}
+
+// Outcome of assigning a read to one of the regions it overlaps
+pub enum OverlapAssignment {
+    Feature(usize),
+    Ambiguous,
+    NoFeature,
+}
+
+// Function to count the bases of the read blocks falling in the region blocks
+pub fn overlap_bases(read_blocks: &[(i64, i64)], blocks: &[(usize, usize)]) -> i64 {
+    let mut overlap = 0;
+    for &(read_start, read_end) in read_blocks {
+        for &(start, end) in blocks {
+            overlap += (read_end.min(end as i64) - read_start.max(start as i64)).max(0);
+        }
+    }
+    overlap
+}
+
+// Function to assign a read to a single region following the htseq-count intersection modes: the read goes to the
+// region covering all of its aligned bases (strict) or all of its bases covered by any candidate (nonempty)
+pub fn assign_read(read_blocks: &[(i64, i64)], candidates: &[(usize, &Region)], strict: bool) -> OverlapAssignment {
+    let candidates: Vec<(usize, &Region, i64)> = candidates.iter()
+        .map(|&(index, region)| (index, region, overlap_bases(read_blocks, &region.blocks)))
+        .filter(|&(_, _, overlap)| overlap > 0)
+        .collect();
+    if candidates.is_empty() {
+        return OverlapAssignment::NoFeature;
+    }
+    let required_bases = if strict {
+        read_blocks.iter().map(|&(start, end)| end - start).sum()
+    } else {
+        let covered = data_loader::merge_intervals(candidates.iter().flat_map(|(_, region, _)| region.blocks.iter().copied()).collect());
+        overlap_bases(read_blocks, &covered)
+    };
+    let mut assigned: Vec<(usize, String)> = candidates.iter()
+        .filter(|&&(_, _, overlap)| overlap == required_bases)
+        .map(|&(index, region, _)| (index, region.key()))
+        .collect();
+    assigned.dedup_by(|a, b| a.1 == b.1);
+    match assigned.as_slice() {
+        [] => OverlapAssignment::NoFeature,
+        [(index, _)] => OverlapAssignment::Feature(*index),
+        _ => OverlapAssignment::Ambiguous,
+    }
+}
+
+// Function to match a contig name against a pattern where '*' matches any run of characters and '?' any single one
+fn matches_pattern(name: &str, pattern: &str) -> bool {
+    let name: Vec<char> = name.chars().collect();
+    let pattern: Vec<char> = pattern.chars().collect();
+    let (mut n, mut p) = (0, 0);
+    // Position of the last '*' in the pattern and of the name when it was reached, for backtracking
+    let mut star: Option<(usize, usize)> = None;
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == name[n]) {
+            n += 1;
+            p += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, n));
+            p += 1;
+        } else if let Some((star_p, star_n)) = star {
+            p = star_p + 1;
+            n = star_n + 1;
+            star = Some((star_p, star_n + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+// Function to check whether a region is on a contig matching any of the patterns, checked against both the BAM and
+// the input contig names
+fn on_matching_contig(region: &Region, patterns: &[String]) -> bool {
+    patterns.iter().any(|pattern| {
+        matches_pattern(&region.chromosome, pattern) || matches_pattern(region.output_chromosome(), pattern)
+    })
+}
+
+// Function to keep only regions on contigs matching any of the inclusion patterns, warning about patterns that match
+// no contig of the BAM header; returns the number of regions removed
+pub fn include_chromosomes(regions: &mut Vec<Region>, patterns: &[String], contigs: &[(String, u64)]) -> usize {
+    for pattern in patterns {
+        if !contigs.iter().any(|(name, _)| matches_pattern(name, pattern)) {
+            warn!("Included contig {} matches no contig of the BAM header", pattern);
+        }
+    }
+    let n_input = regions.len();
+    regions.retain(|region| on_matching_contig(region, patterns));
+    let removed = n_input - regions.len();
+    info!("Kept {} regions on contigs matching {} ({} removed)", regions.len(), patterns.join(","), removed);
+    removed
+}
+
+// Function to drop regions on contigs matching any of the exclusion patterns; returns the number of regions removed
+pub fn exclude_chromosomes(regions: &mut Vec<Region>, patterns: &[String]) -> usize {
+    let n_input = regions.len();
+    regions.retain(|region| !on_matching_contig(region, patterns));
+    let removed = n_input - regions.len();
+    info!("Excluded {} regions on contigs matching {}", removed, patterns.join(","));
+    removed
+}
+
+// Function to get the alternative name of a contig with the 'chr' prefix added or removed
+fn toggle_chr_prefix(chromosome: &str) -> String {
+    match chromosome {
+        "chrM" => "MT".to_string(),
+        "MT" => "chrM".to_string(),
+        _ => match chromosome.strip_prefix("chr") {
+            Some(stripped) => stripped.to_string(),
+            None => format!("chr{}", chromosome),
+        },
+    }
+}
+
+// Function to harmonise the 'chr' prefix of region contigs with the BAM header ('auto', 'add', 'strip' or 'none')
+pub fn harmonise_chr_prefix(regions: &mut [Region], contigs: &[(String, u64)], mode: ChrPrefix) -> usize {
+    let header_names: HashSet<&str> = contigs.iter().map(|(name, _)| name.as_str()).collect();
+    let mut renamed = 0;
+    for region in regions.iter_mut() {
+        let new_name = match mode {
+            ChrPrefix::Add if !region.chromosome.starts_with("chr") => toggle_chr_prefix(&region.chromosome),
+            ChrPrefix::Strip if region.chromosome.starts_with("chr") => toggle_chr_prefix(&region.chromosome),
+            // Decide per region, so files mixing both namings are handled
+            ChrPrefix::Auto if !header_names.contains(region.chromosome.as_str()) => {
+                let toggled = toggle_chr_prefix(&region.chromosome);
+                if !header_names.contains(toggled.as_str()) {
+                    continue;
+                }
+                toggled
+            }
+            _ => continue,
+        };
+        let input_chromosome = std::mem::replace(&mut region.chromosome, new_name);
+        region.input_chromosome.get_or_insert(input_chromosome);
+        renamed += 1;
+    }
+    if renamed > 0 {
+        info!("Renamed the contigs of {} regions to match the BAM header", renamed);
+    }
+    renamed
+}
+
+// Function to check the region contigs against the BAM header, optionally dropping regions on missing contigs
+pub fn check_contigs(
+    regions: &mut Vec<Region>,
+    contigs: &[(String, u64)],
+    skip_missing: bool,
+) -> Result<usize, Box<dyn std::error::Error>> {
+    let lengths: HashMap<&str, u64> = contigs.iter().map(|(name, len)| (name.as_str(), *len)).collect();
+
+    // Report every missing contig up front rather than failing at the first fetch
+    let mut missing: BTreeMap<String, usize> = BTreeMap::new();
+    for region in regions.iter().filter(|r| !lengths.contains_key(r.chromosome.as_str())) {
+        *missing.entry(region.chromosome.clone()).or_insert(0) += 1;
+    }
+    let mut dropped = 0;
+    if !missing.is_empty() {
+        for (contig, n_regions) in &missing {
+            warn!("Contig {} is not in the BAM header ({} regions)", contig, n_regions);
+        }
+        if !skip_missing {
+            return Err(format!(
+                "{} contigs of the regions are missing from the BAM header: {} (use --skip-missing-contigs to drop these regions)",
+                missing.len(),
+                missing.keys().cloned().collect::<Vec<_>>().join(", ")
+            ).into());
+        }
+        let n_before = regions.len();
+        regions.retain(|r| lengths.contains_key(r.chromosome.as_str()));
+        dropped = n_before - regions.len();
+        warn!("Dropped {} regions on contigs missing from the BAM header", dropped);
+    }
+
+    // Regions past the contig ends usually mean the regions and the BAM use different assemblies
+    let beyond_end = regions.iter().filter(|r| r.end as u64 > lengths[r.chromosome.as_str()]).count();
+    if beyond_end > 0 {
+        warn!(
+            "{} regions extend beyond the end of their contig in the BAM header; check that the regions and the BAM use the same genome assembly",
+            beyond_end
+        );
+    }
+    Ok(dropped)
+}
+
+// Function to drop regions with the contig, start, end, strand and blocks of an earlier one, whatever their names (an
+// exon listed once per transcript is fetched once, under the name of its first occurrence), or to keep them for
+// counting separately, suffixing the keys they share with :1, :2, ...; regions of different regions files are never
+// duplicates of each other, as each file gets its own outputs. Returns the number of duplicates
+pub fn remove_duplicate_regions(regions: &mut Vec<Region>, keep_duplicates: bool) -> usize {
+    type RegionIdentity = (Option<String>, String, usize, usize, char, Vec<(usize, usize)>);
+    let identity = |r: &Region| -> RegionIdentity { (r.set.clone(), r.chromosome.clone(), r.start, r.end, r.strand, r.blocks.clone()) };
+    let mut counts: HashMap<RegionIdentity, usize> = HashMap::new();
+    for region in regions.iter() {
+        *counts.entry(identity(region)).or_insert(0) += 1;
+    }
+    let n_duplicates: usize = counts.values().map(|&count| count - 1).sum();
+    if n_duplicates == 0 {
+        return 0;
+    }
+
+    if keep_duplicates {
+        info!("Keeping {} duplicated regions with suffixed keys", n_duplicates);
+        let mut key_counts: HashMap<(RegionIdentity, String), usize> = HashMap::new();
+        for region in regions.iter() {
+            *key_counts.entry((identity(region), region.key())).or_insert(0) += 1;
+        }
+        let mut next_suffix: HashMap<(RegionIdentity, String), usize> = HashMap::new();
+        for region in regions.iter_mut() {
+            let region_key = (identity(region), region.key());
+            if key_counts[&region_key] < 2 {
+                continue;
+            }
+            let suffix = next_suffix.entry(region_key).or_insert(0);
+            *suffix += 1;
+            region.name = Some(format!("{}:{}", region.feature_name(), suffix));
+        }
+    } else {
+        let mut seen: HashSet<RegionIdentity> = HashSet::new();
+        let mut removed: Vec<String> = Vec::new();
+        regions.retain(|region| {
+            let first = seen.insert(identity(region));
+            if !first {
+                removed.push(region.key());
+            }
+            first
+        });
+        // List the first few duplicated regions so that they can be found in the input
+        const LISTED: usize = 10;
+        let listed = removed.iter().unique().take(LISTED).join(", ");
+        let more = if removed.iter().unique().count() > LISTED { ", ..." } else { "" };
+        warn!(
+            "Removed {} duplicated regions, keeping their first occurrence ({}{}); use --keep-duplicates to count them separately",
+            n_duplicates, listed, more
+        );
+    }
+    n_duplicates
+}
+
+// Function to sort regions by contig in BAM header order, then by start and end, so that fetches move forward
+// through the BAM file; returns whether the regions were already sorted
+pub fn sort_regions(regions: &mut [Region], contigs: &[(String, u64)]) -> bool {
+    let contig_index: HashMap<&str, usize> = contigs.iter().enumerate().map(|(i, (name, _))| (name.as_str(), i)).collect();
+    let sort_key = |r: &Region| (contig_index.get(r.chromosome.as_str()).copied().unwrap_or(usize::MAX), r.start, r.end);
+    let already_sorted = regions.windows(2).all(|pair| sort_key(&pair[0]) <= sort_key(&pair[1]));
+    if !already_sorted {
+        regions.sort_by_key(sort_key);
+    }
+    already_sorted
+}
+
+// Function to merge overlapping regions, and regions separated by at most `gap` bases, on the same chromosome
+pub fn merge_regions(regions: Vec<Region>, gap: usize) -> Vec<Region> {
+    let n_input = regions.len();
+    // Group by chromosome (and set) in order of first appearance so that fetches stay grouped by chromosome
+    let mut chromosome_order: Vec<(String, Option<String>)> = Vec::new();
+    let mut by_chromosome: HashMap<(String, Option<String>), Vec<Region>> = HashMap::new();
+    for region in regions {
+        let group_key = (region.chromosome.clone(), region.set.clone());
+        by_chromosome.entry(group_key.clone())
+            .or_insert_with(|| {
+                chromosome_order.push(group_key);
+                Vec::new()
+            })
+            .push(region);
+    }
+
+    let mut merged = Vec::with_capacity(n_input);
+    for chromosome in chromosome_order {
+        let mut chromosome_regions = by_chromosome.remove(&chromosome).unwrap();
+        chromosome_regions.sort_by_key(|r| (r.start, r.end));
+        let mut group: Vec<Region> = Vec::new();
+        let mut group_end = 0;
+        for region in chromosome_regions {
+            if !group.is_empty() && region.start > group_end + gap {
+                merged.push(collapse_group(std::mem::take(&mut group)));
+            }
+            group_end = if group.is_empty() { region.end } else { group_end.max(region.end) };
+            group.push(region);
+        }
+        if !group.is_empty() {
+            merged.push(collapse_group(group));
+        }
+    }
+    info!("Merged {} regions into {} regions", n_input, merged.len());
+    merged
+}
+
+// Function to collapse a group of overlapping regions into a single region spanning all of them
+fn collapse_group(mut group: Vec<Region>) -> Region {
+    if group.len() == 1 {
+        return group.pop().unwrap();
+    }
+    let first = &group[0];
+    let start = first.start;
+    let end = group.iter().map(|r| r.end).max().unwrap();
+    let strand = if group.iter().all(|r| r.strand == first.strand) { first.strand } else { '.' };
+    let mut region = Region::new(first.chromosome.clone(), start, end, None, strand);
+    region.input_chromosome = first.input_chromosome.clone();
+    region.one_based = first.one_based;
+    region.set = first.set.clone();
+    debug!("Merged {} regions into {}", group.len(), region.key());
+    region
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn duplicate_regions_are_keyed_on_coordinates() {
+        let exon = |name: &str| Region::new("chr1".to_string(), 100, 200, Some(name.to_string()), '+');
+        let mut regions = vec![exon("tx1_exon1"), exon("tx2_exon1"), Region::new("chr1".to_string(), 100, 201, None, '+'), exon("tx3_exon1")];
+        assert_eq!(remove_duplicate_regions(&mut regions, false), 2);
+        assert_eq!(regions.iter().map(|region| region.key()).collect::<Vec<_>>(), vec!["tx1_exon1", "chr1:100-201"]);
+
+        // Kept duplicates are suffixed only where their keys would collide
+        let unnamed = || Region::new("chr1".to_string(), 100, 200, None, '+');
+        let mut regions = vec![exon("tx1_exon1"), exon("tx2_exon1"), unnamed(), unnamed()];
+        assert_eq!(remove_duplicate_regions(&mut regions, true), 3);
+        assert_eq!(
+            regions.iter().map(|region| region.key()).collect::<Vec<_>>(),
+            vec!["tx1_exon1", "tx2_exon1", "chr1:100-200:1", "chr1:100-200:2"]
+        );
+    }
+
+    #[test]
+    fn regions_on_other_strands_or_blocks_are_not_duplicates() {
+        // BED12 transcripts over the same span, one skipping the middle exon, and the first one on the other strand
+        let transcript = |name: &str, strand: char, blocks: Vec<(usize, usize)>| {
+            let mut region = Region::new("chr1".to_string(), 100, 500, Some(name.to_string()), strand);
+            region.blocks = blocks;
+            region
+        };
+        let mut regions = vec![
+            transcript("tx1", '+', vec![(100, 200), (300, 350), (400, 500)]),
+            transcript("tx2", '+', vec![(100, 200), (400, 500)]),
+            transcript("tx3", '-', vec![(100, 200), (300, 350), (400, 500)]),
+            transcript("tx4", '+', vec![(100, 200), (300, 350), (400, 500)]),
+        ];
+        assert_eq!(remove_duplicate_regions(&mut regions, false), 1);
+        assert_eq!(regions.iter().map(|region| region.key()).collect::<Vec<_>>(), vec!["tx1", "tx2", "tx3"]);
+    }
+
+    #[test]
+    fn overlap_bases_sums_over_blocks() {
+        let read_blocks = [(100, 130), (1130, 1160)];
+        assert_eq!(overlap_bases(&read_blocks, &[(90, 110), (1150, 1170)]), 20);
+        assert_eq!(overlap_bases(&read_blocks, &[(0, 2000)]), 60);
+        assert_eq!(overlap_bases(&read_blocks, &[(130, 1130)]), 0);
+        assert_eq!(overlap_bases(&read_blocks, &[]), 0);
+        assert_eq!(overlap_bases(&[], &[(0, 2000)]), 0);
+    }
+
+    #[test]
+    fn merged_regions_join_book_ended_and_contained_regions_per_chromosome() {
+        let region = |chromosome: &str, start, end, strand| Region::new(chromosome.to_string(), start, end, None, strand);
+        let spans = |regions: &[Region]| regions.iter().map(|region| (region.chromosome.clone(), region.start, region.end, region.strand)).collect::<Vec<_>>();
+        let span = |chromosome: &str, start, end, strand| (chromosome.to_string(), start, end, strand);
+        let regions = || vec![
+            region("chr2", 150, 250, '+'),
+            region("chr1", 200, 300, '+'),
+            region("chr1", 100, 200, '+'),
+            region("chr1", 1000, 2000, '+'),
+            region("chr1", 1200, 1300, '-'),
+            region("chr1", 2005, 2100, '-'),
+            region("chr1", 150, 250, '+'),
+        ];
+        // Book-ended regions merge, contained regions are absorbed with a mixed strand, and chromosomes stay in their
+        // order of first appearance
+        assert_eq!(spans(&merge_regions(regions(), 0)), vec![
+            span("chr2", 150, 250, '+'),
+            span("chr1", 100, 300, '+'),
+            span("chr1", 1000, 2000, '.'),
+            span("chr1", 2005, 2100, '-'),
+        ]);
+        // Regions up to the gap apart merge too, never across chromosomes
+        assert_eq!(spans(&merge_regions(regions(), 4)), spans(&merge_regions(regions(), 0)));
+        assert_eq!(spans(&merge_regions(regions(), 5)), vec![
+            span("chr2", 150, 250, '+'),
+            span("chr1", 100, 300, '+'),
+            span("chr1", 1000, 2100, '.'),
+        ]);
+    }
+}
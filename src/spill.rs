@@ -0,0 +1,266 @@
+// Modules for counting through temporary files with --low-memory, and for the temporary directory of a run
+use crate::error::KaiError;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashSet};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Entries sorted in memory before being written as one sorted run
+const RUN_ENTRIES: usize = 1 << 21;
+
+// Directory holding the temporary files of a run, removed with everything in it once dropped (on success or error)
+pub struct SpillDir {
+    path: PathBuf,
+    files: AtomicUsize,
+}
+
+impl SpillDir {
+    pub fn create(parent: &Path) -> Result<Self, KaiError> {
+        let nanos = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map_or(0, |time| time.subsec_nanos());
+        let path = parent.join(format!("kai-{}-{}", std::process::id(), nanos));
+        std::fs::create_dir(&path).map_err(|source| spill_error(&path, source))?;
+        Ok(SpillDir { path, files: AtomicUsize::new(0) })
+    }
+
+    // Function to name a new temporary file after the files created so far
+    pub fn file_path(&self, kind: &str, extension: &str) -> PathBuf {
+        self.path.join(format!("{}-{}.{}", kind, self.files.fetch_add(1, Ordering::Relaxed), extension))
+    }
+
+    // Function to create a new temporary file
+    fn create_file(&self, kind: &str) -> Result<(PathBuf, BufWriter<File>), KaiError> {
+        let path = self.file_path(kind, "bin");
+        let file = File::create(&path).map_err(|source| spill_error(&path, source))?;
+        Ok((path, BufWriter::new(file)))
+    }
+}
+
+impl Drop for SpillDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
+
+fn spill_error(path: &Path, source: std::io::Error) -> KaiError {
+    KaiError::Spill { path: path.display().to_string(), source }
+}
+
+// (matrix, feature, barcode, count) triplets of a group of regions in the order they are counted, barcodes numbered
+// by the group
+pub struct Spill {
+    path: PathBuf,
+    file: BufWriter<File>,
+}
+
+impl Spill {
+    pub fn create(dir: &SpillDir) -> Result<Self, KaiError> {
+        let (path, file) = dir.create_file("counts")?;
+        Ok(Spill { path, file })
+    }
+
+    pub fn add(&mut self, matrix: u8, feature: u32, barcode: u32, count: f64) -> Result<(), KaiError> {
+        let mut bytes = [0; 17];
+        bytes[0] = matrix;
+        bytes[1..5].copy_from_slice(&feature.to_le_bytes());
+        bytes[5..9].copy_from_slice(&barcode.to_le_bytes());
+        bytes[9..].copy_from_slice(&count.to_le_bytes());
+        self.file.write_all(&bytes).map_err(|source| spill_error(&self.path, source))
+    }
+
+    // Function to flush the triplets, returning the path of their file
+    pub fn finish(mut self) -> Result<PathBuf, KaiError> {
+        self.file.flush().map_err(|source| spill_error(&self.path, source))?;
+        Ok(self.path)
+    }
+}
+
+// Matrix, feature and barcode column of an entry
+type Key = (u8, u32, u32);
+
+// A count of a run: its matrix, feature and barcode column, and the spill file it comes from
+#[derive(Clone, Copy)]
+struct Entry {
+    matrix: u8,
+    feature: u32,
+    column: u32,
+    file: u32,
+    count: f64,
+}
+
+impl Entry {
+    fn key(&self) -> Key {
+        (self.matrix, self.feature, self.column)
+    }
+}
+
+// Count of a key being summed: the sum of the group being read (as an entry of its file) and the total over the
+// groups before it
+struct Sum {
+    entry: Entry,
+    total: f64,
+}
+
+// Function to read a fixed-size record, returning None at the end of the file
+fn read_record<const N: usize>(reader: &mut impl Read, path: &Path) -> Result<Option<[u8; N]>, KaiError> {
+    let mut bytes = [0; N];
+    match reader.read_exact(&mut bytes) {
+        Ok(()) => Ok(Some(bytes)),
+        Err(error) if error.kind() == ErrorKind::UnexpectedEof => Ok(None),
+        Err(source) => Err(spill_error(path, source)),
+    }
+}
+
+fn open(path: &Path) -> Result<BufReader<File>, KaiError> {
+    File::open(path).map(BufReader::new).map_err(|source| spill_error(path, source))
+}
+
+// Counts of a matrix summed per feature and barcode column, sorted by feature then column in a temporary file
+pub struct SortedMatrix {
+    path: PathBuf,
+    pub entries: usize,
+    pub features: HashSet<u32>,
+}
+
+impl SortedMatrix {
+    // Function to read the (feature, barcode column, count) entries back in order
+    pub fn iter(&self) -> Result<impl Iterator<Item = Result<(u32, usize, f64), KaiError>> + '_, KaiError> {
+        let mut reader = open(&self.path)?;
+        Ok(std::iter::from_fn(move || {
+            read_record::<16>(&mut reader, &self.path).transpose().map(|bytes| {
+                let bytes = bytes?;
+                let feature = u32::from_le_bytes(bytes[0..4].try_into().unwrap());
+                let column = u32::from_le_bytes(bytes[4..8].try_into().unwrap()) as usize;
+                Ok((feature, column, f64::from_le_bytes(bytes[8..].try_into().unwrap())))
+            })
+        }))
+    }
+}
+
+// Function to write a run of entries sorted by matrix, feature and barcode column, keeping the counting order of
+// entries sharing a key (the sort is stable)
+fn write_run(dir: &SpillDir, entries: &mut Vec<Entry>) -> Result<PathBuf, KaiError> {
+    entries.sort_by_key(Entry::key);
+    let (path, mut file) = dir.create_file("run")?;
+    for entry in entries.drain(..) {
+        let mut bytes = [0; 21];
+        bytes[0] = entry.matrix;
+        bytes[1..5].copy_from_slice(&entry.feature.to_le_bytes());
+        bytes[5..9].copy_from_slice(&entry.column.to_le_bytes());
+        bytes[9..13].copy_from_slice(&entry.file.to_le_bytes());
+        bytes[13..].copy_from_slice(&entry.count.to_le_bytes());
+        file.write_all(&bytes).map_err(|source| spill_error(&path, source))?;
+    }
+    file.flush().map_err(|source| spill_error(&path, source))?;
+    Ok(path)
+}
+
+fn read_entry(reader: &mut BufReader<File>, path: &Path) -> Result<Option<Entry>, KaiError> {
+    Ok(read_record::<21>(reader, path)?.map(|bytes| Entry {
+        matrix: bytes[0],
+        feature: u32::from_le_bytes(bytes[1..5].try_into().unwrap()),
+        column: u32::from_le_bytes(bytes[5..9].try_into().unwrap()),
+        file: u32::from_le_bytes(bytes[9..13].try_into().unwrap()),
+        count: f64::from_le_bytes(bytes[13..].try_into().unwrap()),
+    }))
+}
+
+// Function to sort the spill files of every group into one matrix per matrix number: barcodes are renumbered into
+// the merged barcodes (when the group numbered them itself) then into their matrix column, sorted runs of entries
+// are written, and the runs are merged while summing the counts of each feature and column. Counts are summed in
+// counting order within a group, then group by group, as the in-memory counts are, so that outputs are identical
+pub fn sort(
+    dir: &SpillDir,
+    files: &[(PathBuf, Option<Vec<u32>>)],
+    barcode_columns: &[usize],
+    matrices: usize,
+) -> Result<Vec<SortedMatrix>, KaiError> {
+    let mut runs: Vec<PathBuf> = Vec::new();
+    let mut entries: Vec<Entry> = Vec::with_capacity(RUN_ENTRIES);
+    for (file_index, (path, barcode_ids)) in files.iter().enumerate() {
+        let mut reader = open(path)?;
+        while let Some(bytes) = read_record::<17>(&mut reader, path)? {
+            let barcode = u32::from_le_bytes(bytes[5..9].try_into().unwrap());
+            let barcode = barcode_ids.as_ref().map_or(barcode, |ids| ids[barcode as usize]);
+            entries.push(Entry {
+                matrix: bytes[0],
+                feature: u32::from_le_bytes(bytes[1..5].try_into().unwrap()),
+                column: barcode_columns[barcode as usize] as u32,
+                file: file_index as u32,
+                count: f64::from_le_bytes(bytes[9..].try_into().unwrap()),
+            });
+            if entries.len() == RUN_ENTRIES {
+                runs.push(write_run(dir, &mut entries)?);
+            }
+        }
+        drop(reader);
+        let _ = std::fs::remove_file(path);
+    }
+    if !entries.is_empty() {
+        runs.push(write_run(dir, &mut entries)?);
+    }
+    drop(entries);
+
+    // Merge the runs, taking entries sharing a key from the earliest run first to keep their counting order
+    let mut readers: Vec<BufReader<File>> = runs.iter().map(|path| open(path)).collect::<Result<_, _>>()?;
+    let mut heads: Vec<Option<Entry>> = Vec::with_capacity(runs.len());
+    let mut heap: BinaryHeap<Reverse<(Key, usize)>> = BinaryHeap::new();
+    for (run, reader) in readers.iter_mut().enumerate() {
+        let head = read_entry(reader, &runs[run])?;
+        if let Some(entry) = head {
+            heap.push(Reverse((entry.key(), run)));
+        }
+        heads.push(head);
+    }
+    let mut outputs: Vec<(PathBuf, BufWriter<File>)> = (0..matrices).map(|_| dir.create_file("matrix")).collect::<Result<_, _>>()?;
+    let mut sorted: Vec<SortedMatrix> = outputs.iter()
+        .map(|(path, _)| SortedMatrix { path: path.clone(), entries: 0, features: HashSet::new() })
+        .collect();
+    let mut current: Option<Sum> = None;
+    let mut write_sum = |sum: Sum| -> Result<(), KaiError> {
+        let (path, output) = &mut outputs[sum.entry.matrix as usize];
+        let mut bytes = [0; 16];
+        bytes[0..4].copy_from_slice(&sum.entry.feature.to_le_bytes());
+        bytes[4..8].copy_from_slice(&sum.entry.column.to_le_bytes());
+        bytes[8..].copy_from_slice(&(sum.total + sum.entry.count).to_le_bytes());
+        output.write_all(&bytes).map_err(|source| spill_error(path, source))?;
+        sorted[sum.entry.matrix as usize].entries += 1;
+        sorted[sum.entry.matrix as usize].features.insert(sum.entry.feature);
+        Ok(())
+    };
+    while let Some(Reverse((_, run))) = heap.pop() {
+        let entry = heads[run].take().unwrap();
+        current = match current {
+            Some(mut sum) if sum.entry.key() == entry.key() => {
+                if sum.entry.file == entry.file {
+                    sum.entry.count += entry.count;
+                } else {
+                    sum.total += sum.entry.count;
+                    sum.entry = entry;
+                }
+                Some(sum)
+            }
+            previous => {
+                if let Some(previous) = previous {
+                    write_sum(previous)?;
+                }
+                Some(Sum { entry, total: 0.0 })
+            }
+        };
+        heads[run] = read_entry(&mut readers[run], &runs[run])?;
+        if let Some(next) = heads[run] {
+            heap.push(Reverse((next.key(), run)));
+        }
+    }
+    if let Some(last) = current {
+        write_sum(last)?;
+    }
+    for (path, output) in &mut outputs {
+        output.flush().map_err(|source| spill_error(path, source))?;
+    }
+    for path in &runs {
+        let _ = std::fs::remove_file(path);
+    }
+    Ok(sorted)
+}
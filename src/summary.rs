@@ -0,0 +1,130 @@
+// Modules for writing the machine-readable summary of a run (summary.json)
+use std::fmt::Write;
+
+// Value of the JSON summary, objects keeping their fields in the order they are given so that files are stable
+pub enum Json {
+    Null,
+    Bool(bool),
+    Integer(u64),
+    Float(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    pub fn object<K: Into<String>>(fields: Vec<(K, Json)>) -> Self {
+        Json::Object(fields.into_iter().map(|(key, value)| (key.into(), value)).collect())
+    }
+
+    // Function to render the value as JSON indented by two spaces per level, ending with a newline
+    pub fn render(&self) -> String {
+        let mut output = String::new();
+        self.write(&mut output, 0);
+        output.push('\n');
+        output
+    }
+
+    fn write(&self, output: &mut String, depth: usize) {
+        match self {
+            Json::Null => output.push_str("null"),
+            Json::Bool(value) => output.push_str(if *value { "true" } else { "false" }),
+            Json::Integer(value) => output.push_str(&value.to_string()),
+            // JSON has no NaN or infinity
+            Json::Float(value) if !value.is_finite() => output.push_str("null"),
+            Json::Float(value) => output.push_str(&value.to_string()),
+            Json::String(value) => write_string(output, value),
+            Json::Array(values) if values.is_empty() => output.push_str("[]"),
+            Json::Array(values) => {
+                output.push('[');
+                for (i, value) in values.iter().enumerate() {
+                    output.push_str(if i == 0 { "\n" } else { ",\n" });
+                    indent(output, depth + 1);
+                    value.write(output, depth + 1);
+                }
+                output.push('\n');
+                indent(output, depth);
+                output.push(']');
+            }
+            Json::Object(fields) if fields.is_empty() => output.push_str("{}"),
+            Json::Object(fields) => {
+                output.push('{');
+                for (i, (key, value)) in fields.iter().enumerate() {
+                    output.push_str(if i == 0 { "\n" } else { ",\n" });
+                    indent(output, depth + 1);
+                    write_string(output, key);
+                    output.push_str(": ");
+                    value.write(output, depth + 1);
+                }
+                output.push('\n');
+                indent(output, depth);
+                output.push('}');
+            }
+        }
+    }
+}
+
+fn indent(output: &mut String, depth: usize) {
+    output.extend(std::iter::repeat_n(' ', depth * 2));
+}
+
+// Function to write a string literal, escaping quotes, backslashes and control characters
+fn write_string(output: &mut String, value: &str) {
+    output.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => output.push_str("\\\""),
+            '\\' => output.push_str("\\\\"),
+            '\n' => output.push_str("\\n"),
+            '\r' => output.push_str("\\r"),
+            '\t' => output.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(output, "\\u{:04x}", c as u32);
+            }
+            c => output.push(c),
+        }
+    }
+    output.push('"');
+}
+
+impl From<u64> for Json {
+    fn from(value: u64) -> Self {
+        Json::Integer(value)
+    }
+}
+
+impl From<usize> for Json {
+    fn from(value: usize) -> Self {
+        Json::Integer(value as u64)
+    }
+}
+
+impl From<f64> for Json {
+    fn from(value: f64) -> Self {
+        Json::Float(value)
+    }
+}
+
+impl From<bool> for Json {
+    fn from(value: bool) -> Self {
+        Json::Bool(value)
+    }
+}
+
+impl From<&str> for Json {
+    fn from(value: &str) -> Self {
+        Json::String(value.to_string())
+    }
+}
+
+impl From<String> for Json {
+    fn from(value: String) -> Self {
+        Json::String(value)
+    }
+}
+
+impl<T: Into<Json>> From<Option<T>> for Json {
+    fn from(value: Option<T>) -> Self {
+        value.map_or(Json::Null, Into::into)
+    }
+}
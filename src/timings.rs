@@ -0,0 +1,44 @@
+// Modules for timing the stages of a run with --timings
+use log::info;
+use std::time::{Duration, Instant};
+
+// Wall-clock time and counters of each stage, each stage starting where the previous one ended
+pub struct Timings {
+    start: Instant,
+    stage_start: Instant,
+    stages: Vec<(&'static str, Duration, Vec<String>)>,
+}
+
+impl Timings {
+    pub fn new() -> Self {
+        let now = Instant::now();
+        Timings { start: now, stage_start: now, stages: Vec::new() }
+    }
+
+    // Function to close the current stage with its counters and start the next one
+    pub fn end_stage(&mut self, name: &'static str, counters: Vec<String>) {
+        let now = Instant::now();
+        self.stages.push((name, now - self.stage_start, counters));
+        self.stage_start = now;
+    }
+
+    // Function to get the name and wall-clock time of each stage closed so far
+    pub fn stages(&self) -> impl Iterator<Item = (&'static str, Duration)> + '_ {
+        self.stages.iter().map(|(name, elapsed, _)| (*name, *elapsed))
+    }
+
+    // Function to get the wall-clock time since the start of the run
+    pub fn total(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    // Function to log the stages as a table
+    pub fn report(&self) {
+        info!("Timings:");
+        info!("  {:<28} {:>10}  Counters", "Stage", "Time (s)");
+        for (name, elapsed, counters) in &self.stages {
+            info!("  {:<28} {:>10.3}  {}", name, elapsed.as_secs_f64(), counters.join(", "));
+        }
+        info!("  {:<28} {:>10.3}", "Total", self.start.elapsed().as_secs_f64());
+    }
+}
@@ -0,0 +1,111 @@
+// Modules for collapsing UMIs with sequencing errors into molecules
+use std::collections::{HashMap, HashSet};
+use crate::cli::UmiCollapse;
+
+// Function to check whether two UMIs of the same length differ at exactly one position
+fn one_mismatch(a: &str, b: &str) -> bool {
+    a.len() == b.len() && a.bytes().zip(b.bytes()).filter(|(x, y)| x != y).count() == 1
+}
+
+// Function to find the pairs of UMIs within Hamming distance 1, comparing only UMIs that share their first or their
+// second half (a single mismatch leaves one of the halves intact)
+fn neighbours<'a>(umis: &[&'a str]) -> HashMap<&'a str, Vec<&'a str>> {
+    let mut buckets: HashMap<(bool, usize, &str), Vec<&str>> = HashMap::new();
+    for &umi in umis {
+        let half = umi.len() / 2;
+        buckets.entry((true, umi.len(), &umi[..half])).or_default().push(umi);
+        buckets.entry((false, umi.len(), &umi[half..])).or_default().push(umi);
+    }
+    let mut neighbours: HashMap<&str, Vec<&str>> = HashMap::new();
+    let mut seen: HashSet<(&str, &str)> = HashSet::new();
+    for bucket in buckets.values() {
+        for (i, &a) in bucket.iter().enumerate() {
+            for &b in &bucket[i + 1..] {
+                if one_mismatch(a, b) && seen.insert((a.min(b), a.max(b))) {
+                    neighbours.entry(a).or_default().push(b);
+                    neighbours.entry(b).or_default().push(a);
+                }
+            }
+        }
+    }
+    neighbours
+}
+
+// Function to collapse the UMIs of a region and barcode into molecules, returning the UMI representing each molecule:
+// 'hamming1' merges connected UMIs within one mismatch, 'directional' (as in UMI-tools) only lets a UMI absorb a
+// neighbour when count(a) >= 2 * count(b) - 1
+pub fn collapse_umis(umi_counts: &HashMap<String, u32>, method: UmiCollapse) -> Vec<&str> {
+    // Visit UMIs from the most to the least supported, breaking ties by sequence for reproducible results
+    let mut umis: Vec<&str> = umi_counts.keys().map(|umi| umi.as_str()).collect();
+    umis.sort_by(|a, b| umi_counts[*b].cmp(&umi_counts[*a]).then(a.cmp(b)));
+    let neighbours = neighbours(&umis);
+
+    let mut assigned: HashSet<&str> = HashSet::new();
+    let mut representatives = Vec::new();
+    for &head in &umis {
+        if !assigned.insert(head) {
+            continue;
+        }
+        representatives.push(head);
+        let mut queue = vec![head];
+        while let Some(umi) = queue.pop() {
+            for &neighbour in neighbours.get(umi).into_iter().flatten() {
+                let absorbs = method == UmiCollapse::Hamming1 || umi_counts[umi] >= 2 * umi_counts[neighbour] - 1;
+                if absorbs && assigned.insert(neighbour) {
+                    queue.push(neighbour);
+                }
+            }
+        }
+    }
+    representatives
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn counts(umis: &[(&str, u32)]) -> HashMap<String, u32> {
+        umis.iter().map(|&(umi, count)| (umi.to_string(), count)).collect()
+    }
+
+    #[test]
+    fn directional_follows_a_chain_of_decreasing_counts() {
+        // A(10) absorbs B(4) as 10 >= 2 * 4 - 1, and B absorbs C(1) although C is two mismatches from A
+        let umis = counts(&[("AAAA", 10), ("AAAT", 4), ("AATT", 1)]);
+        assert_eq!(collapse_umis(&umis, UmiCollapse::Directional), vec!["AAAA"]);
+        // B(6) is too well supported to be an error of A(10), and one mismatch does not join A and C(3)
+        let umis = counts(&[("AAAA", 10), ("AAAT", 6), ("AATT", 3)]);
+        assert_eq!(collapse_umis(&umis, UmiCollapse::Directional), vec!["AAAA", "AAAT"]);
+        assert_eq!(collapse_umis(&umis, UmiCollapse::Hamming1), vec!["AAAA"]);
+    }
+
+    #[test]
+    fn directional_keeps_tied_neighbours_apart() {
+        let umis = counts(&[("ACGT", 3), ("ACGA", 3)]);
+        assert_eq!(collapse_umis(&umis, UmiCollapse::Directional), vec!["ACGA", "ACGT"]);
+        // Ties of a single read differ by one: 1 >= 2 * 1 - 1
+        let umis = counts(&[("ACGT", 1), ("ACGA", 1)]);
+        assert_eq!(collapse_umis(&umis, UmiCollapse::Directional), vec!["ACGA"]);
+    }
+
+    #[test]
+    fn single_base_umis_are_all_neighbours() {
+        let umis = counts(&[("A", 5), ("C", 1), ("G", 1), ("AC", 1)]);
+        assert_eq!(collapse_umis(&umis, UmiCollapse::Directional), vec!["A", "AC"]);
+    }
+
+    #[test]
+    fn neighbours_are_found_through_either_half() {
+        // Mismatches in the first half share the second half, and the other way round; UMIs of other lengths and
+        // two mismatches apart are not neighbours
+        let umis = ["AAAAAA", "TAAAAA", "AAAAAT", "TAAAAT", "AAAAA"];
+        let found = neighbours(&umis);
+        let mut of_first = found["AAAAAA"].clone();
+        of_first.sort();
+        assert_eq!(of_first, vec!["AAAAAT", "TAAAAA"]);
+        let mut of_last = found["TAAAAT"].clone();
+        of_last.sort();
+        assert_eq!(of_last, vec!["AAAAAT", "TAAAAA"]);
+        assert!(!found.contains_key("AAAAA"));
+    }
+}
@@ -0,0 +1,104 @@
+// Integration tests of the annotated BAM file of --assignments-bam
+mod common;
+
+use common::{Fixture, HEADER};
+use rust_htslib::bam::{self, record::Aux, Read};
+
+// Reads of two overlapping regions A and B on chr1 and region C on chr2, one of them with an aligner XS:A tag and
+// one below --min-mapq
+const READS: [&str; 5] = [
+    "both 0 chr1 151 60 50M * 0 0 * * NH:i:1",
+    "only_a 0 chr1 101 60 30M * 0 0 * * NH:i:1 XS:A:+",
+    "low_mapq 0 chr1 161 5 50M * 0 0 * * NH:i:1",
+    "only_b 0 chr1 281 60 50M * 0 0 * * NH:i:1",
+    "only_c 0 chr2 1001 60 50M * 0 0 * * NH:i:1",
+];
+const REGIONS: &str = "chr1\t100\t200\tA\nchr1\t150\t400\tB\nchr2\t1000\t1100\tC\n";
+
+// Name and XS and XT tags of a read
+type AnnotatedRead = (String, Option<String>, Option<String>);
+
+// Function to read the annotated reads of a BAM file, with its @HD and kai @PG lines
+fn annotated_reads(path: &str) -> (Vec<String>, Vec<AnnotatedRead>) {
+    let mut reader = bam::Reader::from_path(path).unwrap();
+    let header = String::from_utf8_lossy(reader.header().as_bytes()).into_owned();
+    let lines = header.lines().filter(|line| line.starts_with("@HD") || line.contains("PN:kai")).map(str::to_string).collect();
+    let tag = |record: &bam::Record, name: &[u8]| match record.aux(name) {
+        Ok(Aux::String(value)) => Some(value.to_string()),
+        Ok(Aux::Char(value)) => Some((value as char).to_string()),
+        _ => None,
+    };
+    let reads = reader.records()
+        .map(|record| record.unwrap())
+        .map(|record| (String::from_utf8_lossy(record.qname()).into_owned(), tag(&record, b"XS"), tag(&record, b"XT")))
+        .collect();
+    (lines, reads)
+}
+
+fn read(name: &str, xs: Option<&str>, xt: Option<&str>) -> AnnotatedRead {
+    (name.to_string(), xs.map(str::to_string), xt.map(str::to_string))
+}
+
+#[test]
+fn counted_reads_keep_the_aligner_xs_tag() {
+    let fixture = Fixture::new("assignments_counted");
+    let header = format!("{}@PG\tID:kai\tPN:kai\tCL:kai bulk\n", HEADER);
+    let bam = fixture.bam_with_header("reads.bam", &header, &READS);
+    let regions = fixture.file("regions.bed", REGIONS);
+    let output = fixture.output_dir("output");
+    let annotated = fixture.path("annotated.bam");
+    fixture.kai(&["bulk", &bam, &regions, &output, "--min-mapq", "10", "--assignments-bam", &annotated]);
+
+    let (header, reads) = annotated_reads(&annotated);
+    assert_eq!(header[0], "@HD\tVN:1.6\tSO:coordinate");
+    // The @PG ID of the input is not reused
+    assert!(header[2].starts_with("@PG\tID:kai.1\tPN:kai\tPP:kai\tCL:"), "{}", header[2]);
+    assert_eq!(reads, vec![
+        read("only_a", Some("+"), Some("A")),
+        read("both", None, Some("A,B")),
+        read("only_b", None, Some("B")),
+        read("only_c", None, Some("C")),
+    ]);
+}
+
+#[test]
+fn unassigned_reads_get_their_reason() {
+    let fixture = Fixture::new("assignments_unassigned");
+    let bam = fixture.bam("reads.bam", &READS);
+    let regions = fixture.file("regions.bed", REGIONS);
+    let output = fixture.output_dir("output");
+    let (single, threaded) = (fixture.path("single.bam"), fixture.path("threaded.bam"));
+    let args = ["bulk", &bam, &regions, &output, "--min-mapq", "10", "--write-unassigned", "--assignments-bam"];
+    fixture.kai(&[&args[..], &[&single]].concat());
+    fixture.kai(&[&args[..], &[&threaded, "--threads", "2"]].concat());
+
+    let expected = vec![
+        read("only_a", Some("Assigned"), Some("A")),
+        read("both", Some("Assigned"), Some("A,B")),
+        read("low_mapq", Some("low_mapq"), None),
+        read("only_b", Some("Assigned"), Some("B")),
+        read("only_c", Some("Assigned"), Some("C")),
+    ];
+    assert_eq!(annotated_reads(&single).1, expected);
+    assert_eq!(annotated_reads(&threaded).1, expected);
+}
+
+#[test]
+fn unsorted_regions_give_an_unsorted_file() {
+    let fixture = Fixture::new("assignments_unsorted");
+    let bam = fixture.bam("reads.bam", &READS);
+    let regions = fixture.file("regions.bed", "chr1\t150\t400\tB\nchr1\t100\t200\tA\n");
+    let output = fixture.output_dir("output");
+    let annotated = fixture.path("annotated.bam");
+    fixture.kai(&["bulk", &bam, &regions, &output, "--no-sort", "--assignments-bam", &annotated]);
+
+    let (header, reads) = annotated_reads(&annotated);
+    assert_eq!(header[0], "@HD\tVN:1.6\tSO:unsorted");
+    // Reads are written in the order they are first met, with every region they are counted in
+    assert_eq!(reads, vec![
+        read("both", None, Some("B,A")),
+        read("low_mapq", None, Some("B,A")),
+        read("only_b", None, Some("B")),
+        read("only_a", Some("+"), Some("A")),
+    ]);
+}
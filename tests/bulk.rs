@@ -0,0 +1,624 @@
+// Integration tests of the bulk count table
+mod common;
+
+use common::{bulk_counts, read_gz, summary_count, Fixture};
+
+#[test]
+fn fetch_windows_count_adjacent_exons_like_single_fetches() {
+    let fixture = Fixture::new("bulk_fetch_windows");
+    // Three adjacent exons, with reads overlapping one or two of them, a spliced read joining the first and the
+    // last, and an exon far away on chr1 and one on chr2 fetched in windows of their own
+    let exons = [(1000, 1100), (1100, 1200), (1200, 1300), (50000, 50100)];
+    let mut reads: Vec<String> = (960..1300).step_by(7).map(|pos| format!("r{} 0 chr1 {} 60 50M * 0 0 * * NH:i:1", pos, pos + 1)).collect();
+    reads.push("spliced 0 chr1 1051 60 30M150N30M * 0 0 * * NH:i:1".to_string());
+    reads.push("far 0 chr1 50011 60 50M * 0 0 * * NH:i:1".to_string());
+    reads.push("other 0 chr2 1011 60 50M * 0 0 * * NH:i:1".to_string());
+    let bam = fixture.bam("reads.bam", &reads.iter().map(String::as_str).collect::<Vec<_>>());
+    let mut bed: String = exons.iter().enumerate().map(|(i, (start, end))| format!("chr1\t{}\t{}\texon{}\n", start, end, i + 1)).collect();
+    bed.push_str("chr2\t1000\t1100\texon5\n");
+    let regions = fixture.file("regions.bed", &bed);
+
+    let windows = fixture.output_dir("windows");
+    fixture.kai(&["bulk", &bam, &regions, &windows]);
+    let single_fetches = fixture.output_dir("single_fetches");
+    fixture.kai(&["bulk", &bam, &regions, &single_fetches, "--fetch-merge-distance", "0"]);
+    let counts = bulk_counts(&format!("{}/count.tsv.gz", windows));
+    assert_eq!(counts, bulk_counts(&format!("{}/count.tsv.gz", single_fetches)));
+
+    // Reads [pos, pos + 50) overlapping each exon, plus the spliced read in the first and last exons
+    let expected = |(start, end): (i64, i64)| (960..1300).step_by(7).filter(|&pos| pos < end && pos + 50 > start).count();
+    assert_eq!(counts["exon1"], (expected((1000, 1100)) + 1).to_string());
+    assert_eq!(counts["exon2"], expected((1100, 1200)).to_string());
+    assert_eq!(counts["exon3"], (expected((1200, 1300)) + 1).to_string());
+    assert_eq!((counts["exon4"].as_str(), counts["exon5"].as_str()), ("1", "1"));
+}
+
+// Reads on either strand of a + region, a - region and an unstranded region, with a pair whose read 1 is reverse
+const STRANDED_READS: [&str; 13] = [
+    "plus_f1 0 chr1 111 60 50M * 0 0 * * NH:i:1",
+    "plus_f2 0 chr1 131 60 50M * 0 0 * * NH:i:1",
+    "plus_r1 16 chr1 151 60 50M * 0 0 * * NH:i:1",
+    "pair 81 chr1 201 60 50M = 171 -80 * * NH:i:1",
+    "pair 161 chr1 171 60 50M = 201 80 * * NH:i:1",
+    "minus_f1 0 chr1 1011 60 50M * 0 0 * * NH:i:1",
+    "minus_r1 16 chr1 1031 60 50M * 0 0 * * NH:i:1",
+    "minus_r2 16 chr1 1051 60 50M * 0 0 * * NH:i:1",
+    "minus_r3 16 chr1 1071 60 50M * 0 0 * * NH:i:1",
+    "none_f1 0 chr1 2011 60 50M * 0 0 * * NH:i:1",
+    "none_r1 16 chr1 2031 60 50M * 0 0 * * NH:i:1",
+    "outside_f1 0 chr1 5011 60 50M * 0 0 * * NH:i:1",
+    "outside_r1 16 chr1 5031 60 50M * 0 0 * * NH:i:1",
+];
+const STRANDED_REGIONS: &str = "chr1\t100\t300\tplus\t0\t+\nchr1\t1000\t1200\tminus\t0\t-\nchr1\t2000\t2200\tunstranded\t0\t.\n";
+
+#[test]
+fn stranded_counting_follows_the_region_strand() {
+    let fixture = Fixture::new("bulk_stranded");
+    let bam = fixture.bam("reads.bam", &STRANDED_READS);
+    let regions = fixture.file("regions.bed", STRANDED_REGIONS);
+    let counts = |strandedness: &str| {
+        let counts = fixture.count_bulk(&bam, &regions, strandedness, &["--strandedness", strandedness]);
+        ["plus", "minus", "unstranded"].map(|region| counts[region].clone())
+    };
+    assert_eq!(counts("none"), ["5", "4", "2"]);
+    // Both mates of the pair take the strand of read 1
+    assert_eq!(counts("forward"), ["2", "3", "2"]);
+    assert_eq!(counts("reverse"), ["3", "1", "2"]);
+}
+
+#[test]
+fn regions_are_read_from_stdin() {
+    let fixture = Fixture::new("bulk_stdin_regions");
+    let bam = fixture.bam("reads.bam", &STRANDED_READS);
+    let bed = "chr1\t100\t300\tplus\nchr1\t1000\t1200\tminus\n";
+    let piped = fixture.output_dir("piped");
+    fixture.kai_with_stdin(&["bulk", &bam, "-", &piped], bed);
+    let counts = bulk_counts(&format!("{}/count.tsv.gz", piped));
+    let regions = fixture.file("regions.bed", bed);
+    assert_eq!(counts, fixture.count_bulk(&bam, &regions, "file", &[]));
+    assert_eq!((counts["plus"].as_str(), counts["minus"].as_str()), ("5", "4"));
+}
+
+#[test]
+fn single_base_one_based_region_counts_reads_at_its_base() {
+    let fixture = Fixture::new("bulk_one_based");
+    // Reads ending at base 999 or 1000, and starting at base 1000 or 1001 (1-based)
+    let reads = [
+        "ends_before 0 chr1 950 60 50M * 0 0 * * NH:i:1",
+        "ends_at 0 chr1 951 60 50M * 0 0 * * NH:i:1",
+        "starts_at 0 chr1 1000 60 50M * 0 0 * * NH:i:1",
+        "starts_after 0 chr1 1001 60 50M * 0 0 * * NH:i:1",
+    ];
+    let bam = fixture.bam("reads.bam", &reads);
+    let regions = fixture.file("regions.bed", "chr1\t1000\t1000\n");
+    let output = fixture.output_dir("output");
+    fixture.kai(&["bulk", &bam, &regions, &output, "--coordinates", "one-based"]);
+    let table = read_gz(&format!("{}/count.tsv.gz", output));
+    assert_eq!(table.lines().nth(1), Some("chr1\t1000\t1000\tchr1:1000-1000\t2"));
+    // The region given as --region is the same base
+    let output = fixture.output_dir("region");
+    fixture.kai(&["bulk", &bam, &output, "--coordinates", "one-based", "--region", "chr1:1000-1000"]);
+    assert_eq!(bulk_counts(&format!("{}/count.tsv.gz", output))["chr1:1000-1000"], "2");
+}
+
+#[test]
+fn tss_windows_are_reported_in_one_based_coordinates() {
+    let fixture = Fixture::new("bulk_tss_one_based");
+    let bam = fixture.bam("reads.bam", &["read 0 chr1 951 60 50M * 0 0 * * NH:i:1"]);
+    let genes = fixture.file("genes.gtf", "chr1	test	gene	1001	2000	.	+	.	gene_id \"plus\";\n");
+    let output = fixture.output_dir("output");
+    fixture.kai(&["bulk", &bam, &output, "--tss-regions", &genes, "--upstream", "100", "--downstream", "50", "--coordinates", "one-based"]);
+    // The window holds bases 901-1050 around the TSS at base 1001, like a region given with the same coordinates
+    let table = read_gz(&format!("{}/count.tsv.gz", output));
+    assert_eq!(table.lines().nth(1), Some("chr1\t901\t1050\tplus\t1"));
+}
+
+#[test]
+fn grouped_regions_count_a_read_once_per_group() {
+    let fixture = Fixture::new("bulk_group_map");
+    // A read across the two exons of gene A, one across exons of genes A and B, and one inside the exon of gene B
+    let reads = [
+        "same_gene 0 chr1 1071 60 60M * 0 0 * * NH:i:1",
+        "two_genes 0 chr1 1171 60 60M * 0 0 * * NH:i:1",
+        "gene_b 0 chr1 1241 60 50M * 0 0 * * NH:i:1",
+    ];
+    let bam = fixture.bam("reads.bam", &reads);
+    let regions = fixture.file("regions.bed", "chr1\t1000\t1100\te1\nchr1\t1100\t1200\te2\nchr1\t1200\t1300\te3\n");
+    let groups = fixture.file("groups.tsv", "e1\tgeneA\ne2\tgeneA\ne3\tgeneB\n");
+    let counts = fixture.count_bulk(&bam, &regions, "grouped", &["--group-map", &groups]);
+    assert_eq!((counts["geneA"].as_str(), counts["geneB"].as_str()), ("2", "2"));
+    let exons = fixture.count_bulk(&bam, &regions, "exons", &[]);
+    assert_eq!([&exons["e1"], &exons["e2"], &exons["e3"]], ["1", "2", "2"]);
+}
+
+#[test]
+fn blacklisted_reads_are_not_counted_in_their_regions() {
+    let fixture = Fixture::new("bulk_blacklist");
+    // Reads overlapping both the region and the blacklisted interval, touching its edges, or skipping it by an intron
+    let reads = [
+        "overlaps 0 chr1 1121 60 50M * 0 0 * * NH:i:1",
+        "ends_at_start 0 chr1 1101 60 50M * 0 0 * * NH:i:1",
+        "starts_at_end 0 chr1 1201 60 50M * 0 0 * * NH:i:1",
+        "spliced_over 0 chr1 1131 60 20M80N20M * 0 0 * * NH:i:1",
+        "clear 0 chr1 1011 60 50M * 0 0 * * NH:i:1",
+    ];
+    let bam = fixture.bam("reads.bam", &reads);
+    let regions = fixture.file("regions.bed", "chr1\t1000\t1300\tregion\n");
+    let blacklist = fixture.file("blacklist.bed", "chr1\t1150\t1200\n");
+    let counts = fixture.count_bulk(&bam, &regions, "blacklisted", &["--blacklist", &blacklist]);
+    assert_eq!(counts["region"], "4");
+    assert_eq!(summary_count(&fixture.path("blacklisted"), "unassigned_reads", "blacklisted"), 1);
+    assert_eq!(fixture.count_bulk(&bam, &regions, "all", &[])["region"], "5");
+}
+
+#[test]
+fn primary_only_counts_one_alignment_per_read() {
+    let fixture = Fixture::new("bulk_primary_only");
+    // A read aligned three times in the region, once as primary, and a chimeric read with a supplementary alignment
+    let reads = [
+        "multi 0 chr1 1011 60 50M * 0 0 * * NH:i:3",
+        "multi 256 chr1 1111 0 50M * 0 0 * * NH:i:3",
+        "multi 256 chr1 1211 0 50M * 0 0 * * NH:i:3",
+        "chimeric 0 chr1 1051 60 50M * 0 0 * * NH:i:1",
+        "chimeric 2048 chr1 1151 60 50M * 0 0 * * NH:i:1",
+    ];
+    let bam = fixture.bam("reads.bam", &reads);
+    let regions = fixture.file("regions.bed", "chr1\t1000\t1300\tregion\n");
+    let counts = fixture.count_bulk(&bam, &regions, "primary", &["--primary-only", "--max-loci", "3"]);
+    assert_eq!(counts["region"], "2");
+    assert_eq!(summary_count(&fixture.path("primary"), "skipped_reads", "secondary"), 2);
+    assert_eq!(summary_count(&fixture.path("primary"), "skipped_reads", "supplementary"), 1);
+    assert_eq!(fixture.count_bulk(&bam, &regions, "all", &["--max-loci", "3"])["region"], "5");
+}
+
+#[test]
+fn primary_multimap_mode_ignores_max_loci() {
+    let fixture = Fixture::new("bulk_multimap_primary");
+    // A unique read, a read aligned three times with the secondary flag on two alignments, and one aligned three times
+    // by an aligner that only sets HI
+    let reads = [
+        "unique 0 chr1 1011 60 50M * 0 0 * * NH:i:1",
+        "flagged 0 chr1 1041 60 50M * 0 0 * * NH:i:3 HI:i:1",
+        "flagged 256 chr1 1141 0 50M * 0 0 * * NH:i:3 HI:i:2",
+        "flagged 256 chr1 1241 0 50M * 0 0 * * NH:i:3 HI:i:3",
+        "hi_only 0 chr1 1071 3 50M * 0 0 * * NH:i:3 HI:i:2",
+        "hi_only 0 chr1 1171 3 50M * 0 0 * * NH:i:3 HI:i:1",
+        "hi_only 0 chr1 1201 3 50M * 0 0 * * NH:i:3 HI:i:3",
+    ];
+    let bam = fixture.bam("reads.bam", &reads);
+    let regions = fixture.file("regions.bed", "chr1\t1000\t1300\tregion\n");
+    let count = |output: &str, options: &[&str]| fixture.count_bulk(&bam, &regions, output, options)["region"].clone();
+    assert_eq!(count("skip", &[]), "1");
+    assert_eq!(count("skip_three_loci", &["--max-loci", "3"]), "7");
+    // Each alignment of a multimapper adds 1/NH whatever --max-loci
+    assert_eq!(count("fraction", &["--multimap", "fraction"]), count("fraction_three_loci", &["--multimap", "fraction", "--max-loci", "3"]));
+    assert_eq!(count("fraction", &["--multimap", "fraction"]).parse::<f64>().unwrap(), 3.0);
+    // One alignment per read, with or without the secondary flag, whatever --max-loci
+    assert_eq!(count("primary", &["--multimap", "primary"]), "3");
+    assert_eq!(count("primary_one_locus", &["--multimap", "primary", "--max-loci", "1"]), "3");
+}
+
+#[test]
+fn em_splits_a_multimapper_by_the_unique_reads() {
+    let fixture = Fixture::new("bulk_multimap_em");
+    // Three unique reads in A, one in B, and a read aligned once in each: the EM share of A converges to p = (3 + p) / 5
+    let reads = [
+        "a1 0 chr1 1011 60 50M * 0 0 * * NH:i:1",
+        "a2 0 chr1 1021 60 50M * 0 0 * * NH:i:1",
+        "a3 0 chr1 1031 60 50M * 0 0 * * NH:i:1",
+        "multi 0 chr1 1041 3 50M * 0 0 * * NH:i:2 HI:i:1",
+        "b1 0 chr1 5011 60 50M * 0 0 * * NH:i:1",
+        "multi 256 chr1 5041 3 50M * 0 0 * * NH:i:2 HI:i:2",
+    ];
+    let bam = fixture.bam("reads.bam", &reads);
+    let regions = fixture.file("regions.bed", "chr1\t1000\t1100\tA\nchr1\t5000\t5100\tB\n");
+    let counts = fixture.count_bulk(&bam, &regions, "em", &["--multimap", "em"]);
+    let count = |region: &str| counts[region].parse::<f64>().unwrap();
+    assert!((count("A") - 3.75).abs() < 1e-3, "{:?}", counts);
+    assert!((count("B") - 1.25).abs() < 1e-3, "{:?}", counts);
+    assert_eq!(summary_count(&fixture.path("em"), "reads", "multimapped_em"), 1);
+    // A single round shares the read by the unique reads alone, which here is the same split
+    let one_round = fixture.count_bulk(&bam, &regions, "one_round", &["--multimap", "em", "--em-iterations", "1"]);
+    assert_eq!(one_round, counts);
+}
+
+#[test]
+fn read_filter_keeps_the_mate_in_the_region() {
+    let fixture = Fixture::new("bulk_read_mate");
+    // A pair whose read 1 overlaps the region and read 2 lies past its end, and a pair inside the region
+    let pairs = [
+        "split 99 chr1 1051 60 50M = 1401 400 * * NH:i:1",
+        "split 147 chr1 1401 60 50M = 1051 -400 * * NH:i:1",
+        "inside 99 chr1 1101 60 50M = 1151 100 * * NH:i:1",
+        "inside 147 chr1 1151 60 50M = 1101 -100 * * NH:i:1",
+    ];
+    let bam = fixture.bam("pairs.bam", &pairs);
+    let regions = fixture.file("regions.bed", "chr1\t1000\t1300\tregion\n");
+    assert_eq!(fixture.count_bulk(&bam, &regions, "both", &[])["region"], "3");
+    assert_eq!(fixture.count_bulk(&bam, &regions, "read1", &["--read", "1"])["region"], "2");
+    assert_eq!(fixture.count_bulk(&bam, &regions, "read2", &["--read", "2"])["region"], "1");
+    // The reads 1 of both pairs are left out by the mate filter
+    assert_eq!(summary_count(&fixture.path("read2"), "skipped_reads", "other_mate"), 2);
+
+    // Single-end reads are counted as with --read both
+    let single = fixture.bam("single.bam", &["single 0 chr1 1051 60 50M * 0 0 * * NH:i:1"]);
+    let output = fixture.output_dir("single_end");
+    let run = fixture.kai(&["bulk", &single, &regions, &output, "--read", "1"]);
+    assert_eq!(bulk_counts(&format!("{}/count.tsv.gz", output))["region"], "1");
+    assert!(String::from_utf8_lossy(&run.stderr).contains("Single-end reads found"), "{}", String::from_utf8_lossy(&run.stderr));
+}
+
+#[test]
+fn overlap_fraction_is_taken_of_the_read_or_the_region() {
+    let fixture = Fixture::new("bulk_min_overlap");
+    // Reads containing a 10-base region or covering half of it, and a spliced read whose two blocks cover 60 bases of
+    // a 100-base region
+    let reads = [
+        "contains 0 chr1 981 60 50M * 0 0 * * NH:i:1",
+        "half 0 chr1 1006 60 50M * 0 0 * * NH:i:1",
+        "spliced 0 chr1 2001 60 30M40N30M * 0 0 * * NH:i:1",
+    ];
+    let bam = fixture.bam("reads.bam", &reads);
+    let regions = fixture.file("regions.bed", "chr1\t1000\t1010\ttiny\nchr1\t2000\t2100\twide\n");
+    let counts = |output: &str, options: &[&str]| {
+        let counts = fixture.count_bulk(&bam, &regions, output, options);
+        [counts["tiny"].clone(), counts["wide"].clone()]
+    };
+    assert_eq!(counts("any", &[]), ["2", "1"]);
+    // The overlap of the spliced read adds up over its blocks
+    assert_eq!(counts("bases", &["--min-overlap", "31"]), ["0", "1"]);
+    assert_eq!(counts("of_read", &["--min-overlap-frac", "0.55"]), ["0", "1"]);
+    // Only the read containing the tiny region covers more than half of it
+    assert_eq!(counts("of_region", &["--min-overlap-frac", "0.55", "--frac-of", "region"]), ["1", "1"]);
+    assert_eq!(summary_count(&fixture.path("of_region"), "skipped_reads", "low_overlap"), 1);
+}
+
+#[test]
+fn spliced_and_unspliced_reads_are_counted_apart() {
+    let fixture = Fixture::new("bulk_reads_splicing");
+    // A spliced read, an unspliced one, one with a deletion and one with soft clips, all in the same region
+    let reads = [
+        "spliced 0 chr1 1001 60 50M1000N50M * 0 0 * * NH:i:1",
+        "unspliced 0 chr1 1101 60 100M * 0 0 * * NH:i:1",
+        "deletion 0 chr1 1201 60 50M5D50M * 0 0 * * NH:i:1",
+        "clipped 0 chr1 1301 60 5S90M5S * 0 0 * * NH:i:1",
+    ];
+    let bam = fixture.bam("reads.bam", &reads);
+    let regions = fixture.file("regions.bed", "chr1\t1000\t3000\tregion\n");
+    assert_eq!(fixture.count_bulk(&bam, &regions, "all", &[])["region"], "4");
+    assert_eq!(fixture.count_bulk(&bam, &regions, "spliced", &["--reads", "spliced"])["region"], "1");
+    assert_eq!(summary_count(&fixture.path("spliced"), "skipped_reads", "splicing"), 3);
+    assert_eq!(fixture.count_bulk(&bam, &regions, "unspliced", &["--reads", "unspliced"])["region"], "3");
+    assert_eq!(summary_count(&fixture.path("unspliced"), "skipped_reads", "splicing"), 1);
+}
+
+#[test]
+fn soft_clip_fraction_adds_both_ends() {
+    let fixture = Fixture::new("bulk_softclip");
+    // Reads of 100 bases clipped by 10 + 10, 5 + 5 and 30 bases, and one hard-clipped by 10 + 10
+    let reads = [
+        "both_ends 0 chr1 1011 60 10S80M10S * 0 0 * * NH:i:1",
+        "light 0 chr1 1021 60 5S90M5S * 0 0 * * NH:i:1",
+        "one_end 0 chr1 1031 60 30S70M * 0 0 * * NH:i:1",
+        "hard 0 chr1 1041 60 10H80M10H * 0 0 * * NH:i:1",
+    ];
+    let bam = fixture.bam("reads.bam", &reads);
+    let regions = fixture.file("regions.bed", "chr1\t1000\t1300\tregion\n");
+    // The reads clipped by 5 + 5 soft bases or by hard clips alone stay under 15%
+    assert_eq!(fixture.count_bulk(&bam, &regions, "soft", &["--max-softclip-frac", "0.15"])["region"], "2");
+    assert_eq!(summary_count(&fixture.path("soft"), "skipped_reads", "clipped"), 2);
+    let counts = fixture.count_bulk(&bam, &regions, "hard", &["--max-softclip-frac", "0.15", "--count-hardclip"]);
+    assert_eq!(counts["region"], "1");
+    assert_eq!(summary_count(&fixture.path("hard"), "skipped_reads", "clipped"), 3);
+}
+
+// Reads overlapping the region chr1:1000-1100, with their 5' or 3' end outside it or behind a soft clip
+const END_READS: [&str; 6] = [
+    "plus_over_start 0 chr1 981 60 50M * 0 0 * * NH:i:1",
+    "plus_clipped 0 chr1 1001 60 10S50M * 0 0 * * NH:i:1",
+    "plus_inside 0 chr1 1021 60 50M * 0 0 * * NH:i:1",
+    "plus_over_end 0 chr1 1071 60 50M * 0 0 * * NH:i:1",
+    "minus_clipped 16 chr1 1051 60 50M5S * 0 0 * * NH:i:1",
+    "minus_over_end 16 chr1 1071 60 50M * 0 0 * * NH:i:1",
+];
+
+#[test]
+fn read_ends_are_counted_past_soft_clips() {
+    let fixture = Fixture::new("bulk_count_mode");
+    let bam = fixture.bam("reads.bam", &END_READS);
+    let regions = fixture.file("regions.bed", "chr1\t1000\t1100\tregion\n");
+    assert_eq!(fixture.count_bulk(&bam, &regions, "overlap", &[])["region"], "6");
+    // The 5' ends of the clipped reads are their first and last aligned bases, inside the region
+    assert_eq!(fixture.count_bulk(&bam, &regions, "5prime", &["--count-mode", "5prime"])["region"], "4");
+    assert_eq!(fixture.count_bulk(&bam, &regions, "3prime", &["--count-mode", "3prime"])["region"], "5");
+}
+
+#[test]
+fn tn5_shift_moves_reads_over_a_peak_boundary() {
+    let fixture = Fixture::new("bulk_tn5_shift");
+    // Reads missing the peak chr1:1000-1100 by 3 bases on either side, one starting at its last 3 bases, and one
+    // starting 4 bases before it
+    let reads = [
+        "plus_before 0 chr1 948 60 50M * 0 0 * * NH:i:1",
+        "minus_after 16 chr1 1104 60 50M * 0 0 * * NH:i:1",
+        "plus_edge 0 chr1 1098 60 50M * 0 0 * * NH:i:1",
+        "plus_start 0 chr1 997 60 50M * 0 0 * * NH:i:1",
+    ];
+    let bam = fixture.bam("reads.bam", &reads);
+    let regions = fixture.file("regions.bed", "chr1\t1000\t1100\tpeak\nchr1\t1100\t1200\tflank\n");
+    assert_eq!(fixture.count_bulk(&bam, &regions, "unshifted", &[])["peak"], "2");
+    // Shifted +4 and -5 bases, the reads missing the peak reach it and the one starting at its last bases leaves it
+    assert_eq!(fixture.count_bulk(&bam, &regions, "shifted", &["--tn5-shift"])["peak"], "3");
+    // The 5' end of the read starting before the peak is shifted into it, and that of the read starting at its last
+    // bases into the flank after it
+    let ends = |output: &str, options: &[&str]| {
+        let counts = fixture.count_bulk(&bam, &regions, output, &[&["--count-mode", "5prime"], options].concat());
+        [counts["peak"].clone(), counts["flank"].clone()]
+    };
+    assert_eq!(ends("5prime_unshifted", &[]), ["1", "1"]);
+    assert_eq!(ends("5prime_shifted", &["--tn5-shift"]), ["1", "2"]);
+}
+
+#[test]
+fn insert_size_filter_takes_the_absolute_tlen() {
+    let fixture = Fixture::new("bulk_insert_size");
+    // A pair of an 80-base and one of a 200-base fragment, their reverse mates with a negative TLEN, and a single read
+    let reads = [
+        "short 99 chr1 1011 60 50M = 1041 80 * * NH:i:1",
+        "short 147 chr1 1041 60 50M = 1011 -80 * * NH:i:1",
+        "long 99 chr1 1061 60 50M = 1211 200 * * NH:i:1",
+        "long 147 chr1 1211 60 50M = 1061 -200 * * NH:i:1",
+        "single 0 chr1 1101 60 50M * 0 0 * * NH:i:1",
+    ];
+    let bam = fixture.bam("reads.bam", &reads);
+    let regions = fixture.file("regions.bed", "chr1\t1000\t1300\tregion\n");
+    assert_eq!(fixture.count_bulk(&bam, &regions, "short", &["--max-insert", "100"])["region"], "3");
+    assert_eq!(summary_count(&fixture.path("short"), "skipped_reads", "insert_size"), 2);
+    assert_eq!(fixture.count_bulk(&bam, &regions, "long", &["--min-insert", "150"])["region"], "3");
+    let options = ["--min-insert", "150", "--insert-filter-single", "drop"];
+    assert_eq!(fixture.count_bulk(&bam, &regions, "paired", &options)["region"], "2");
+    assert_eq!(summary_count(&fixture.path("paired"), "skipped_reads", "insert_size"), 3);
+}
+
+#[test]
+fn read_groups_get_a_column_each() {
+    let fixture = Fixture::new("bulk_read_groups");
+    let header = format!("{}@RG\tID:rg1\tSM:sample1\n@RG\tID:rg2\tSM:sample2\n", common::HEADER);
+    // Reads of both read groups in region A, of the second in region B, and one without RG tag
+    let reads = [
+        "a1 0 chr1 1011 60 50M * 0 0 * * NH:i:1 RG:Z:rg1",
+        "a2 0 chr1 1021 60 50M * 0 0 * * NH:i:1 RG:Z:rg1",
+        "a3 0 chr1 1031 60 50M * 0 0 * * NH:i:1 RG:Z:rg2",
+        "b1 0 chr1 2011 60 50M * 0 0 * * NH:i:1 RG:Z:rg2",
+        "b2 0 chr1 2021 60 50M * 0 0 * * NH:i:1",
+    ];
+    let bam = fixture.bam_with_header("reads.bam", &header, &reads);
+    let regions = fixture.file("regions.bed", "chr1\t1000\t1100\tA\nchr1\t2000\t2100\tB\n");
+    let output = fixture.output_dir("output");
+    fixture.kai(&["bulk", &bam, &regions, &output, "--by-read-group"]);
+    let table = read_gz(&format!("{}/count.tsv.gz", output));
+    assert_eq!(table.lines().collect::<Vec<_>>(), [
+        "Chr\tStart\tEnd\tRegion\trg1\trg2\tunknown_rg\tCount",
+        "chr1\t1000\t1100\tA\t2\t1\t0\t3",
+        "chr1\t2000\t2100\tB\t0\t1\t1\t2",
+    ]);
+}
+
+#[test]
+fn skip_qcfail_leaves_out_reads_failing_quality_checks() {
+    let fixture = Fixture::new("bulk_skip_qcfail");
+    let reads = [
+        "passed 0 chr1 1011 60 50M * 0 0 * * NH:i:1",
+        "qcfail 512 chr1 1021 60 50M * 0 0 * * NH:i:1",
+        "duplicate 1024 chr1 1031 60 50M * 0 0 * * NH:i:1",
+    ];
+    let bam = fixture.bam("reads.bam", &reads);
+    let regions = fixture.file("regions.bed", "chr1\t1000\t1100\tregion\n");
+    assert_eq!(fixture.count_bulk(&bam, &regions, "all", &[])["region"], "3");
+    let output = fixture.output_dir("skipped");
+    let run = fixture.kai(&["bulk", &bam, &regions, &output, "--skip-qcfail", "--exclude-flags", "dup"]);
+    assert_eq!(bulk_counts(&format!("{}/count.tsv.gz", output))["region"], "1");
+    assert_eq!(summary_count(&output, "excluded_flag", "qcfail"), 1);
+    assert_eq!(summary_count(&output, "excluded_flag", "dup"), 1);
+    // The log gives the mask of both flags
+    let log = String::from_utf8_lossy(&run.stderr);
+    assert!(log.contains("Excluded flags: 1536"), "{}", log);
+}
+
+#[test]
+fn gapped_overlaps_count_reads_spanning_a_region_by_their_intron() {
+    let fixture = Fixture::new("bulk_gapped_overlap");
+    // A read whose 500-base skip holds one region and reaches into another over the end of its first block
+    let bam = fixture.bam("reads.bam", &["spliced 0 chr1 1001 60 50M500N50M * 0 0 * * NH:i:1"]);
+    let regions = fixture.file("regions.bed", "chr1\t1200\t1300\tgap\nchr1\t1040\t1100\tedge\n");
+    let counts = |output: &str, options: &[&str]| {
+        let counts = fixture.count_bulk(&bam, &regions, output, options);
+        [counts["gap"].clone(), counts["edge"].clone()]
+    };
+    assert_eq!(counts("matched", &[]), ["0", "1"]);
+    // Overlapping the edge region by both a block and the skip still counts the read once
+    assert_eq!(counts("gapped", &["--count-gapped-overlap"]), ["1", "1"]);
+    assert_eq!(summary_count(&fixture.path("gapped"), "reads", "gapped_overlap"), 1);
+    let output = fixture.output_dir("split");
+    fixture.kai(&["bulk", &bam, &regions, &output, "--count-gapped-overlap", "--split-gapped-column"]);
+    let table = read_gz(&format!("{}/count.tsv.gz", output));
+    assert_eq!(table.lines().collect::<Vec<_>>(), [
+        "Chr\tStart\tEnd\tRegion\tCount\tGappedCount",
+        "chr1\t1040\t1100\tedge\t1\t0",
+        "chr1\t1200\t1300\tgap\t0\t1",
+    ]);
+}
+
+#[test]
+fn contained_reads_end_inside_the_region() {
+    let fixture = Fixture::new("bulk_contained");
+    // Reads inside chr1:1000-1100, ending at its last base, hanging one base over its end, and soft-clipped before
+    // its start
+    let reads = [
+        "inside 0 chr1 1001 60 50M * 0 0 * * NH:i:1",
+        "at_end 0 chr1 1051 60 50M * 0 0 * * NH:i:1",
+        "hanging 0 chr1 1052 60 50M * 0 0 * * NH:i:1",
+        "clipped 0 chr1 1001 60 5S45M * 0 0 * * NH:i:1",
+    ];
+    let bam = fixture.bam("reads.bam", &reads);
+    let regions = fixture.file("regions.bed", "chr1\t1000\t1100\tregion\n");
+    assert_eq!(fixture.count_bulk(&bam, &regions, "overlap", &[])["region"], "4");
+    assert_eq!(fixture.count_bulk(&bam, &regions, "contained", &["--contained"])["region"], "3");
+    assert_eq!(summary_count(&fixture.path("contained"), "skipped_reads", "not_contained"), 1);
+    let counts = fixture.count_bulk(&bam, &regions, "with_clips", &["--contained", "--contained-include-clips"]);
+    assert_eq!(counts["region"], "2");
+}
+
+#[test]
+fn unique_assignment_skips_a_read_shared_by_two_regions() {
+    let fixture = Fixture::new("bulk_assign_unique");
+    // Regions A and B overlapping over chr1:1080-1100, with a read in that overlap and one in each region alone
+    let reads = [
+        "only_a 0 chr1 1011 60 50M * 0 0 * * NH:i:1",
+        "shared 0 chr1 1061 60 50M * 0 0 * * NH:i:1",
+        "only_b 0 chr1 1131 60 50M * 0 0 * * NH:i:1",
+    ];
+    let bam = fixture.bam("reads.bam", &reads);
+    let regions = fixture.file("regions.bed", "chr1\t1000\t1100\tA\nchr1\t1080\t1200\tB\n");
+    let counts = |output: &str, options: &[&str]| {
+        let counts = fixture.count_bulk(&bam, &regions, output, options);
+        [counts["A"].clone(), counts["B"].clone()]
+    };
+    assert_eq!(counts("all", &[]), ["2", "2"]);
+    for engine in ["fetch", "sweep"] {
+        assert_eq!(counts(engine, &["--assign", "unique", "--engine", engine]), ["1", "1"]);
+        // The shared read is skipped once, and left out of each of its regions
+        assert_eq!(summary_count(&fixture.path(engine), "skipped_reads", "multiple_regions"), 1);
+        assert_eq!(summary_count(&fixture.path(engine), "unassigned_reads", "multiple_regions"), 2);
+    }
+}
+
+#[test]
+fn missing_nh_policies_on_reads_without_nh() {
+    let fixture = Fixture::new("bulk_missing_nh");
+    // Alignments of a BWA-style BAM file without NH tags: a unique read, and a read with a secondary alignment
+    let reads = [
+        "unique 0 chr1 1011 60 50M * 0 0 * * AS:i:50",
+        "repeat 0 chr1 1031 0 50M * 0 0 * * AS:i:50",
+        "repeat 256 chr1 1061 0 50M * 0 0 * * AS:i:50",
+    ];
+    let bam = fixture.bam("reads.bam", &reads);
+    let regions = fixture.file("regions.bed", "chr1\t1000\t1200\tregion\n");
+    let count = |policy: &str| fixture.count_bulk(&bam, &regions, policy, &["--missing-nh", policy])["region"].clone();
+    assert_eq!(count("pass"), "3");
+    assert_eq!(count("drop"), "0");
+    assert_eq!(summary_count(&fixture.path("drop"), "skipped_reads", "missing_nh"), 3);
+    // The secondary alignment makes the read a multimapper over --max-loci 1
+    assert_eq!(count("use-flags"), "2");
+    assert_eq!(summary_count(&fixture.path("use-flags"), "skipped_reads", "multimapped"), 1);
+    for policy in ["pass", "drop", "use-flags"] {
+        assert_eq!(summary_count(&fixture.path(policy), "reads", "missing_nh"), 3);
+    }
+}
+
+#[test]
+fn sweep_engine_counts_like_the_fetch_engine() {
+    let fixture = Fixture::new("bulk_sweep_engine");
+    let reads = common::scattered_reads(3000);
+    let bam = fixture.bam("reads.bam", &reads.iter().map(String::as_str).collect::<Vec<_>>());
+    let regions = fixture.file("regions.bed", &common::spaced_regions());
+    for options in [&[][..], &["--multimap", "fraction"], &["--strandedness", "forward", "--count-mode", "5prime"]] {
+        let (fetch, sweep) = (fixture.output_dir("fetch"), fixture.output_dir("sweep"));
+        fixture.kai(&[&["bulk", &bam, &regions, &fetch, "--engine", "fetch"], options].concat());
+        fixture.kai(&[&["bulk", &bam, &regions, &sweep, "--engine", "sweep"], options].concat());
+        let (fetch, sweep) = (read_gz(&format!("{}/count.tsv.gz", fetch)), read_gz(&format!("{}/count.tsv.gz", sweep)));
+        assert_eq!(fetch, sweep, "{:?}", options);
+    }
+}
+
+#[test]
+fn threads_give_identical_files() {
+    let fixture = Fixture::new("bulk_threads");
+    let reads = common::scattered_reads(3000);
+    let bam = fixture.bam("reads.bam", &reads.iter().map(String::as_str).collect::<Vec<_>>());
+    let regions = fixture.file("regions.bed", &common::spaced_regions());
+    for engine in ["fetch", "sweep"] {
+        let (one, four) = (fixture.output_dir("one"), fixture.output_dir("four"));
+        fixture.kai(&["bulk", &bam, &regions, &one, "--engine", engine, "--threads", "1"]);
+        fixture.kai(&["bulk", &bam, &regions, &four, "--engine", engine, "--threads", "4"]);
+        let (one, four) = (std::fs::read(format!("{}/count.tsv.gz", one)).unwrap(), std::fs::read(format!("{}/count.tsv.gz", four)).unwrap());
+        assert!(one == four, "count.tsv.gz differs between 1 and 4 threads with --engine {}", engine);
+    }
+}
+
+#[test]
+fn uncompressed_count_table_holds_the_same_counts() {
+    let fixture = Fixture::new("bulk_compression");
+    let bam = fixture.bam("reads.bam", &STRANDED_READS);
+    let regions = fixture.file("regions.bed", STRANDED_REGIONS);
+    let (gzip, none) = (fixture.output_dir("gzip"), fixture.output_dir("none"));
+    fixture.kai(&["bulk", &bam, &regions, &gzip, "--compression-level", "9"]);
+    fixture.kai(&["bulk", &bam, &regions, &none, "--compression", "none"]);
+    assert_eq!(std::fs::read_to_string(format!("{}/count.tsv", none)).unwrap(), read_gz(&format!("{}/count.tsv.gz", gzip)));
+}
+
+#[test]
+fn count_table_is_written_to_stdout() {
+    let fixture = Fixture::new("bulk_stdout");
+    let bam = fixture.bam("reads.bam", &STRANDED_READS);
+    let regions = fixture.file("regions.bed", STRANDED_REGIONS);
+    let run = fixture.kai(&["bulk", &bam, &regions, "-"]);
+    // Only the table goes to stdout, the log staying on stderr
+    let table = String::from_utf8(run.stdout).unwrap();
+    assert_eq!(table.lines().next(), Some("Chr\tStart\tEnd\tRegion\tCount"));
+    assert_eq!(table.lines().nth(1), Some("chr1\t100\t300\tplus\t5"));
+    assert_eq!(table.lines().count(), 4);
+    assert!(String::from_utf8_lossy(&run.stderr).contains("Running kai"));
+
+    let error = fixture.kai_error(&["single", &bam, &regions, "-"]);
+    assert!(error.contains("Single mode writes several files"), "{}", error);
+}
+
+#[test]
+fn normalised_columns_match_hand_computed_values() {
+    let fixture = Fixture::new("bulk_normalize");
+    // Three reads in a 100-base region and one in a 1000-base region: the per-kilobase rates are 30 and 1
+    let reads = [
+        "a1 0 chr1 1011 60 50M * 0 0 * * NH:i:1",
+        "a2 0 chr1 1021 60 50M * 0 0 * * NH:i:1",
+        "a3 0 chr1 1031 60 50M * 0 0 * * NH:i:1",
+        "b1 0 chr1 2011 60 50M * 0 0 * * NH:i:1",
+    ];
+    let bam = fixture.bam("reads.bam", &reads);
+    let regions = fixture.file("regions.bed", "chr1\t1000\t1100\tA\nchr1\t2000\t3000\tB\n");
+    let table = |output: &str, options: &[&str]| {
+        let output = fixture.output_dir(output);
+        fixture.kai(&[&["bulk", &bam, &regions, &output, "--normalize", "cpm,rpkm,tpm"], options].concat());
+        read_gz(&format!("{}/count.tsv.gz", output))
+    };
+    assert_eq!(table("counted", &[]).lines().collect::<Vec<_>>(), [
+        "Chr\tStart\tEnd\tRegion\tCount\tCPM\tRPKM\tTPM",
+        "chr1\t1000\t1100\tA\t3\t750000.0000\t7500000.0000\t967741.9355",
+        "chr1\t2000\t3000\tB\t1\t250000.0000\t250000.0000\t32258.0645",
+    ]);
+    // A library of a million reads leaves CPM as the counts, TPM not depending on it
+    assert_eq!(table("library", &["--library-size", "1000000"]).lines().collect::<Vec<_>>(), [
+        "Chr\tStart\tEnd\tRegion\tCount\tCPM\tRPKM\tTPM",
+        "chr1\t1000\t1100\tA\t3\t3.0000\t30.0000\t967741.9355",
+        "chr1\t2000\t3000\tB\t1\t1.0000\t1.0000\t32258.0645",
+    ]);
+}
+
+#[test]
+fn extended_reads_overlap_regions_past_their_3prime_end() {
+    let fixture = Fixture::new("bulk_extend_reads");
+    // Reads ending 150 bases before the region on either side, on the strand pointing towards it
+    let reads = [
+        "plus 0 chr1 1001 60 50M * 0 0 * * NH:i:1",
+        "minus 16 chr1 1451 60 50M * 0 0 * * NH:i:1",
+    ];
+    let bam = fixture.bam("reads.bam", &reads);
+    let regions = fixture.file("regions.bed", "chr1\t1200\t1300\tregion\n");
+    let count = |output: &str, options: &[&str]| fixture.count_bulk(&bam, &regions, output, options)["region"].clone();
+    assert_eq!(count("unextended", &[]), "0");
+    assert_eq!(count("200", &["--extend-reads", "200"]), "0");
+    assert_eq!(count("250", &["--extend-reads", "250"]), "2");
+    // --min-overlap is taken of the extended fragments, overlapping the region by 50 and 100 bases
+    assert_eq!(count("250_min", &["--extend-reads", "250", "--min-overlap", "51"]), "0");
+    assert_eq!(count("300_min", &["--extend-reads", "300", "--min-overlap", "51"]), "2");
+}
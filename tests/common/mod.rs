@@ -0,0 +1,189 @@
+// Helpers shared by the integration tests: SAM records written to indexed BAM files, and runs of the kai binary
+#![allow(dead_code)]
+use flate2::read::MultiGzDecoder;
+use rust_htslib::bam::{self, Read};
+use std::collections::HashMap;
+use std::io::{Read as _, Write};
+use std::path::PathBuf;
+use std::process::{Command, Output, Stdio};
+
+// Header of the test BAM files
+pub const HEADER: &str = "@HD\tVN:1.6\tSO:coordinate\n@SQ\tSN:chr1\tLN:100000\n@SQ\tSN:chr2\tLN:100000\n";
+
+// Directory of a test, emptied when created
+pub struct Fixture {
+    pub dir: PathBuf,
+}
+
+impl Fixture {
+    pub fn new(name: &str) -> Fixture {
+        let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join(name);
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        Fixture { dir }
+    }
+
+    // Function to get the path of a file of the fixture
+    pub fn path(&self, name: &str) -> String {
+        self.dir.join(name).to_string_lossy().into_owned()
+    }
+
+    // Function to write a text file, returning its path
+    pub fn file(&self, name: &str, content: &str) -> String {
+        let path = self.path(name);
+        std::fs::write(&path, content).unwrap();
+        path
+    }
+
+    // Function to write an indexed BAM file from SAM records given with their fields separated by spaces, sorted by
+    // position; returns its path
+    pub fn bam(&self, name: &str, records: &[&str]) -> String {
+        self.bam_with_header(name, HEADER, records)
+    }
+
+    pub fn bam_with_header(&self, name: &str, header: &str, records: &[&str]) -> String {
+        let mut records: Vec<Vec<&str>> = records.iter().map(|record| record.split_whitespace().collect()).collect();
+        records.sort_by_key(|fields| (fields[2] == "*", fields[2].to_string(), fields[3].parse::<i64>().unwrap()));
+        let mut sam = header.to_string();
+        for fields in records {
+            sam.push_str(&fields.join("\t"));
+            sam.push('\n');
+        }
+        let sam_path = self.file(&format!("{}.sam", name), &sam);
+        let path = self.path(name);
+        let mut reader = bam::Reader::from_path(&sam_path).unwrap();
+        let header = bam::Header::from_template(reader.header());
+        {
+            let mut writer = bam::Writer::from_path(&path, &header, bam::Format::Bam).unwrap();
+            for record in reader.records() {
+                writer.write(&record.unwrap()).unwrap();
+            }
+        }
+        bam::index::build(&path, None, bam::index::Type::Bai, 1).unwrap();
+        path
+    }
+
+    // Function to run kai, returning its output whether it succeeded or not
+    pub fn run(&self, args: &[&str]) -> Output {
+        Command::new(env!("CARGO_BIN_EXE_kai")).args(args).current_dir(&self.dir).output().unwrap()
+    }
+
+    // Function to run kai, failing the test with its log when it does not succeed
+    pub fn kai(&self, args: &[&str]) -> Output {
+        let output = self.run(args);
+        assert!(output.status.success(), "kai {} failed:\n{}", args.join(" "), String::from_utf8_lossy(&output.stderr));
+        output
+    }
+
+    // Function to run kai with the given text on its standard input, failing the test when it does not succeed
+    pub fn kai_with_stdin(&self, args: &[&str], input: &str) -> Output {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_kai"))
+            .args(args)
+            .current_dir(&self.dir)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        child.stdin.take().unwrap().write_all(input.as_bytes()).unwrap();
+        let output = child.wait_with_output().unwrap();
+        assert!(output.status.success(), "kai {} failed:\n{}", args.join(" "), String::from_utf8_lossy(&output.stderr));
+        output
+    }
+
+    // Function to run kai expecting a failure, returning its log
+    pub fn kai_error(&self, args: &[&str]) -> String {
+        let output = self.run(args);
+        assert!(!output.status.success(), "kai {} succeeded", args.join(" "));
+        String::from_utf8_lossy(&output.stderr).into_owned()
+    }
+
+    // Function to create an output directory of the fixture, returning its path
+    pub fn output_dir(&self, name: &str) -> String {
+        let path = self.path(name);
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        path
+    }
+
+    // Function to count a BAM file in bulk mode into a new output directory with the given options, returning the
+    // Count column per region
+    pub fn count_bulk(&self, bam: &str, regions: &str, output: &str, options: &[&str]) -> HashMap<String, String> {
+        let output = self.output_dir(output);
+        self.kai(&[&["bulk", bam, regions, &output], options].concat());
+        bulk_counts(&format!("{}/count.tsv.gz", output))
+    }
+}
+
+// Function to read a gzip-compressed file
+pub fn read_gz(path: &str) -> String {
+    let mut content = String::new();
+    MultiGzDecoder::new(std::fs::File::open(path).unwrap()).read_to_string(&mut content).unwrap();
+    content
+}
+
+// Function to read the Region and Count columns of a bulk count table
+pub fn bulk_counts(path: &str) -> HashMap<String, String> {
+    let table = if path.ends_with(".gz") { read_gz(path) } else { std::fs::read_to_string(path).unwrap() };
+    let mut lines = table.lines();
+    let header: Vec<&str> = lines.next().unwrap().split('\t').collect();
+    let region = header.iter().position(|&column| column == "Region").unwrap();
+    let count = header.iter().position(|&column| column == "Count").unwrap();
+    lines.map(|line| {
+        let fields: Vec<&str> = line.split('\t').collect();
+        (fields[region].to_string(), fields[count].to_string())
+    }).collect()
+}
+
+// Function to parse a MatrixMarket coordinate matrix into its dimensions and (row, column) -> value entries, checking
+// the header, the 1-based indices and the number of entries
+pub fn read_mtx(path: &str) -> ((usize, usize), HashMap<(usize, usize), f64>) {
+    let content = read_gz(path);
+    let mut lines = content.lines().filter(|line| !line.starts_with('%') || line.starts_with("%%"));
+    let banner = lines.next().unwrap();
+    assert!(banner.starts_with("%%MatrixMarket matrix coordinate"), "bad banner {}", banner);
+    let size: Vec<usize> = lines.next().unwrap().split(' ').map(|field| field.parse().unwrap()).collect();
+    let mut entries = HashMap::new();
+    for line in lines {
+        let fields: Vec<&str> = line.split(' ').collect();
+        let (row, column): (usize, usize) = (fields[0].parse().unwrap(), fields[1].parse().unwrap());
+        assert!((1..=size[0]).contains(&row) && (1..=size[1]).contains(&column), "entry {} outside {:?}", line, size);
+        assert!(entries.insert((row, column), fields[2].parse().unwrap()).is_none(), "duplicated entry {}", line);
+    }
+    assert_eq!(entries.len(), size[2]);
+    ((size[0], size[1]), entries)
+}
+
+// Function to read a count of summary.json in an output directory, given its section and key
+pub fn summary_count(output: &str, section: &str, key: &str) -> u64 {
+    let summary = std::fs::read_to_string(format!("{}/summary.json", output)).unwrap();
+    let section = &summary[summary.find(&format!("\"{}\": {{", section)).unwrap()..];
+    let value = &section[section.find(&format!("\"{}\": ", key)).unwrap() + key.len() + 4..];
+    value[..value.find([',', '\n']).unwrap()].parse().unwrap()
+}
+
+// Function to draw reads at pseudo-random positions of chr1 and chr2, with varied CIGARs, strands and NH tags, and CB
+// and UB tags of a few barcodes and UMIs
+pub fn scattered_reads(count: usize) -> Vec<String> {
+    let cigars = ["50M", "20M100N30M", "5S45M", "30M2D20M", "10M5I35M"];
+    let mut state: u64 = 42;
+    let mut next = |bound: u64| {
+        state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        (state >> 33) % bound
+    };
+    (0..count)
+        .map(|read| {
+            let chromosome = ["chr1", "chr2"][next(2) as usize];
+            let (pos, cigar, flag, nh) = (next(20000) + 1, cigars[next(5) as usize], [0, 16][next(2) as usize], 1 + next(4) / 3);
+            let (barcode, umi) = (["AAAA", "CCCC", "GGGG", "TTTT"][next(4) as usize], ["ACGT", "TGCA"][next(2) as usize]);
+            format!("r{} {} {} {} 60 {} * 0 0 * * NH:i:{} CB:Z:{} UB:Z:{}", read, flag, chromosome, pos, cigar, nh, barcode, umi)
+        })
+        .collect()
+}
+
+// Regions of 300 bases every 500 bases of chr1 and chr2, none overlapping another
+pub fn spaced_regions() -> String {
+    ["chr1", "chr2"].iter()
+        .flat_map(|chromosome| (0..40).map(move |i| format!("{}\t{}\t{}\t{}_{}\n", chromosome, i * 500, i * 500 + 300, chromosome, i)))
+        .collect()
+}
@@ -0,0 +1,45 @@
+// Integration tests of the messages kai exits with on bad inputs
+mod common;
+
+use common::Fixture;
+
+// Function to check that a failed run gives a message rather than a panic
+fn assert_message(error: &str, message: &str) {
+    assert!(error.contains(message), "{}", error);
+    assert!(!error.contains("panicked"), "{}", error);
+}
+
+#[test]
+fn non_numeric_bed_start_is_reported_with_its_line() {
+    let fixture = Fixture::new("errors_bed_start");
+    let bam = fixture.bam("reads.bam", &["read 0 chr1 1011 60 50M * 0 0 * * NH:i:1"]);
+    let regions = fixture.file("regions.bed", "chr1\t1000\t1100\tA\nchr1\tstart\t2100\tB\n");
+    let output = fixture.output_dir("output");
+    let error = fixture.kai_error(&["bulk", &bam, &regions, &output]);
+    assert_message(&error, "regions.bed:2");
+    assert_message(&error, "Non-numeric start coordinate");
+}
+
+#[test]
+fn bam_file_without_index_is_reported() {
+    let fixture = Fixture::new("errors_bam_index");
+    let bam = fixture.bam("reads.bam", &["read 0 chr1 1011 60 50M * 0 0 * * NH:i:1"]);
+    std::fs::remove_file(format!("{}.bai", bam)).unwrap();
+    let regions = fixture.file("regions.bed", "chr1\t1000\t1100\tA\n");
+    let output = fixture.output_dir("output");
+    let error = fixture.kai_error(&["bulk", &bam, &regions, &output]);
+    assert_message(&error, "has no index");
+    assert_message(&error, &bam);
+}
+
+#[test]
+fn bigwig_coverage_track_is_rejected() {
+    let fixture = Fixture::new("errors_bigwig");
+    let bam = fixture.bam("reads.bam", &["read 0 chr1 1011 60 50M * 0 0 * * NH:i:1"]);
+    let regions = fixture.file("regions.bed", "chr1\t1000\t1100\tA\n");
+    let output = fixture.output_dir("output");
+    let error = fixture.kai_error(&["bulk", &bam, &regions, &output, "--coverage-out", "bigwig"]);
+    assert_message(&error, "--coverage-out bigwig is not supported");
+    // Nothing is written
+    assert_eq!(std::fs::read_dir(&output).unwrap().count(), 0);
+}
@@ -0,0 +1,78 @@
+// Integration tests of the featureCounts layout of the bulk outputs (--output-format featurecounts)
+mod common;
+
+use common::Fixture;
+use std::collections::HashMap;
+
+// Reads of two overlapping regions A and B on chr1: one counted in both, one below --min-mapq in both, one unmapped
+// read placed at its mate, and reads outside every region on chr1 and chr2
+const READS: [&str; 7] = [
+    "both 0 chr1 151 60 30M * 0 0 * * NH:i:1",
+    "only_a 0 chr1 101 60 30M * 0 0 * * NH:i:1",
+    "low_mapq 0 chr1 161 5 30M * 0 0 * * NH:i:1",
+    "unmapped 4 chr1 171 0 * * 0 0 * *",
+    "only_b 0 chr1 281 60 50M * 0 0 * * NH:i:1",
+    "outside 0 chr1 5001 60 50M * 0 0 * * NH:i:1",
+    "other_contig 0 chr2 1001 60 50M * 0 0 * * NH:i:1",
+];
+const REGIONS: &str = "chr1\t100\t200\tA\nchr1\t150\t400\tB\n";
+
+// Function to read the .summary file as its header and the counts of its first column per status
+fn read_summary(path: &str) -> (String, Vec<(String, u64)>) {
+    let content = std::fs::read_to_string(path).unwrap();
+    let mut lines = content.lines();
+    let header = lines.next().unwrap().to_string();
+    let rows = lines
+        .map(|line| {
+            let (status, count) = line.split_once('\t').unwrap();
+            (status.to_string(), count.parse().unwrap())
+        })
+        .collect();
+    (header, rows)
+}
+
+#[test]
+fn summary_tallies_every_read_once() {
+    let fixture = Fixture::new("featurecounts_summary");
+    let bam = fixture.bam("reads.bam", &READS);
+    let regions = fixture.file("regions.bed", REGIONS);
+    let output = fixture.output_dir("output");
+    fixture.kai(&["bulk", &bam, &regions, &output, "--output-format", "featurecounts", "--min-mapq", "10"]);
+
+    let (header, rows) = read_summary(&format!("{}/featurecounts.txt.summary", output));
+    assert_eq!(header, format!("Status\t{}", bam));
+    let statuses: Vec<&str> = rows.iter().map(|(status, _)| status.as_str()).collect();
+    assert_eq!(statuses, [
+        "Assigned",
+        "Unassigned_Unmapped",
+        "Unassigned_Read_Type",
+        "Unassigned_Singleton",
+        "Unassigned_MappingQuality",
+        "Unassigned_Chimera",
+        "Unassigned_FragmentLength",
+        "Unassigned_Duplicate",
+        "Unassigned_MultiMapping",
+        "Unassigned_Secondary",
+        "Unassigned_NonSplit",
+        "Unassigned_NoFeatures",
+        "Unassigned_Overlapping_Length",
+        "Unassigned_Ambiguity",
+    ]);
+    // The column sums to the reads of the BAM file, including those outside every region
+    assert_eq!(rows.iter().map(|(_, count)| count).sum::<u64>(), READS.len() as u64);
+    let counts: HashMap<&str, u64> = rows.iter().map(|(status, count)| (status.as_str(), *count)).collect();
+    assert_eq!(counts["Assigned"], 3);
+    assert_eq!(counts["Unassigned_MappingQuality"], 1);
+    assert_eq!(counts["Unassigned_Unmapped"], 1);
+    assert_eq!(counts["Unassigned_NoFeatures"], 2);
+}
+
+#[test]
+fn summary_needs_the_bam_index() {
+    let fixture = Fixture::new("featurecounts_stream");
+    let bam = fixture.bam("reads.bam", &READS);
+    let regions = fixture.file("regions.bed", REGIONS);
+    let output = fixture.output_dir("output");
+    let error = fixture.kai_error(&["bulk", &bam, &regions, &output, "--output-format", "featurecounts", "--stream"]);
+    assert!(error.contains("cannot be used with --stream"), "{}", error);
+}
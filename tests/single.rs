@@ -0,0 +1,309 @@
+// Integration tests of the single-cell matrices
+mod common;
+
+use common::{read_gz, read_mtx, summary_count, Fixture};
+
+// Reads of 20 barcodes over three regions, each barcode reading a different number of times into each region
+fn barcoded_reads() -> Vec<String> {
+    let mut reads = Vec::new();
+    for barcode in 0..20 {
+        let sequence: String = (0..6).map(|i| ['A', 'C', 'G', 'T'][(barcode >> (2 * i)) & 3]).collect();
+        for region in 0..3 {
+            for copy in 0..(barcode + region) % 4 {
+                let pos = 1 + region * 2000 + barcode * 10 + copy;
+                reads.push(format!("r{}_{}_{} 0 chr1 {} 60 50M * 0 0 * * CB:Z:{}", barcode, region, copy, pos, sequence));
+            }
+        }
+    }
+    reads
+}
+
+const REGIONS: &str = "chr1\t0\t1000\tA\nchr1\t2000\t3000\tB\nchr1\t4000\t5000\tC\n";
+
+#[test]
+fn matrices_are_byte_identical_between_runs() {
+    let fixture = Fixture::new("single_reproducible");
+    let reads = barcoded_reads();
+    let bam = fixture.bam("reads.bam", &reads.iter().map(String::as_str).collect::<Vec<_>>());
+    let regions = fixture.file("regions.bed", REGIONS);
+    let (first, second) = (fixture.output_dir("first"), fixture.output_dir("second"));
+    fixture.kai(&["single", &bam, &regions, &first]);
+    fixture.kai(&["single", &bam, &regions, &second]);
+    for file in ["matrix.mtx.gz", "barcodes.tsv.gz", "features.tsv.gz"] {
+        let (a, b) = (std::fs::read(format!("{}/{}", first, file)).unwrap(), std::fs::read(format!("{}/{}", second, file)).unwrap());
+        assert!(a == b, "{} differs between runs", file);
+    }
+
+    // Entries are sorted by feature, then barcode
+    let matrix = read_gz(&format!("{}/matrix.mtx.gz", first));
+    let entries: Vec<(usize, usize)> = matrix.lines()
+        .filter(|line| !line.starts_with('%'))
+        .skip(1)
+        .map(|line| {
+            let fields: Vec<usize> = line.split(' ').take(2).map(|field| field.parse().unwrap()).collect();
+            (fields[0], fields[1])
+        })
+        .collect();
+    assert!(entries.windows(2).all(|pair| pair[0] < pair[1]));
+    let ((features, _), _) = read_mtx(&format!("{}/matrix.mtx.gz", first));
+    assert_eq!(features, 3);
+}
+
+#[test]
+fn matrix_entries_match_the_barcode_counts() {
+    let fixture = Fixture::new("single_round_trip");
+    let reads = barcoded_reads();
+    let bam = fixture.bam("reads.bam", &reads.iter().map(String::as_str).collect::<Vec<_>>());
+    let regions = fixture.file("regions.bed", REGIONS);
+    let output = fixture.output_dir("output");
+    fixture.kai(&["single", &bam, &regions, &output]);
+
+    let ((n_features, n_barcodes), entries) = read_mtx(&format!("{}/matrix.mtx.gz", output));
+    let features: Vec<String> = read_gz(&format!("{}/features.tsv.gz", output)).lines().map(|line| line.split('\t').next().unwrap().to_string()).collect();
+    let barcodes: Vec<String> = read_gz(&format!("{}/barcodes.tsv.gz", output)).lines().map(str::to_string).collect();
+    assert_eq!((n_features, n_barcodes), (features.len(), barcodes.len()));
+    // The last barcode has entries, so that an index shifted by one would fall outside the matrix
+    assert!(entries.keys().any(|&(_, column)| column == n_barcodes));
+
+    let mut listed = 0;
+    for line in read_gz(&format!("{}/count_barcodes.tsv.gz", output)).lines().skip(1) {
+        let fields: Vec<&str> = line.split('\t').collect();
+        let row = features.iter().position(|feature| feature == fields[0]).unwrap() + 1;
+        let column = barcodes.iter().position(|barcode| barcode == fields[1]).unwrap() + 1;
+        assert_eq!(entries.get(&(row, column)), Some(&fields[2].parse::<f64>().unwrap()), "{}", line);
+        listed += 1;
+    }
+    assert_eq!(listed, entries.len());
+}
+
+#[test]
+fn stranded_counting_follows_the_region_strand() {
+    let fixture = Fixture::new("single_stranded");
+    // Reads of two barcodes on either strand of a + region and a - region
+    let reads = [
+        "a1 0 chr1 111 60 50M * 0 0 * * CB:Z:AAAA",
+        "a2 16 chr1 131 60 50M * 0 0 * * CB:Z:AAAA",
+        "a3 16 chr1 1011 60 50M * 0 0 * * CB:Z:AAAA",
+        "c1 0 chr1 151 60 50M * 0 0 * * CB:Z:CCCC",
+        "c2 0 chr1 171 60 50M * 0 0 * * CB:Z:CCCC",
+        "c3 0 chr1 1031 60 50M * 0 0 * * CB:Z:CCCC",
+    ];
+    let bam = fixture.bam("reads.bam", &reads);
+    let regions = fixture.file("regions.bed", "chr1\t100\t300\tplus\t0\t+\nchr1\t1000\t1200\tminus\t0\t-\n");
+    let counts = |strandedness: &str| {
+        let output = fixture.output_dir(strandedness);
+        fixture.kai(&["single", &bam, &regions, &output, "--strandedness", strandedness]);
+        let barcodes = read_gz(&format!("{}/barcodes.tsv.gz", output));
+        let barcodes: Vec<&str> = barcodes.lines().collect();
+        // Features are named by the name column, the second of three-column features.tsv.gz
+        let features = read_gz(&format!("{}/features.tsv.gz", output));
+        let features: Vec<&str> = features.lines().map(|line| line.split('\t').nth(1).unwrap_or(line)).collect();
+        let (_, entries) = read_mtx(&format!("{}/matrix.mtx.gz", output));
+        let mut counts: Vec<(String, String, f64)> = entries.into_iter()
+            .map(|((feature, barcode), count)| (features[feature - 1].to_string(), barcodes[barcode - 1].to_string(), count))
+            .collect();
+        counts.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        counts
+    };
+    let count = |feature: &str, barcode: &str, count: f64| (feature.to_string(), barcode.to_string(), count);
+    assert_eq!(counts("none"), [count("minus", "AAAA", 1.0), count("minus", "CCCC", 1.0), count("plus", "AAAA", 2.0), count("plus", "CCCC", 2.0)]);
+    assert_eq!(counts("forward"), [count("minus", "AAAA", 1.0), count("plus", "AAAA", 1.0), count("plus", "CCCC", 2.0)]);
+    assert_eq!(counts("reverse"), [count("minus", "CCCC", 1.0), count("plus", "AAAA", 1.0)]);
+}
+
+#[test]
+fn read_ends_are_counted_past_soft_clips() {
+    let fixture = Fixture::new("single_count_mode");
+    // Reads of one barcode overlapping the region, with their 5' or 3' end outside it or behind a soft clip
+    let reads = [
+        "plus_over_start 0 chr1 981 60 50M * 0 0 * * CB:Z:AAAA",
+        "plus_clipped 0 chr1 1001 60 10S50M * 0 0 * * CB:Z:AAAA",
+        "plus_inside 0 chr1 1021 60 50M * 0 0 * * CB:Z:AAAA",
+        "plus_over_end 0 chr1 1071 60 50M * 0 0 * * CB:Z:AAAA",
+        "minus_clipped 16 chr1 1051 60 50M5S * 0 0 * * CB:Z:AAAA",
+        "minus_over_end 16 chr1 1071 60 50M * 0 0 * * CB:Z:AAAA",
+    ];
+    let bam = fixture.bam("reads.bam", &reads);
+    let regions = fixture.file("regions.bed", "chr1\t1000\t1100\tregion\n");
+    let count = |mode: &str| {
+        let output = fixture.output_dir(mode);
+        fixture.kai(&["single", &bam, &regions, &output, "--count-mode", mode]);
+        let (_, entries) = read_mtx(&format!("{}/matrix.mtx.gz", output));
+        entries.get(&(1, 1)).copied()
+    };
+    assert_eq!(count("overlap"), Some(6.0));
+    assert_eq!(count("5prime"), Some(4.0));
+    assert_eq!(count("3prime"), Some(5.0));
+}
+
+#[test]
+fn barcodes_one_mismatch_from_two_listed_ones_stay_unlisted() {
+    let fixture = Fixture::new("single_correct_barcodes");
+    // Reads of a listed barcode, of one a mismatch away from it, of one a mismatch away from both ACCA and AGCA, and of
+    // one far from every listed barcode
+    let reads = [
+        "exact 0 chr1 1011 60 50M * 0 0 * * CB:Z:AAAA",
+        "corrected 0 chr1 1021 60 50M * 0 0 * * CB:Z:AAAT",
+        "ambiguous 0 chr1 1031 60 50M * 0 0 * * CB:Z:ATCA",
+        "distant 0 chr1 1041 60 50M * 0 0 * * CB:Z:TTTT",
+    ];
+    let bam = fixture.bam("reads.bam", &reads);
+    let regions = fixture.file("regions.bed", "chr1\t1000\t1100\tregion\n");
+    let whitelist = fixture.file("whitelist.txt", "AAAA\nACCA\nAGCA\n");
+    let output = fixture.output_dir("output");
+    fixture.kai(&["single", &bam, &regions, &output, "--cell-barcodes", &whitelist, "--correct-barcodes"]);
+
+    let barcodes = read_gz(&format!("{}/barcodes.tsv.gz", output));
+    let column = barcodes.lines().position(|barcode| barcode == "AAAA").unwrap() + 1;
+    let (_, entries) = read_mtx(&format!("{}/matrix.mtx.gz", output));
+    assert_eq!(entries.into_iter().collect::<Vec<_>>(), [((1, column), 2.0)]);
+    assert_eq!(summary_count(&output, "barcode_reads", "listed"), 1);
+    assert_eq!(summary_count(&output, "barcode_reads", "corrected"), 1);
+    assert_eq!(summary_count(&output, "barcode_reads", "unlisted"), 2);
+    assert_eq!(summary_count(&output, "skipped_reads", "unlisted_barcode"), 2);
+}
+
+#[test]
+fn regions_without_reads_are_listed_as_features() {
+    let fixture = Fixture::new("single_empty_features");
+    let bam = fixture.bam("reads.bam", &["a1 0 chr1 1011 60 50M * 0 0 * * CB:Z:AAAA", "c1 0 chr1 3011 60 50M * 0 0 * * CB:Z:CCCC"]);
+    // The second region has no reads
+    let regions = fixture.file("regions.bed", "chr1\t1000\t1100\tfirst\nchr1\t2000\t2100\tempty\nchr1\t3000\t3100\tlast\n");
+    let features = |output: &str, options: &[&str]| {
+        let output = fixture.output_dir(output);
+        fixture.kai(&[&["single", &bam, &regions, &output], options].concat());
+        let features: Vec<String> = read_gz(&format!("{}/features.tsv.gz", output)).lines().map(|line| line.split('\t').next().unwrap().to_string()).collect();
+        let ((rows, _), entries) = read_mtx(&format!("{}/matrix.mtx.gz", output));
+        assert_eq!(rows, features.len());
+        let mut rows: Vec<usize> = entries.keys().map(|&(row, _)| row).collect();
+        rows.sort();
+        (features, rows)
+    };
+    assert_eq!(features("all", &[]), (vec!["first".to_string(), "empty".to_string(), "last".to_string()], vec![1, 3]));
+    let observed = features("observed", &["--observed-features-only"]);
+    assert_eq!(observed, (vec!["first".to_string(), "last".to_string()], vec![1, 2]));
+}
+
+#[test]
+fn sweep_engine_counts_like_the_fetch_engine() {
+    let fixture = Fixture::new("single_sweep_engine");
+    let reads = common::scattered_reads(3000);
+    let bam = fixture.bam("reads.bam", &reads.iter().map(String::as_str).collect::<Vec<_>>());
+    let regions = fixture.file("regions.bed", &common::spaced_regions());
+    let (fetch, sweep) = (fixture.output_dir("fetch"), fixture.output_dir("sweep"));
+    fixture.kai(&["single", &bam, &regions, &fetch, "--engine", "fetch", "--umi-tag", "UB"]);
+    fixture.kai(&["single", &bam, &regions, &sweep, "--engine", "sweep", "--umi-tag", "UB"]);
+    for file in ["matrix.mtx.gz", "barcodes.tsv.gz", "features.tsv.gz"] {
+        assert_eq!(read_gz(&format!("{}/{}", fetch, file)), read_gz(&format!("{}/{}", sweep, file)), "{}", file);
+    }
+}
+
+#[test]
+fn threads_give_identical_files() {
+    let fixture = Fixture::new("single_threads");
+    let reads = common::scattered_reads(3000);
+    let bam = fixture.bam("reads.bam", &reads.iter().map(String::as_str).collect::<Vec<_>>());
+    let regions = fixture.file("regions.bed", &common::spaced_regions());
+    let (one, four) = (fixture.output_dir("one"), fixture.output_dir("four"));
+    fixture.kai(&["single", &bam, &regions, &one, "--threads", "1"]);
+    fixture.kai(&["single", &bam, &regions, &four, "--threads", "4"]);
+    for file in ["matrix.mtx.gz", "barcodes.tsv.gz", "features.tsv.gz", "count_barcodes.tsv.gz"] {
+        let (a, b) = (std::fs::read(format!("{}/{}", one, file)).unwrap(), std::fs::read(format!("{}/{}", four, file)).unwrap());
+        assert!(a == b, "{} differs between 1 and 4 threads", file);
+    }
+}
+
+#[test]
+fn low_memory_matrices_are_identical() {
+    let fixture = Fixture::new("single_low_memory");
+    let reads = common::scattered_reads(3000);
+    let bam = fixture.bam("reads.bam", &reads.iter().map(String::as_str).collect::<Vec<_>>());
+    let regions = fixture.file("regions.bed", &common::spaced_regions());
+    let (memory, disk, tmp) = (fixture.output_dir("memory"), fixture.output_dir("disk"), fixture.output_dir("tmp"));
+    fixture.kai(&["single", &bam, &regions, &memory]);
+    fixture.kai(&["single", &bam, &regions, &disk, "--low-memory", "--tmp-dir", &tmp]);
+    for file in ["matrix.mtx.gz", "barcodes.tsv.gz", "features.tsv.gz", "count_barcodes.tsv.gz"] {
+        let (a, b) = (std::fs::read(format!("{}/{}", memory, file)).unwrap(), std::fs::read(format!("{}/{}", disk, file)).unwrap());
+        assert!(a == b, "{} differs with --low-memory", file);
+    }
+    // The spill files are removed
+    assert_eq!(std::fs::read_dir(&tmp).unwrap().count(), 0);
+}
+
+#[test]
+fn cellranger_layout_is_read_like_read_10x_mtx() {
+    let fixture = Fixture::new("single_cellranger");
+    let reads = ["a1 0 chr1 1011 60 50M * 0 0 * * CB:Z:AAAA", "c1 0 chr1 3011 60 50M * 0 0 * * CB:Z:CCCC"];
+    let bam = fixture.bam("reads.bam", &reads);
+    let regions = fixture.file("regions.bed", "chr1\t1000\t1100\tfirst\nchr1\t2000\t2100\tempty\nchr1\t3000\t3100\tlast\n");
+    // The output directory is created, holding the three files read_10x_mtx reads besides summary.json
+    let output = fixture.path("sample/matrix");
+    fixture.kai(&["single", &bam, &regions, &output, "--output-format", "cellranger", "--feature-type", "Gene Expression"]);
+    let mut files: Vec<String> = std::fs::read_dir(&output).unwrap().map(|entry| entry.unwrap().file_name().into_string().unwrap()).collect();
+    files.sort();
+    assert_eq!(files, ["barcodes.tsv.gz", "features.tsv.gz", "matrix.mtx.gz", "summary.json"]);
+    let features = read_gz(&format!("{}/features.tsv.gz", output));
+    assert_eq!(features.lines().collect::<Vec<_>>(), [
+        "first\tfirst\tGene Expression",
+        "empty\tempty\tGene Expression",
+        "last\tlast\tGene Expression",
+    ]);
+    let barcodes = read_gz(&format!("{}/barcodes.tsv.gz", output));
+    assert_eq!(barcodes.lines().collect::<Vec<_>>(), ["AAAA", "CCCC"]);
+    // Features are the rows and barcodes the columns, as read_10x_mtx transposes them
+    let ((rows, columns), entries) = read_mtx(&format!("{}/matrix.mtx.gz", output));
+    assert_eq!((rows, columns), (3, 2));
+    let mut entries: Vec<((usize, usize), f64)> = entries.into_iter().collect();
+    entries.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    assert_eq!(entries, [((1, 1), 1.0), ((3, 2), 1.0)]);
+}
+
+#[test]
+fn uncompressed_outputs_hold_the_same_content() {
+    let fixture = Fixture::new("single_compression");
+    let reads = common::scattered_reads(1000);
+    let bam = fixture.bam("reads.bam", &reads.iter().map(String::as_str).collect::<Vec<_>>());
+    let regions = fixture.file("regions.bed", &common::spaced_regions());
+    let (gzip, fast, none) = (fixture.output_dir("gzip"), fixture.output_dir("fast"), fixture.output_dir("none"));
+    fixture.kai(&["single", &bam, &regions, &gzip]);
+    fixture.kai(&["single", &bam, &regions, &fast, "--compression-level", "1"]);
+    fixture.kai(&["single", &bam, &regions, &none, "--compression", "none"]);
+    for file in ["matrix.mtx", "barcodes.tsv", "features.tsv", "count_barcodes.tsv"] {
+        let content = read_gz(&format!("{}/{}.gz", gzip, file));
+        assert_eq!(read_gz(&format!("{}/{}.gz", fast, file)), content, "{}", file);
+        assert_eq!(std::fs::read_to_string(format!("{}/{}", none, file)).unwrap(), content, "{}", file);
+        assert!(!std::path::Path::new(&format!("{}/{}.gz", none, file)).exists(), "{}.gz", file);
+    }
+}
+
+#[test]
+fn transposed_matrix_swaps_rows_and_columns() {
+    let fixture = Fixture::new("single_transpose");
+    let reads = barcoded_reads();
+    let bam = fixture.bam("reads.bam", &reads.iter().map(String::as_str).collect::<Vec<_>>());
+    let regions = fixture.file("regions.bed", REGIONS);
+    let (features, barcodes) = (fixture.output_dir("features"), fixture.output_dir("barcodes"));
+    fixture.kai(&["single", &bam, &regions, &features]);
+    fixture.kai(&["single", &bam, &regions, &barcodes, "--transpose"]);
+    for file in ["barcodes.tsv.gz", "features.tsv.gz"] {
+        assert_eq!(read_gz(&format!("{}/{}", features, file)), read_gz(&format!("{}/{}", barcodes, file)), "{}", file);
+    }
+    let ((rows, columns), entries) = read_mtx(&format!("{}/matrix.mtx.gz", features));
+    let ((transposed_rows, transposed_columns), transposed) = read_mtx(&format!("{}/matrix.mtx.gz", barcodes));
+    assert_eq!((transposed_rows, transposed_columns), (columns, rows));
+    let swapped = transposed.into_iter().map(|((barcode, feature), count)| ((feature, barcode), count)).collect();
+    assert_eq!(entries, swapped);
+
+    // Entries stay sorted by row, then column, and a comment line gives the axes
+    let matrix = read_gz(&format!("{}/matrix.mtx.gz", barcodes));
+    assert_eq!(matrix.lines().nth(1), Some("% rows: barcodes (barcodes.tsv.gz), columns: features (features.tsv.gz)"));
+    let entries: Vec<(usize, usize)> = matrix.lines()
+        .filter(|line| !line.starts_with('%'))
+        .skip(1)
+        .map(|line| {
+            let fields: Vec<usize> = line.split(' ').take(2).map(|field| field.parse().unwrap()).collect();
+            (fields[0], fields[1])
+        })
+        .collect();
+    assert!(entries.windows(2).all(|pair| pair[0] < pair[1]));
+}